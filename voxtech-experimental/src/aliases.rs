@@ -27,3 +27,6 @@ pub type PRwLockWriteGuard<'a, T> =
   parking_lot::RwLockWriteGuard<'a, T>;
 pub type MappedPRwLockWriteGuard<'a, T> =
   parking_lot::MappedRwLockWriteGuard<'a, T>;
+
+/* --- parking_lot::Condvar関連 --- */
+pub type PCondvar = parking_lot::Condvar;
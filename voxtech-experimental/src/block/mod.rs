@@ -0,0 +1,285 @@
+//! Block
+//! ブロックの定義とその登録簿
+
+use serde::{Deserialize, Serialize};
+
+use crate::registry::{Registry, RegistryDef};
+use crate::types::TileFace;
+
+/// ブロックの数値ID
+pub type BlockId = u16;
+
+/// 空気ブロックのID
+pub const AIR: BlockId = 0;
+
+/// 組み込みのブロック定義
+const BUILTIN_BLOCKS: &str =
+  include_str!("blocks.json");
+
+/// ブロック定義の読み込み時のエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockRegistryError {
+  /// 同じIDが複数回定義された
+  DuplicateId(BlockId),
+  /// 同じ名前が複数回定義された
+  DuplicateName(String),
+  /// 光量が0..=15の範囲外
+  InvalidLightEmission { name: String, value: u8 },
+  /// 空気(ID 0)が空気として定義されていない
+  InvalidAir,
+}
+impl std::fmt::Display for BlockRegistryError {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::DuplicateId(id) => {
+        write!(
+          f,
+          "block id {id} is defined twice"
+        )
+      }
+      Self::DuplicateName(name) => {
+        write!(
+          f,
+          "block name `{name}` is defined twice"
+        )
+      }
+      Self::InvalidLightEmission { name, value } => {
+        write!(
+          f,
+          "block `{name}` has light emission {value} (max 15)"
+        )
+      }
+      Self::InvalidAir => f.write_str(
+        "block id 0 must be non-solid and transparent",
+      ),
+    }
+  }
+}
+impl std::error::Error for BlockRegistryError {}
+
+/// 面毎のテクスチャ名
+///
+/// 個別の面、`side`(東西南北)、`all`の順に優先される。
+#[derive(
+  Debug,
+  Clone,
+  Default,
+  PartialEq,
+  Serialize,
+  Deserialize,
+)]
+#[serde(default)]
+pub struct BlockTextures {
+  pub all: Option<String>,
+  pub side: Option<String>,
+  pub west: Option<String>,
+  pub east: Option<String>,
+  pub south: Option<String>,
+  pub north: Option<String>,
+  pub bottom: Option<String>,
+  pub top: Option<String>,
+}
+impl BlockTextures {
+  /// 面に使用するテクスチャ名
+  pub fn face(&self, face: TileFace) -> Option<&str> {
+    let (own, side) = match face {
+      TileFace::WST => (&self.west, true),
+      TileFace::EST => (&self.east, true),
+      TileFace::STH => (&self.south, true),
+      TileFace::NTH => (&self.north, true),
+      TileFace::BTM => (&self.bottom, false),
+      TileFace::TOP => (&self.top, false),
+      TileFace::UNDEF => return None,
+    };
+    own
+      .as_ref()
+      .or(
+        self
+          .side
+          .as_ref()
+          .filter(|_| side),
+      )
+      .or(self.all.as_ref())
+      .map(String::as_str)
+  }
+}
+
+fn default_true() -> bool {
+  true
+}
+
+fn default_fluid_delay() -> u64 {
+  5
+}
+
+fn default_level_drop() -> u8 {
+  1
+}
+
+/// 流体の性質
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct FluidDef {
+  /// 更新の間隔(ティック)
+  #[serde(default = "default_fluid_delay")]
+  pub delay: u64,
+  /// 横に一ブロック広がる毎に下がる水位
+  #[serde(default = "default_level_drop")]
+  pub level_drop: u8,
+  /// 二つ以上の水源に挟まれた流れが水源になるか
+  #[serde(default)]
+  pub renewable: bool,
+}
+
+/// ブロックの定義
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct BlockDef {
+  pub id: BlockId,
+  /// 識別用の名前
+  pub name: String,
+  /// 表示用の名前
+  #[serde(default)]
+  pub display_name: String,
+  /// 当たり判定を持つか
+  #[serde(default = "default_true")]
+  pub solid: bool,
+  /// 光と視線を遮るか
+  #[serde(default = "default_true")]
+  pub opaque: bool,
+  #[serde(default)]
+  pub textures: BlockTextures,
+  /// 発する光量(0..=15)
+  #[serde(default)]
+  pub light_emission: u8,
+  /// 硬さ
+  #[serde(default)]
+  pub hardness: f32,
+  /// 流体の場合はその性質
+  #[serde(default)]
+  pub fluid: Option<FluidDef>,
+  /// 支えを失うと落下するか
+  #[serde(default)]
+  pub gravity: bool,
+}
+impl BlockDef {
+  /// 空気の定義
+  pub fn air() -> Self {
+    Self {
+      id: AIR,
+      name: "air".into(),
+      display_name: "Air".into(),
+      solid: false,
+      opaque: false,
+      textures: BlockTextures::default(),
+      light_emission: 0,
+      hardness: 0.,
+      fluid: None,
+      gravity: false,
+    }
+  }
+}
+
+impl RegistryDef for BlockDef {
+  type Id = BlockId;
+  type Error = BlockRegistryError;
+
+  const LIST_KEY: &'static str = "blocks";
+  const BUILTIN: &'static str = BUILTIN_BLOCKS;
+
+  #[inline]
+  fn id(&self) -> BlockId {
+    self.id
+  }
+
+  #[inline]
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn duplicate_id(id: BlockId) -> BlockRegistryError {
+    BlockRegistryError::DuplicateId(id)
+  }
+
+  fn duplicate_name(
+    name: String,
+  ) -> BlockRegistryError {
+    BlockRegistryError::DuplicateName(name)
+  }
+
+  fn validate(&self) -> Result<(), BlockRegistryError> {
+    if 15 < self.light_emission {
+      return Err(
+        BlockRegistryError::InvalidLightEmission {
+          name: self.name.clone(),
+          value: self.light_emission,
+        },
+      );
+    }
+    Ok(())
+  }
+
+  /// ID 0が未定義の場合は空気を補う
+  fn complete(
+    registry: &mut Registry<Self>,
+  ) -> Result<(), BlockRegistryError> {
+    match registry.get(AIR) {
+      None => registry.register(Self::air()),
+      Some(air) if air.solid || air.opaque => {
+        Err(BlockRegistryError::InvalidAir)
+      }
+      Some(_) => Ok(()),
+    }
+  }
+}
+
+/// ブロックIDと定義の登録簿
+pub type BlockRegistry = Registry<BlockDef>;
+impl Registry<BlockDef> {
+  /// 当たり判定を持つか
+  /// 未登録のIDは空気として扱う
+  #[inline]
+  pub fn is_solid(&self, id: BlockId) -> bool {
+    self
+      .get(id)
+      .is_some_and(|def| def.solid)
+  }
+
+  /// 光と視線を遮るか
+  /// 未登録のIDは空気として扱う
+  #[inline]
+  pub fn is_opaque(&self, id: BlockId) -> bool {
+    self
+      .get(id)
+      .is_some_and(|def| def.opaque)
+  }
+
+  /// 流体の性質
+  /// 流体でない場合と未登録のIDは`None`を返す
+  #[inline]
+  pub fn fluid(&self, id: BlockId) -> Option<&FluidDef> {
+    self.get(id)?.fluid.as_ref()
+  }
+
+  /// 支えを失うと落下するか
+  /// 未登録のIDは空気として扱う
+  #[inline]
+  pub fn has_gravity(&self, id: BlockId) -> bool {
+    self
+      .get(id)
+      .is_some_and(|def| def.gravity)
+  }
+
+  /// 発する光量
+  #[inline]
+  pub fn light_emission(&self, id: BlockId) -> u8 {
+    self
+      .get(id)
+      .map_or(0, |def| def.light_emission)
+  }
+}
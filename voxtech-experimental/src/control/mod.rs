@@ -14,6 +14,11 @@ pub struct UserMoveControl {
   pub rot_dn: bool,
   pub rot_up: bool,
 }
+impl Default for UserMoveControl {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 impl UserMoveControl {
   pub fn new() -> Self {
     Self {
@@ -58,6 +63,11 @@ impl UserMoveControl {
 pub struct UserControlMouseVelocity {
   pub input: [f64; 2],
 }
+impl Default for UserControlMouseVelocity {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 impl UserControlMouseVelocity {
   pub fn new() -> Self {
     Self { input: [0., 0.] }
@@ -76,17 +86,59 @@ impl UserControlMouseVelocity {
   }
 }
 
+/// プレイヤー入力の内マウスボタン
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserMouseButtons {
+  /// 左ボタン(ブロックの破壊)
+  pub left: bool,
+  /// 右ボタン(ブロックの設置)
+  pub right: bool,
+}
+impl UserMouseButtons {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// ボタン入力
+  pub fn input(
+    &mut self,
+    button: winit::event::MouseButton,
+    state: winit::event::ElementState,
+  ) {
+    let i = state == winit::event::ElementState::Pressed;
+    match button {
+      winit::event::MouseButton::Left => self.left = i,
+      winit::event::MouseButton::Right => self.right = i,
+      _ => {}
+    }
+  }
+}
+
 /// プレイヤー制御に関わる入力
 pub struct UserControlInput {
   pub move_key: UserMoveControl,
   pub mouse_velocity: UserControlMouseVelocity,
+  pub mouse_button: UserMouseButtons,
+  /// 選択中のホットバーの番号(0..9)
+  pub hotbar_slot: usize,
+  /// 移動の仕方の切り替えが押されたか
+  /// 次の固定間隔の更新の後で元に戻る
+  pub toggle_mode: bool,
   open_menu: bool,
 }
+impl Default for UserControlInput {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 impl UserControlInput {
   pub fn new() -> Self {
     Self {
       move_key: UserMoveControl::new(),
       mouse_velocity: UserControlMouseVelocity::new(),
+      mouse_button: UserMouseButtons::new(),
+      hotbar_slot: 0,
+      toggle_mode: false,
       open_menu: false,
     }
   }
@@ -101,6 +153,7 @@ impl UserControlInput {
       // Escキーが入力された際のカーソルの再表示・グラブの解除
       winit::event::ElementState::Pressed => {
         self.open_menu = true;
+        self.mouse_button = UserMouseButtons::new();
         window.set_cursor_visible(true);
         window
           .set_cursor_grab(winit::window::CursorGrabMode::None)
@@ -138,6 +191,27 @@ impl UserControlInput {
         self.press_escape(key_event, window)
       }
 
+      // 数字キーでホットバーを選択する
+      winit::keyboard::PhysicalKey::Code(code)
+        if !self.open_menu
+          && key_event.state
+            == winit::event::ElementState::Pressed
+          && let Some(slot) = Self::hotbar_key(code) =>
+      {
+        self.hotbar_slot = slot
+      }
+
+      // Fキーで移動の仕方を切り替える
+      winit::keyboard::PhysicalKey::Code(
+        winit::keyboard::KeyCode::KeyF,
+      ) if !self.open_menu
+        && !key_event.repeat
+        && key_event.state
+          == winit::event::ElementState::Pressed =>
+      {
+        self.toggle_mode = true
+      }
+
       // 移動キーの入力処理
       _ if !self.open_menu => self.move_key.input(key_event),
 
@@ -146,6 +220,38 @@ impl UserControlInput {
     }
   }
 
+  /// ホットバーの番号に対応する数字キー
+  fn hotbar_key(code: KeyCode) -> Option<usize> {
+    Some(match code {
+      KeyCode::Digit1 => 0,
+      KeyCode::Digit2 => 1,
+      KeyCode::Digit3 => 2,
+      KeyCode::Digit4 => 3,
+      KeyCode::Digit5 => 4,
+      KeyCode::Digit6 => 5,
+      KeyCode::Digit7 => 6,
+      KeyCode::Digit8 => 7,
+      KeyCode::Digit9 => 8,
+      _ => return None,
+    })
+  }
+
+  /// マウスボタン入力
+  /// メニューが開かれている間は全て離されたものとして扱う
+  pub fn mouse_button_input(
+    &mut self,
+    button: winit::event::MouseButton,
+    state: winit::event::ElementState,
+  ) {
+    if self.open_menu {
+      self.mouse_button = UserMouseButtons::new();
+    } else {
+      self
+        .mouse_button
+        .input(button, state);
+    }
+  }
+
   /// マウス入力
   #[inline]
   pub fn mouse_input(&mut self, velocity: [f64; 2]) {
@@ -156,8 +262,13 @@ impl UserControlInput {
     }
   }
 
-  /// 定期更新
+  /// 描画毎の更新
   pub fn update(&mut self) {
     self.mouse_velocity.reset();
   }
+
+  /// 固定間隔の更新毎の更新
+  pub fn end_tick(&mut self) {
+    self.toggle_mode = false;
+  }
 }
@@ -1,5 +1,5 @@
 use bytemuck::{Pod, Zeroable};
-use wgpu::{util::DeviceExt, wgc::instance};
+use wgpu::util::DeviceExt;
 
 /// カメラのインスタンス
 #[repr(C)]
@@ -120,8 +120,8 @@ impl WGPUContext {
   /// 描画処理
   pub fn rendering(
     &self,
-    renderer: &world_renderer::WorldRenderer,
-    block_rdr: &[block_rdr::BlockRenderInstance],
+    renderer: &mut world_renderer::WorldRenderer,
+    meshes: &block_rdr::ChunkMeshes,
   ) -> Result<(), wgpu::SurfaceError> {
     self.window.request_redraw();
     let output = self
@@ -130,7 +130,7 @@ impl WGPUContext {
     let view = output.texture.create_view(
       &wgpu::TextureViewDescriptor::default(),
     );
-    renderer.rendering(&view, self, block_rdr);
+    renderer.rendering(&view, self, meshes);
     output.present();
 
     Ok(())
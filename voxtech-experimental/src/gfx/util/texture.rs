@@ -94,7 +94,7 @@ impl Texture {
         origin: Origin3d::ZERO,
         aspect: TextureAspect::All,
       },
-      &diffuse_image,
+      diffuse_image,
       TexelCopyBufferLayout {
         offset: 0,
         bytes_per_row: Some(4 * dimensions.0),
@@ -177,8 +177,8 @@ impl Texture {
 }
 
 pub struct DiffuseTexture {
-  texture: Texture,
-  bindgroup: BindGroup,
+  pub texture: Texture,
+  pub bindgroup: BindGroup,
 }
 impl DiffuseTexture {
   pub fn new_diffuse_from_image(
@@ -1,26 +1,40 @@
 //! Block-Renderer
 //! ブロックレンダラ
 
+use hashbrown::HashMap;
 use wgpu::{util::DeviceExt, Buffer};
 
+use super::mesher::{self, ChunkMesh};
+use crate::job::{JobKind, JobOutput, JobPool};
+use crate::types::{BlockPos, TileFace};
+use crate::world::change::DirtyFlags;
+use crate::world::World;
+
+/// チャンク一つ分のブロックのインスタンス
+///
+/// 面毎のインスタンスを一つのバッファに続けて並べ、向き毎に
+/// 範囲を切り出して描画する。
 pub struct BlockRenderInstance {
-  pub instances: Vec<super::types::BakedInstance>,
+  chunk_pos: BlockPos,
   buffer: Buffer,
+  /// 向き毎のインスタンスの範囲(インスタンスの番号)
+  ranges: [std::ops::Range<u32>; 6],
 }
 impl BlockRenderInstance {
   pub fn new(
     context: &super::super::WGPUContext,
-    block_count: u32,
+    chunk_pos: BlockPos,
+    mesh: &ChunkMesh,
   ) -> Self {
-    let instances = (0..block_count.min(4096))
-      .map(
-        |i| super::types::BakedInstance {
-          stride: i,
-          tex_pos: [0., 0.],
-          tex_scale: [0., 0.],
-        },
-      )
-      .collect::<Vec<_>>();
+    let mut instances =
+      Vec::with_capacity(mesh.face_count());
+    let ranges = std::array::from_fn(|i| {
+      let start = instances.len() as u32;
+      instances.extend_from_slice(
+        mesh.faces(TileFace::from(i as u8)),
+      );
+      start..instances.len() as u32
+    });
     let buffer = context
       .device
       .create_buffer_init(
@@ -30,13 +44,181 @@ impl BlockRenderInstance {
           usage: wgpu::BufferUsages::VERTEX,
         },
       );
-    Self { instances, buffer }
+    Self {
+      chunk_pos,
+      buffer,
+      ranges,
+    }
+  }
+
+  /// 描画するチャンクの座標
+  #[inline]
+  pub fn chunk_pos(&self) -> BlockPos {
+    self.chunk_pos
+  }
+
+  /// チャンクの原点のブロック座標
+  #[inline]
+  pub fn origin(&self) -> BlockPos {
+    self.chunk_pos.up_level(2)
+  }
+
+  /// シェーダーへ渡す原点
+  /// 座標が`i32`に収まらない場合は`None`を返す
+  pub fn gpu_origin(&self) -> Option<[i32; 3]> {
+    let [x, y, z] = self.origin().as_array();
+    Some([
+      i32::try_from(x).ok()?,
+      i32::try_from(y).ok()?,
+      i32::try_from(z).ok()?,
+    ])
   }
+
+  /// `face`を向いた面の数
+  #[inline]
+  pub fn instance_count(&self, face: TileFace) -> u32 {
+    self
+      .ranges
+      .get(face as usize)
+      .map_or(0, |range| range.len() as u32)
+  }
+
+  /// `face`を向いた面のインスタンスを設定する
   pub fn rendering(
     &self,
     render_pass: &mut wgpu::RenderPass,
+    face: TileFace,
+  ) {
+    let size = std::mem::size_of::<
+      super::types::BakedInstance,
+    >() as u64;
+    let range = &self.ranges[face as usize];
+    render_pass.set_vertex_buffer(
+      1,
+      self.buffer.slice(
+        range.start as u64 * size
+          ..range.end as u64 * size,
+      ),
+    );
+  }
+}
+
+/// 読み込まれているチャンク毎のインスタンス
+///
+/// メッシュの更新フラグが立ったチャンクのインスタンスを作り直し、
+/// 読み込まれなくなったチャンクのものを破棄する。面の無いチャンクは
+/// 描画しない。
+#[derive(Default)]
+pub struct ChunkMeshes {
+  map: HashMap<BlockPos, BlockRenderInstance>,
+}
+impl ChunkMeshes {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// ワールドの読み込み状況と更新フラグに合わせる
+  /// `jobs`が`None`の場合はこのスレッドでメッシュを生成する
+  ///
+  /// ワーカースレッド群が渡された場合はチャンクの複製を渡して生成を
+  /// 任せ、同じチャンクの古い生成は取り消す。
+  pub fn sync(
+    &mut self,
+    context: &super::super::WGPUContext,
+    world: &mut World,
+    mut jobs: Option<&mut JobPool<JobOutput>>,
+  ) {
+    self.map.retain(|chunk_pos, _| {
+      world.chunk(chunk_pos).is_some()
+    });
+    for chunk_pos in world.take_dirty(DirtyFlags::MESH)
+    {
+      let Some(snapshot) = world.snapshot(&chunk_pos)
+      else {
+        continue;
+      };
+      match jobs.as_deref_mut() {
+        Some(jobs) => {
+          jobs.cancel_chunk(
+            &chunk_pos,
+            Some(JobKind::Mesh),
+          );
+          jobs.submit(
+            JobKind::Mesh,
+            chunk_pos,
+            0,
+            move |_| {
+              Some(JobOutput::Meshed(
+                mesher::mesh_snapshot(&snapshot),
+              ))
+            },
+          );
+        }
+        None => self.insert(
+          context,
+          chunk_pos,
+          &mesher::mesh_snapshot(&snapshot),
+        ),
+      }
+    }
+  }
+
+  /// ワーカーで生成されたメッシュを受け取る
+  ///
+  /// チャンクが読み込まれていないか、再びメッシュの更新フラグが
+  /// 立っていて生成し直す予定の場合は捨てる。
+  pub fn receive_meshed(
+    &mut self,
+    context: &super::super::WGPUContext,
+    world: &World,
+    chunk_pos: BlockPos,
+    mesh: &ChunkMesh,
+  ) {
+    if world
+      .chunk(&chunk_pos)
+      .is_some_and(|chunk| {
+        !chunk
+          .dirty()
+          .contains(DirtyFlags::MESH)
+      })
+    {
+      self.insert(context, chunk_pos, mesh);
+    }
+  }
+
+  /// チャンクのインスタンスを置き換える
+  fn insert(
+    &mut self,
+    context: &super::super::WGPUContext,
+    chunk_pos: BlockPos,
+    mesh: &ChunkMesh,
   ) {
-    render_pass
-      .set_vertex_buffer(1, self.buffer.slice(..));
+    if mesh.is_empty() {
+      self.map.remove(&chunk_pos);
+    } else {
+      self.map.insert(
+        chunk_pos,
+        BlockRenderInstance::new(
+          context, chunk_pos, mesh,
+        ),
+      );
+    }
+  }
+
+  /// 描画するチャンクを走査する
+  pub fn iter(
+    &self,
+  ) -> impl Iterator<Item = &BlockRenderInstance> {
+    self.map.values()
+  }
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.map.len()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.map.is_empty()
   }
 }
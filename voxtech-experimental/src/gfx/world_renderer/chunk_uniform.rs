@@ -0,0 +1,155 @@
+//! Chunk-Uniform
+//! 動的オフセットで切り替えるチャンク毎のユニフォーム
+
+use super::types::ChunkUniform;
+
+/// チャンク毎の原点を並べたユニフォームバッファ
+///
+/// 各チャンクの値をデバイスのオフセットの境界に揃えて並べ、描画時に
+/// 動的オフセットで選ぶ。容量が足りなくなると作り直す。
+pub struct ChunkUniforms {
+  pub bindgroup_layout: wgpu::BindGroupLayout,
+  pub bindgroup: wgpu::BindGroup,
+  buffer: wgpu::Buffer,
+  /// 一チャンク分の間隔(バイト)
+  stride: u64,
+  /// 並べられるチャンクの数
+  capacity: usize,
+  staging: Vec<u8>,
+}
+impl ChunkUniforms {
+  /// 初期の容量
+  const INITIAL_CAPACITY: usize = 64;
+
+  pub fn new(
+    context: &super::super::WGPUContext,
+  ) -> Self {
+    let align = context
+      .device
+      .limits()
+      .min_uniform_buffer_offset_alignment
+      as u64;
+    let size =
+      std::mem::size_of::<ChunkUniform>() as u64;
+    let stride = size.div_ceil(align) * align;
+    let bindgroup_layout = context
+      .device
+      .create_bind_group_layout(
+        &wgpu::BindGroupLayoutDescriptor {
+          label: Some("Chunk bindgroup layout"),
+          entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+              ty: wgpu::BufferBindingType::Uniform,
+              has_dynamic_offset: true,
+              min_binding_size: wgpu::BufferSize::new(
+                size,
+              ),
+            },
+            count: None,
+          }],
+        },
+      );
+    let (buffer, bindgroup) = Self::create(
+      context,
+      &bindgroup_layout,
+      stride,
+      Self::INITIAL_CAPACITY,
+    );
+    Self {
+      bindgroup_layout,
+      bindgroup,
+      buffer,
+      stride,
+      capacity: Self::INITIAL_CAPACITY,
+      staging: Vec::new(),
+    }
+  }
+
+  /// `capacity`個分のバッファとバインドグループを生成する
+  fn create(
+    context: &super::super::WGPUContext,
+    layout: &wgpu::BindGroupLayout,
+    stride: u64,
+    capacity: usize,
+  ) -> (wgpu::Buffer, wgpu::BindGroup) {
+    let buffer = context.device.create_buffer(
+      &wgpu::BufferDescriptor {
+        label: Some("Chunk uniform buffer"),
+        size: stride * capacity as u64,
+        usage: wgpu::BufferUsages::UNIFORM
+          | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+      },
+    );
+    let bindgroup = context
+      .device
+      .create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Chunk bindgroup"),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+          binding: 0,
+          resource: wgpu::BindingResource::Buffer(
+            wgpu::BufferBinding {
+              buffer: &buffer,
+              offset: 0,
+              size: wgpu::BufferSize::new(
+                std::mem::size_of::<ChunkUniform>()
+                  as u64,
+              ),
+            },
+          ),
+        }],
+      });
+    (buffer, bindgroup)
+  }
+
+  /// チャンクの原点を順に書き込む
+  /// `i`番目のチャンクは`offset(i)`で選ぶ
+  pub fn update(
+    &mut self,
+    context: &super::super::WGPUContext,
+    origins: &[[i32; 3]],
+  ) {
+    if origins.is_empty() {
+      return;
+    }
+    if self.capacity < origins.len() {
+      self.capacity = origins
+        .len()
+        .next_power_of_two();
+      (self.buffer, self.bindgroup) = Self::create(
+        context,
+        &self.bindgroup_layout,
+        self.stride,
+        self.capacity,
+      );
+    }
+    self.staging.clear();
+    self.staging.resize(
+      self.stride as usize * origins.len(),
+      0,
+    );
+    for (i, [x, y, z]) in origins.iter().enumerate() {
+      let uniform = ChunkUniform {
+        origin: [*x, *y, *z, 0],
+      };
+      let at = i * self.stride as usize;
+      self.staging
+        [at..at + std::mem::size_of::<ChunkUniform>()]
+        .copy_from_slice(bytemuck::bytes_of(&uniform));
+    }
+    context.queue.write_buffer(
+      &self.buffer,
+      0,
+      &self.staging,
+    );
+  }
+
+  /// `i`番目のチャンクの動的オフセット
+  #[inline]
+  pub fn offset(&self, i: usize) -> u32 {
+    (self.stride * i as u64) as u32
+  }
+}
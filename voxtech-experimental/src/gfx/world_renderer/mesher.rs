@@ -0,0 +1,277 @@
+//! Mesher
+//! チャンクのブロックから面毎のインスタンスを生成する
+
+use super::types::BakedInstance;
+use crate::block::AIR;
+use crate::types::{BlockPos, TileFace};
+use crate::world::snapshot::ChunkSnapshot;
+use crate::world::World;
+
+/// チャンク一つ分の面毎のインスタンス
+///
+/// `TileFace`の番号順に並び、それぞれ同じ番号の頂点バッファと
+/// 組み合わせて描画する。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkMesh {
+  faces: [Vec<BakedInstance>; 6],
+}
+impl ChunkMesh {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// `face`を向いた面のインスタンス
+  #[inline]
+  pub fn faces(
+    &self,
+    face: TileFace,
+  ) -> &[BakedInstance] {
+    self
+      .faces
+      .get(face as usize)
+      .map_or(&[], |faces| faces.as_slice())
+  }
+
+  /// 全ての向きの面の数
+  pub fn face_count(&self) -> usize {
+    self
+      .faces
+      .iter()
+      .map(|faces| faces.len())
+      .sum()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self
+      .faces
+      .iter()
+      .all(|faces| faces.is_empty())
+  }
+
+  #[inline]
+  fn push(&mut self, face: TileFace, index: u16) {
+    self.faces[face as usize].push(BakedInstance {
+      stride: index as u32,
+      tex_pos: [0., 0.],
+      tex_scale: [0., 0.],
+    });
+  }
+}
+
+/// チャンクのメッシュを生成する
+/// チャンクが読み込まれていない場合は空のメッシュを返す
+pub fn mesh_chunk(
+  world: &World,
+  chunk_pos: &BlockPos,
+) -> ChunkMesh {
+  world
+    .snapshot(chunk_pos)
+    .map_or_else(ChunkMesh::new, |snapshot| {
+      mesh_snapshot(&snapshot)
+    })
+}
+
+/// チャンクの複製からメッシュを生成する
+///
+/// 空気でないブロックの各面の内、隣が不透明なブロックか同じ
+/// ブロックである面を隠す。境界の面は隣接するチャンクを参照し、
+/// 読み込まれていなかった隣は空気として扱う。
+pub fn mesh_snapshot(
+  snapshot: &ChunkSnapshot,
+) -> ChunkMesh {
+  let mut mesh = ChunkMesh::new();
+  let chunk = snapshot.chunk();
+  if chunk.is_empty() {
+    return mesh;
+  }
+  let chunk_pos = snapshot.chunk_pos();
+  let registry = snapshot.registry();
+  for cell in 0..64u8 {
+    if chunk.is_cell_empty(cell) {
+      continue;
+    }
+    for block in 0..64u16 {
+      let index = ((cell as u16) << 6) | block;
+      let pos =
+        BlockPos::from_chunk_index(&chunk_pos, index);
+      let id = chunk.get(&pos);
+      if id == AIR {
+        continue;
+      }
+      for face in TileFace::ALL {
+        let neighbor = pos.neighbor(face);
+        let neighbor =
+          if neighbor.chunk_pos() == chunk_pos {
+            chunk.get(&neighbor)
+          } else {
+            snapshot.get_block(&neighbor)
+          };
+        if neighbor != id
+          && !registry.is_opaque(neighbor)
+        {
+          mesh.push(face, index);
+        }
+      }
+    }
+  }
+  mesh
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::block::BlockId;
+  use crate::world::change::ChangeCause;
+  use crate::world::Chunk;
+
+  fn id(world: &World, name: &str) -> BlockId {
+    world
+      .registry()
+      .id_of(name)
+      .unwrap()
+  }
+
+  fn empty_world() -> World {
+    let mut world = World::new();
+    world.spawn_chunk(
+      BlockPos::new(0, 0, 0),
+      Chunk::empty_chunk,
+    );
+    world
+  }
+
+  fn place(
+    world: &mut World,
+    pos: BlockPos,
+    block: &str,
+  ) {
+    let block = id(world, block);
+    world.set_block(&pos, block, ChangeCause::Player);
+  }
+
+  fn origin_mesh(world: &World) -> ChunkMesh {
+    mesh_chunk(world, &BlockPos::new(0, 0, 0))
+  }
+
+  #[test]
+  fn single_block() {
+    let mut world = empty_world();
+    place(
+      &mut world,
+      BlockPos::new(3, 4, 5),
+      "stone",
+    );
+    let mesh = origin_mesh(&world);
+    assert_eq!(mesh.face_count(), 6);
+    for face in TileFace::ALL {
+      assert_eq!(mesh.faces(face).len(), 1);
+    }
+    assert!(mesh_chunk(
+      &world,
+      &BlockPos::new(1, 0, 0)
+    )
+    .is_empty());
+  }
+
+  #[test]
+  fn adjacent_blocks() {
+    let mut world = empty_world();
+    place(
+      &mut world,
+      BlockPos::new(3, 4, 5),
+      "stone",
+    );
+    place(
+      &mut world,
+      BlockPos::new(4, 4, 5),
+      "stone",
+    );
+    let mesh = origin_mesh(&world);
+    assert_eq!(mesh.face_count(), 10);
+    assert_eq!(
+      mesh.faces(TileFace::EST).len(),
+      1
+    );
+    assert_eq!(
+      mesh.faces(TileFace::WST).len(),
+      1
+    );
+  }
+
+  #[test]
+  fn solid_chunk_outer_faces() {
+    let mut world = World::new();
+    let stone = id(&world, "stone");
+    world.spawn_chunk(BlockPos::new(0, 0, 0), || {
+      Chunk::new(&BlockPos::new(0, 0, 0), |_| {
+        stone
+      })
+    });
+    let mesh = origin_mesh(&world);
+    assert_eq!(mesh.face_count(), 6 * 16 * 16);
+    for face in TileFace::ALL {
+      assert_eq!(mesh.faces(face).len(), 16 * 16);
+    }
+  }
+
+  #[test]
+  fn loaded_neighbor_hides_border() {
+    let mut world = World::new();
+    let stone = id(&world, "stone");
+    for x in 0..2 {
+      let chunk_pos = BlockPos::new(x, 0, 0);
+      world.spawn_chunk(chunk_pos, || {
+        Chunk::new(&chunk_pos, |_| stone)
+      });
+    }
+    let mesh = origin_mesh(&world);
+    assert!(mesh
+      .faces(TileFace::EST)
+      .is_empty());
+    assert_eq!(
+      mesh.faces(TileFace::WST).len(),
+      16 * 16
+    );
+    assert_eq!(mesh.face_count(), 5 * 16 * 16);
+    let neighbor =
+      mesh_chunk(&world, &BlockPos::new(1, 0, 0));
+    assert!(neighbor
+      .faces(TileFace::WST)
+      .is_empty());
+  }
+
+  #[test]
+  fn transparent_next_to_opaque() {
+    let mut world = empty_world();
+    place(
+      &mut world,
+      BlockPos::new(3, 4, 5),
+      "stone",
+    );
+    place(
+      &mut world,
+      BlockPos::new(4, 4, 5),
+      "leaves",
+    );
+    let mesh = origin_mesh(&world);
+    // 石は葉に面した面も残し、葉は石に面した面を隠す
+    assert_eq!(mesh.face_count(), 6 + 5);
+    assert_eq!(
+      mesh.faces(TileFace::EST).len(),
+      2
+    );
+    assert_eq!(
+      mesh.faces(TileFace::WST).len(),
+      1
+    );
+
+    place(
+      &mut world,
+      BlockPos::new(4, 5, 5),
+      "leaves",
+    );
+    let mesh = origin_mesh(&world);
+    assert_eq!(mesh.face_count(), 6 + 5 + 4);
+  }
+}
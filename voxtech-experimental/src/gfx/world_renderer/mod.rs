@@ -4,6 +4,8 @@ use wgpu::{
 };
 
 pub mod block_rdr;
+pub mod chunk_uniform;
+pub mod mesher;
 pub mod types;
 
 /// VoxTechのWorld用描画構造体
@@ -13,7 +15,10 @@ pub struct WorldRenderer {
   vertices: [Buffer; 6],
   indices: Buffer,
   camera: super::camera::CameraUniformInstance,
+  chunks: chunk_uniform::ChunkUniforms,
   depth_texture: super::util::texture::Texture,
+  /// 原点が`i32`に収まらず描画しなかったチャンク数
+  skipped_chunks: usize,
 }
 impl WorldRenderer {
   pub fn new(
@@ -24,13 +29,16 @@ impl WorldRenderer {
       super::camera::CameraUniformInstance::new(
         context, camera,
       );
+    let chunks =
+      chunk_uniform::ChunkUniforms::new(context);
     let pipeline_layout = context
       .device
       .create_pipeline_layout(
         &wgpu::PipelineLayoutDescriptor {
           label: Some("World render pipeline Layout"),
           bind_group_layouts: &[
-            &camera.bindgroup_layout
+            &camera.bindgroup_layout,
+            &chunks.bindgroup_layout,
           ],
           push_constant_ranges: &[],
         },
@@ -146,7 +154,9 @@ impl WorldRenderer {
       vertices,
       indices,
       camera,
+      chunks,
       depth_texture,
+      skipped_chunks: 0,
     })
   }
   pub fn resize(
@@ -168,12 +178,34 @@ impl WorldRenderer {
       .camera
       .update(context, camera);
   }
+  /// 読み込まれているチャンクをそれぞれの位置に描画する
+  /// 原点が`i32`に収まらないチャンクは描画せず、その数が変わった時に
+  /// 知らせる
   pub fn rendering(
-    &self,
+    &mut self,
     view: &wgpu::TextureView,
     context: &super::WGPUContext,
-    block_rdr_instance: &[block_rdr::BlockRenderInstance],
+    meshes: &block_rdr::ChunkMeshes,
   ) {
+    let (block_rdr_instance, origins): (
+      Vec<_>,
+      Vec<_>,
+    ) = meshes
+      .iter()
+      .filter_map(|block_rdr| {
+        Some((block_rdr, block_rdr.gpu_origin()?))
+      })
+      .unzip();
+    let skipped = meshes.len() - origins.len();
+    if skipped != self.skipped_chunks {
+      if 0 < skipped {
+        eprintln!(
+          "{skipped} chunks are out of the renderable range"
+        );
+      }
+      self.skipped_chunks = skipped;
+    }
+    self.chunks.update(context, &origins);
     let mut encoder = context
       .device
       .create_command_encoder(
@@ -227,20 +259,31 @@ impl WorldRenderer {
         self.indices.slice(..),
         wgpu::IndexFormat::Uint16,
       );
-      for i in 0..block_rdr_instance.len() {
-        block_rdr_instance[i]
-          .rendering(&mut render_pass);
-        for j in 0..self.vertices.len() {
+      for (i, block_rdr) in
+        block_rdr_instance.iter().enumerate()
+      {
+        render_pass.set_bind_group(
+          1,
+          &self.chunks.bindgroup,
+          &[self.chunks.offset(i)],
+        );
+        for (face, vertices) in
+          self.vertices.iter().enumerate()
+        {
+          let face = types::TileFace::from(face as u8);
+          let count = block_rdr.instance_count(face);
+          if count == 0 {
+            continue;
+          }
+          block_rdr.rendering(&mut render_pass, face);
           render_pass.set_vertex_buffer(
             0,
-            self.vertices[j].slice(..),
+            vertices.slice(..),
           );
           render_pass.draw_indexed(
             0..types::TILE_INDICES.len() as u32,
             0,
-            0..block_rdr_instance[i]
-              .instances
-              .len() as u32,
+            0..count,
           );
         }
       }
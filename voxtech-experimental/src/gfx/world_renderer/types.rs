@@ -1,5 +1,7 @@
 use bytemuck::{Pod, Zeroable};
 
+pub use crate::types::TileFace;
+
 /// 頂点構造体
 #[repr(C)]
 #[derive(
@@ -173,55 +175,6 @@ pub const TILE_INDICES: &[u16] = &[
   0, 2, 3, // 2ポリゴン目
 ];
 
-/// ブロックのタイルが向いている面
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum TileFace {
-  /// 西(X-)
-  WST = 0,
-  /// 東(X+)
-  EST = 1,
-  /// 南(Y-)
-  STH = 2,
-  /// 北(Y+)
-  NTH = 3,
-  /// 下(Z-)
-  BTM = 4,
-  /// 上(Z+)
-  TOP = 5,
-  /// 未定義
-  UNDEF = u8::MAX,
-}
-impl std::fmt::Display for TileFace {
-  fn fmt(
-    &self,
-    f: &mut std::fmt::Formatter<'_>,
-  ) -> std::fmt::Result {
-    f.write_str(match self {
-      TileFace::WST => "West",
-      TileFace::EST => "East",
-      TileFace::STH => "South",
-      TileFace::NTH => "North",
-      TileFace::BTM => "Bottom",
-      TileFace::TOP => "North",
-      TileFace::UNDEF => "Undefined",
-    })
-  }
-}
-impl From<u8> for TileFace {
-  fn from(value: u8) -> Self {
-    match value {
-      0 => Self::WST,
-      1 => Self::EST,
-      2 => Self::STH,
-      3 => Self::NTH,
-      4 => Self::BTM,
-      5 => Self::TOP,
-      _ => Self::UNDEF,
-    }
-  }
-}
-
 /// インスタンス構造体
 #[repr(C)]
 #[derive(
@@ -250,3 +203,13 @@ impl BakedInstance {
     }
   }
 }
+
+/// チャンク毎のユニフォーム
+#[repr(C)]
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable,
+)]
+pub struct ChunkUniform {
+  /// チャンクの原点のブロック座標(wは未使用)
+  pub origin: [i32; 4],
+}
@@ -3,6 +3,11 @@ struct CameraUniform {
 }
 @group(0) @binding(0) var<uniform> camera: CameraUniform;
 
+struct ChunkUniform {
+  origin: vec4<i32>,
+}
+@group(1) @binding(0) var<uniform> chunk: ChunkUniform;
+
 struct InstanceInput {
   @location(8) stride: u32, 
   @location(9) offset: vec2<f32>, 
@@ -37,7 +42,8 @@ fn vs_main(
 ) -> VertexOutput {
   var out: VertexOutput;
   var stride = vec4<f32>(
-    calculate_stride(instance.stride),
+    calculate_stride(instance.stride)
+      + vec3<f32>(chunk.origin.xyz),
     0.0,
   );
   out.position = camera.view_proj * (model.position + stride);
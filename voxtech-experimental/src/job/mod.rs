@@ -0,0 +1,602 @@
+//! Job
+//! チャンクの生成、光源計算、メッシュ構築を行うワーカースレッド群
+
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use hashbrown::HashMap;
+
+use crate::gfx::world_renderer::mesher::ChunkMesh;
+use crate::types::BlockPos;
+use crate::world::snapshot::ChunkLight;
+use crate::world::Chunk;
+use crate::{PCondvar, PMutex, PRwLock};
+
+/// ジョブの種類
+///
+/// ワーカーは`Generate`、`Light`、`Mesh`の順に待ち行列を確認する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKind {
+  Generate = 0,
+  Light = 1,
+  Mesh = 2,
+}
+impl JobKind {
+  pub const ALL: [Self; 3] = [
+    Self::Generate,
+    Self::Light,
+    Self::Mesh,
+  ];
+}
+
+/// ジョブの結果
+pub enum JobOutput {
+  /// 構造物まで書き込んだ生成済みのチャンク
+  Generated(Chunk),
+  /// チャンクの複製から計算した光量
+  Lit(ChunkLight),
+  /// チャンクの複製から生成したメッシュ
+  Meshed(ChunkMesh),
+}
+
+/// ジョブの番号
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  Hash,
+  PartialOrd,
+  Ord,
+)]
+pub struct JobId(u64);
+
+/// ジョブの取り消しの通知
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+impl CancelToken {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  #[inline]
+  pub fn cancel(&self) {
+    self
+      .0
+      .store(true, Ordering::Release);
+  }
+
+  #[inline]
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::Acquire)
+  }
+}
+
+type Task<R> =
+  Box<dyn FnOnce(&CancelToken) -> Option<R> + Send>;
+
+/// 待ち行列中のジョブ
+struct QueuedJob<R> {
+  id: JobId,
+  kind: JobKind,
+  chunk_pos: BlockPos,
+  priority: i64,
+  cancel: CancelToken,
+  task: Task<R>,
+}
+impl<R> QueuedJob<R> {
+  /// 優先度の値が小さく、先に投入されたものほど大きい
+  #[inline]
+  fn order(
+    &self,
+  ) -> (
+    std::cmp::Reverse<i64>,
+    std::cmp::Reverse<JobId>,
+  ) {
+    (
+      std::cmp::Reverse(self.priority),
+      std::cmp::Reverse(self.id),
+    )
+  }
+}
+impl<R> PartialEq for QueuedJob<R> {
+  fn eq(&self, other: &Self) -> bool {
+    self.id == other.id
+  }
+}
+impl<R> Eq for QueuedJob<R> {}
+impl<R> PartialOrd for QueuedJob<R> {
+  fn partial_cmp(
+    &self,
+    other: &Self,
+  ) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+impl<R> Ord for QueuedJob<R> {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    self.order().cmp(&other.order())
+  }
+}
+
+/// 完了したジョブの結果
+pub struct JobResult<R> {
+  pub id: JobId,
+  pub kind: JobKind,
+  pub chunk_pos: BlockPos,
+  pub output: R,
+  cancel: CancelToken,
+}
+
+/// 実行待ち・実行中のジョブの情報
+struct JobEntry {
+  kind: JobKind,
+  chunk_pos: BlockPos,
+  cancel: CancelToken,
+}
+
+/// ワーカー間で共有する状態
+struct Shared<R> {
+  queues: PMutex<Queues<R>>,
+  available: PCondvar,
+  /// 取り消し用の実行待ち・実行中のジョブの一覧
+  jobs: PRwLock<HashMap<JobId, JobEntry>>,
+}
+
+struct Queues<R> {
+  heaps: [BinaryHeap<QueuedJob<R>>; 3],
+  shutdown: bool,
+}
+impl<R> Queues<R> {
+  fn pop(&mut self) -> Option<QueuedJob<R>> {
+    self
+      .heaps
+      .iter_mut()
+      .find_map(BinaryHeap::pop)
+  }
+
+  fn len(&self) -> usize {
+    self
+      .heaps
+      .iter()
+      .map(BinaryHeap::len)
+      .sum()
+  }
+}
+
+/// 優先度付きの待ち行列を持つワーカースレッド群
+///
+/// 結果はチャンネルを通してメインスレッドへ返す。取り消されたジョブは
+/// 実行されず、実行中に取り消された場合も結果は捨てられる。
+pub struct JobPool<R: Send + 'static> {
+  shared: Arc<Shared<R>>,
+  workers: Vec<JoinHandle<()>>,
+  results: mpsc::Receiver<JobResult<R>>,
+  next_id: u64,
+}
+impl<R: Send + 'static> Default for JobPool<R> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+impl<R: Send + 'static> JobPool<R> {
+  /// 論理コア数より一つ少ないスレッド数で起動する
+  pub fn new() -> Self {
+    Self::with_threads(Self::max_threads() - 1)
+  }
+
+  /// 使用できる最大のスレッド数
+  pub fn max_threads() -> usize {
+    std::thread::available_parallelism()
+      .map_or(1, |n| n.get())
+  }
+
+  /// スレッド数を指定して起動する
+  /// 値は1から論理コア数までに制限される
+  pub fn with_threads(threads: usize) -> Self {
+    let threads = threads.clamp(1, Self::max_threads());
+    let shared = Arc::new(Shared {
+      queues: PMutex::new(Queues {
+        heaps: Default::default(),
+        shutdown: false,
+      }),
+      available: PCondvar::new(),
+      jobs: PRwLock::new(HashMap::new()),
+    });
+    let (sender, results) = mpsc::channel();
+    let workers = (0..threads)
+      .map(|i| {
+        let shared = Arc::clone(&shared);
+        let sender = sender.clone();
+        std::thread::Builder::new()
+          .name(format!("voxtech-worker-{i}"))
+          .spawn(move || Self::worker(&shared, &sender))
+          .expect("worker thread spawn failure")
+      })
+      .collect();
+    Self {
+      shared,
+      workers,
+      results,
+      next_id: 0,
+    }
+  }
+
+  fn worker(
+    shared: &Shared<R>,
+    sender: &mpsc::Sender<JobResult<R>>,
+  ) {
+    loop {
+      let job = {
+        let mut queues = shared.queues.lock();
+        loop {
+          if queues.shutdown {
+            return;
+          }
+          if let Some(job) = queues.pop() {
+            break job;
+          }
+          shared
+            .available
+            .wait(&mut queues);
+        }
+      };
+      let output = if job.cancel.is_cancelled() {
+        None
+      } else {
+        (job.task)(&job.cancel)
+      };
+      shared
+        .jobs
+        .write()
+        .remove(&job.id);
+      let Some(output) = output else {
+        continue;
+      };
+      if job.cancel.is_cancelled() {
+        continue;
+      }
+      let result = JobResult {
+        id: job.id,
+        kind: job.kind,
+        chunk_pos: job.chunk_pos,
+        output,
+        cancel: job.cancel,
+      };
+      if sender.send(result).is_err() {
+        return;
+      }
+    }
+  }
+
+  /// ワーカースレッド数
+  #[inline]
+  pub fn thread_count(&self) -> usize {
+    self.workers.len()
+  }
+
+  /// ジョブを投入する
+  /// `priority`が小さいものほど先に実行される
+  pub fn submit(
+    &mut self,
+    kind: JobKind,
+    chunk_pos: BlockPos,
+    priority: i64,
+    task: impl FnOnce(&CancelToken) -> Option<R>
+      + Send
+      + 'static,
+  ) -> JobId {
+    let id = JobId(self.next_id);
+    self.next_id += 1;
+    let cancel = CancelToken::new();
+    self.shared.jobs.write().insert(
+      id,
+      JobEntry {
+        kind,
+        chunk_pos,
+        cancel: cancel.clone(),
+      },
+    );
+    self.shared.queues.lock().heaps[kind as usize]
+      .push(QueuedJob {
+        id,
+        kind,
+        chunk_pos,
+        priority,
+        cancel,
+        task: Box::new(task),
+      });
+    self
+      .shared
+      .available
+      .notify_one();
+    id
+  }
+
+  /// ジョブを取り消す
+  /// 既に完了していた場合は`false`を返す
+  pub fn cancel(&self, id: JobId) -> bool {
+    match self
+      .shared
+      .jobs
+      .write()
+      .remove(&id)
+    {
+      Some(entry) => {
+        entry.cancel.cancel();
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// チャンクのジョブを取り消し、取り消した数を返す
+  /// `kind`が`None`の場合は全ての種類を取り消す
+  pub fn cancel_chunk(
+    &self,
+    chunk_pos: &BlockPos,
+    kind: Option<JobKind>,
+  ) -> usize {
+    let mut cancelled = 0;
+    self
+      .shared
+      .jobs
+      .write()
+      .retain(|_, entry| {
+        let hit = entry.chunk_pos == *chunk_pos
+          && kind.is_none_or(|kind| entry.kind == kind);
+        if hit {
+          entry.cancel.cancel();
+          cancelled += 1;
+        }
+        !hit
+      });
+    cancelled
+  }
+
+  /// チャンクの実行待ち・実行中のジョブがあるか
+  pub fn is_pending(
+    &self,
+    chunk_pos: &BlockPos,
+    kind: JobKind,
+  ) -> bool {
+    self
+      .shared
+      .jobs
+      .read()
+      .values()
+      .any(|entry| {
+        entry.chunk_pos == *chunk_pos
+          && entry.kind == kind
+      })
+  }
+
+  /// 実行待ち・実行中のジョブ数
+  pub fn pending(&self) -> usize {
+    self.shared.jobs.read().len()
+  }
+
+  /// 実行待ちのジョブ数
+  pub fn queued(&self) -> usize {
+    self.shared.queues.lock().len()
+  }
+
+  /// 完了したジョブの結果を一つ受け取る
+  pub fn try_recv(&self) -> Option<JobResult<R>> {
+    self
+      .results
+      .try_iter()
+      .find(|result| !result.cancel.is_cancelled())
+  }
+
+  /// 完了したジョブの結果を全て受け取る
+  pub fn drain(
+    &self,
+  ) -> impl Iterator<Item = JobResult<R>> + '_ {
+    self
+      .results
+      .try_iter()
+      .filter(|result| !result.cancel.is_cancelled())
+  }
+}
+impl<R: Send + 'static> Drop for JobPool<R> {
+  fn drop(&mut self) {
+    {
+      let mut queues = self.shared.queues.lock();
+      queues.shutdown = true;
+      for heap in queues.heaps.iter_mut() {
+        heap.clear();
+      }
+    }
+    self
+      .shared
+      .available
+      .notify_all();
+    for worker in self.workers.drain(..) {
+      let _ = worker.join();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+
+  use super::*;
+
+  /// `n`個の結果を受け取るまで待つ
+  fn collect(
+    pool: &JobPool<usize>,
+    n: usize,
+  ) -> Vec<(JobKind, usize)> {
+    let deadline =
+      Instant::now() + Duration::from_secs(10);
+    let mut results = Vec::new();
+    while results.len() < n {
+      assert!(
+        Instant::now() < deadline,
+        "timed out"
+      );
+      match pool.try_recv() {
+        Some(result) => {
+          results.push((result.kind, result.output))
+        }
+        None => {
+          std::thread::sleep(Duration::from_millis(1))
+        }
+      }
+    }
+    results
+  }
+
+  /// 唯一のワーカーを塞ぐジョブを投入し、解放用の送信側を返す
+  fn block_worker(
+    pool: &mut JobPool<usize>,
+    output: Option<usize>,
+  ) -> (JobId, mpsc::Sender<()>) {
+    let (started, wait_started) = mpsc::channel();
+    let (release, wait_release) = mpsc::channel::<()>();
+    let id = pool.submit(
+      JobKind::Generate,
+      BlockPos::new(0, 0, 0),
+      0,
+      move |_| {
+        started.send(()).unwrap();
+        let _ = wait_release.recv();
+        output
+      },
+    );
+    wait_started.recv().unwrap();
+    (id, release)
+  }
+
+  #[test]
+  fn priority_within_queue() {
+    let mut pool = JobPool::with_threads(1);
+    let (_, release) = block_worker(&mut pool, None);
+    for (i, priority) in [5, 1, 3, 1, 0]
+      .into_iter()
+      .enumerate()
+    {
+      pool.submit(
+        JobKind::Mesh,
+        BlockPos::new(i as i64, 0, 0),
+        priority,
+        move |_| Some(i),
+      );
+    }
+    assert_eq!(pool.queued(), 5);
+    release.send(()).unwrap();
+    let order: Vec<usize> = collect(&pool, 5)
+      .into_iter()
+      .map(|(_, i)| i)
+      .collect();
+    // 同じ優先度は投入順
+    assert_eq!(order, [4, 1, 3, 2, 0]);
+  }
+
+  #[test]
+  fn kinds_in_stage_order() {
+    let mut pool = JobPool::with_threads(1);
+    let (_, release) = block_worker(&mut pool, None);
+    let jobs = [
+      (JobKind::Mesh, 0),
+      (JobKind::Light, 0),
+      (JobKind::Generate, 10),
+      (JobKind::Mesh, -5),
+      (JobKind::Light, 3),
+    ];
+    for (i, (kind, priority)) in
+      jobs.into_iter().enumerate()
+    {
+      pool.submit(
+        kind,
+        BlockPos::new(i as i64, 0, 0),
+        priority,
+        move |_| Some(i),
+      );
+    }
+    release.send(()).unwrap();
+    assert_eq!(
+      collect(&pool, jobs.len()),
+      [
+        (JobKind::Generate, 2),
+        (JobKind::Light, 1),
+        (JobKind::Light, 4),
+        (JobKind::Mesh, 3),
+        (JobKind::Mesh, 0),
+      ]
+    );
+  }
+
+  #[test]
+  fn cancelled_jobs_deliver_nothing() {
+    let mut pool = JobPool::with_threads(1);
+    let running = BlockPos::new(0, 0, 0);
+    let (_, release) = block_worker(&mut pool, Some(1));
+    let queued = pool.submit(
+      JobKind::Light,
+      BlockPos::new(1, 0, 0),
+      0,
+      |_| Some(2),
+    );
+    assert!(
+      pool.is_pending(&running, JobKind::Generate)
+    );
+    assert_eq!(pool.pending(), 2);
+
+    // 実行待ちと実行中のジョブを取り消す
+    assert!(pool.cancel(queued));
+    assert!(!pool.cancel(queued));
+    assert_eq!(
+      pool.cancel_chunk(&running, Some(JobKind::Mesh)),
+      0
+    );
+    assert_eq!(
+      pool.cancel_chunk(&running, None),
+      1
+    );
+    assert!(
+      !pool.is_pending(&running, JobKind::Generate)
+    );
+    assert_eq!(pool.pending(), 0);
+    release.send(()).unwrap();
+
+    // 後に投入したジョブの結果だけが届く
+    pool.submit(
+      JobKind::Mesh,
+      BlockPos::new(2, 0, 0),
+      0,
+      |_| Some(3),
+    );
+    assert_eq!(
+      collect(&pool, 1),
+      [(JobKind::Mesh, 3)]
+    );
+    assert!(pool.try_recv().is_none());
+    assert_eq!(pool.queued(), 0);
+  }
+
+  #[test]
+  fn thread_count_is_clamped() {
+    let max = JobPool::<usize>::max_threads();
+    assert_eq!(
+      JobPool::<usize>::with_threads(0).thread_count(),
+      1
+    );
+    assert_eq!(
+      JobPool::<usize>::with_threads(max)
+        .thread_count(),
+      max
+    );
+    assert_eq!(
+      JobPool::<usize>::with_threads(usize::MAX)
+        .thread_count(),
+      max
+    );
+  }
+}
@@ -11,10 +11,14 @@ use winit::{
 
 pub mod aliases;
 pub use aliases::*;
+pub mod block;
 pub mod gfx;
+pub mod job;
+pub mod registry;
 
 pub mod control;
 pub mod player;
+pub mod timing;
 pub mod world;
 
 pub mod types;
@@ -26,11 +30,100 @@ pub struct App {
   camera: Option<gfx::camera::CameraInstance>,
   world_renderer:
     Option<gfx::world_renderer::WorldRenderer>,
-  block_renderer: Option<
-    Vec<gfx::world_renderer::block_rdr::BlockRenderInstance,
-  >>,
+  chunk_meshes: gfx::world_renderer::block_rdr::ChunkMeshes,
   user_input: control::UserControlInput,
   player: player::Player,
+  world: world::World,
+  save: Option<world::save::WorldSave>,
+  streamer: world::stream::ChunkStreamer,
+  jobs: job::JobPool<job::JobOutput>,
+  timestep: timing::FixedTimestep,
+}
+impl App {
+  /// セーブ先のディレクトリ
+  const SAVES_DIR: &str = "saves";
+  /// 既定のワールド名
+  const WORLD_NAME: &str = "world";
+  /// ローカルプレイヤーの名前
+  const PLAYER_NAME: &str = "player";
+
+  /// 種値`seed`の地形の生成器
+  fn generator(
+    world: &world::World,
+    seed: u64,
+  ) -> Arc<dyn world::r#gen::TerrainGenerator> {
+    let registry = world.registry();
+    Arc::new(world::r#gen::CaveCarver::new(
+      world::r#gen::DefaultTerrainGenerator::new(
+        seed,
+        registry,
+        Arc::clone(world.biomes()),
+      ),
+      seed,
+      registry,
+    ))
+  }
+
+  /// ワールドを開き、プレイヤーの状態を復元する
+  fn open_world(
+    &mut self,
+    save: world::save::WorldSave,
+  ) -> StdResult<()> {
+    self.player =
+      match save.load_player(Self::PLAYER_NAME)? {
+        Some(state) => player::Player::from_state(&state),
+        None => {
+          player::Player::from_state(&player::PlayerState {
+            position: save.meta().spawn,
+            yaw: 0.,
+            pitch: 0.,
+          })
+        }
+      };
+    self
+      .world
+      .set_storage(save.region_storage()?);
+    let seed = save.meta().seed;
+    let generator = Self::generator(&self.world, seed);
+    self.world.set_generator(generator);
+    let structures =
+      world::r#gen::StructurePlacer::with_default_features(
+        seed,
+        Arc::clone(self.world.registry()),
+        Arc::clone(self.world.biomes()),
+      );
+    self
+      .world
+      .set_structures(structures);
+    let ticks = self.world.ticks_mut();
+    ticks.set_seed(seed);
+    ticks.set_time(save.meta().game_time);
+    self
+      .world
+      .register_default_tick_handlers();
+    self.save = Some(save);
+    Ok(())
+  }
+
+  /// 固定間隔の更新と描画の経過時間
+  #[inline]
+  pub fn timestep(&self) -> &timing::FixedTimestep {
+    &self.timestep
+  }
+
+  /// ワールドとプレイヤーの状態を保存する
+  fn save_world(&mut self) -> StdResult<()> {
+    let Some(save) = self.save.as_mut() else {
+      return Ok(());
+    };
+    save.meta_mut().game_time = self.world.time();
+    self.world.save_all()?;
+    save.save_player(
+      Self::PLAYER_NAME,
+      &self.player.state(),
+    )?;
+    save.save_meta()
+  }
 }
 impl ApplicationHandler for App {
   fn resumed(&mut self, event_loop: &ActiveEventLoop) {
@@ -81,12 +174,8 @@ impl ApplicationHandler for App {
         &wgpu_ctx, &camera,
       )
       .expect("World renderer initialize failure");
-    let block_renderer = [
-      gfx::world_renderer::block_rdr::BlockRenderInstance::new(&wgpu_ctx, 1088)
-    ].into();
     self.wgpu_ctx = Some(wgpu_ctx);
     self.world_renderer = Some(world_renderer);
-    self.block_renderer = Some(block_renderer);
     self.camera = Some(camera);
   }
 
@@ -105,25 +194,78 @@ impl ApplicationHandler for App {
         if let Some(world_renderer) =
           self.world_renderer.as_mut()
         {
+          // 経過時間に応じて固定間隔で更新する
+          let ticks = self
+            .timestep
+            .begin_frame(std::time::Instant::now());
+          let dt = self.timestep.tick_seconds();
+          self.player.look(&self.user_input);
+          self.user_input.update();
+          for _ in 0..ticks {
+            self.player.update(
+              &self.user_input,
+              &self.world,
+              dt,
+            );
+            self.player.interact(
+              &self.user_input,
+              &mut self.world,
+            );
+            self.user_input.end_tick();
+            self.world.tick();
+          }
           if let Some(camera) = self.camera.as_mut() {
-            self
-              .player
-              .update(&self.user_input);
-            self.user_input.update();
-            self
-              .player
-              .update_camera(camera);
+            self.player.update_camera(
+              camera,
+              self.timestep.alpha(),
+            );
             world_renderer
-              .update_camera(&wgpu_ctx, &camera);
+              .update_camera(wgpu_ctx, camera);
+          }
+          for result in self.jobs.drain() {
+            match result.output {
+              job::JobOutput::Generated(chunk) => {
+                self.streamer.receive_generated(
+                  &mut self.world,
+                  result.chunk_pos,
+                  chunk,
+                )
+              }
+              job::JobOutput::Lit(light) => {
+                self.world.apply_light(
+                  &result.chunk_pos,
+                  light,
+                );
+              }
+              job::JobOutput::Meshed(mesh) => {
+                self.chunk_meshes.receive_meshed(
+                  wgpu_ctx,
+                  &self.world,
+                  result.chunk_pos,
+                  &mesh,
+                )
+              }
+            }
           }
-          let Some(block_rdr) =
-            self.block_renderer.as_ref()
-          else {
-            return;
-          };
-          match wgpu_ctx
-            .rendering(world_renderer, &block_rdr)
-          {
+          if let Err(e) = self.streamer.update(
+            &mut self.world,
+            self.player.position(),
+            Some(&mut self.jobs),
+          ) {
+            eprintln!("chunk streaming error: {e}")
+          }
+          self
+            .world
+            .update_light(Some(&mut self.jobs));
+          self.chunk_meshes.sync(
+            wgpu_ctx,
+            &mut self.world,
+            Some(&mut self.jobs),
+          );
+          match wgpu_ctx.rendering(
+            world_renderer,
+            &self.chunk_meshes,
+          ) {
             Ok(_) => {}
             Err(wgpu::SurfaceError::Lost) => {
               wgpu_ctx.reconfigure()
@@ -140,12 +282,24 @@ impl ApplicationHandler for App {
       WindowEvent::Resized(_) => {
         wgpu_ctx.resize();
         if let Some(wr) = self.world_renderer.as_mut() {
-          wr.resize(&wgpu_ctx);
+          wr.resize(wgpu_ctx);
         }
       }
 
       // ウィンドウを閉じる要求が来た時の処理
-      WindowEvent::CloseRequested => event_loop.exit(),
+      WindowEvent::CloseRequested => {
+        if let Err(e) = self.save_world() {
+          eprintln!("world save error: {e}")
+        }
+        event_loop.exit()
+      }
+
+      // マウスボタン入力処理
+      WindowEvent::MouseInput { state, button, .. } => {
+        self
+          .user_input
+          .mouse_button_input(button, state);
+      }
 
       // キーボード入力処理
       WindowEvent::KeyboardInput { event, .. } => {
@@ -165,14 +319,11 @@ impl ApplicationHandler for App {
     _device_id: DeviceId,
     event: DeviceEvent,
   ) {
-    match event {
-      // マウス入力処理
-      DeviceEvent::MouseMotion { delta } => {
-        self
-          .user_input
-          .mouse_input([delta.0, delta.1]);
-      }
-      _ => {}
+    // マウス入力処理
+    if let DeviceEvent::MouseMotion { delta } = event {
+      self
+        .user_input
+        .mouse_input([delta.0, delta.1]);
     }
   }
 }
@@ -185,11 +336,31 @@ fn main() {
     window: None,
     wgpu_ctx: None,
     world_renderer: None,
-    block_renderer: None,
+    chunk_meshes:
+      gfx::world_renderer::block_rdr::ChunkMeshes::new(),
     camera: None,
     user_input: control::UserControlInput::new(),
     player: player::Player::new(),
+    world: world::World::new(),
+    save: None,
+    streamer: world::stream::ChunkStreamer::new(),
+    jobs: job::JobPool::new(),
+    timestep: timing::FixedTimestep::default(),
   };
+  let seed = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs());
+  match world::save::WorldSave::open_or_create(
+    App::SAVES_DIR,
+    App::WORLD_NAME,
+    seed,
+    App::generator(&app.world, seed).as_ref(),
+  )
+  .and_then(|save| app.open_world(save))
+  {
+    Ok(()) => {}
+    Err(e) => eprintln!("world open error: {e}"),
+  }
   event_loop
     .run_app(&mut app)
     .expect("Error occured");
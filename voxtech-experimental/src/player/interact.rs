@@ -0,0 +1,340 @@
+//! Interact
+//! 視線の先のブロックの破壊と設置
+
+use crate::block::{BlockId, BlockRegistry, AIR};
+use crate::control::UserControlInput;
+use crate::gfx::camera::CameraInstance;
+use crate::types::{Aabb, BlockPos, TileFace};
+use crate::world::change::ChangeCause;
+use crate::world::raycast::RaycastHit;
+use crate::world::World;
+
+/// ホットバーに並べるブロックの名前
+const HOTBAR_BLOCKS: [&str; 9] = [
+  "stone",
+  "dirt",
+  "grass",
+  "cobblestone",
+  "log",
+  "leaves",
+  "sand",
+  "glowstone",
+  "water",
+];
+
+/// マウス操作によるブロックの破壊と設置
+///
+/// カメラの視線を`reach`まで飛ばし、左ボタンで当たったブロックを壊し、
+/// 右ボタンで当たった面の手前に選択中のブロックを置く。ボタンを
+/// 押し続けた場合は`cooldown`回の更新毎に繰り返す。
+pub struct BlockInteraction {
+  /// 届く距離(ブロック)
+  pub reach: f64,
+  /// 操作を繰り返すまでの更新回数
+  pub cooldown: u32,
+  remaining: u32,
+  hotbar: Vec<BlockId>,
+}
+impl Default for BlockInteraction {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+impl BlockInteraction {
+  pub fn new() -> Self {
+    Self {
+      reach: 5.,
+      cooldown: 15,
+      remaining: 0,
+      hotbar: Vec::new(),
+    }
+  }
+
+  /// ホットバーのブロックを登録簿から解決する
+  /// 登録簿に無いブロックは空気になる
+  pub fn set_registry(
+    &mut self,
+    registry: &BlockRegistry,
+  ) {
+    self.hotbar = HOTBAR_BLOCKS
+      .iter()
+      .map(|name| {
+        registry
+          .id_of(name)
+          .unwrap_or(AIR)
+      })
+      .collect();
+  }
+
+  /// 選択中のブロック
+  pub fn selected(
+    &self,
+    input: &UserControlInput,
+  ) -> BlockId {
+    self
+      .hotbar
+      .get(input.hotbar_slot)
+      .copied()
+      .unwrap_or(AIR)
+  }
+
+  /// 視線の先のブロック
+  pub fn target(
+    &self,
+    camera: &CameraInstance,
+    world: &World,
+  ) -> Option<RaycastHit> {
+    world.raycast(
+      camera.position,
+      camera.rotation * nalgebra::Vector3::y(),
+      self.reach,
+    )
+  }
+
+  /// ブロックを置ける位置か
+  ///
+  /// 読み込まれた空気か流体の位置で、固体のブロックであれば
+  /// プレイヤーの当たり判定と重ならない必要がある。
+  pub fn can_place(
+    world: &World,
+    pos: &BlockPos,
+    block: BlockId,
+    player_box: &Aabb,
+  ) -> bool {
+    let current = world.get_block(pos);
+    world
+      .chunk(&pos.chunk_pos())
+      .is_some()
+      && (current == AIR
+        || world
+          .registry()
+          .fluid(current)
+          .is_some())
+      && !(world.registry().is_solid(block)
+        && player_box.intersects(&Aabb::block(pos)))
+  }
+
+  /// 入力に応じてブロックを破壊・設置する
+  /// 変更は`World::set_block`を通して行うため、更新フラグと変更
+  /// イベントが発生する
+  ///
+  /// ホットバーが未解決の場合はワールドの登録簿から解決する。
+  pub fn update(
+    &mut self,
+    input: &UserControlInput,
+    camera: &CameraInstance,
+    world: &mut World,
+    player_box: &Aabb,
+  ) {
+    if self.hotbar.is_empty() {
+      self.set_registry(world.registry());
+    }
+    let buttons = input.mouse_button;
+    if !buttons.left && !buttons.right {
+      self.remaining = 0;
+      return;
+    }
+    if 0 < self.remaining {
+      self.remaining -= 1;
+      return;
+    }
+    let Some(hit) = self.target(camera, world) else {
+      return;
+    };
+    if buttons.left {
+      world.set_block(
+        &hit.pos,
+        AIR,
+        ChangeCause::Player,
+      );
+    } else {
+      let block = self.selected(input);
+      let pos = hit.pos.neighbor(hit.face);
+      if block == AIR
+        || hit.face == TileFace::UNDEF
+        || !Self::can_place(
+          world, &pos, block, player_box,
+        )
+      {
+        return;
+      }
+      world.set_block(&pos, block, ChangeCause::Player);
+    }
+    self.remaining = self.cooldown;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::world::Chunk;
+
+  /// 床と、視線の先`y`に石の壁があるワールド
+  fn world(wall_y: i64) -> World {
+    let mut world = World::new();
+    let stone = world
+      .registry()
+      .id_of("stone")
+      .unwrap();
+    let chunk_pos = BlockPos::new(0, 0, 0);
+    world.spawn_chunk(chunk_pos, || {
+      Chunk::new(&chunk_pos, |pos| {
+        if pos.get_z() == 0
+          || pos == BlockPos::new(8, wall_y, 2)
+        {
+          stone
+        } else {
+          AIR
+        }
+      })
+    });
+    world
+  }
+
+  /// `(8.5, 2.5, 2.5)`から`+y`を向くカメラ
+  fn camera() -> CameraInstance {
+    CameraInstance {
+      position: [8.5, 2.5, 2.5].into(),
+      velocity: nalgebra::Vector3::zeros(),
+      rotation: nalgebra::UnitQuaternion::identity(),
+    }
+  }
+
+  /// カメラから離れた位置のプレイヤー
+  fn far_box() -> Aabb {
+    Aabb::block(&BlockPos::new(1, 1, 1))
+  }
+
+  fn input(
+    left: bool,
+    right: bool,
+  ) -> UserControlInput {
+    let mut input = UserControlInput::new();
+    input.mouse_button.left = left;
+    input.mouse_button.right = right;
+    input
+  }
+
+  #[test]
+  fn can_place_rules() {
+    let world = world(5);
+    let registry = world.registry();
+    let stone = registry.id_of("stone").unwrap();
+    let water = registry.id_of("water").unwrap();
+    let pos = BlockPos::new(8, 4, 2);
+    let player = Aabb::new(
+      [7.8, 3.7, 1.].into(),
+      [8.4, 4.3, 2.8].into(),
+    );
+    assert!(BlockInteraction::can_place(
+      &world,
+      &pos,
+      stone,
+      &far_box()
+    ));
+    // 固体はプレイヤーと重なる位置に置けない
+    assert!(!BlockInteraction::can_place(
+      &world, &pos, stone, &player
+    ));
+    assert!(BlockInteraction::can_place(
+      &world, &pos, water, &player
+    ));
+    // 埋まっている位置と読み込まれていない位置
+    assert!(!BlockInteraction::can_place(
+      &world,
+      &BlockPos::new(8, 5, 2),
+      stone,
+      &far_box()
+    ));
+    assert!(!BlockInteraction::can_place(
+      &world,
+      &BlockPos::new(8, 4, 20),
+      stone,
+      &far_box()
+    ));
+  }
+
+  #[test]
+  fn place_inside_player_is_rejected() {
+    let mut world = world(5);
+    let mut interaction = BlockInteraction::new();
+    let pos = BlockPos::new(8, 4, 2);
+    let player = Aabb::block(&pos);
+    interaction.update(
+      &input(false, true),
+      &camera(),
+      &mut world,
+      &player,
+    );
+    assert_eq!(world.get_block(&pos), AIR);
+    // 置けなかった操作は待ち時間を生まない
+    interaction.update(
+      &input(false, true),
+      &camera(),
+      &mut world,
+      &far_box(),
+    );
+    assert_ne!(world.get_block(&pos), AIR);
+  }
+
+  #[test]
+  fn beyond_reach_has_no_hit() {
+    let mut world = world(8);
+    let mut interaction = BlockInteraction::new();
+    assert!(interaction
+      .target(&camera(), &world)
+      .is_none());
+    interaction.update(
+      &input(true, false),
+      &camera(),
+      &mut world,
+      &far_box(),
+    );
+    assert_ne!(
+      world.get_block(&BlockPos::new(8, 8, 2)),
+      AIR
+    );
+    interaction.reach = 6.;
+    let hit = interaction
+      .target(&camera(), &world)
+      .unwrap();
+    assert_eq!(hit.pos, BlockPos::new(8, 8, 2));
+  }
+
+  #[test]
+  fn cooldown_ignores_held_button() {
+    let mut world = world(5);
+    let stone = world
+      .registry()
+      .id_of("stone")
+      .unwrap();
+    let behind = BlockPos::new(8, 6, 2);
+    world.set_block(
+      &behind,
+      stone,
+      ChangeCause::Other,
+    );
+    let mut interaction = BlockInteraction::new();
+    let cooldown = interaction.cooldown;
+    let held = input(true, false);
+    let mut update = |world: &mut World| {
+      interaction.update(
+        &held,
+        &camera(),
+        world,
+        &far_box(),
+      );
+    };
+    update(&mut world);
+    assert_eq!(
+      world.get_block(&BlockPos::new(8, 5, 2)),
+      AIR
+    );
+    for _ in 0..cooldown {
+      update(&mut world);
+      assert_eq!(world.get_block(&behind), stone);
+    }
+    update(&mut world);
+    assert_eq!(world.get_block(&behind), AIR);
+  }
+}
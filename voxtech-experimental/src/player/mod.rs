@@ -1,42 +1,173 @@
+use serde::{Deserialize, Serialize};
+
 use crate::control::UserControlInput;
+use crate::gfx::camera::CameraInstance;
+use crate::types::Aabb;
+use crate::world::World;
+
+pub mod interact;
+pub mod physics;
+
+/// 保存用のプレイヤーの状態
+#[derive(
+  Debug, Clone, Copy, PartialEq, Serialize, Deserialize,
+)]
+pub struct PlayerState {
+  pub position: [f64; 3],
+  pub yaw: f64,
+  pub pitch: f64,
+}
 
 pub struct Player {
   position: nalgebra::Point3<f64>,
-  velocity: nalgebra::Vector3<f64>,
+  /// 前回の更新の前の位置(描画の補間用)
+  previous: nalgebra::Point3<f64>,
+  body: physics::Body,
+  mode: physics::MovementMode,
   yaw: f64,
   pitch: f64,
   roll: f64,
+  interaction: interact::BlockInteraction,
+}
+impl Default for Player {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 impl Player {
   pub fn new() -> Self {
     Self {
       position: [0., 0., 0.].into(),
-      velocity: [0., 0., 0.].into(),
+      previous: [0., 0., 0.].into(),
+      body: physics::Body::new(),
+      mode: physics::MovementMode::Walk,
       yaw: 0.,
       pitch: 0.,
       roll: 0.,
+      interaction: interact::BlockInteraction::new(),
     }
   }
 
-  pub fn update(&mut self, input: &UserControlInput) {
-    if input.move_key.l {
-      self.velocity.x -= 5. / 60.
-    }
-    if input.move_key.r {
-      self.velocity.x += 5. / 60.
-    }
-    if input.move_key.dn {
-      self.velocity.z -= 5. / 60.
-    }
-    if input.move_key.up {
-      self.velocity.z += 5. / 60.
+  /// 当たり判定の幅
+  pub const WIDTH: f64 = 0.6;
+  /// 当たり判定の高さ
+  pub const HEIGHT: f64 = 1.8;
+  /// 足元から視点までの高さ
+  pub const EYE_HEIGHT: f64 = 1.62;
+
+  /// 保存された状態からプレイヤーを復元する
+  pub fn from_state(state: &PlayerState) -> Self {
+    Self {
+      position: state.position.into(),
+      previous: state.position.into(),
+      yaw: state
+        .yaw
+        .rem_euclid(std::f64::consts::PI * 2.),
+      pitch: state.pitch.clamp(
+        -std::f64::consts::FRAC_PI_2,
+        std::f64::consts::FRAC_PI_2,
+      ),
+      ..Self::new()
     }
-    if input.move_key.bw {
-      self.velocity.y -= 5. / 60.
+  }
+
+  /// 位置
+  #[inline]
+  pub fn position(&self) -> nalgebra::Point3<f64> {
+    self.position
+  }
+
+  /// 速度と接地の状態
+  #[inline]
+  pub fn body(&self) -> &physics::Body {
+    &self.body
+  }
+
+  /// 移動の仕方
+  #[inline]
+  pub fn mode(&self) -> physics::MovementMode {
+    self.mode
+  }
+
+  #[inline]
+  pub fn set_mode(
+    &mut self,
+    mode: physics::MovementMode,
+  ) {
+    self.mode = mode;
+  }
+
+  /// 当たり判定(位置は視点として足元を求める)
+  pub fn bounding_box(&self) -> Aabb {
+    let half = Self::WIDTH / 2.;
+    let feet = self.position.z - Self::EYE_HEIGHT;
+    Aabb::new(
+      [
+        self.position.x - half,
+        self.position.y - half,
+        feet,
+      ]
+      .into(),
+      [
+        self.position.x + half,
+        self.position.y + half,
+        feet + Self::HEIGHT,
+      ]
+      .into(),
+    )
+  }
+
+  /// ブロックの破壊と設置
+  #[inline]
+  pub fn interaction(&self) -> &interact::BlockInteraction {
+    &self.interaction
+  }
+
+  #[inline]
+  pub fn interaction_mut(
+    &mut self,
+  ) -> &mut interact::BlockInteraction {
+    &mut self.interaction
+  }
+
+  /// マウス入力に応じて視線の先のブロックを破壊・設置する
+  /// 視線は描画用の補間を含まない今回の更新の位置から飛ばす
+  pub fn interact(
+    &mut self,
+    input: &UserControlInput,
+    world: &mut crate::world::World,
+  ) {
+    let player_box = self.bounding_box();
+    let eye = self.eye();
+    self.interaction.update(
+      input,
+      &eye,
+      world,
+      &player_box,
+    );
+  }
+
+  /// 今回の更新の位置と向きの視点
+  pub fn eye(&self) -> CameraInstance {
+    CameraInstance {
+      position: self.position,
+      velocity: nalgebra::Vector3::zeros(),
+      rotation: self.rotation(),
     }
-    if input.move_key.fw {
-      self.velocity.y += 5. / 60.
+  }
+
+  /// 保存用の状態
+  pub fn state(&self) -> PlayerState {
+    PlayerState {
+      position: self.position.into(),
+      yaw: self.yaw,
+      pitch: self.pitch,
     }
+  }
+
+  /// マウス入力に応じて向きを変える
+  /// 視点の操作が遅れないよう描画毎に呼ぶ
+  pub fn look(&mut self, input: &UserControlInput) {
     self.yaw = (self.yaw
       - (0.12
         * input.mouse_velocity.input[0]
@@ -54,22 +185,79 @@ impl Player {
       );
   }
 
+  /// 入力に応じて移動の仕方に従い`dt`秒分動く
+  ///
+  /// 歩行では上昇キーで跳躍し、飛行と観戦では上昇・下降キーで
+  /// 鉛直に動く。固定間隔の更新毎に呼ぶ。
+  pub fn update(
+    &mut self,
+    input: &UserControlInput,
+    world: &World,
+    dt: f64,
+  ) {
+    self.previous = self.position;
+    if input.toggle_mode {
+      self.mode = self.mode.next();
+    }
+    let mut wish = nalgebra::Vector3::zeros();
+    if input.move_key.l {
+      wish.x -= 1.;
+    }
+    if input.move_key.r {
+      wish.x += 1.;
+    }
+    if input.move_key.bw {
+      wish.y -= 1.;
+    }
+    if input.move_key.fw {
+      wish.y += 1.;
+    }
+    if !self.mode.has_gravity() {
+      if input.move_key.up {
+        wish.z += 1.;
+      }
+      if input.move_key.dn {
+        wish.z -= 1.;
+      }
+    }
+    let wish = self.heading()
+      * wish
+        .try_normalize(f64::EPSILON)
+        .unwrap_or_default();
+    let offset = self.body.step(
+      &self.bounding_box(),
+      self.mode,
+      &wish,
+      input.move_key.up,
+      dt,
+      &physics::world_solid(world),
+    );
+    self.position += offset;
+  }
+
+  /// 水平方向の向き
+  fn heading(&self) -> nalgebra::UnitQuaternion<f64> {
+    nalgebra::UnitQuaternion::from_axis_angle(
+      &nalgebra::Vector3::z_axis(),
+      self.yaw,
+    )
+  }
+
+  /// カメラを視点に合わせる
+  /// 位置は前回と今回の更新の間を`alpha`(0..1)で補間する
   pub fn update_camera(
     &mut self,
-    camera: &mut super::gfx::camera::CameraInstance,
+    camera: &mut CameraInstance,
+    alpha: f64,
   ) {
-    let rotation =
-      nalgebra::UnitQuaternion::from_axis_angle(
-        &nalgebra::UnitVector3::new_normalize(
-          nalgebra::Vector3::z(),
-        ),
-        self.yaw,
-      );
-    self.position =
-      self.position + rotation * self.velocity;
-    self.velocity = [0., 0., 0.].into();
-    camera.position = self.position;
-    camera.rotation = rotation
+    camera.position = self.previous
+      + (self.position - self.previous) * alpha;
+    camera.rotation = self.rotation();
+  }
+
+  /// 視点の向き
+  fn rotation(&self) -> nalgebra::UnitQuaternion<f64> {
+    self.heading()
       * nalgebra::UnitQuaternion::from_axis_angle(
         &nalgebra::UnitVector3::new_normalize(
           nalgebra::Vector3::x(),
@@ -81,6 +269,6 @@ impl Player {
           nalgebra::Vector3::y(),
         ),
         self.roll,
-      );
+      )
   }
 }
@@ -0,0 +1,609 @@
+//! Physics
+//! 直方体の当たり判定による歩行の物理
+
+use nalgebra::Vector3;
+
+use crate::types::{Aabb, BlockPos};
+use crate::world::World;
+
+/// 重力加速度(ブロック/秒²)
+pub const GRAVITY: f64 = 28.;
+/// 落下速度の上限(ブロック/秒)
+pub const MAX_FALL_SPEED: f64 = 60.;
+/// 跳躍の初速(ブロック/秒)
+pub const JUMP_VELOCITY: f64 = 9.;
+/// 自動で登れる段差の高さ(ブロック)
+pub const STEP_HEIGHT: f64 = 1.;
+/// 歩行中の空中での加速と減速の割合
+pub const AIR_CONTROL: f64 = 0.2;
+
+/// 接しているだけの面を重なりとみなさないための許容誤差
+const EPSILON: f64 = 1e-7;
+
+/// 移動の仕方毎の速さと加減速
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovementParams {
+  /// 最高速度(ブロック/秒)
+  pub speed: f64,
+  /// 入力がある時に目標の速度に近づく割合(1/秒)
+  pub acceleration: f64,
+  /// 入力が無い時に止まろうとする割合(1/秒)
+  pub friction: f64,
+}
+
+/// 移動の仕方
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Hash,
+)]
+pub enum MovementMode {
+  /// 重力と当たり判定のある歩行
+  #[default]
+  Walk,
+  /// 当たり判定のある飛行
+  Fly,
+  /// 当たり判定の無い観戦
+  Spectator,
+}
+impl MovementMode {
+  pub const ALL: [Self; 3] = [
+    Self::Walk,
+    Self::Fly,
+    Self::Spectator,
+  ];
+
+  /// 速さと加減速
+  pub fn params(&self) -> MovementParams {
+    match self {
+      Self::Walk => MovementParams {
+        speed: 4.3,
+        acceleration: 20.,
+        friction: 20.,
+      },
+      Self::Fly => MovementParams {
+        speed: 11.,
+        acceleration: 6.,
+        friction: 4.,
+      },
+      Self::Spectator => MovementParams {
+        speed: 15.,
+        acceleration: 10.,
+        friction: 10.,
+      },
+    }
+  }
+
+  /// 重力を受けるか
+  #[inline]
+  pub fn has_gravity(&self) -> bool {
+    *self == Self::Walk
+  }
+
+  /// ブロックとの当たり判定があるか
+  #[inline]
+  pub fn collides(&self) -> bool {
+    *self != Self::Spectator
+  }
+
+  /// 切り替え順で次の移動の仕方
+  pub fn next(&self) -> Self {
+    match self {
+      Self::Walk => Self::Fly,
+      Self::Fly => Self::Spectator,
+      Self::Spectator => Self::Walk,
+    }
+  }
+}
+
+/// 移動の結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Collision {
+  /// 実際に移動した量
+  pub offset: Vector3<f64>,
+  /// 軸毎に移動が遮られたか
+  pub blocked: [bool; 3],
+}
+
+/// 読み込まれていないブロックも固体として扱う判定
+///
+/// 読み込まれる前のチャンクへ落ちたり入り込んだりしないようにする。
+pub fn world_solid(
+  world: &World,
+) -> impl Fn(&BlockPos) -> bool + '_ {
+  |pos| {
+    world
+      .chunk(&pos.chunk_pos())
+      .is_none()
+      || world
+        .registry()
+        .is_solid(world.get_block(pos))
+  }
+}
+
+/// 直方体を軸`axis`に沿って`distance`だけ動かせる量
+///
+/// 動く前から重なっているブロックは無視するため、埋まった状態からは
+/// 抜け出せる。
+pub fn sweep_axis(
+  aabb: &Aabb,
+  axis: usize,
+  distance: f64,
+  is_solid: &impl Fn(&BlockPos) -> bool,
+) -> f64 {
+  if distance == 0. {
+    return 0.;
+  }
+  let mut swept = *aabb;
+  if 0. < distance {
+    swept.max[axis] += distance;
+  } else {
+    swept.min[axis] += distance;
+  }
+  let mut distance = distance;
+  for pos in swept.block_range() {
+    if !is_solid(&pos) {
+      continue;
+    }
+    let block = Aabb::block(&pos);
+    let overlaps = (0..3)
+      .filter(|i| *i != axis)
+      .all(|i| {
+        aabb.min[i] < block.max[i] - EPSILON
+          && block.min[i] + EPSILON < aabb.max[i]
+      });
+    if !overlaps {
+      continue;
+    }
+    if 0. < distance
+      && aabb.max[axis] <= block.min[axis] + EPSILON
+    {
+      distance = distance
+        .min(block.min[axis] - aabb.max[axis])
+        .max(0.);
+    } else if distance < 0.
+      && block.max[axis] - EPSILON <= aabb.min[axis]
+    {
+      distance = distance
+        .max(block.max[axis] - aabb.min[axis])
+        .min(0.);
+    }
+  }
+  distance
+}
+
+/// 直方体を鉛直、東西、南北の順に一軸ずつ動かす
+pub fn move_aabb(
+  aabb: &Aabb,
+  motion: &Vector3<f64>,
+  is_solid: &impl Fn(&BlockPos) -> bool,
+) -> Collision {
+  let mut aabb = *aabb;
+  let mut offset = Vector3::zeros();
+  let mut blocked = [false; 3];
+  for axis in [2, 0, 1] {
+    let moved = sweep_axis(
+      &aabb,
+      axis,
+      motion[axis],
+      is_solid,
+    );
+    blocked[axis] =
+      EPSILON < (motion[axis] - moved).abs();
+    offset[axis] = moved;
+    let mut delta = Vector3::zeros();
+    delta[axis] = moved;
+    aabb = aabb.translated(&delta);
+  }
+  Collision { offset, blocked }
+}
+
+/// 移動する物体の速度と接地の状態
+///
+/// 位置は持たず、当たり判定の直方体を受け取って移動量を返す。
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Body {
+  pub velocity: Vector3<f64>,
+  /// 足元が固体のブロックに接しているか
+  pub on_ground: bool,
+}
+impl Body {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// `dt`秒分の移動量を求める
+  ///
+  /// `wish`は長さ1以下の目標の移動方向で、入力がある間は`acceleration`
+  /// で、無い間は`friction`で目標の速度に近づく。歩行では鉛直方向を
+  /// 重力と跳躍に任せ、空中では加減速が`AIR_CONTROL`倍になる。地上で
+  /// 水平方向に遮られた場合は`STEP_HEIGHT`まで持ち上げて動かし直し、
+  /// より遠くまで進めれば段差を登る。
+  pub fn step(
+    &mut self,
+    aabb: &Aabb,
+    mode: MovementMode,
+    wish: &Vector3<f64>,
+    jump: bool,
+    dt: f64,
+    is_solid: &impl Fn(&BlockPos) -> bool,
+  ) -> Vector3<f64> {
+    let params = mode.params();
+    let control =
+      if mode.has_gravity() && !self.on_ground {
+        AIR_CONTROL
+      } else {
+        1.
+      };
+    let rate = if wish.norm_squared() == 0. {
+      params.friction
+    } else {
+      params.acceleration
+    };
+    let blend = (rate * control * dt).min(1.);
+    let axes = if mode.has_gravity() {
+      2
+    } else {
+      3
+    };
+    for i in 0..axes {
+      self.velocity[i] += (wish[i] * params.speed
+        - self.velocity[i])
+        * blend;
+    }
+    if mode.has_gravity() {
+      if jump && self.on_ground {
+        self.velocity.z = JUMP_VELOCITY;
+      }
+      self.velocity.z = (self.velocity.z
+        - GRAVITY * dt)
+        .max(-MAX_FALL_SPEED);
+    }
+
+    let motion = self.velocity * dt;
+    if !mode.collides() {
+      self.on_ground = false;
+      return motion;
+    }
+    let mut collision =
+      move_aabb(aabb, &motion, is_solid);
+    if mode.has_gravity()
+      && self.on_ground
+      && (collision.blocked[0] || collision.blocked[1])
+      && let Some(stepped) =
+        Self::step_up(aabb, &motion, is_solid)
+      && collision
+        .offset
+        .xy()
+        .norm_squared()
+        < stepped
+          .offset
+          .xy()
+          .norm_squared()
+    {
+      collision = stepped;
+    }
+    self.on_ground =
+      collision.blocked[2] && motion.z < 0.;
+    for i in 0..3 {
+      if collision.blocked[i] {
+        self.velocity[i] = 0.;
+      }
+    }
+    collision.offset
+  }
+
+  /// 持ち上げてから水平に動かし、段差の上に下ろす
+  fn step_up(
+    aabb: &Aabb,
+    motion: &Vector3<f64>,
+    is_solid: &impl Fn(&BlockPos) -> bool,
+  ) -> Option<Collision> {
+    let rise =
+      sweep_axis(aabb, 2, STEP_HEIGHT, is_solid);
+    if rise <= EPSILON {
+      return None;
+    }
+    let raised =
+      aabb.translated(&Vector3::new(0., 0., rise));
+    let horizontal =
+      Vector3::new(motion.x, motion.y, 0.);
+    let moved =
+      move_aabb(&raised, &horizontal, is_solid);
+    let shifted = raised.translated(&moved.offset);
+    let fall = sweep_axis(
+      &shifted,
+      2,
+      motion.z.min(0.) - rise,
+      is_solid,
+    );
+    let offset =
+      moved.offset + Vector3::new(0., 0., rise + fall);
+    Some(Collision {
+      offset,
+      blocked: [
+        moved.blocked[0],
+        moved.blocked[1],
+        EPSILON
+          < (motion.z.min(0.) - rise - fall).abs(),
+      ],
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DT: f64 = 0.05;
+
+  /// 原点のブロックの中央にある0.6ブロック角の直方体
+  fn cube() -> Aabb {
+    Aabb::new(
+      [0.2, 0.2, 0.2].into(),
+      [0.8, 0.8, 0.8].into(),
+    )
+  }
+
+  /// 床(z < 0)の上に立つ幅0.6、高さ1.8の直方体
+  fn standing() -> Aabb {
+    Aabb::new(
+      [0.2, 0.2, 0.].into(),
+      [0.8, 0.8, 1.8].into(),
+    )
+  }
+
+  /// 歩行で`ticks`回動かし、最後の直方体を返す
+  fn simulate(
+    body: &mut Body,
+    aabb: Aabb,
+    wish: Vector3<f64>,
+    ticks: usize,
+    is_solid: &impl Fn(&BlockPos) -> bool,
+  ) -> Aabb {
+    simulate_mode(
+      body,
+      MovementMode::Walk,
+      aabb,
+      wish,
+      ticks,
+      is_solid,
+    )
+  }
+
+  /// 移動の仕方`mode`で`ticks`回動かし、最後の直方体を返す
+  fn simulate_mode(
+    body: &mut Body,
+    mode: MovementMode,
+    aabb: Aabb,
+    wish: Vector3<f64>,
+    ticks: usize,
+    is_solid: &impl Fn(&BlockPos) -> bool,
+  ) -> Aabb {
+    let mut aabb = aabb;
+    for _ in 0..ticks {
+      let offset = body.step(
+        &aabb, mode, &wish, false, DT, is_solid,
+      );
+      aabb = aabb.translated(&offset);
+    }
+    aabb
+  }
+
+  #[test]
+  fn walls_stop_each_axis() {
+    for axis in 0..3 {
+      for sign in [1., -1.] {
+        let wall = |pos: &BlockPos| {
+          pos.as_array()[axis] == 2 * sign as i64
+        };
+        let mut motion = Vector3::zeros();
+        motion[axis] = 5. * sign;
+        let collision =
+          move_aabb(&cube(), &motion, &wall);
+        assert!(
+          (collision.offset[axis] - 1.2 * sign).abs()
+            < 1e-9
+        );
+        assert_eq!(
+          collision.blocked,
+          std::array::from_fn(|i| i == axis)
+        );
+        let open = |_: &BlockPos| false;
+        let collision =
+          move_aabb(&cube(), &motion, &open);
+        assert_eq!(collision.offset, motion);
+        assert_eq!(collision.blocked, [false; 3]);
+      }
+    }
+  }
+
+  #[test]
+  fn lands_on_ground() {
+    let floor = |pos: &BlockPos| pos.get_z() < 0;
+    let mut body = Body::new();
+    let start =
+      standing().translated(&Vector3::new(0., 0., 3.));
+    let aabb = simulate(
+      &mut body,
+      start,
+      Vector3::zeros(),
+      5,
+      &floor,
+    );
+    assert!(0. < aabb.min.z);
+    assert!(!body.on_ground);
+    let aabb = simulate(
+      &mut body,
+      aabb,
+      Vector3::zeros(),
+      40,
+      &floor,
+    );
+    assert!(aabb.min.z.abs() < 1e-9);
+    assert!(body.on_ground);
+    assert_eq!(body.velocity.z, 0.);
+  }
+
+  #[test]
+  fn jumps_only_from_ground() {
+    let floor = |pos: &BlockPos| pos.get_z() < 0;
+    let mut body = Body::new();
+    let mut aabb = simulate(
+      &mut body,
+      standing(),
+      Vector3::zeros(),
+      2,
+      &floor,
+    );
+    assert!(body.on_ground);
+    let mut peak: f64 = 0.;
+    for tick in 0..60 {
+      let offset = body.step(
+        &aabb,
+        MovementMode::Walk,
+        &Vector3::zeros(),
+        true,
+        DT,
+        &floor,
+      );
+      aabb = aabb.translated(&offset);
+      peak = peak.max(aabb.min.z);
+      if tick == 0 {
+        assert!(0. < aabb.min.z);
+        assert!(!body.on_ground);
+      }
+      if tick == 5 {
+        // 空中では跳べない
+        assert!(
+          body.velocity.z
+            < JUMP_VELOCITY - GRAVITY * DT * 5.
+        );
+      }
+    }
+    let expected =
+      JUMP_VELOCITY.powi(2) / (2. * GRAVITY);
+    assert!(
+      (peak - expected).abs() < 0.25,
+      "{peak}"
+    );
+  }
+
+  #[test]
+  fn steps_up_one_block() {
+    let step = |pos: &BlockPos| {
+      pos.get_z() < 0
+        || (2 <= pos.get_x() && pos.get_z() == 0)
+    };
+    let mut body = Body::new();
+    let aabb = simulate(
+      &mut body,
+      standing(),
+      Vector3::new(1., 0., 0.),
+      40,
+      &step,
+    );
+    assert!((aabb.min.z - 1.).abs() < 1e-9);
+    assert!(3. < aabb.min.x);
+    assert!(body.on_ground);
+  }
+
+  #[test]
+  fn refuses_two_block_step() {
+    let wall = |pos: &BlockPos| {
+      pos.get_z() < 0
+        || (2 <= pos.get_x() && pos.get_z() <= 1)
+    };
+    let mut body = Body::new();
+    let aabb = simulate(
+      &mut body,
+      standing(),
+      Vector3::new(1., 0., 0.),
+      40,
+      &wall,
+    );
+    assert!(aabb.min.z.abs() < 1e-9);
+    assert!((aabb.max.x - 2.).abs() < 1e-9);
+    assert!(body.on_ground);
+  }
+
+  #[test]
+  fn spectator_passes_through_walls() {
+    let wall = |pos: &BlockPos| pos.get_x() == 2;
+    let mut body = Body::new();
+    let aabb = simulate_mode(
+      &mut body,
+      MovementMode::Spectator,
+      cube(),
+      Vector3::x(),
+      40,
+      &wall,
+    );
+    assert!(3. < aabb.min.x, "{aabb:?}");
+    // 重力を受けず高さは変わらない
+    assert_eq!(aabb.min.z, cube().min.z);
+    assert!(!body.on_ground);
+  }
+
+  #[test]
+  fn fly_has_no_gravity_and_collides() {
+    let wall = |pos: &BlockPos| {
+      pos.get_x() == 2 || pos.get_z() < -5
+    };
+    let mut body = Body::new();
+    let hover = simulate_mode(
+      &mut body,
+      MovementMode::Fly,
+      cube(),
+      Vector3::zeros(),
+      40,
+      &wall,
+    );
+    assert_eq!(hover, cube());
+    assert_eq!(body.velocity, Vector3::zeros());
+
+    let aabb = simulate_mode(
+      &mut body,
+      MovementMode::Fly,
+      cube(),
+      Vector3::x(),
+      40,
+      &wall,
+    );
+    assert!(
+      (aabb.max.x - 2.).abs() < 1e-6,
+      "{aabb:?}"
+    );
+    assert_eq!(body.velocity.x, 0.);
+    assert_eq!(aabb.min.z, cube().min.z);
+
+    // 上下にも入力の通りに動く
+    let aabb = simulate_mode(
+      &mut body,
+      MovementMode::Fly,
+      cube(),
+      Vector3::z(),
+      20,
+      &wall,
+    );
+    assert!(cube().min.z + 1. < aabb.min.z);
+  }
+
+  #[test]
+  fn modes_cycle() {
+    assert_eq!(
+      MovementMode::Walk.next(),
+      MovementMode::Fly
+    );
+    assert_eq!(
+      MovementMode::Fly.next(),
+      MovementMode::Spectator
+    );
+    assert_eq!(
+      MovementMode::Spectator.next(),
+      MovementMode::Walk
+    );
+    for mode in MovementMode::ALL {
+      assert_eq!(mode.next().next().next(), mode);
+      assert_ne!(mode.next(), mode);
+    }
+  }
+}
@@ -0,0 +1,281 @@
+//! Registry
+//! 数値IDと名前で引く、JSONで定義される登録簿
+
+use std::sync::Arc;
+
+use hashbrown::HashMap;
+use serde::de::DeserializeOwned;
+
+/// 登録簿に載せる定義
+pub trait RegistryDef:
+  Clone + DeserializeOwned + Sized
+{
+  /// 数値ID
+  type Id: Copy + Into<usize>;
+  /// 読み込み時のエラー
+  type Error: std::error::Error + 'static;
+
+  /// JSONファイルで定義の一覧を持つキー
+  const LIST_KEY: &'static str;
+  /// 組み込みの定義のJSON
+  const BUILTIN: &'static str;
+
+  fn id(&self) -> Self::Id;
+
+  /// 識別用の名前
+  fn name(&self) -> &str;
+
+  /// 同じIDが複数回定義された時のエラー
+  fn duplicate_id(id: Self::Id) -> Self::Error;
+
+  /// 同じ名前が複数回定義された時のエラー
+  fn duplicate_name(name: String) -> Self::Error;
+
+  /// 登録前に定義を検証する
+  fn validate(&self) -> Result<(), Self::Error> {
+    Ok(())
+  }
+
+  /// 全ての定義を登録した後に検証し、必要な定義を補う
+  fn complete(
+    _registry: &mut Registry<Self>,
+  ) -> Result<(), Self::Error> {
+    Ok(())
+  }
+}
+
+/// IDと定義の登録簿
+#[derive(Debug, Clone)]
+pub struct Registry<D: RegistryDef> {
+  defs: Vec<Option<D>>,
+  names: HashMap<String, D::Id>,
+}
+impl<D: RegistryDef> Default for Registry<D> {
+  fn default() -> Self {
+    Self::builtin()
+  }
+}
+impl<D: RegistryDef> Registry<D> {
+  /// 定義の一覧から登録簿を作る
+  pub fn from_defs(
+    defs: impl IntoIterator<Item = D>,
+  ) -> Result<Self, D::Error> {
+    let mut registry = Self {
+      defs: Vec::new(),
+      names: HashMap::new(),
+    };
+    for def in defs {
+      registry.register(def)?;
+    }
+    D::complete(&mut registry)?;
+    Ok(registry)
+  }
+
+  pub(crate) fn register(
+    &mut self,
+    def: D,
+  ) -> Result<(), D::Error> {
+    def.validate()?;
+    let index = def.id().into();
+    if self.defs.len() <= index {
+      self
+        .defs
+        .resize(index + 1, None);
+    }
+    if self.defs[index].is_some() {
+      return Err(D::duplicate_id(def.id()));
+    }
+    if self
+      .names
+      .contains_key(def.name())
+    {
+      return Err(D::duplicate_name(
+        def.name().into(),
+      ));
+    }
+    self
+      .names
+      .insert(def.name().into(), def.id());
+    self.defs[index] = Some(def);
+    Ok(())
+  }
+
+  /// JSON文字列から読み込む
+  pub fn from_json_str(
+    json: &str,
+  ) -> crate::StdResult<Self> {
+    let mut file: serde_json::Map<
+      String,
+      serde_json::Value,
+    > = serde_json::from_str(json)?;
+    let list = file
+      .remove(D::LIST_KEY)
+      .ok_or_else(|| {
+        <serde_json::Error as serde::de::Error>::missing_field(
+          D::LIST_KEY,
+        )
+      })?;
+    let defs: Vec<D> = serde_json::from_value(list)?;
+    Ok(Self::from_defs(defs)?)
+  }
+
+  /// JSONファイルから読み込む
+  pub fn load(
+    path: impl AsRef<std::path::Path>,
+  ) -> crate::StdResult<Self> {
+    let json = std::fs::read_to_string(path)?;
+    Self::from_json_str(&json)
+  }
+
+  /// 組み込みの定義
+  pub fn builtin() -> Self {
+    Self::from_json_str(D::BUILTIN).unwrap_or_else(
+      |e| {
+        panic!(
+          "builtin `{}` definitions are invalid: {e}",
+          D::LIST_KEY
+        )
+      },
+    )
+  }
+
+  /// 共有用の組み込みの定義
+  pub fn shared_builtin() -> Arc<Self> {
+    Arc::new(Self::builtin())
+  }
+
+  #[inline]
+  pub fn get(&self, id: D::Id) -> Option<&D> {
+    self
+      .defs
+      .get(id.into())?
+      .as_ref()
+  }
+
+  pub fn by_name(&self, name: &str) -> Option<&D> {
+    self.get(*self.names.get(name)?)
+  }
+
+  #[inline]
+  pub fn id_of(&self, name: &str) -> Option<D::Id> {
+    self.names.get(name).copied()
+  }
+
+  /// 登録済みの定義を走査する
+  pub fn iter(&self) -> impl Iterator<Item = &D> {
+    self.defs.iter().flatten()
+  }
+
+  /// 登録済みの定義の数
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.names.len()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.names.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::block::{
+    BlockDef, BlockRegistry, BlockRegistryError, AIR,
+  };
+  use crate::world::r#gen::biome::BiomeRegistryError;
+  use crate::world::r#gen::BiomeRegistry;
+
+  fn block(id: u16, name: &str) -> BlockDef {
+    BlockDef {
+      id,
+      name: name.into(),
+      ..BlockDef::air()
+    }
+  }
+
+  #[test]
+  fn builtin() {
+    let blocks = BlockRegistry::builtin();
+    let stone = blocks.id_of("stone").unwrap();
+    assert_eq!(
+      blocks.get(stone).unwrap().name,
+      "stone"
+    );
+    assert_eq!(
+      blocks
+        .by_name("stone")
+        .unwrap()
+        .id,
+      stone
+    );
+    assert_eq!(
+      blocks.iter().count(),
+      blocks.len()
+    );
+    let biomes = BiomeRegistry::builtin();
+    assert!(!biomes.is_empty());
+    for def in biomes.iter() {
+      assert_eq!(
+        biomes.id_of(&def.name),
+        Some(def.id)
+      );
+    }
+  }
+
+  #[test]
+  fn duplicates() {
+    let error = BlockRegistry::from_defs([
+      block(1, "a"),
+      block(1, "b"),
+    ])
+    .unwrap_err();
+    assert_eq!(
+      error,
+      BlockRegistryError::DuplicateId(1)
+    );
+    let error = BlockRegistry::from_defs([
+      block(1, "a"),
+      block(2, "a"),
+    ])
+    .unwrap_err();
+    assert_eq!(
+      error,
+      BlockRegistryError::DuplicateName("a".into())
+    );
+  }
+
+  #[test]
+  fn completes_definitions() {
+    let blocks =
+      BlockRegistry::from_defs([block(3, "c")])
+        .unwrap();
+    assert_eq!(
+      blocks.get(AIR).unwrap().name,
+      "air"
+    );
+    assert_eq!(blocks.len(), 2);
+    assert!(blocks.get(2).is_none());
+    assert_eq!(
+      BiomeRegistry::from_defs([]).unwrap_err(),
+      BiomeRegistryError::Empty
+    );
+  }
+
+  #[test]
+  fn json() {
+    let blocks = BlockRegistry::from_json_str(
+      r#"{"blocks": [{"id": 1, "name": "rock"}]}"#,
+    )
+    .unwrap();
+    assert_eq!(blocks.id_of("rock"), Some(1));
+    assert!(BlockRegistry::from_json_str(
+      r#"{"biomes": []}"#
+    )
+    .is_err());
+    assert!(BlockRegistry::from_json_str(
+      r#"{"blocks": [{"id": 1, "name": "lamp", "light_emission": 16}]}"#
+    )
+    .is_err());
+  }
+}
@@ -0,0 +1,228 @@
+//! Timing
+//! フレームレートに依らない固定間隔の更新
+
+use std::time::{Duration, Instant};
+
+/// 固定間隔の更新の蓄積器
+///
+/// フレーム毎に経過時間を蓄え、一ティック分貯まる毎に更新を一回
+/// 行う。端数は描画時の補間の割合として使う。処理が追いつかない
+/// 場合は`max_ticks_per_frame`を超えた分の時間を捨てる。
+#[derive(Debug, Clone)]
+pub struct FixedTimestep {
+  tick: Duration,
+  /// 一フレームで行う更新の上限
+  pub max_ticks_per_frame: u32,
+  accumulator: Duration,
+  last_frame: Option<Instant>,
+  frame_time: Duration,
+  frame_rate: f64,
+  ticks: u64,
+  frames: u64,
+  ticks_this_frame: u32,
+}
+impl Default for FixedTimestep {
+  fn default() -> Self {
+    Self::new(Self::DEFAULT_RATE)
+  }
+}
+impl FixedTimestep {
+  /// 既定の更新頻度(Hz)
+  pub const DEFAULT_RATE: u32 = 60;
+  /// フレームレートの平滑化の係数
+  const FRAME_RATE_SMOOTHING: f64 = 0.05;
+
+  /// 毎秒`rate`回更新する
+  pub fn new(rate: u32) -> Self {
+    Self {
+      tick: Duration::from_secs(1) / rate.max(1),
+      max_ticks_per_frame: 8,
+      accumulator: Duration::ZERO,
+      last_frame: None,
+      frame_time: Duration::ZERO,
+      frame_rate: 0.,
+      ticks: 0,
+      frames: 0,
+      ticks_this_frame: 0,
+    }
+  }
+
+  /// フレームの開始時刻から今回行う更新の回数を求める
+  /// 最初のフレームでは更新しない
+  pub fn begin_frame(&mut self, now: Instant) -> u32 {
+    let elapsed = self
+      .last_frame
+      .map_or(Duration::ZERO, |last| {
+        now.saturating_duration_since(last)
+      });
+    self.last_frame = Some(now);
+    self.advance(elapsed)
+  }
+
+  /// 経過時間`elapsed`を蓄えて今回行う更新の回数を求める
+  pub fn advance(&mut self, elapsed: Duration) -> u32 {
+    self.frames += 1;
+    self.frame_time = elapsed;
+    if !elapsed.is_zero() {
+      let rate = 1. / elapsed.as_secs_f64();
+      self.frame_rate = if self.frame_rate == 0. {
+        rate
+      } else {
+        self.frame_rate
+          + (rate - self.frame_rate)
+            * Self::FRAME_RATE_SMOOTHING
+      };
+    }
+    self.accumulator += elapsed;
+    let mut ticks = 0;
+    while self.tick <= self.accumulator {
+      if ticks == self.max_ticks_per_frame {
+        self.accumulator = Duration::ZERO;
+        break;
+      }
+      self.accumulator -= self.tick;
+      ticks += 1;
+    }
+    self.ticks += ticks as u64;
+    self.ticks_this_frame = ticks;
+    ticks
+  }
+
+  /// 一ティックの長さ
+  #[inline]
+  pub fn tick_duration(&self) -> Duration {
+    self.tick
+  }
+
+  /// 一ティックの長さ(秒)
+  #[inline]
+  pub fn tick_seconds(&self) -> f64 {
+    self.tick.as_secs_f64()
+  }
+
+  /// 前回と今回の更新の間の描画位置の割合(0..1)
+  #[inline]
+  pub fn alpha(&self) -> f64 {
+    (self.accumulator.as_secs_f64()
+      / self.tick.as_secs_f64())
+    .min(1.)
+  }
+
+  /// 直前のフレームの経過時間
+  #[inline]
+  pub fn frame_time(&self) -> Duration {
+    self.frame_time
+  }
+
+  /// 平滑化したフレームレート(fps)
+  #[inline]
+  pub fn frame_rate(&self) -> f64 {
+    self.frame_rate
+  }
+
+  /// 開始からの更新の回数
+  #[inline]
+  pub fn ticks(&self) -> u64 {
+    self.ticks
+  }
+
+  /// 開始からのフレームの数
+  #[inline]
+  pub fn frames(&self) -> u64 {
+    self.frames
+  }
+
+  /// 直前のフレームで行った更新の回数
+  #[inline]
+  pub fn ticks_this_frame(&self) -> u32 {
+    self.ticks_this_frame
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn advance_counts_whole_ticks() {
+    let mut timestep = FixedTimestep::new(60);
+    let tick = timestep.tick_duration();
+    assert_eq!(
+      timestep.advance(Duration::ZERO),
+      0
+    );
+    assert_eq!(timestep.alpha(), 0.);
+    assert_eq!(timestep.advance(tick / 2), 0);
+    assert!((timestep.alpha() - 0.5).abs() < 1e-6);
+    assert_eq!(timestep.advance(tick / 2), 1);
+    assert_eq!(timestep.alpha(), 0.);
+    assert_eq!(timestep.advance(tick), 1);
+    assert_eq!(timestep.alpha(), 0.);
+    assert_eq!(
+      timestep.advance(tick * 5 + tick / 4),
+      5
+    );
+    assert!((timestep.alpha() - 0.25).abs() < 1e-6);
+    assert_eq!(timestep.ticks(), 7);
+    assert_eq!(timestep.frames(), 5);
+    assert_eq!(timestep.ticks_this_frame(), 5);
+  }
+
+  #[test]
+  fn cap_discards_the_accumulator() {
+    let mut timestep = FixedTimestep::new(60);
+    let tick = timestep.tick_duration();
+    let max = timestep.max_ticks_per_frame;
+    assert_eq!(
+      timestep.advance(tick * 100 + tick / 2),
+      max
+    );
+    assert_eq!(timestep.alpha(), 0.);
+    // 捨てた分は次のフレームに持ち越さない
+    assert_eq!(timestep.advance(tick / 2), 0);
+    assert_eq!(timestep.ticks(), max as u64);
+    // ちょうど上限の分は捨てずに端数を残す
+    assert_eq!(
+      timestep.advance(tick * max + tick / 4),
+      max
+    );
+    assert!(0. < timestep.alpha());
+  }
+
+  #[test]
+  fn alpha_is_below_one() {
+    let mut timestep = FixedTimestep::new(60);
+    let tick = timestep.tick_duration();
+    for i in 0..200u32 {
+      timestep.advance(tick * (i % 11) / 7);
+      let alpha = timestep.alpha();
+      assert!(
+        (0. ..1.).contains(&alpha),
+        "{alpha}"
+      );
+    }
+  }
+
+  #[test]
+  fn zero_rate_is_clamped() {
+    let mut timestep = FixedTimestep::new(0);
+    assert_eq!(
+      timestep.tick_duration(),
+      Duration::from_secs(1)
+    );
+    assert_eq!(
+      timestep.advance(Duration::from_millis(1500)),
+      1
+    );
+    assert!((timestep.alpha() - 0.5).abs() < 1e-6);
+  }
+
+  #[test]
+  fn first_frame_does_not_tick() {
+    let mut timestep = FixedTimestep::new(60);
+    let start = Instant::now();
+    assert_eq!(timestep.begin_frame(start), 0);
+    let later = start + timestep.tick_duration() * 3;
+    assert_eq!(timestep.begin_frame(later), 3);
+  }
+}
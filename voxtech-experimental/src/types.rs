@@ -1,3 +1,8 @@
+//! 座標系
+//! ブロック座標とチャンク・セル・ブロックへの分解、Morton符号化、直方体
+
+use std::ops::{Add, Div, Mul, Rem, Sub};
+
 /// 64分木の内部座標
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -6,10 +11,9 @@ impl From<BlockPos> for Tree64InnerPos {
   #[inline]
   fn from(value: BlockPos) -> Self {
     Self(
-      (*value.x() & 3) as u8
-        | (*value.y() & 3 << 2) as u8
-        | (*value.z() & 3 << 4) as u8
-        | (*value.w() & 3 << 6) as u8,
+      (value.get_x() & 3) as u8
+        | ((value.get_y() & 3) << 2) as u8
+        | ((value.get_z() & 3) << 4) as u8,
     )
   }
 }
@@ -27,6 +31,12 @@ impl Tree64InnerPos {
   pub fn is_valid(&self) -> bool {
     self.0 < 64
   }
+
+  /// 子ノード配列上の番号
+  #[inline]
+  pub fn index(&self) -> usize {
+    self.0 as usize
+  }
 }
 
 /// 64分木の内部距離
@@ -34,104 +44,843 @@ impl Tree64InnerPos {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Tree64InnerDist(u8);
 
+/// ブロックのタイルが向いている面
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TileFace {
+  /// 西(X-)
+  WST = 0,
+  /// 東(X+)
+  EST = 1,
+  /// 南(Y-)
+  STH = 2,
+  /// 北(Y+)
+  NTH = 3,
+  /// 下(Z-)
+  BTM = 4,
+  /// 上(Z+)
+  TOP = 5,
+  /// 未定義
+  UNDEF = u8::MAX,
+}
+impl std::fmt::Display for TileFace {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    f.write_str(match self {
+      TileFace::WST => "West",
+      TileFace::EST => "East",
+      TileFace::STH => "South",
+      TileFace::NTH => "North",
+      TileFace::BTM => "Bottom",
+      TileFace::TOP => "Top",
+      TileFace::UNDEF => "Undefined",
+    })
+  }
+}
+impl From<u8> for TileFace {
+  fn from(value: u8) -> Self {
+    match value {
+      0 => Self::WST,
+      1 => Self::EST,
+      2 => Self::STH,
+      3 => Self::NTH,
+      4 => Self::BTM,
+      5 => Self::TOP,
+      _ => Self::UNDEF,
+    }
+  }
+}
+impl TileFace {
+  /// 未定義を除く全ての面
+  pub const ALL: [TileFace; 6] = [
+    Self::WST,
+    Self::EST,
+    Self::STH,
+    Self::NTH,
+    Self::BTM,
+    Self::TOP,
+  ];
+
+  /// 面の法線方向への隣接ブロックまでの距離
+  #[inline]
+  pub fn offset(&self) -> BlockDist {
+    match self {
+      Self::WST => BlockDist::new(-1, 0, 0),
+      Self::EST => BlockDist::new(1, 0, 0),
+      Self::STH => BlockDist::new(0, -1, 0),
+      Self::NTH => BlockDist::new(0, 1, 0),
+      Self::BTM => BlockDist::new(0, 0, -1),
+      Self::TOP => BlockDist::new(0, 0, 1),
+      Self::UNDEF => BlockDist::new(0, 0, 0),
+    }
+  }
+
+  /// 反対側の面
+  #[inline]
+  pub fn opposite(&self) -> Self {
+    match self {
+      Self::WST => Self::EST,
+      Self::EST => Self::WST,
+      Self::STH => Self::NTH,
+      Self::NTH => Self::STH,
+      Self::BTM => Self::TOP,
+      Self::TOP => Self::BTM,
+      Self::UNDEF => Self::UNDEF,
+    }
+  }
+}
+
 /// ブロック用の座標構造体
+///
+/// 4ブロック四方をセル、4セル四方(16ブロック四方)をチャンクとし、
+/// 各階層の内部座標は64分木の番号`x | y << 2 | z << 4`で表す。
 #[repr(C, align(32))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct BlockPos(pub [i64; 4]);
+pub struct BlockPos([i64; 3]);
 impl From<[i64; 3]> for BlockPos {
+  #[inline]
   fn from(value: [i64; 3]) -> Self {
-    Self([value[0], value[1], value[2], 0])
+    Self(value)
   }
 }
 impl BlockPos {
+  /// Morton符号で扱える各軸のビット数
+  pub const MORTON_BITS: u32 = 21;
+
+  #[inline]
   pub fn new(x: i64, y: i64, z: i64) -> Self {
-    Self([x, y, z, 0])
+    Self([x, y, z])
+  }
+  #[inline]
+  pub fn as_array(&self) -> [i64; 3] {
+    self.0
+  }
+  #[inline]
+  pub fn as_64index(&self) -> u8 {
+    (self.get_x() & 0b11) as u8
+      | ((self.get_y() & 0b11) << 2) as u8
+      | ((self.get_z() & 0b11) << 4) as u8
   }
-
   #[inline]
-  pub fn x(&self) -> &i64 {
-    &self.0[0]
+  pub fn from_64index(pos: u8) -> Self {
+    Self::new(
+      (pos & 0b11) as i64,
+      ((pos >> 2) & 0b11) as i64,
+      ((pos >> 4) & 0b11) as i64,
+    )
   }
   #[inline]
-  pub fn y(&self) -> &i64 {
-    &self.0[1]
+  pub fn get_x(&self) -> i64 {
+    self.0[0]
   }
   #[inline]
-  pub fn z(&self) -> &i64 {
-    &self.0[2]
+  pub fn get_y(&self) -> i64 {
+    self.0[1]
   }
   #[inline]
-  pub fn w(&self) -> &i64 {
-    &self.0[3]
+  pub fn get_z(&self) -> i64 {
+    self.0[2]
   }
   #[inline]
-  pub fn x_mut(&mut self) -> &mut i64 {
-    &mut self.0[0]
+  pub fn set_x(&mut self, x: i64) {
+    self.0[0] = x
   }
   #[inline]
-  pub fn y_mut(&mut self) -> &mut i64 {
-    &mut self.0[1]
+  pub fn set_y(&mut self, y: i64) {
+    self.0[1] = y
   }
   #[inline]
-  pub fn z_mut(&mut self) -> &mut i64 {
-    &mut self.0[2]
+  pub fn set_z(&mut self, z: i64) {
+    self.0[2] = z
   }
+
+  /// 一つ上の階層の座標と、その内部座標(0..4)に分割する
+  ///
+  /// 負の座標でも算術シフトと2の補数のマスクにより
+  /// `-1`は上位`-1`・内部`3`のように床方向へ分割される。
   #[inline]
-  pub fn w_mut(&mut self) -> &mut i64 {
-    &mut self.0[3]
+  pub fn split_inner(&self) -> (BlockPos, BlockPos) {
+    let to = Self::new(
+      self.0[0] >> 2,
+      self.0[1] >> 2,
+      self.0[2] >> 2,
+    );
+    let inner = Self::new(
+      self.0[0] & 0b11,
+      self.0[1] & 0b11,
+      self.0[2] & 0b11,
+    );
+    (to, inner)
+  }
+  /// `split_inner`の逆変換
+  #[inline]
+  pub fn merge_inner(
+    &self,
+    inner: BlockPos,
+  ) -> BlockPos {
+    Self::new(
+      (self.0[0] << 2) | (inner.get_x() & 0b11),
+      (self.0[1] << 2) | (inner.get_y() & 0b11),
+      (self.0[2] << 2) | (inner.get_z() & 0b11),
+    )
   }
 
+  /// `split_inner`の64分木の内部座標版
+  #[inline]
+  pub fn split_innerpos(&self) -> (Tree64InnerPos, Self) {
+    let (to, inner) = self.split_inner();
+    (Tree64InnerPos::from(inner), to)
+  }
+  /// `merge_inner`の64分木の内部座標版
   #[inline]
   pub fn insert_innerpos(
     &self,
     inner_pos: Tree64InnerPos,
   ) -> Self {
-    Self([
-      self.0[0] << 2 | (inner_pos.0 & 3) as i64,
-      self.0[1] << 2 | (inner_pos.0 >> 2 & 3) as i64,
-      self.0[2] << 2 | (inner_pos.0 >> 4 & 3) as i64,
-      self.0[3] << 2 | (inner_pos.0 >> 6 & 3) as i64,
-    ])
+    self.merge_inner(Self::from_64index(inner_pos.0))
   }
 
   #[inline]
-  pub fn split_innerpos(&self) -> (Tree64InnerPos, Self) {
+  pub fn up_level(&self, shift: u8) -> Self {
+    Self::new(
+      self.0[0] << (2 * shift),
+      self.0[1] << (2 * shift),
+      self.0[2] << (2 * shift),
+    )
+  }
+  #[inline]
+  pub fn down_level(&self, shift: u8) -> Self {
+    Self::new(
+      self.0[0] >> (2 * shift),
+      self.0[1] >> (2 * shift),
+      self.0[2] >> (2 * shift),
+    )
+  }
+
+  /// このブロックを含むチャンクの座標
+  #[inline]
+  pub fn chunk_pos(&self) -> Self {
+    self.down_level(2)
+  }
+  /// このブロックを含むセルの座標
+  #[inline]
+  pub fn cell_pos(&self) -> Self {
+    self.down_level(1)
+  }
+  /// チャンク内のセルの番号
+  #[inline]
+  pub fn cell_index(&self) -> u8 {
+    self.cell_pos().as_64index()
+  }
+  /// セル内のブロックの番号
+  #[inline]
+  pub fn block_index(&self) -> u8 {
+    self.as_64index()
+  }
+  /// チャンクの座標、チャンク内のセルの番号、セル内のブロックの番号に分解する
+  #[inline]
+  pub fn split_chunk(&self) -> (Self, u8, u8) {
     (
-      Tree64InnerPos::new(
-        (self.0[0] & 3) as u8
-          | (self.0[1] & 3 << 2) as u8
-          | (self.0[2] & 3 << 4) as u8
-          | (self.0[3] & 3 << 6) as u8,
-      ),
-      Self([
-        self.0[0] >> 2,
-        self.0[1] >> 2,
-        self.0[2] >> 2,
-        self.0[3] >> 2,
-      ]),
+      self.chunk_pos(),
+      self.cell_index(),
+      self.block_index(),
     )
   }
+  /// チャンク内のブロックの番号(`cell << 6 | block`の12bit)
+  #[inline]
+  pub fn chunk_index(&self) -> u16 {
+    ((self.cell_index() as u16) << 6)
+      | self.block_index() as u16
+  }
+  /// チャンクの座標とチャンク内のブロックの番号から座標を求める
+  #[inline]
+  pub fn from_chunk_index(
+    chunk_pos: &Self,
+    index: u16,
+  ) -> Self {
+    chunk_pos
+      .merge_inner(Self::from_64index(
+        (index >> 6) as u8 & 63,
+      ))
+      .merge_inner(Self::from_64index(index as u8 & 63))
+  }
 
+  /// 隣接するブロックの座標
   #[inline]
-  pub fn level_up(&self, level: u8) -> Self {
-    Self([
-      self.0[0] << level * 2,
-      self.0[1] << level * 2,
-      self.0[2] << level * 2,
-      self.0[3] << level * 2,
-    ])
+  pub fn neighbor(&self, face: TileFace) -> Self {
+    *self + face.offset()
   }
+
+  /// Morton(Z-order)符号に変換する
+  ///
+  /// 各軸の下位21bitを用いるため、`-2^20..2^20`の範囲で可逆。
+  #[inline]
+  pub fn to_morton(&self) -> u64 {
+    let mask = (1u64 << Self::MORTON_BITS) - 1;
+    morton_spread(self.0[0] as u64 & mask)
+      | (morton_spread(self.0[1] as u64 & mask) << 1)
+      | (morton_spread(self.0[2] as u64 & mask) << 2)
+  }
+  /// Morton(Z-order)符号から変換する
   #[inline]
-  pub fn level_down(&self, level: u8) -> Self {
-    Self([
-      self.0[0] >> level * 2,
-      self.0[1] >> level * 2,
-      self.0[2] >> level * 2,
-      self.0[3] >> level * 2,
+  pub fn from_morton(code: u64) -> Self {
+    let unpack = |v: u64| {
+      let v = morton_compact(v) as i64;
+      let shift = 64 - Self::MORTON_BITS;
+      (v << shift) >> shift
+    };
+    Self::new(
+      unpack(code),
+      unpack(code >> 1),
+      unpack(code >> 2),
+    )
+  }
+}
+impl Sub<BlockPos> for BlockPos {
+  type Output = BlockDist;
+
+  fn sub(self, rhs: BlockPos) -> Self::Output {
+    BlockDist([
+      self.0[0] - rhs.0[0],
+      self.0[1] - rhs.0[1],
+      self.0[2] - rhs.0[2],
     ])
   }
 }
+impl Add<BlockDist> for BlockPos {
+  type Output = BlockPos;
+
+  fn add(self, rhs: BlockDist) -> Self::Output {
+    BlockPos([
+      self.0[0] + rhs.0[0],
+      self.0[1] + rhs.0[1],
+      self.0[2] + rhs.0[2],
+    ])
+  }
+}
+impl Sub<BlockDist> for BlockPos {
+  type Output = BlockPos;
+
+  fn sub(self, rhs: BlockDist) -> Self::Output {
+    BlockPos([
+      self.0[0] - rhs.0[0],
+      self.0[1] - rhs.0[1],
+      self.0[2] - rhs.0[2],
+    ])
+  }
+}
+
+/// 21bitの値を3bit毎に1bitずつ配置する
+#[inline]
+fn morton_spread(v: u64) -> u64 {
+  let mut v = v & 0x1f_ffff;
+  v = (v | (v << 32)) & 0x001f_0000_0000_ffff;
+  v = (v | (v << 16)) & 0x001f_0000_ff00_00ff;
+  v = (v | (v << 8)) & 0x100f_00f0_0f00_f00f;
+  v = (v | (v << 4)) & 0x10c3_0c30_c30c_30c3;
+  v = (v | (v << 2)) & 0x1249_2492_4924_9249;
+  v
+}
+
+/// `morton_spread`の逆変換
+#[inline]
+fn morton_compact(v: u64) -> u64 {
+  let mut v = v & 0x1249_2492_4924_9249;
+  v = (v | (v >> 2)) & 0x10c3_0c30_c30c_30c3;
+  v = (v | (v >> 4)) & 0x100f_00f0_0f00_f00f;
+  v = (v | (v >> 8)) & 0x001f_0000_ff00_00ff;
+  v = (v | (v >> 16)) & 0x001f_0000_0000_ffff;
+  v = (v | (v >> 32)) & 0x1f_ffff;
+  v
+}
+
+/// ブロック座標の半開区間`[min, max)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRange {
+  pub min: BlockPos,
+  pub max: BlockPos,
+}
+impl BlockRange {
+  #[inline]
+  pub fn new(min: BlockPos, max: BlockPos) -> Self {
+    Self { min, max }
+  }
+
+  /// チャンク一つ分の範囲
+  #[inline]
+  pub fn chunk(chunk_pos: &BlockPos) -> Self {
+    let min = chunk_pos.up_level(2);
+    Self::new(min, min + BlockDist::new(16, 16, 16))
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    (0..3).any(|i| self.max.0[i] <= self.min.0[i])
+  }
+
+  /// 範囲内のブロック数
+  #[inline]
+  pub fn len(&self) -> u64 {
+    (0..3)
+      .map(|i| (self.max.0[i] - self.min.0[i]).max(0))
+      .product::<i64>() as u64
+  }
+
+  #[inline]
+  pub fn contains(&self, pos: &BlockPos) -> bool {
+    (0..3).all(|i| {
+      (self.min.0[i]..self.max.0[i]).contains(&pos.0[i])
+    })
+  }
+
+  /// X、Y、Zの順に走査する
+  #[inline]
+  pub fn iter(&self) -> BlockRangeIter {
+    BlockRangeIter {
+      range: *self,
+      next: (!self.is_empty()).then_some(self.min),
+    }
+  }
+}
+impl IntoIterator for BlockRange {
+  type Item = BlockPos;
+  type IntoIter = BlockRangeIter;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.iter()
+  }
+}
+
+/// `BlockRange`の走査子
+#[derive(Debug, Clone)]
+pub struct BlockRangeIter {
+  range: BlockRange,
+  next: Option<BlockPos>,
+}
+impl Iterator for BlockRangeIter {
+  type Item = BlockPos;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let current = self.next?;
+    let mut next = current;
+    self.next = 'step: {
+      for i in 0..3 {
+        next.0[i] += 1;
+        if next.0[i] < self.range.max.0[i] {
+          break 'step Some(next);
+        }
+        next.0[i] = self.range.min.0[i];
+      }
+      None
+    };
+    Some(current)
+  }
+}
 
 #[repr(C, align(32))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct BlockDist(pub [i64; 4]);
+pub struct BlockDist([i64; 3]);
+impl BlockDist {
+  #[inline]
+  pub fn new(x: i64, y: i64, z: i64) -> Self {
+    Self([x, y, z])
+  }
+  #[inline]
+  pub fn get_x(&self) -> i64 {
+    self.0[0]
+  }
+  #[inline]
+  pub fn get_y(&self) -> i64 {
+    self.0[1]
+  }
+  #[inline]
+  pub fn get_z(&self) -> i64 {
+    self.0[2]
+  }
+  #[inline]
+  pub fn set_x(&mut self, x: i64) {
+    self.0[0] = x
+  }
+  #[inline]
+  pub fn set_y(&mut self, y: i64) {
+    self.0[1] = y
+  }
+  #[inline]
+  pub fn set_z(&mut self, z: i64) {
+    self.0[2] = z
+  }
+  #[inline]
+  pub fn up_level(&self, shift: u8) -> Self {
+    Self::new(
+      self.0[0] << (2 * shift),
+      self.0[1] << (2 * shift),
+      self.0[2] << (2 * shift),
+    )
+  }
+  #[inline]
+  pub fn down_level(&self, shift: u8) -> Self {
+    Self::new(
+      self.0[0] >> (2 * shift),
+      self.0[1] >> (2 * shift),
+      self.0[2] >> (2 * shift),
+    )
+  }
+}
+impl Add<BlockDist> for BlockDist {
+  type Output = BlockDist;
+
+  #[inline]
+  fn add(self, rhs: BlockDist) -> Self::Output {
+    BlockDist([
+      self.0[0] + rhs.0[0],
+      self.0[1] + rhs.0[1],
+      self.0[2] + rhs.0[2],
+    ])
+  }
+}
+impl Sub<BlockDist> for BlockDist {
+  type Output = BlockDist;
+
+  #[inline]
+  fn sub(self, rhs: BlockDist) -> Self::Output {
+    BlockDist([
+      self.0[0] - rhs.0[0],
+      self.0[1] - rhs.0[1],
+      self.0[2] - rhs.0[2],
+    ])
+  }
+}
+impl Mul<BlockDist> for BlockDist {
+  type Output = BlockDist;
+
+  fn mul(self, rhs: BlockDist) -> Self::Output {
+    BlockDist([
+      self.0[0] * rhs.0[0],
+      self.0[1] * rhs.0[1],
+      self.0[2] * rhs.0[2],
+    ])
+  }
+}
+impl Div<BlockDist> for BlockDist {
+  type Output = BlockDist;
+
+  #[inline]
+  fn div(self, rhs: BlockDist) -> Self::Output {
+    BlockDist([
+      self.0[0] / rhs.0[0],
+      self.0[1] / rhs.0[1],
+      self.0[2] / rhs.0[2],
+    ])
+  }
+}
+impl Rem<BlockDist> for BlockDist {
+  type Output = BlockDist;
+
+  #[inline]
+  fn rem(self, rhs: BlockDist) -> Self::Output {
+    BlockDist([
+      self.0[0] % rhs.0[0],
+      self.0[1] % rhs.0[1],
+      self.0[2] % rhs.0[2],
+    ])
+  }
+}
+impl Mul<i64> for BlockDist {
+  type Output = BlockDist;
+
+  #[inline]
+  fn mul(self, rhs: i64) -> Self::Output {
+    BlockDist([
+      self.0[0] * rhs,
+      self.0[1] * rhs,
+      self.0[2] * rhs,
+    ])
+  }
+}
+impl Div<i64> for BlockDist {
+  type Output = BlockDist;
+
+  #[inline]
+  fn div(self, rhs: i64) -> Self::Output {
+    BlockDist([
+      self.0[0] / rhs,
+      self.0[1] / rhs,
+      self.0[2] / rhs,
+    ])
+  }
+}
+impl Rem<i64> for BlockDist {
+  type Output = BlockDist;
+
+  #[inline]
+  fn rem(self, rhs: i64) -> Self::Output {
+    BlockDist([
+      self.0[0] % rhs,
+      self.0[1] % rhs,
+      self.0[2] % rhs,
+    ])
+  }
+}
+
+/// 軸に平行な直方体(ワールド座標)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+  pub min: nalgebra::Point3<f64>,
+  pub max: nalgebra::Point3<f64>,
+}
+impl Aabb {
+  #[inline]
+  pub fn new(
+    min: nalgebra::Point3<f64>,
+    max: nalgebra::Point3<f64>,
+  ) -> Self {
+    Self { min, max }
+  }
+
+  /// ブロック一つ分の直方体
+  #[inline]
+  pub fn block(pos: &BlockPos) -> Self {
+    let min = nalgebra::Point3::new(
+      pos.get_x() as f64,
+      pos.get_y() as f64,
+      pos.get_z() as f64,
+    );
+    Self::new(min, min + nalgebra::Vector3::repeat(1.))
+  }
+
+  /// 平行移動した直方体
+  #[inline]
+  pub fn translated(
+    &self,
+    offset: &nalgebra::Vector3<f64>,
+  ) -> Self {
+    Self::new(self.min + offset, self.max + offset)
+  }
+
+  /// 内部が重なるか(面が接するだけの場合は重ならない)
+  #[inline]
+  pub fn intersects(&self, other: &Self) -> bool {
+    (0..3).all(|i| {
+      self.min[i] < other.max[i]
+        && other.min[i] < self.max[i]
+    })
+  }
+
+  /// 重なる可能性のあるブロックの範囲
+  #[inline]
+  pub fn block_range(&self) -> BlockRange {
+    BlockRange::new(
+      BlockPos::new(
+        self.min.x.floor() as i64,
+        self.min.y.floor() as i64,
+        self.min.z.floor() as i64,
+      ),
+      BlockPos::new(
+        self.max.x.ceil() as i64,
+        self.max.y.ceil() as i64,
+        self.max.z.ceil() as i64,
+      ),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const EDGE: i64 = 1 << (BlockPos::MORTON_BITS - 1);
+  /// 無作為に選ぶ座標の数
+  const RANDOM_CASES: usize = 20_000;
+
+  /// 境界の前後の値
+  /// Morton符号の両端とセル、チャンクの境界を含む
+  fn edge_values() -> Vec<i64> {
+    let mut values = vec![
+      -EDGE,
+      -EDGE + 1,
+      EDGE - 2,
+      EDGE - 1,
+    ];
+    for base in [
+      -4096, -32, -16, -4, 0, 4, 16, 32, 4096,
+    ] {
+      values.extend([base - 1, base, base + 1]);
+    }
+    values
+  }
+
+  /// 固定の種から決まる線形合同法の乱数
+  struct Lcg(u64);
+  impl Lcg {
+    fn next(&mut self) -> u64 {
+      self.0 = self
+        .0
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+      self.0
+    }
+
+    /// Morton符号で表せる範囲の値
+    fn coord(&mut self) -> i64 {
+      (self.next() >> (64 - BlockPos::MORTON_BITS))
+        as i64
+        - EDGE
+    }
+  }
+
+  /// 境界の前後の値の全ての組み合わせと無作為な座標
+  fn positions() -> impl Iterator<Item = BlockPos> {
+    let edges = edge_values();
+    let mut grid = Vec::new();
+    for x in &edges {
+      for y in &edges {
+        for z in &edges {
+          grid.push(BlockPos::new(*x, *y, *z));
+        }
+      }
+    }
+    let mut rng = Lcg(0x9e37_79b9_7f4a_7c15);
+    let random: Vec<BlockPos> = (0..RANDOM_CASES)
+      .map(|_| {
+        BlockPos::new(
+          rng.coord(),
+          rng.coord(),
+          rng.coord(),
+        )
+      })
+      .collect();
+    grid.into_iter().chain(random)
+  }
+
+  /// `i64`の両端を含む、Morton符号の範囲外の座標
+  fn extreme_positions(
+  ) -> impl Iterator<Item = BlockPos> {
+    let values = [
+      i64::MIN,
+      i64::MIN + 1,
+      i64::MAX - 1,
+      i64::MAX,
+    ];
+    values
+      .into_iter()
+      .flat_map(move |x| {
+        values
+          .into_iter()
+          .map(move |y| BlockPos::new(x, y, x))
+      })
+  }
+
+  #[test]
+  fn morton_round_trip() {
+    for pos in positions() {
+      assert_eq!(
+        BlockPos::from_morton(pos.to_morton()),
+        pos
+      );
+    }
+    assert_eq!(
+      BlockPos::new(0, 0, 0).to_morton(),
+      0
+    );
+    assert_eq!(
+      BlockPos::new(1, 0, 0).to_morton(),
+      1
+    );
+    assert_eq!(
+      BlockPos::new(0, 1, 0).to_morton(),
+      2
+    );
+    assert_eq!(
+      BlockPos::new(0, 0, 1).to_morton(),
+      4
+    );
+    assert_eq!(
+      BlockPos::new(-1, -1, -1).to_morton(),
+      (1 << 63) - 1
+    );
+  }
+
+  #[test]
+  fn chunk_index_round_trip() {
+    for pos in positions().chain(extreme_positions()) {
+      let chunk_pos = pos.chunk_pos();
+      let index = pos.chunk_index();
+      assert!(index < 4096);
+      assert_eq!(
+        BlockPos::from_chunk_index(&chunk_pos, index),
+        pos
+      );
+      assert_eq!(pos.split_chunk().0, chunk_pos);
+    }
+    for chunk_pos in [
+      BlockPos::new(-1, -1, -1),
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(3, -2, 7),
+    ] {
+      let range = BlockRange::chunk(&chunk_pos);
+      for index in 0..4096u16 {
+        let pos =
+          BlockPos::from_chunk_index(&chunk_pos, index);
+        assert!(range.contains(&pos));
+        assert_eq!(pos.chunk_index(), index);
+      }
+    }
+  }
+
+  #[test]
+  fn split_merge_round_trip() {
+    for pos in positions().chain(extreme_positions()) {
+      let (to, inner) = pos.split_inner();
+      assert!(inner
+        .as_array()
+        .iter()
+        .all(|c| (0..4).contains(c)));
+      assert_eq!(to.merge_inner(inner), pos);
+      let (inner_pos, to_pos) = pos.split_innerpos();
+      assert_eq!(to_pos, to);
+      assert_eq!(
+        inner_pos.index(),
+        inner.as_64index() as usize
+      );
+      assert_eq!(
+        to.insert_innerpos(inner_pos),
+        pos
+      );
+    }
+    assert_eq!(
+      BlockPos::new(-1, -4, -5).split_inner(),
+      (
+        BlockPos::new(-1, -1, -2),
+        BlockPos::new(3, 0, 3)
+      )
+    );
+  }
+
+  #[test]
+  fn neighbor_opposite() {
+    for face in TileFace::ALL {
+      assert_ne!(face.opposite(), face);
+      assert_eq!(face.opposite().opposite(), face);
+    }
+    for pos in positions() {
+      for face in TileFace::ALL {
+        let neighbor = pos.neighbor(face);
+        assert_ne!(neighbor, pos);
+        assert_eq!(
+          neighbor.neighbor(face.opposite()),
+          pos
+        );
+        assert_eq!(neighbor - pos, face.offset());
+      }
+    }
+    for (i, face) in TileFace::ALL.iter().enumerate() {
+      assert_eq!(TileFace::from(i as u8), *face);
+    }
+    assert_eq!(
+      TileFace::UNDEF.opposite(),
+      TileFace::UNDEF
+    );
+  }
+}
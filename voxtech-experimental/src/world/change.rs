@@ -0,0 +1,88 @@
+//! Change
+//! チャンクの更新フラグとブロックの変更イベント
+
+use crate::block::BlockId;
+use crate::types::BlockPos;
+
+/// チャンクの更新が必要な処理を表すフラグ
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Hash,
+)]
+pub struct DirtyFlags(u8);
+impl DirtyFlags {
+  pub const NONE: Self = Self(0);
+  /// メッシュの再構築
+  pub const MESH: Self = Self(1 << 0);
+  /// 光量の変化
+  pub const LIGHT: Self = Self(1 << 1);
+  /// 保存
+  pub const SAVE: Self = Self(1 << 2);
+  pub const ALL: Self = Self(0b0111);
+
+  #[inline]
+  pub fn bits(&self) -> u8 {
+    self.0
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.0 == 0
+  }
+
+  /// `other`の全てのフラグを含むか
+  #[inline]
+  pub fn contains(&self, other: Self) -> bool {
+    self.0 & other.0 == other.0
+  }
+
+  /// `other`のいずれかのフラグを含むか
+  #[inline]
+  pub fn intersects(&self, other: Self) -> bool {
+    self.0 & other.0 != 0
+  }
+
+  #[inline]
+  pub fn insert(&mut self, other: Self) {
+    self.0 |= other.0;
+  }
+
+  #[inline]
+  pub fn remove(&mut self, other: Self) {
+    self.0 &= !other.0;
+  }
+}
+impl std::ops::BitOr for DirtyFlags {
+  type Output = Self;
+  fn bitor(self, rhs: Self) -> Self::Output {
+    Self(self.0 | rhs.0)
+  }
+}
+impl std::ops::BitOrAssign for DirtyFlags {
+  fn bitor_assign(&mut self, rhs: Self) {
+    self.insert(rhs);
+  }
+}
+
+/// ブロックの変更の原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeCause {
+  /// プレイヤーによる設置と破壊
+  Player,
+  /// ブロックの更新処理
+  Tick,
+  /// 流体の流れ
+  Fluid,
+  /// 落下するブロック
+  Gravity,
+  /// その他(コマンドやスクリプトなど)
+  Other,
+}
+
+/// ブロックの変更イベント
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockChange {
+  pub pos: BlockPos,
+  pub old: BlockId,
+  pub new: BlockId,
+  pub cause: ChangeCause,
+}
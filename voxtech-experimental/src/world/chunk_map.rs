@@ -0,0 +1,370 @@
+//! Chunk-Map
+//! 読み込み済みのチャンクと空の領域を畳み込んだ占有の木
+
+use hashbrown::HashMap;
+
+use super::tree64::Tree64;
+use super::Chunk;
+use crate::types::{BlockDist, BlockPos, BlockRange};
+
+/// 読み込まれているチャンクの格納先
+///
+/// チャンクはハッシュ表で引き、ブロックの読み書きの度に木を辿らない
+/// ようにする。別に空気以外を含むチャンクの位置を`Tree64`で持ち、
+/// 読み込まれていない領域と空気のみの領域を大きな葉へ畳み込む。
+/// 光線はこの葉の範囲を一度に通過する。
+pub struct ChunkMap {
+  chunks: HashMap<BlockPos, Chunk>,
+  /// 空気以外を含むチャンクの位置
+  occupied: Tree64<bool>,
+}
+impl Default for ChunkMap {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+impl ChunkMap {
+  /// 占有の木の深さ(各軸`4^11 = 2^22`チャンク)
+  pub const DEPTH: u8 = 11;
+  /// 占有の木の原点へのずらし幅
+  /// チャンク座標は各軸`-2^21..2^21`の範囲を扱える
+  const OFFSET: i64 = 1 << (Self::DEPTH as i64 * 2 - 1);
+
+  pub fn new() -> Self {
+    Self {
+      chunks: HashMap::new(),
+      occupied: Tree64::new(Self::DEPTH, false),
+    }
+  }
+
+  #[inline]
+  fn index_pos(chunk_pos: &BlockPos) -> BlockPos {
+    *chunk_pos
+      + BlockDist::new(
+        Self::OFFSET,
+        Self::OFFSET,
+        Self::OFFSET,
+      )
+  }
+
+  /// 格納できる範囲のチャンク座標か
+  #[inline]
+  pub fn in_bounds(chunk_pos: &BlockPos) -> bool {
+    chunk_pos
+      .as_array()
+      .iter()
+      .all(|c| {
+        (-Self::OFFSET..Self::OFFSET).contains(c)
+      })
+  }
+
+  #[inline]
+  pub fn contains_key(
+    &self,
+    chunk_pos: &BlockPos,
+  ) -> bool {
+    self
+      .chunks
+      .contains_key(chunk_pos)
+  }
+
+  #[inline]
+  pub fn get(
+    &self,
+    chunk_pos: &BlockPos,
+  ) -> Option<&Chunk> {
+    self.chunks.get(chunk_pos)
+  }
+
+  /// ブロックを書き換えて空かどうかが変わった場合は`refresh`を呼ぶ
+  #[inline]
+  pub fn get_mut(
+    &mut self,
+    chunk_pos: &BlockPos,
+  ) -> Option<&mut Chunk> {
+    self.chunks.get_mut(chunk_pos)
+  }
+
+  /// チャンクを挿入し、置き換えたチャンクを返す
+  ///
+  /// # Panics
+  /// `in_bounds`の範囲外の座標の場合
+  pub fn insert(
+    &mut self,
+    chunk_pos: BlockPos,
+    chunk: Chunk,
+  ) -> Option<Chunk> {
+    assert!(
+      Self::in_bounds(&chunk_pos),
+      "chunk {chunk_pos:?} is out of the chunk map"
+    );
+    self.occupied.set(
+      &Self::index_pos(&chunk_pos),
+      !chunk.is_empty(),
+    );
+    self
+      .chunks
+      .insert(chunk_pos, chunk)
+  }
+
+  /// チャンクを取り除いて返す
+  pub fn remove(
+    &mut self,
+    chunk_pos: &BlockPos,
+  ) -> Option<Chunk> {
+    let chunk = self.chunks.remove(chunk_pos)?;
+    self.occupied.set(
+      &Self::index_pos(chunk_pos),
+      false,
+    );
+    Some(chunk)
+  }
+
+  /// チャンクが空かどうかを占有の木へ反映する
+  pub fn refresh(&mut self, chunk_pos: &BlockPos) {
+    let occupied = self
+      .chunks
+      .get(chunk_pos)
+      .is_some_and(|chunk| !chunk.is_empty());
+    self.occupied.set(
+      &Self::index_pos(chunk_pos),
+      occupied,
+    );
+  }
+
+  /// チャンクを含む、空気以外のブロックが無い領域
+  ///
+  /// 占有の木で畳み込まれた葉の範囲をブロック座標で返す。空気以外を
+  /// 含むチャンクか、格納できる範囲外の場合はチャンク一つ分を返す。
+  pub fn empty_region(
+    &self,
+    chunk_pos: &BlockPos,
+  ) -> BlockRange {
+    match self
+      .occupied
+      .leaf(&Self::index_pos(chunk_pos))
+    {
+      Some(leaf) if !*leaf.value => {
+        let min = (leaf.origin
+          - BlockDist::new(
+            Self::OFFSET,
+            Self::OFFSET,
+            Self::OFFSET,
+          ))
+        .up_level(2);
+        let size = leaf.size() << 4;
+        BlockRange::new(
+          min,
+          min + BlockDist::new(size, size, size),
+        )
+      }
+      _ => BlockRange::chunk(chunk_pos),
+    }
+  }
+
+  /// チャンクの座標とチャンクを走査する
+  pub fn iter(
+    &self,
+  ) -> impl Iterator<Item = (&BlockPos, &Chunk)> {
+    self.chunks.iter()
+  }
+
+  pub fn iter_mut(
+    &mut self,
+  ) -> impl Iterator<Item = (&BlockPos, &mut Chunk)> {
+    self.chunks.iter_mut()
+  }
+
+  /// チャンクの座標を走査する
+  pub fn keys(
+    &self,
+  ) -> impl Iterator<Item = &BlockPos> {
+    self.chunks.keys()
+  }
+
+  /// チャンクを走査する
+  pub fn values(&self) -> impl Iterator<Item = &Chunk> {
+    self.chunks.values()
+  }
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.chunks.len()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.chunks.is_empty()
+  }
+
+  /// 占有の木のノード数
+  pub fn node_count(&self) -> usize {
+    self.occupied.node_count()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::hint::black_box;
+  use std::time::Instant;
+
+  use super::*;
+  use crate::block::AIR;
+
+  fn solid_chunk(chunk_pos: &BlockPos) -> Chunk {
+    Chunk::new(chunk_pos, |_| 1)
+  }
+
+  #[test]
+  fn insert_get_remove() {
+    let mut map = ChunkMap::new();
+    let positions = [
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(-1, -1, -1),
+      BlockPos::new(3, -7, 12),
+      BlockPos::new(-(1 << 21), (1 << 21) - 1, 0),
+    ];
+    for pos in positions {
+      assert!(map
+        .insert(pos, Chunk::empty_chunk())
+        .is_none());
+    }
+    assert_eq!(map.len(), positions.len());
+    for pos in &positions {
+      assert!(map.contains_key(pos));
+    }
+    assert!(!map.contains_key(&BlockPos::new(1, 0, 0)));
+    assert!(map
+      .remove(&positions[1])
+      .is_some());
+    assert!(map
+      .remove(&positions[1])
+      .is_none());
+    assert!(!map.contains_key(&positions[1]));
+    assert_eq!(map.len(), positions.len() - 1);
+    assert_eq!(
+      map.keys().count(),
+      positions.len() - 1
+    );
+  }
+
+  #[test]
+  fn collapses_empty_and_unloaded() {
+    let mut map = ChunkMap::new();
+    let empty = map.node_count();
+    for x in -4..4 {
+      map.insert(
+        BlockPos::new(x, 0, 0),
+        Chunk::empty_chunk(),
+      );
+    }
+    assert_eq!(map.node_count(), empty);
+    let pos = BlockPos::new(-4, 0, 0);
+    map.insert(pos, solid_chunk(&pos));
+    assert!(empty < map.node_count());
+    let chunk = map.get_mut(&pos).unwrap();
+    for block in BlockRange::chunk(&pos) {
+      chunk.set(&block, AIR);
+    }
+    map.refresh(&pos);
+    assert_eq!(map.node_count(), empty);
+    map.insert(pos, solid_chunk(&pos));
+    map.remove(&pos);
+    assert_eq!(map.node_count(), empty);
+  }
+
+  #[test]
+  fn empty_region() {
+    let mut map = ChunkMap::new();
+    let solid = BlockPos::new(-1, 0, 0);
+    map.insert(solid, solid_chunk(&solid));
+    assert_eq!(
+      map.empty_region(&solid),
+      BlockRange::chunk(&solid)
+    );
+    // 隣のチャンクは1チャンク分の葉に収まる
+    let next = BlockPos::new(-2, 0, 0);
+    assert_eq!(
+      map.empty_region(&next),
+      BlockRange::chunk(&next)
+    );
+    // 離れた位置は大きな葉にまとめて含まれる
+    let far = BlockPos::new(100, 100, 100);
+    let region = map.empty_region(&far);
+    assert!(region.contains(&far.up_level(2)));
+    assert!(!region.contains(&solid.up_level(2)));
+    let beyond = far + BlockDist::new(1000, -50, 1000);
+    assert!(region.contains(&beyond.up_level(2)));
+  }
+
+  #[test]
+  fn bounds() {
+    assert!(ChunkMap::in_bounds(
+      &BlockPos::new(-(1 << 21), 0, (1 << 21) - 1)
+    ));
+    assert!(!ChunkMap::in_bounds(
+      &BlockPos::new(1 << 21, 0, 0)
+    ));
+  }
+
+  /// ハッシュ表と`Tree64`の索引による引き方の比較
+  ///
+  /// `cargo test --release -- --ignored bench`で実行する。
+  #[test]
+  #[ignore]
+  fn bench_lookup() {
+    let positions: Vec<BlockPos> = BlockRange::new(
+      BlockPos::new(-16, -16, -4),
+      BlockPos::new(16, 16, 4),
+    )
+    .iter()
+    .collect();
+    let mut map = ChunkMap::new();
+    let mut index = Tree64::new(ChunkMap::DEPTH, 0u32);
+    for (slot, pos) in positions.iter().enumerate() {
+      map.insert(*pos, Chunk::empty_chunk());
+      index.set(
+        &ChunkMap::index_pos(pos),
+        slot as u32 + 1,
+      );
+    }
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let queries: Vec<BlockPos> = (0..1 << 20)
+      .map(|_| {
+        seed = seed
+          .wrapping_mul(6364136223846793005)
+          .wrapping_add(1442695040888963407);
+        positions
+          [(seed >> 33) as usize % positions.len()]
+      })
+      .collect();
+
+    let start = Instant::now();
+    let mut hits = 0;
+    for pos in &queries {
+      hits +=
+        black_box(map.get(pos)).is_some() as usize;
+    }
+    let hash = start.elapsed();
+    let start = Instant::now();
+    let mut tree_hits = 0;
+    for pos in &queries {
+      tree_hits +=
+        black_box(index.get(&ChunkMap::index_pos(pos)))
+          .is_some_and(|slot| *slot != 0)
+          as usize;
+    }
+    let tree = start.elapsed();
+    assert_eq!(hits, queries.len());
+    assert_eq!(tree_hits, queries.len());
+    println!(
+      "{} lookups: HashMap {hash:?}, Tree64 {tree:?}",
+      queries.len()
+    );
+    println!(
+      "index nodes: Tree64 slots {}, occupancy {}",
+      index.node_count(),
+      map.node_count()
+    );
+  }
+}
@@ -0,0 +1,392 @@
+//! Entity
+//! 落下中のブロックやアイテムなど、ブロック格子に縛られない物体
+
+use std::collections::BTreeMap;
+
+use super::change::ChangeCause;
+use super::tick::TickHandler;
+use super::World;
+use crate::block::{BlockId, AIR};
+use crate::types::{BlockDist, BlockPos};
+
+/// 一ティック毎に加わる下向きの速度(ブロック/ティック)
+pub const GRAVITY: f64 = 0.04;
+/// 一ティック毎に速度に掛かる空気抵抗の係数
+pub const DRAG: f64 = 0.98;
+/// アイテムが消えるまでのティック数
+pub const ITEM_LIFETIME: u64 = 6000;
+
+/// エンティティの番号
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  Hash,
+  PartialOrd,
+  Ord,
+)]
+pub struct EntityId(u64);
+
+/// エンティティの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntityKind {
+  /// 落下中のブロック
+  FallingBlock(BlockId),
+  /// 落ちているアイテム
+  Item(BlockId),
+}
+
+/// 一回の移動の結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+  /// 空中にいる
+  Airborne,
+  /// 読み込まれていないチャンクにいるため止まっている
+  Frozen,
+  /// 固体のブロックの上に着地した
+  Landed(BlockPos),
+}
+
+/// エンティティ
+///
+/// 位置は底面の中心で、ブロック一つ分の柱として鉛直方向にのみ
+/// 当たり判定を持つ。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entity {
+  pub kind: EntityKind,
+  pub position: [f64; 3],
+  pub velocity: [f64; 3],
+  /// 出現してからのティック数
+  pub age: u64,
+}
+impl Entity {
+  pub fn new(
+    kind: EntityKind,
+    position: [f64; 3],
+  ) -> Self {
+    Self {
+      kind,
+      position,
+      velocity: [0.; 3],
+      age: 0,
+    }
+  }
+
+  /// 底面が含まれるブロック
+  pub fn block_pos(&self) -> BlockPos {
+    BlockPos::new(
+      self.position[0].floor() as i64,
+      self.position[1].floor() as i64,
+      self.position[2].floor() as i64,
+    )
+  }
+
+  /// 重力を加えて一ティック分移動する
+  ///
+  /// 通過するブロックを上から順に調べ、固体のブロックがあればその上に
+  /// 着地する。読み込まれていないブロックの手前では読み込まれるまで
+  /// 止まる。
+  pub fn step(&mut self, world: &World) -> Motion {
+    let pos = self.block_pos();
+    if world
+      .chunk(&pos.chunk_pos())
+      .is_none()
+    {
+      self.velocity = [0.; 3];
+      return Motion::Frozen;
+    }
+    self.velocity[2] =
+      (self.velocity[2] - GRAVITY) * DRAG;
+    let z = self.position[2];
+    let next = z + self.velocity[2];
+    let (x, y) = (pos.get_x(), pos.get_y());
+    let mut cz = z.ceil() as i64 - 1;
+    while next.floor() as i64 <= cz {
+      let below = BlockPos::new(x, y, cz);
+      if world
+        .chunk(&below.chunk_pos())
+        .is_none()
+      {
+        self.position[2] = (cz + 1) as f64;
+        self.velocity[2] = 0.;
+        return Motion::Frozen;
+      }
+      if world
+        .registry()
+        .is_solid(world.get_block(&below))
+      {
+        self.position[2] = (cz + 1) as f64;
+        self.velocity[2] = 0.;
+        return Motion::Landed(BlockPos::new(
+          x,
+          y,
+          cz + 1,
+        ));
+      }
+      cz -= 1;
+    }
+    self.position[2] = next;
+    Motion::Airborne
+  }
+}
+
+/// エンティティの一覧
+///
+/// 番号順に処理するため、同じ状態からの更新は再現性がある。
+/// エンティティは保存されない。
+#[derive(Debug, Clone, Default)]
+pub struct Entities {
+  map: BTreeMap<EntityId, Entity>,
+  next_id: u64,
+}
+impl Entities {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn spawn(&mut self, entity: Entity) -> EntityId {
+    let id = EntityId(self.next_id);
+    self.next_id += 1;
+    self.map.insert(id, entity);
+    id
+  }
+
+  #[inline]
+  pub fn get(&self, id: EntityId) -> Option<&Entity> {
+    self.map.get(&id)
+  }
+
+  #[inline]
+  pub fn get_mut(
+    &mut self,
+    id: EntityId,
+  ) -> Option<&mut Entity> {
+    self.map.get_mut(&id)
+  }
+
+  #[inline]
+  pub fn remove(
+    &mut self,
+    id: EntityId,
+  ) -> Option<Entity> {
+    self.map.remove(&id)
+  }
+
+  /// 番号順に走査する
+  pub fn iter(
+    &self,
+  ) -> impl Iterator<Item = (&EntityId, &Entity)> {
+    self.map.iter()
+  }
+
+  /// 番号の一覧
+  pub fn ids(&self) -> Vec<EntityId> {
+    self
+      .map
+      .keys()
+      .copied()
+      .collect()
+  }
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.map.len()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.map.is_empty()
+  }
+}
+
+/// 支えを失ったブロックの落下
+///
+/// ブロック自身か隣接するブロックが変わると確認を予約し、下が固体で
+/// なければ落下するエンティティに置き換える。下のチャンクが
+/// 読み込まれていない場合は落下しない。
+pub struct GravityHandler {
+  /// 確認までのティック数
+  delay: u64,
+}
+impl Default for GravityHandler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+impl GravityHandler {
+  pub fn new() -> Self {
+    Self { delay: 2 }
+  }
+}
+impl TickHandler for GravityHandler {
+  fn scheduled_tick(
+    &self,
+    world: &mut World,
+    pos: &BlockPos,
+    block: BlockId,
+  ) {
+    let below = *pos + BlockDist::new(0, 0, -1);
+    if world
+      .chunk(&below.chunk_pos())
+      .is_none()
+      || world
+        .registry()
+        .is_solid(world.get_block(&below))
+    {
+      return;
+    }
+    world.set_block(pos, AIR, ChangeCause::Gravity);
+    world.spawn_entity(Entity::new(
+      EntityKind::FallingBlock(block),
+      [
+        pos.get_x() as f64 + 0.5,
+        pos.get_y() as f64 + 0.5,
+        pos.get_z() as f64,
+      ],
+    ));
+  }
+
+  fn block_updated(
+    &self,
+    world: &mut World,
+    pos: &BlockPos,
+    block: BlockId,
+  ) {
+    world.schedule_tick(pos, block, self.delay, 0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::TileFace;
+  use crate::world::Chunk;
+
+  /// 床が石のチャンクに組み込みの更新処理を登録したワールド
+  fn flat_world() -> World {
+    let mut world = World::new();
+    let stone = id(&world, "stone");
+    world.spawn_chunk(BlockPos::new(0, 0, 0), || {
+      Chunk::new(&BlockPos::new(0, 0, 0), |pos| {
+        if pos.get_z() == 0 {
+          stone
+        } else {
+          AIR
+        }
+      })
+    });
+    world.register_default_tick_handlers();
+    world
+      .ticks_mut()
+      .random_ticks_per_chunk = 0;
+    world
+  }
+
+  fn id(world: &World, name: &str) -> BlockId {
+    world
+      .registry()
+      .id_of(name)
+      .unwrap()
+  }
+
+  fn run(world: &mut World, ticks: u64) {
+    for _ in 0..ticks {
+      world.tick();
+    }
+  }
+
+  /// 石の上に砂を置いてから、下の石を取り除く
+  fn drop_sand(world: &mut World, pos: &BlockPos) {
+    let (sand, stone) = (
+      id(world, "sand"),
+      id(world, "stone"),
+    );
+    let support = pos.neighbor(TileFace::BTM);
+    world.set_block(
+      &support,
+      stone,
+      ChangeCause::Player,
+    );
+    world.set_block(pos, sand, ChangeCause::Player);
+    run(world, 5);
+    assert_eq!(world.get_block(pos), sand);
+    assert!(world.entities().is_empty());
+    world.set_block(
+      &support,
+      AIR,
+      ChangeCause::Player,
+    );
+  }
+
+  #[test]
+  fn falls_without_support() {
+    let mut world = flat_world();
+    let pos = BlockPos::new(5, 5, 5);
+    drop_sand(&mut world, &pos);
+    run(&mut world, 3);
+    assert_eq!(world.get_block(&pos), AIR);
+    let kinds: Vec<EntityKind> = world
+      .entities()
+      .iter()
+      .map(|(_, entity)| entity.kind)
+      .collect();
+    assert_eq!(
+      kinds,
+      [EntityKind::FallingBlock(id(
+        &world, "sand"
+      ))]
+    );
+  }
+
+  #[test]
+  fn lands_as_block() {
+    let mut world = flat_world();
+    drop_sand(
+      &mut world,
+      &BlockPos::new(5, 5, 5),
+    );
+    run(&mut world, 100);
+    assert!(world.entities().is_empty());
+    assert_eq!(
+      world.get_block(&BlockPos::new(5, 5, 1)),
+      id(&world, "sand")
+    );
+    for z in 2..6 {
+      assert_eq!(
+        world.get_block(&BlockPos::new(5, 5, z)),
+        AIR
+      );
+    }
+  }
+
+  #[test]
+  fn breaks_into_item_in_fluid() {
+    let mut world = flat_world();
+    let water = id(&world, "water");
+    let landing = BlockPos::new(5, 5, 1);
+    world.set_block(
+      &landing,
+      water,
+      ChangeCause::Player,
+    );
+    drop_sand(
+      &mut world,
+      &BlockPos::new(5, 5, 5),
+    );
+    run(&mut world, 100);
+    assert_eq!(world.get_block(&landing), water);
+    let entities: Vec<Entity> = world
+      .entities()
+      .iter()
+      .map(|(_, entity)| *entity)
+      .collect();
+    assert_eq!(entities.len(), 1);
+    assert_eq!(
+      entities[0].kind,
+      EntityKind::Item(id(&world, "sand"))
+    );
+    assert_eq!(entities[0].block_pos(), landing);
+  }
+}
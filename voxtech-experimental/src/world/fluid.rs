@@ -0,0 +1,456 @@
+//! Fluid
+//! 水や溶岩などの流体の水位と流れ
+
+use super::change::ChangeCause;
+use super::tick::TickHandler;
+use super::World;
+use crate::block::{BlockId, FluidDef, AIR};
+use crate::types::{BlockDist, BlockPos, TileFace};
+
+/// 流体ブロック毎の水位
+///
+/// 値0を水源とするため、生成された流体や既存のセーブの流体は水源になる。
+/// 流れは水位1..=7を持ち、上から流れ落ちている流れは最大の水位として扱う。
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Hash,
+)]
+pub struct FluidState(u8);
+impl FluidState {
+  /// 水源
+  pub const SOURCE: Self = Self(0);
+  /// 水源と落下中の流れの水位
+  pub const MAX_LEVEL: u8 = 8;
+  const LEVEL_MASK: u8 = 0x0f;
+  const FALLING: u8 = 0x10;
+  const FLOWING: u8 = 0x20;
+
+  /// 水位`level`の横への流れ
+  #[inline]
+  pub fn flowing(level: u8) -> Self {
+    Self(
+      Self::FLOWING
+        | level.clamp(1, Self::MAX_LEVEL - 1),
+    )
+  }
+
+  /// 落下中の流れ
+  #[inline]
+  pub fn falling() -> Self {
+    Self(
+      Self::FLOWING
+        | Self::FALLING
+        | (Self::MAX_LEVEL - 1),
+    )
+  }
+
+  #[inline]
+  pub fn from_bits(bits: u8) -> Self {
+    Self(bits)
+  }
+
+  #[inline]
+  pub fn bits(&self) -> u8 {
+    self.0
+  }
+
+  #[inline]
+  pub fn is_source(&self) -> bool {
+    self.0 & Self::FLOWING == 0
+  }
+
+  #[inline]
+  pub fn is_falling(&self) -> bool {
+    self.0 & Self::FALLING != 0
+  }
+
+  /// 水位(1..=8)
+  #[inline]
+  pub fn level(&self) -> u8 {
+    if self.is_source() || self.is_falling() {
+      Self::MAX_LEVEL
+    } else {
+      self.0 & Self::LEVEL_MASK
+    }
+  }
+}
+
+/// 水平方向の面
+const HORIZONTAL: [TileFace; 4] = [
+  TileFace::WST,
+  TileFace::EST,
+  TileFace::STH,
+  TileFace::NTH,
+];
+
+/// 流体の流れ
+///
+/// 予約更新で水位を周囲から計算し直し、下が空いていれば落下し、
+/// 塞がれていれば水位を下げながら横へ広がる。同じ流体が合流した
+/// ブロックは最も高い水位になり、再生する流体は二つ以上の水源に
+/// 挟まれると水源になる。空気にのみ流れ込むため、固体や別の流体で
+/// 止まる。読み込まれていないチャンクへは流れない。
+pub struct FluidHandler {
+  block: BlockId,
+  def: FluidDef,
+}
+impl FluidHandler {
+  pub fn new(block: BlockId, def: FluidDef) -> Self {
+    Self { block, def }
+  }
+
+  /// 読み込まれた空気のブロックか
+  fn is_open(world: &World, pos: &BlockPos) -> bool {
+    world.chunk(&pos.chunk_pos()).is_some()
+      && world.get_block(pos) == AIR
+  }
+
+  /// 下が塞がれていて横へ広がれるか
+  fn is_supported(
+    &self,
+    world: &World,
+    pos: &BlockPos,
+  ) -> bool {
+    let below = *pos + BlockDist::new(0, 0, -1);
+    match world.get_block(&below) {
+      block if block == self.block => {
+        world.fluid_state(&below).is_source()
+      }
+      _ => !Self::is_open(world, &below),
+    }
+  }
+
+  /// 周囲から計算した流れの水位
+  /// 流れが途切れた場合は`None`を返す
+  fn recompute(
+    &self,
+    world: &World,
+    pos: &BlockPos,
+  ) -> Option<FluidState> {
+    let above = *pos + BlockDist::new(0, 0, 1);
+    if world.get_block(&above) == self.block {
+      return Some(FluidState::falling());
+    }
+    let mut level = 0;
+    let mut sources = 0;
+    for face in HORIZONTAL {
+      let neighbor = pos.neighbor(face);
+      if world.get_block(&neighbor) != self.block {
+        continue;
+      }
+      let state = world.fluid_state(&neighbor);
+      if state.is_source() {
+        sources += 1;
+      }
+      if self.is_supported(world, &neighbor) {
+        level = level.max(
+          state
+            .level()
+            .saturating_sub(self.def.level_drop),
+        );
+      }
+    }
+    if self.def.renewable
+      && 2 <= sources
+      && self.is_supported(world, pos)
+    {
+      return Some(FluidState::SOURCE);
+    }
+    (0 < level).then(|| FluidState::flowing(level))
+  }
+
+  /// 空気のブロックへ流れ込む
+  fn fill(
+    &self,
+    world: &mut World,
+    pos: &BlockPos,
+    state: FluidState,
+  ) {
+    if Self::is_open(world, pos) {
+      world.set_block(pos, self.block, ChangeCause::Fluid);
+      world.set_fluid_state(pos, state);
+    }
+  }
+}
+impl TickHandler for FluidHandler {
+  fn scheduled_tick(
+    &self,
+    world: &mut World,
+    pos: &BlockPos,
+    _block: BlockId,
+  ) {
+    let state = world.fluid_state(pos);
+    let state = if state.is_source() {
+      state
+    } else {
+      match self.recompute(world, pos) {
+        None => {
+          world.set_block(pos, AIR, ChangeCause::Fluid);
+          return;
+        }
+        Some(next) => {
+          if next != state {
+            world.set_fluid_state(pos, next);
+          }
+          next
+        }
+      }
+    };
+    let below = *pos + BlockDist::new(0, 0, -1);
+    if Self::is_open(world, &below) {
+      self.fill(world, &below, FluidState::falling());
+      return;
+    }
+    if !self.is_supported(world, pos) {
+      return;
+    }
+    let level = state
+      .level()
+      .saturating_sub(self.def.level_drop);
+    if level == 0 {
+      return;
+    }
+    for face in HORIZONTAL {
+      self.fill(
+        world,
+        &pos.neighbor(face),
+        FluidState::flowing(level),
+      );
+    }
+  }
+
+  fn block_updated(
+    &self,
+    world: &mut World,
+    pos: &BlockPos,
+    block: BlockId,
+  ) {
+    world.schedule_tick(pos, block, self.def.delay, 0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::world::Chunk;
+
+  /// 床が石のチャンクを並べ、組み込みの更新処理を登録したワールド
+  fn flat_world(chunks: &[BlockPos]) -> World {
+    let mut world = World::new();
+    let stone = world
+      .registry()
+      .id_of("stone")
+      .unwrap();
+    for chunk_pos in chunks {
+      world.spawn_chunk(*chunk_pos, || {
+        Chunk::new(chunk_pos, |pos| {
+          if pos.get_z() == 0 {
+            stone
+          } else {
+            AIR
+          }
+        })
+      });
+    }
+    world.register_default_tick_handlers();
+    world
+      .ticks_mut()
+      .random_ticks_per_chunk = 0;
+    world
+  }
+
+  fn water(world: &World) -> BlockId {
+    world
+      .registry()
+      .id_of("water")
+      .unwrap()
+  }
+
+  fn place(world: &mut World, pos: BlockPos) {
+    let water = water(world);
+    world.set_block(&pos, water, ChangeCause::Player);
+  }
+
+  fn run(world: &mut World, ticks: u64) {
+    for _ in 0..ticks {
+      world.tick();
+    }
+  }
+
+  /// 水でなければ`None`
+  fn level(
+    world: &World,
+    x: i64,
+    y: i64,
+    z: i64,
+  ) -> Option<u8> {
+    let pos = BlockPos::new(x, y, z);
+    (world.get_block(&pos) == water(world))
+      .then(|| world.fluid_state(&pos).level())
+  }
+
+  #[test]
+  fn falls_and_spreads() {
+    let mut world =
+      flat_world(&[BlockPos::new(0, 0, 0)]);
+    place(
+      &mut world,
+      BlockPos::new(8, 8, 5),
+    );
+    run(&mut world, 200);
+    for z in 1..5 {
+      let pos = BlockPos::new(8, 8, z);
+      assert!(world
+        .fluid_state(&pos)
+        .is_falling());
+      assert_eq!(level(&world, 8, 8, z), Some(8));
+    }
+    assert_eq!(level(&world, 8, 8, 0), None);
+    assert_eq!(level(&world, 9, 8, 2), None);
+    assert_eq!(level(&world, 9, 8, 1), Some(7));
+    assert_eq!(level(&world, 11, 8, 1), Some(5));
+    assert_eq!(level(&world, 8, 15, 1), Some(1));
+    assert_eq!(
+      level(&world, 11, 11, 1),
+      Some(2)
+    );
+    assert_eq!(level(&world, 0, 8, 1), None);
+    assert_eq!(level(&world, 12, 12, 1), None);
+  }
+
+  #[test]
+  fn stops_at_solids() {
+    let mut world =
+      flat_world(&[BlockPos::new(0, 0, 0)]);
+    let stone = world
+      .registry()
+      .id_of("stone")
+      .unwrap();
+    for y in 0..16 {
+      world.set_block(
+        &BlockPos::new(10, y, 1),
+        stone,
+        ChangeCause::Player,
+      );
+    }
+    place(
+      &mut world,
+      BlockPos::new(8, 8, 1),
+    );
+    run(&mut world, 200);
+    assert_eq!(level(&world, 9, 8, 1), Some(7));
+    assert_eq!(
+      world.get_block(&BlockPos::new(10, 8, 1)),
+      stone
+    );
+    for y in 0..16 {
+      assert_eq!(level(&world, 11, y, 1), None);
+    }
+    assert_eq!(level(&world, 8, 8, 2), None);
+  }
+
+  #[test]
+  fn merges_to_highest_level() {
+    let mut world =
+      flat_world(&[BlockPos::new(0, 0, 0)]);
+    place(
+      &mut world,
+      BlockPos::new(4, 8, 1),
+    );
+    place(
+      &mut world,
+      BlockPos::new(12, 8, 1),
+    );
+    run(&mut world, 200);
+    assert_eq!(level(&world, 8, 8, 1), Some(4));
+    assert_eq!(level(&world, 7, 8, 1), Some(5));
+    assert_eq!(level(&world, 9, 8, 1), Some(5));
+    assert!(!world
+      .fluid_state(&BlockPos::new(8, 8, 1))
+      .is_source());
+  }
+
+  #[test]
+  fn renews_between_sources() {
+    let mut world =
+      flat_world(&[BlockPos::new(0, 0, 0)]);
+    place(
+      &mut world,
+      BlockPos::new(4, 8, 1),
+    );
+    place(
+      &mut world,
+      BlockPos::new(6, 8, 1),
+    );
+    run(&mut world, 200);
+    assert!(world
+      .fluid_state(&BlockPos::new(5, 8, 1))
+      .is_source());
+    assert!(!world
+      .fluid_state(&BlockPos::new(5, 9, 1))
+      .is_source());
+  }
+
+  #[test]
+  fn crosses_chunk_border() {
+    let mut world =
+      flat_world(&[BlockPos::new(0, 0, 0)]);
+    place(
+      &mut world,
+      BlockPos::new(14, 8, 1),
+    );
+    run(&mut world, 200);
+    assert_eq!(level(&world, 15, 8, 1), Some(7));
+    assert_eq!(level(&world, 16, 8, 1), None);
+    assert!(world
+      .chunk(&BlockPos::new(1, 0, 0))
+      .is_none());
+
+    let mut world = flat_world(&[
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(1, 0, 0),
+    ]);
+    place(
+      &mut world,
+      BlockPos::new(14, 8, 1),
+    );
+    run(&mut world, 200);
+    assert_eq!(level(&world, 16, 8, 1), Some(6));
+    assert_eq!(level(&world, 17, 8, 1), Some(5));
+    assert_eq!(level(&world, 20, 8, 1), Some(2));
+  }
+
+  #[test]
+  fn bounded_per_tick() {
+    let mut world =
+      flat_world(&[BlockPos::new(0, 0, 0)]);
+    world
+      .ticks_mut()
+      .max_scheduled_per_tick = 1;
+    let first = BlockPos::new(4, 8, 1);
+    let second = BlockPos::new(12, 8, 1);
+    place(&mut world, first);
+    place(&mut world, second);
+    let delay = world
+      .registry()
+      .fluid(water(&world))
+      .unwrap()
+      .delay;
+    run(&mut world, delay);
+    let spread = |world: &World, pos: &BlockPos| {
+      HORIZONTAL
+        .iter()
+        .filter(|face| {
+          world.get_block(&pos.neighbor(**face))
+            == water(world)
+        })
+        .count()
+    };
+    assert_eq!(spread(&world, &first), 0);
+    run(&mut world, 1);
+    assert_eq!(spread(&world, &first), 4);
+    assert_eq!(spread(&world, &second), 0);
+    assert_eq!(world.ticks().len(), 1 + 4 + 1);
+    run(&mut world, 1);
+    assert_eq!(spread(&world, &second), 4);
+  }
+}
@@ -0,0 +1,126 @@
+//! Biome
+//! バイオームの定義とその登録簿
+
+use serde::{Deserialize, Serialize};
+
+use crate::registry::{Registry, RegistryDef};
+
+/// バイオームの数値ID
+pub type BiomeId = u8;
+
+/// 組み込みのバイオーム定義
+const BUILTIN_BIOMES: &str =
+  include_str!("biomes.json");
+
+/// バイオーム定義の読み込み時のエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BiomeRegistryError {
+  /// 同じIDが複数回定義された
+  DuplicateId(BiomeId),
+  /// 同じ名前が複数回定義された
+  DuplicateName(String),
+  /// バイオームが一つも定義されていない
+  Empty,
+}
+impl std::fmt::Display for BiomeRegistryError {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::DuplicateId(id) => {
+        write!(
+          f,
+          "biome id {id} is defined twice"
+        )
+      }
+      Self::DuplicateName(name) => {
+        write!(
+          f,
+          "biome name `{name}` is defined twice"
+        )
+      }
+      Self::Empty => f.write_str("no biome is defined"),
+    }
+  }
+}
+impl std::error::Error for BiomeRegistryError {}
+
+fn default_subsurface_depth() -> i64 {
+  3
+}
+
+/// バイオームの定義
+///
+/// 気温と湿度は気候図上の中心点で、各列は最も近い中心点を持つ
+/// バイオームになる。
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct BiomeDef {
+  pub id: BiomeId,
+  /// 識別用の名前
+  pub name: String,
+  /// 表示用の名前
+  #[serde(default)]
+  pub display_name: String,
+  /// 気候図上の気温(-1..=1)
+  pub temperature: f64,
+  /// 気候図上の湿度(-1..=1)
+  pub humidity: f64,
+  /// 地表の基準の高さ
+  pub base_height: f64,
+  /// 地表の高さの振れ幅
+  pub height_amplitude: f64,
+  /// 地表のブロック名
+  pub surface_block: String,
+  /// 地表の下のブロック名
+  pub subsurface_block: String,
+  /// 地表の下のブロックの層の厚さ
+  #[serde(default = "default_subsurface_depth")]
+  pub subsurface_depth: i64,
+  /// 木などの装飾を置く列の割合(0..=1)
+  #[serde(default)]
+  pub decoration_density: f64,
+}
+
+impl RegistryDef for BiomeDef {
+  type Id = BiomeId;
+  type Error = BiomeRegistryError;
+
+  const LIST_KEY: &'static str = "biomes";
+  const BUILTIN: &'static str = BUILTIN_BIOMES;
+
+  #[inline]
+  fn id(&self) -> BiomeId {
+    self.id
+  }
+
+  #[inline]
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn duplicate_id(id: BiomeId) -> BiomeRegistryError {
+    BiomeRegistryError::DuplicateId(id)
+  }
+
+  fn duplicate_name(
+    name: String,
+  ) -> BiomeRegistryError {
+    BiomeRegistryError::DuplicateName(name)
+  }
+
+  /// バイオームが一つも無い登録簿は使えない
+  fn complete(
+    registry: &mut Registry<Self>,
+  ) -> Result<(), BiomeRegistryError> {
+    if registry.is_empty() {
+      return Err(BiomeRegistryError::Empty);
+    }
+    Ok(())
+  }
+}
+
+/// バイオームIDと定義の登録簿
+pub type BiomeRegistry = Registry<BiomeDef>;
@@ -0,0 +1,502 @@
+//! Carve
+//! 3Dノイズによる洞窟と張り出しの掘削
+
+use crate::block::{BlockId, BlockRegistry, AIR};
+use crate::types::{BlockDist, BlockPos, BlockRange};
+
+use super::noise::{derive_seed, Fbm};
+use super::{
+  BiomeId, Chunk, TerrainGenerator, CHUNK_SIZE,
+};
+
+/// 掘削の設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaveSettings {
+  /// 掘削する高さの下限(含む)
+  pub min_z: i64,
+  /// 掘削する高さの上限(含まない)
+  pub max_z: i64,
+  /// 上下限に近付くにつれて空洞を減らす幅
+  pub fade_height: i64,
+  /// 大空洞のノイズの周波数
+  pub cheese_frequency: f64,
+  /// 大空洞になるノイズ値の閾値(大きい程空洞が減る)
+  pub cheese_threshold: f64,
+  /// 通路のノイズの周波数
+  pub spaghetti_frequency: f64,
+  /// 通路の太さ(二つのノイズが共に0に近い範囲)
+  pub spaghetti_width: f64,
+  /// 張り出しのノイズの周波数
+  pub overhang_frequency: f64,
+  /// 張り出しになるノイズ値の閾値
+  pub overhang_threshold: f64,
+  /// 張り出しを掘る地表からの深さ
+  pub overhang_depth: i64,
+}
+impl Default for CaveSettings {
+  fn default() -> Self {
+    Self {
+      min_z: -128,
+      max_z: 64,
+      fade_height: 8,
+      cheese_frequency: 1. / 48.,
+      cheese_threshold: 0.45,
+      spaghetti_frequency: 1. / 64.,
+      spaghetti_width: 0.06,
+      overhang_frequency: 1. / 24.,
+      overhang_threshold: 0.3,
+      overhang_depth: 8,
+    }
+  }
+}
+
+/// 元の地形生成器の結果を3Dノイズで掘る生成器
+///
+/// 大空洞、通路、地表付近の張り出しを掘る。
+/// 判定は座標のみから求まるため、チャンク単位で独立に実行できる。
+/// 固体でないブロックの直下は掘らず、水などが流れ込む穴を開けない。
+pub struct CaveCarver<G> {
+  inner: G,
+  settings: CaveSettings,
+  /// IDを添字とした掘削可能なブロックの表
+  carvable: Vec<bool>,
+  cheese: Fbm,
+  spaghetti: [Fbm; 2],
+  overhang: Fbm,
+}
+impl<G: TerrainGenerator> CaveCarver<G> {
+  pub fn new(
+    inner: G,
+    seed: u64,
+    registry: &BlockRegistry,
+  ) -> Self {
+    Self::with_settings(
+      inner,
+      seed,
+      registry,
+      CaveSettings::default(),
+    )
+  }
+
+  pub fn with_settings(
+    inner: G,
+    seed: u64,
+    registry: &BlockRegistry,
+    settings: CaveSettings,
+  ) -> Self {
+    let mut carvable = Vec::new();
+    for def in registry.iter() {
+      let index = def.id as usize;
+      if carvable.len() <= index {
+        carvable.resize(index + 1, false);
+      }
+      carvable[index] = def.id != AIR && def.solid;
+    }
+    let seed = derive_seed(seed, 0x4341_5645);
+    Self {
+      inner,
+      settings,
+      carvable,
+      cheese: Fbm::new(
+        derive_seed(seed, 0),
+        3,
+        settings.cheese_frequency,
+      ),
+      spaghetti: [
+        Fbm::new(
+          derive_seed(seed, 1),
+          2,
+          settings.spaghetti_frequency,
+        ),
+        Fbm::new(
+          derive_seed(seed, 2),
+          2,
+          settings.spaghetti_frequency,
+        ),
+      ],
+      overhang: Fbm::new(
+        derive_seed(seed, 3),
+        2,
+        settings.overhang_frequency,
+      ),
+    }
+  }
+
+  #[inline]
+  pub fn inner(&self) -> &G {
+    &self.inner
+  }
+
+  #[inline]
+  pub fn settings(&self) -> &CaveSettings {
+    &self.settings
+  }
+
+  #[inline]
+  fn is_carvable(&self, block: BlockId) -> bool {
+    self
+      .carvable
+      .get(block as usize)
+      .copied()
+      .unwrap_or(false)
+  }
+
+  /// 座標を空洞にするか
+  /// `surface`は列の地表の高さ(不明な場合は`None`)
+  pub fn is_carved(
+    &self,
+    pos: &BlockPos,
+    surface: Option<i64>,
+  ) -> bool {
+    let s = &self.settings;
+    let [x, y, z] = pos.as_array();
+    let (fx, fy, fz) = (x as f64, y as f64, z as f64);
+    if let Some(surface) = surface {
+      let depth = surface - z;
+      if (2..s.overhang_depth).contains(&depth)
+        && s.overhang_threshold
+          < self
+            .overhang
+            .sample3(fx, fy, fz * 2.)
+      {
+        return true;
+      }
+    }
+    if z < s.min_z || s.max_z <= z {
+      return false;
+    }
+    // 上下限付近では閾値を上げて空洞を閉じる
+    let edge = (z - s.min_z).min(s.max_z - 1 - z);
+    let fade = if edge < s.fade_height {
+      (s.fade_height - edge) as f64
+        / s.fade_height as f64
+    } else {
+      0.
+    };
+    if s.cheese_threshold + fade
+      < self
+        .cheese
+        .sample3(fx, fy, fz * 1.5)
+    {
+      return true;
+    }
+    let width = s.spaghetti_width * (1. - fade);
+    self.spaghetti[0]
+      .sample3(fx, fy, fz)
+      .abs()
+      < width
+      && self.spaghetti[1]
+        .sample3(fx, fy, fz)
+        .abs()
+        < width
+  }
+
+  /// 元のブロック`block`とその上のブロック`above`から掘削後のブロックを求める
+  fn carve(
+    &self,
+    pos: &BlockPos,
+    block: BlockId,
+    above: BlockId,
+    surface: Option<i64>,
+  ) -> BlockId {
+    if !self.is_carvable(block)
+      || (above != AIR && !self.is_carvable(above))
+      || !self.is_carved(pos, surface)
+    {
+      block
+    } else {
+      AIR
+    }
+  }
+}
+impl<G: TerrainGenerator> TerrainGenerator
+  for CaveCarver<G>
+{
+  fn block_at(&self, pos: &BlockPos) -> BlockId {
+    let block = self.inner.block_at(pos);
+    if !self.is_carvable(block) {
+      return block;
+    }
+    let above = self
+      .inner
+      .block_at(&(*pos + BlockDist::new(0, 0, 1)));
+    self.carve(
+      pos,
+      block,
+      above,
+      self.surface_height(pos.get_x(), pos.get_y()),
+    )
+  }
+
+  fn surface_height(
+    &self,
+    x: i64,
+    y: i64,
+  ) -> Option<i64> {
+    self.inner.surface_height(x, y)
+  }
+
+  fn biome_at(
+    &self,
+    x: i64,
+    y: i64,
+  ) -> Option<BiomeId> {
+    self.inner.biome_at(x, y)
+  }
+
+  /// 元の生成器でチャンクを生成してから下から順に掘る
+  fn generate_chunk(
+    &self,
+    chunk_pos: &BlockPos,
+  ) -> Chunk {
+    let mut chunk = self
+      .inner
+      .generate_chunk(chunk_pos);
+    if chunk.is_empty() {
+      return chunk;
+    }
+    let origin = chunk_pos.up_level(2);
+    let surfaces: Vec<Option<i64>> = (0..CHUNK_SIZE
+      * CHUNK_SIZE)
+      .map(|i| {
+        self.surface_height(
+          origin.get_x() + i % CHUNK_SIZE,
+          origin.get_y() + i / CHUNK_SIZE,
+        )
+      })
+      .collect();
+    // 走査はzの昇順なので、上のブロックはまだ掘られていない
+    for pos in BlockRange::chunk(chunk_pos) {
+      let block = chunk.get(&pos);
+      if !self.is_carvable(block) {
+        continue;
+      }
+      let above_pos = pos + BlockDist::new(0, 0, 1);
+      let above = if above_pos.chunk_pos() == *chunk_pos
+      {
+        chunk.get(&above_pos)
+      } else {
+        self.inner.block_at(&above_pos)
+      };
+      let local = pos - origin;
+      let surface =
+        surfaces[(local.get_y() * CHUNK_SIZE
+          + local.get_x()) as usize];
+      let carved =
+        self.carve(&pos, block, above, surface);
+      if carved != block {
+        chunk.set(&pos, carved);
+      }
+    }
+    chunk
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::tests::{chunk_hash, generator};
+  use super::super::TerrainSettings;
+  use super::*;
+  use crate::block::BlockRegistry;
+
+  const SEED: u64 = 0x5eed;
+
+  /// 高さで層を分けた地形
+  /// `z < 16`は石、`16 <= z < 18`は水、それより上は空気
+  struct Layers {
+    stone: BlockId,
+    water: BlockId,
+  }
+  impl Layers {
+    fn new(registry: &BlockRegistry) -> Self {
+      Self {
+        stone: registry.id_of("stone").unwrap(),
+        water: registry.id_of("water").unwrap(),
+      }
+    }
+  }
+  impl TerrainGenerator for Layers {
+    fn block_at(&self, pos: &BlockPos) -> BlockId {
+      match pos.get_z() {
+        ..16 => self.stone,
+        16..18 => self.water,
+        _ => AIR,
+      }
+    }
+  }
+
+  /// 張り出しを掘らず、上下限の間を全て掘る設定
+  fn carve_all() -> CaveSettings {
+    CaveSettings {
+      min_z: -64,
+      max_z: 64,
+      fade_height: 1,
+      cheese_threshold: -10.,
+      overhang_threshold: 10.,
+      ..CaveSettings::default()
+    }
+  }
+
+  fn layers_carver(
+    settings: CaveSettings,
+  ) -> CaveCarver<Layers> {
+    let registry = BlockRegistry::builtin();
+    CaveCarver::with_settings(
+      Layers::new(&registry),
+      SEED,
+      &registry,
+      settings,
+    )
+  }
+
+  /// 地表と地中、負の座標を含むチャンク
+  fn chunks() -> Vec<BlockPos> {
+    BlockRange::new(
+      BlockPos::new(-1, 0, -3),
+      BlockPos::new(1, 1, 1),
+    )
+    .iter()
+    .collect()
+  }
+
+  const GOLDEN: [u64; 8] = [
+    0x0b926ff341736325,
+    0x0b926ff341736325,
+    0x0b926ff341736325,
+    0x0b926ff341736325,
+    0x6e15a772af5a8d9d,
+    0x7435bf8062db95c5,
+    0x9a862d067832645f,
+    0x40a900ba723e8aa6,
+  ];
+
+  #[test]
+  fn golden_hashes() {
+    let registry = BlockRegistry::builtin();
+    let carver = CaveCarver::new(
+      generator(TerrainSettings::default()),
+      SEED,
+      &registry,
+    );
+    let hashes: Vec<u64> = chunks()
+      .iter()
+      .map(|pos| {
+        chunk_hash(&carver.generate_chunk(pos))
+      })
+      .collect();
+    assert_eq!(hashes, GOLDEN);
+    // 掘削で元の地形から変わったチャンクがある
+    let inner = generator(TerrainSettings::default());
+    assert!(chunks().iter().any(|pos| {
+      chunk_hash(&inner.generate_chunk(pos))
+        != chunk_hash(&carver.generate_chunk(pos))
+    }));
+  }
+
+  #[test]
+  fn chunk_matches_block_at() {
+    let registry = BlockRegistry::builtin();
+    let carver = CaveCarver::new(
+      generator(TerrainSettings::default()),
+      SEED,
+      &registry,
+    );
+    let layers = layers_carver(carve_all());
+    for chunk_pos in chunks() {
+      let chunk = carver.generate_chunk(&chunk_pos);
+      let layered = layers.generate_chunk(&chunk_pos);
+      for pos in BlockRange::chunk(&chunk_pos) {
+        assert_eq!(
+          chunk.get(&pos),
+          carver.block_at(&pos)
+        );
+        assert_eq!(
+          layered.get(&pos),
+          layers.block_at(&pos)
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn keeps_blocks_under_water() {
+    let carver = layers_carver(carve_all());
+    let stone = carver.inner().stone;
+    let water = carver.inner().water;
+    // 最上段の上は隣のチャンクの水なので掘らない
+    let chunk =
+      carver.generate_chunk(&BlockPos::new(0, 0, 0));
+    for x in 0..16 {
+      for y in 0..16 {
+        assert_eq!(
+          chunk.get(&BlockPos::new(x, y, 15)),
+          stone
+        );
+        assert_eq!(
+          chunk.get(&BlockPos::new(x, y, 14)),
+          AIR
+        );
+      }
+    }
+    let chunk =
+      carver.generate_chunk(&BlockPos::new(0, 0, 1));
+    assert_eq!(
+      chunk.get(&BlockPos::new(3, 4, 16)),
+      water
+    );
+  }
+
+  #[test]
+  fn bounded_by_min_and_max() {
+    let carver = layers_carver(CaveSettings {
+      min_z: -20,
+      max_z: 5,
+      ..carve_all()
+    });
+    let stone = carver.inner().stone;
+    for z in -40..16 {
+      let expected = if (-20..5).contains(&z) {
+        AIR
+      } else {
+        stone
+      };
+      for (x, y) in [(0, 0), (-7, 3), (15, -16)] {
+        assert_eq!(
+          carver.block_at(&BlockPos::new(x, y, z)),
+          expected,
+          "z = {z}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn fades_near_bounds() {
+    let carver = layers_carver(CaveSettings {
+      min_z: -64,
+      max_z: 0,
+      fade_height: 16,
+      cheese_threshold: 0.,
+      overhang_threshold: 10.,
+      ..CaveSettings::default()
+    });
+    let carved = |z: i64| {
+      BlockRange::new(
+        BlockPos::new(0, 0, z),
+        BlockPos::new(64, 64, z + 1),
+      )
+      .iter()
+      .filter(|pos| carver.block_at(pos) == AIR)
+      .count()
+    };
+    // 上下限の段は閉じ、離れる程に空洞が増える
+    assert_eq!(carved(-64), 0);
+    assert_eq!(carved(-1), 0);
+    let near: usize = (-63..-60).map(carved).sum();
+    let middle: usize = (-34..-31).map(carved).sum();
+    assert!(
+      near < middle,
+      "{near} < {middle}"
+    );
+  }
+}
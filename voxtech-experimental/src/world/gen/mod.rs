@@ -0,0 +1,558 @@
+//! Gen
+//! 地形の生成
+
+use std::sync::Arc;
+
+use crate::block::{BlockId, BlockRegistry, AIR};
+use crate::types::BlockPos;
+
+use super::{Chunk, CHUNK_SIZE};
+
+pub mod biome;
+pub mod carve;
+pub mod noise;
+pub mod structure;
+
+pub use biome::{BiomeDef, BiomeId, BiomeRegistry};
+pub use carve::{CaveCarver, CaveSettings};
+pub use structure::StructurePlacer;
+use noise::{derive_seed, Fbm};
+
+/// 地形の生成器
+///
+/// 同じ種値と座標に対して常に同じブロックを返さなければならない。
+/// チャンクは任意のスレッド、任意の順番で生成される。
+pub trait TerrainGenerator: Send + Sync {
+  /// 座標のブロック
+  fn block_at(&self, pos: &BlockPos) -> BlockId;
+
+  /// 列(x, y)の地表の高さ(高さマップを持たない場合は`None`)
+  fn surface_height(
+    &self,
+    _x: i64,
+    _y: i64,
+  ) -> Option<i64> {
+    None
+  }
+
+  /// 列(x, y)のバイオーム(バイオームを持たない場合は`None`)
+  fn biome_at(
+    &self,
+    _x: i64,
+    _y: i64,
+  ) -> Option<BiomeId> {
+    None
+  }
+
+  /// チャンクを生成する
+  fn generate_chunk(
+    &self,
+    chunk_pos: &BlockPos,
+  ) -> Chunk {
+    Chunk::new(chunk_pos, |pos| {
+      self.block_at(&pos)
+    })
+  }
+}
+
+/// 地形を生成し、届く範囲の構造物を書き込んだチャンク
+///
+/// 結果は呼ぶ順番によらないため、ワーカースレッドから任意の順番で
+/// 呼べる。
+pub fn generate_populated(
+  generator: &dyn TerrainGenerator,
+  structures: Option<&StructurePlacer>,
+  chunk_pos: &BlockPos,
+) -> Chunk {
+  let mut chunk = generator.generate_chunk(chunk_pos);
+  if let Some(structures) = structures {
+    structures.populate(chunk_pos, &mut chunk, generator);
+  }
+  chunk
+}
+
+/// 既定の地形の設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainSettings {
+  /// 海面の高さ
+  pub sea_level: i64,
+  /// 地表の起伏の周波数(1ブロックあたり)
+  pub frequency: f64,
+  /// 起伏のノイズを重ねる数
+  pub octaves: u32,
+  /// 気温と湿度のノイズの周波数(1ブロックあたり)
+  pub climate_frequency: f64,
+  /// 気温と湿度のノイズの倍率
+  pub climate_scale: f64,
+  /// 高さを混ぜ合わせる気候図上の幅
+  pub blend_width: f64,
+}
+impl Default for TerrainSettings {
+  fn default() -> Self {
+    Self {
+      sea_level: 0,
+      frequency: 1. / 128.,
+      octaves: 5,
+      climate_frequency: 1. / 768.,
+      climate_scale: 1.6,
+      blend_width: 0.15,
+    }
+  }
+}
+
+/// 列の地表の高さとバイオーム
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Column {
+  pub height: i64,
+  pub biome: BiomeId,
+}
+
+/// ブロックIDを解決したバイオームの地形の設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BiomeLayers {
+  id: BiomeId,
+  temperature: f64,
+  humidity: f64,
+  base_height: f64,
+  height_amplitude: f64,
+  surface: BlockId,
+  subsurface: BlockId,
+  subsurface_depth: i64,
+}
+
+/// 高さマップとバイオームによる既定の地形生成器
+///
+/// 気温と湿度のノイズから列毎のバイオームを選び、地表から順に
+/// バイオームの地表、地表の下、石を積み、海面より低い所を水で満たす。
+/// 水底の地表は地表の下のブロックで覆う。
+/// 高さは気候図上で近いバイオームの値を重み付けして混ぜるため、
+/// バイオームの境界で段差にならない。
+#[derive(Debug, Clone)]
+pub struct DefaultTerrainGenerator {
+  seed: u64,
+  settings: TerrainSettings,
+  stone: BlockId,
+  water: BlockId,
+  biomes: Arc<BiomeRegistry>,
+  /// IDを添字としたバイオーム毎の設定
+  layers: Vec<Option<BiomeLayers>>,
+  height: Fbm,
+  temperature: Fbm,
+  humidity: Fbm,
+}
+impl DefaultTerrainGenerator {
+  pub fn new(
+    seed: u64,
+    blocks: &BlockRegistry,
+    biomes: Arc<BiomeRegistry>,
+  ) -> Self {
+    Self::with_settings(
+      seed,
+      TerrainSettings::default(),
+      blocks,
+      biomes,
+    )
+  }
+
+  /// 未登録のブロック名は空気になる
+  pub fn with_settings(
+    seed: u64,
+    settings: TerrainSettings,
+    blocks: &BlockRegistry,
+    biomes: Arc<BiomeRegistry>,
+  ) -> Self {
+    let id = |name: &str| {
+      blocks
+        .id_of(name)
+        .unwrap_or(AIR)
+    };
+    let mut layers = Vec::new();
+    for def in biomes.iter() {
+      let index = def.id as usize;
+      if layers.len() <= index {
+        layers.resize(index + 1, None);
+      }
+      layers[index] = Some(BiomeLayers {
+        id: def.id,
+        temperature: def.temperature,
+        humidity: def.humidity,
+        base_height: def.base_height,
+        height_amplitude: def.height_amplitude,
+        surface: id(&def.surface_block),
+        subsurface: id(&def.subsurface_block),
+        subsurface_depth: def.subsurface_depth,
+      });
+    }
+    Self {
+      seed,
+      settings,
+      stone: id("stone"),
+      water: id("water"),
+      biomes,
+      layers,
+      height: Fbm::new(
+        derive_seed(seed, 0x4845_4947_4854),
+        settings.octaves,
+        settings.frequency,
+      ),
+      temperature: Fbm::new(
+        derive_seed(seed, 0x5445_4d50),
+        3,
+        settings.climate_frequency,
+      ),
+      humidity: Fbm::new(
+        derive_seed(seed, 0x4855_4d49),
+        3,
+        settings.climate_frequency,
+      ),
+    }
+  }
+
+  #[inline]
+  pub fn seed(&self) -> u64 {
+    self.seed
+  }
+
+  #[inline]
+  pub fn settings(&self) -> &TerrainSettings {
+    &self.settings
+  }
+
+  #[inline]
+  pub fn biomes(&self) -> &Arc<BiomeRegistry> {
+    &self.biomes
+  }
+
+  /// 列(x, y)の気温と湿度(-1..=1)
+  pub fn climate_at(
+    &self,
+    x: i64,
+    y: i64,
+  ) -> (f64, f64) {
+    let (fx, fy) = (x as f64, y as f64);
+    let scale = self.settings.climate_scale;
+    (
+      (self.temperature.sample2(fx, fy) * scale)
+        .clamp(-1., 1.),
+      (self.humidity.sample2(fx, fy) * scale)
+        .clamp(-1., 1.),
+    )
+  }
+
+  /// 列(x, y)の地表の高さとバイオーム
+  pub fn column_at(&self, x: i64, y: i64) -> Column {
+    let (temperature, humidity) = self.climate_at(x, y);
+    let distance = |layers: &BiomeLayers| {
+      (layers.temperature - temperature)
+        .hypot(layers.humidity - humidity)
+    };
+    let nearest = self
+      .layers
+      .iter()
+      .flatten()
+      .map(|layers| (distance(layers), layers))
+      .min_by(|a, b| a.0.total_cmp(&b.0))
+      .expect("biome registry is empty");
+    // 最も近いバイオームとの距離の差が小さい程重くする
+    let noise = self
+      .height
+      .sample2(x as f64, y as f64);
+    let height = |layers: &BiomeLayers| {
+      layers.base_height + layers.height_amplitude * noise
+    };
+    let mut sum = 0.;
+    let mut total = 0.;
+    for layers in self.layers.iter().flatten() {
+      let weight = (self.settings.blend_width
+        - (distance(layers) - nearest.0))
+        .max(0.)
+        .powi(2);
+      sum += weight * height(layers);
+      total += weight;
+    }
+    // 混ぜ合わせる幅が0の場合は最も近いバイオームの高さのみを使う
+    let height = if 0. < total {
+      sum / total
+    } else {
+      height(nearest.1)
+    };
+    Column {
+      height: height.floor() as i64,
+      biome: nearest.1.id,
+    }
+  }
+
+  /// 列(x, y)の地表の高さ
+  #[inline]
+  pub fn height_at(&self, x: i64, y: i64) -> i64 {
+    self.column_at(x, y).height
+  }
+
+  /// 列`column`の高さ`z`のブロック
+  pub fn column_block(
+    &self,
+    column: &Column,
+    z: i64,
+  ) -> BlockId {
+    let sea_level = self.settings.sea_level;
+    let Some(Some(layers)) = self
+      .layers
+      .get(column.biome as usize)
+    else {
+      return AIR;
+    };
+    if column.height < z {
+      if z <= sea_level {
+        self.water
+      } else {
+        AIR
+      }
+    } else if column.height == z
+      && sea_level <= column.height
+    {
+      layers.surface
+    } else if column.height - layers.subsurface_depth
+      <= z
+    {
+      layers.subsurface
+    } else {
+      self.stone
+    }
+  }
+}
+impl TerrainGenerator for DefaultTerrainGenerator {
+  fn block_at(&self, pos: &BlockPos) -> BlockId {
+    self.column_block(
+      &self.column_at(pos.get_x(), pos.get_y()),
+      pos.get_z(),
+    )
+  }
+
+  fn surface_height(
+    &self,
+    x: i64,
+    y: i64,
+  ) -> Option<i64> {
+    Some(self.height_at(x, y))
+  }
+
+  fn biome_at(
+    &self,
+    x: i64,
+    y: i64,
+  ) -> Option<BiomeId> {
+    Some(self.column_at(x, y).biome)
+  }
+
+  /// 列毎の地表の高さとバイオームを先に求めてから埋める
+  fn generate_chunk(
+    &self,
+    chunk_pos: &BlockPos,
+  ) -> Chunk {
+    let origin = chunk_pos.up_level(2);
+    let columns: Vec<Column> = (0..CHUNK_SIZE
+      * CHUNK_SIZE)
+      .map(|i| {
+        self.column_at(
+          origin.get_x() + i % CHUNK_SIZE,
+          origin.get_y() + i / CHUNK_SIZE,
+        )
+      })
+      .collect();
+    Chunk::new(chunk_pos, |pos| {
+      let local = pos - origin;
+      self.column_block(
+        &columns[(local.get_y() * CHUNK_SIZE
+          + local.get_x()) as usize],
+        pos.get_z(),
+      )
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::BlockRange;
+
+  const SEED: u64 = 0x5eed;
+
+  pub(super) fn generator(
+    settings: TerrainSettings,
+  ) -> DefaultTerrainGenerator {
+    DefaultTerrainGenerator::with_settings(
+      SEED,
+      settings,
+      &BlockRegistry::builtin(),
+      BiomeRegistry::shared_builtin(),
+    )
+  }
+
+  /// チャンクのブロックをFNV-1aで要約する
+  pub(super) fn chunk_hash(chunk: &Chunk) -> u64 {
+    (0..super::super::CHUNK_VOLUME as u16)
+      .map(|i| {
+        chunk.get(&BlockPos::from_chunk_index(
+          &BlockPos::new(0, 0, 0),
+          i,
+        ))
+      })
+      .flat_map(BlockId::to_le_bytes)
+      .fold(
+        0xcbf2_9ce4_8422_2325,
+        |hash, byte| {
+          (hash ^ byte as u64)
+            .wrapping_mul(0x0100_0000_01b3)
+        },
+      )
+  }
+
+  /// 地表、水中、地中、空中と負の座標を含むチャンク
+  fn chunks() -> Vec<BlockPos> {
+    BlockRange::new(
+      BlockPos::new(-2, -1, 0),
+      BlockPos::new(1, 2, 2),
+    )
+    .iter()
+    .collect()
+  }
+
+  fn hashes(
+    generator: &DefaultTerrainGenerator,
+    chunks: &[BlockPos],
+  ) -> Vec<(BlockPos, u64)> {
+    chunks
+      .iter()
+      .map(|chunk_pos| {
+        (
+          *chunk_pos,
+          chunk_hash(
+            &generator.generate_chunk(chunk_pos),
+          ),
+        )
+      })
+      .collect()
+  }
+
+  /// `chunks()`の順に並べた既知の要約
+  const GOLDEN: [u64; 18] = [
+    0x16157ce59ed513e5,
+    0xc62a7bac09b81ece,
+    0x162abe691c68473d,
+    0x9920f786dd9dd7b7,
+    0xdc7ce939b4175f85,
+    0x82350320e3c015bc,
+    0x6aaf5969d440eeef,
+    0x6192d1732b66bc6d,
+    0xa8a15e1869de804c,
+    0x77e92c4fb970693f,
+    0x69081925a95369c7,
+    0xb9d103fd6854a325,
+    0x39507dca106d95b6,
+    0xb9d103fd6854a325,
+    0xb9d103fd6854a325,
+    0x20ac19850c62e7f6,
+    0xb9d103fd6854a325,
+    0xb9d103fd6854a325,
+  ];
+
+  fn golden() -> Vec<(BlockPos, u64)> {
+    chunks()
+      .into_iter()
+      .zip(GOLDEN)
+      .collect()
+  }
+
+  #[test]
+  fn golden_hashes() {
+    let generator =
+      generator(TerrainSettings::default());
+    assert_eq!(
+      hashes(&generator, &chunks()),
+      golden()
+    );
+  }
+
+  /// `chunks()`の順に並べ直す
+  fn sorted(
+    mut hashed: Vec<(BlockPos, u64)>,
+  ) -> Vec<(BlockPos, u64)> {
+    let chunks = chunks();
+    hashed.sort_by_key(|(pos, _)| {
+      chunks
+        .iter()
+        .position(|c| c == pos)
+    });
+    hashed
+  }
+
+  #[test]
+  fn independent_of_order() {
+    let generator =
+      generator(TerrainSettings::default());
+    let mut reversed = chunks();
+    reversed.reverse();
+    assert_eq!(
+      sorted(hashes(&generator, &reversed)),
+      golden()
+    );
+    // 隣接しない順
+    let mut scattered = chunks();
+    scattered.sort_by_key(|pos| pos.to_morton() % 7);
+    assert_eq!(
+      sorted(hashes(&generator, &scattered)),
+      golden()
+    );
+  }
+
+  #[test]
+  fn independent_of_thread() {
+    let generator = Arc::new(generator(
+      TerrainSettings::default(),
+    ));
+    let chunks = chunks();
+    let workers: Vec<_> = (0..4)
+      .map(|worker| {
+        let generator = Arc::clone(&generator);
+        let chunks: Vec<BlockPos> = chunks
+          .iter()
+          .rev()
+          .skip(worker)
+          .step_by(4)
+          .copied()
+          .collect();
+        std::thread::spawn(move || {
+          hashes(&generator, &chunks)
+        })
+      })
+      .collect();
+    let hashed = workers
+      .into_iter()
+      .flat_map(|worker| worker.join().unwrap())
+      .collect();
+    assert_eq!(sorted(hashed), golden());
+  }
+
+  #[test]
+  fn zero_blend_width() {
+    let sharp = generator(TerrainSettings {
+      blend_width: 0.,
+      ..Default::default()
+    });
+    let nearly = generator(TerrainSettings {
+      blend_width: 1e-12,
+      ..Default::default()
+    });
+    let mut heights = Vec::new();
+    for x in (-2048..2048).step_by(97) {
+      for y in (-2048..2048).step_by(89) {
+        let column = sharp.column_at(x, y);
+        assert_eq!(column, nearly.column_at(x, y));
+        heights.push(column.height);
+      }
+    }
+    assert!(heights
+      .iter()
+      .any(|h| *h != heights[0]));
+  }
+}
@@ -0,0 +1,232 @@
+//! Noise
+//! 種値から決定的に求まる勾配ノイズ
+//!
+//! 乱数表を持たず、格子点の座標と種値のハッシュから勾配を選ぶため、
+//! スレッドや呼び出し順によらず同じ入力には同じ値を返す。
+
+/// SplitMix64の攪拌関数
+#[inline]
+pub fn mix64(mut z: u64) -> u64 {
+  z =
+    (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+  z =
+    (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+  z ^ (z >> 31)
+}
+
+/// 種値と整数座標のハッシュ
+#[inline]
+pub fn hash3(seed: u64, x: i64, y: i64, z: i64) -> u64 {
+  let mut h = mix64(seed ^ 0x9E37_79B9_7F4A_7C15);
+  for v in [x, y, z] {
+    h = mix64(h ^ v as u64);
+  }
+  h
+}
+
+/// 種値から別系統の種値を派生させる
+#[inline]
+pub fn derive_seed(seed: u64, salt: u64) -> u64 {
+  mix64(seed ^ mix64(salt))
+}
+
+/// ハッシュを[0, 1)の実数に変換する
+#[inline]
+pub fn unit_f64(hash: u64) -> f64 {
+  (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// 2Dシンプレックスノイズ用の単位勾配
+const GRAD2: [[f64; 2]; 8] = [
+  [1., 0.],
+  [-1., 0.],
+  [0., 1.],
+  [0., -1.],
+  [
+    std::f64::consts::FRAC_1_SQRT_2,
+    std::f64::consts::FRAC_1_SQRT_2,
+  ],
+  [
+    -std::f64::consts::FRAC_1_SQRT_2,
+    std::f64::consts::FRAC_1_SQRT_2,
+  ],
+  [
+    std::f64::consts::FRAC_1_SQRT_2,
+    -std::f64::consts::FRAC_1_SQRT_2,
+  ],
+  [
+    -std::f64::consts::FRAC_1_SQRT_2,
+    -std::f64::consts::FRAC_1_SQRT_2,
+  ],
+];
+
+/// 3D勾配ノイズ用の立方体の辺方向の勾配
+const GRAD3: [[f64; 3]; 12] = [
+  [1., 1., 0.],
+  [-1., 1., 0.],
+  [1., -1., 0.],
+  [-1., -1., 0.],
+  [1., 0., 1.],
+  [-1., 0., 1.],
+  [1., 0., -1.],
+  [-1., 0., -1.],
+  [0., 1., 1.],
+  [0., -1., 1.],
+  [0., 1., -1.],
+  [0., -1., -1.],
+];
+
+/// 2Dシンプレックスノイズ(おおよそ[-1, 1])
+pub fn simplex2(seed: u64, x: f64, y: f64) -> f64 {
+  const F2: f64 = 0.366_025_403_784_438_6;
+  const G2: f64 = 0.211_324_865_405_187_1;
+
+  let s = (x + y) * F2;
+  let i = (x + s).floor();
+  let j = (y + s).floor();
+  let t = (i + j) * G2;
+  let x0 = x - (i - t);
+  let y0 = y - (j - t);
+  let (i1, j1) = if y0 < x0 {
+    (1, 0)
+  } else {
+    (0, 1)
+  };
+  let corners = [
+    (0, 0, x0, y0),
+    (
+      i1,
+      j1,
+      x0 - i1 as f64 + G2,
+      y0 - j1 as f64 + G2,
+    ),
+    (
+      1,
+      1,
+      x0 - 1. + 2. * G2,
+      y0 - 1. + 2. * G2,
+    ),
+  ];
+  let (i, j) = (i as i64, j as i64);
+  let mut sum = 0.;
+  for (di, dj, dx, dy) in corners {
+    let t = 0.5 - dx * dx - dy * dy;
+    if t <= 0. {
+      continue;
+    }
+    let g = GRAD2
+      [(hash3(seed, i + di, j + dj, 0) & 7) as usize];
+    let t = t * t;
+    sum += t * t * (g[0] * dx + g[1] * dy);
+  }
+  (sum * 99.2).clamp(-1., 1.)
+}
+
+/// 3Dパーリン勾配ノイズ(おおよそ[-1, 1])
+pub fn gradient3(
+  seed: u64,
+  x: f64,
+  y: f64,
+  z: f64,
+) -> f64 {
+  #[inline]
+  fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+  }
+  #[inline]
+  fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+  }
+
+  let (fx, fy, fz) = (x.floor(), y.floor(), z.floor());
+  let (ix, iy, iz) = (fx as i64, fy as i64, fz as i64);
+  let (dx, dy, dz) = (x - fx, y - fy, z - fz);
+  let dot = |cx: i64, cy: i64, cz: i64| {
+    let g =
+      GRAD3[(hash3(seed, ix + cx, iy + cy, iz + cz)
+        % 12) as usize];
+    g[0] * (dx - cx as f64)
+      + g[1] * (dy - cy as f64)
+      + g[2] * (dz - cz as f64)
+  };
+  let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+  let x00 = lerp(dot(0, 0, 0), dot(1, 0, 0), u);
+  let x10 = lerp(dot(0, 1, 0), dot(1, 1, 0), u);
+  let x01 = lerp(dot(0, 0, 1), dot(1, 0, 1), u);
+  let x11 = lerp(dot(0, 1, 1), dot(1, 1, 1), u);
+  lerp(
+    lerp(x00, x10, v),
+    lerp(x01, x11, v),
+    w,
+  )
+  .clamp(-1., 1.)
+}
+
+/// 周波数を変えたノイズを重ねるフラクタルノイズ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fbm {
+  pub seed: u64,
+  /// 重ねる数
+  pub octaves: u32,
+  /// 最初の層の周波数(1ブロックあたり)
+  pub frequency: f64,
+  /// 層毎の周波数の倍率
+  pub lacunarity: f64,
+  /// 層毎の振幅の倍率
+  pub gain: f64,
+}
+impl Fbm {
+  pub fn new(
+    seed: u64,
+    octaves: u32,
+    frequency: f64,
+  ) -> Self {
+    Self {
+      seed,
+      octaves,
+      frequency,
+      lacunarity: 2.,
+      gain: 0.5,
+    }
+  }
+
+  /// 各層を重ね、振幅の合計で正規化する
+  fn accumulate(
+    &self,
+    mut sample: impl FnMut(u64, f64) -> f64,
+  ) -> f64 {
+    let mut sum = 0.;
+    let mut amplitude = 1.;
+    let mut total = 0.;
+    let mut frequency = self.frequency;
+    for octave in 0..self.octaves {
+      sum += amplitude
+        * sample(
+          derive_seed(self.seed, octave as u64),
+          frequency,
+        );
+      total += amplitude;
+      amplitude *= self.gain;
+      frequency *= self.lacunarity;
+    }
+    if total == 0. {
+      0.
+    } else {
+      sum / total
+    }
+  }
+
+  /// 2Dシンプレックスノイズを重ねた値([-1, 1])
+  pub fn sample2(&self, x: f64, y: f64) -> f64 {
+    self.accumulate(|seed, f| {
+      simplex2(seed, x * f, y * f)
+    })
+  }
+
+  /// 3D勾配ノイズを重ねた値([-1, 1])
+  pub fn sample3(&self, x: f64, y: f64, z: f64) -> f64 {
+    self.accumulate(|seed, f| {
+      gradient3(seed, x * f, y * f, z * f)
+    })
+  }
+}
@@ -0,0 +1,794 @@
+//! Structure
+//! チャンクを跨ぐ構造物(木、遺跡)の配置
+
+use std::sync::Arc;
+
+use hashbrown::HashMap;
+
+use crate::block::{BlockId, BlockRegistry, AIR};
+use crate::types::{BlockDist, BlockPos};
+use crate::PMutex;
+
+use super::noise::{
+  derive_seed, hash3, mix64, unit_f64,
+};
+use super::{
+  BiomeRegistry, Chunk, TerrainGenerator, CHUNK_SIZE,
+};
+
+/// 構造物が起点のチャンクからはみ出せるチャンク数
+pub const STRUCTURE_REACH: i64 = 1;
+
+/// 書き込み先にあるブロックの置き換え方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replace {
+  /// 空気のみを置き換える
+  Air,
+  /// 固体でないブロックを置き換える
+  NonSolid,
+  /// 常に置き換える
+  Always,
+}
+
+/// 構造物によるブロックの書き込み
+///
+/// 書き込みは(優先度, 構造物のハッシュ, 構造物内の順番)の昇順に
+/// 適用されるため、チャンクの生成順によらず結果が一意に決まる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StructureWrite {
+  pub pos: BlockPos,
+  pub block: BlockId,
+  pub replace: Replace,
+  priority: u8,
+  instance: u64,
+  seq: u32,
+}
+impl StructureWrite {
+  #[inline]
+  fn key(&self) -> (u8, u64, u32) {
+    (
+      self.priority,
+      self.instance,
+      self.seq,
+    )
+  }
+}
+
+/// 一つの構造物の書き込みを集める
+pub struct StructureWriter<'a> {
+  priority: u8,
+  instance: u64,
+  writes: &'a mut Vec<StructureWrite>,
+}
+impl StructureWriter<'_> {
+  /// ブロックを書き込む
+  pub fn set(
+    &mut self,
+    pos: BlockPos,
+    block: BlockId,
+    replace: Replace,
+  ) {
+    if block == AIR && replace != Replace::Always {
+      return;
+    }
+    let seq = self.writes.len() as u32;
+    self
+      .writes
+      .push(StructureWrite {
+        pos,
+        block,
+        replace,
+        priority: self.priority,
+        instance: self.instance,
+        seq,
+      });
+  }
+}
+
+/// 構造物の配置に使う情報
+pub struct FeatureContext<'a> {
+  pub generator: &'a dyn TerrainGenerator,
+  pub blocks: &'a BlockRegistry,
+  pub biomes: &'a BiomeRegistry,
+}
+impl FeatureContext<'_> {
+  /// 列の装飾の密度(バイオームを持たない場合は0)
+  pub fn decoration_density(
+    &self,
+    x: i64,
+    y: i64,
+  ) -> f64 {
+    self
+      .generator
+      .biome_at(x, y)
+      .and_then(|id| self.biomes.get(id))
+      .map_or(0., |def| def.decoration_density)
+  }
+
+  /// 起点が固体の地表の上の空気か
+  pub fn is_open_ground(
+    &self,
+    origin: &BlockPos,
+  ) -> bool {
+    let ground = self
+      .generator
+      .block_at(&(*origin - BlockDist::new(0, 0, 1)));
+    self.blocks.is_solid(ground)
+      && self.generator.block_at(origin) == AIR
+  }
+}
+
+/// 地表に置く構造物の種類
+pub trait Feature: Send + Sync {
+  /// 優先度(大きい程後に書き込まれ、他の構造物を上書きする)
+  fn priority(&self) -> u8;
+
+  /// 列に置かれる確率の上限(地表を求める前の足切りに使う)
+  fn max_chance(&self) -> f64;
+
+  /// 地表の一つ上の起点に構造物を置く
+  /// `roll`は列毎の[0, 1)の乱数、`hash`は形を決めるための乱数
+  fn place(
+    &self,
+    ctx: &FeatureContext,
+    origin: BlockPos,
+    roll: f64,
+    hash: u64,
+    out: &mut StructureWriter,
+  );
+}
+
+/// 木
+///
+/// バイオームの装飾の密度に従って草や雪の上に置く。
+pub struct TreeFeature {
+  log: BlockId,
+  leaves: BlockId,
+  ground: Vec<BlockId>,
+  max_density: f64,
+}
+impl TreeFeature {
+  pub fn new(
+    blocks: &BlockRegistry,
+    biomes: &BiomeRegistry,
+  ) -> Self {
+    let id = |name| {
+      blocks
+        .id_of(name)
+        .unwrap_or(AIR)
+    };
+    Self {
+      log: id("log"),
+      leaves: id("leaves"),
+      ground: ["grass", "dirt", "snow"]
+        .into_iter()
+        .filter_map(|name| blocks.id_of(name))
+        .collect(),
+      max_density: biomes
+        .iter()
+        .map(|def| def.decoration_density)
+        .fold(0., f64::max),
+    }
+  }
+}
+impl Feature for TreeFeature {
+  fn priority(&self) -> u8 {
+    1
+  }
+
+  fn max_chance(&self) -> f64 {
+    self.max_density
+  }
+
+  fn place(
+    &self,
+    ctx: &FeatureContext,
+    origin: BlockPos,
+    roll: f64,
+    hash: u64,
+    out: &mut StructureWriter,
+  ) {
+    if ctx.decoration_density(
+      origin.get_x(),
+      origin.get_y(),
+    ) <= roll
+      || !ctx.is_open_ground(&origin)
+      || !self.ground.contains(
+        &ctx.generator.block_at(
+          &(origin - BlockDist::new(0, 0, 1)),
+        ),
+      )
+    {
+      return;
+    }
+    let height = 4 + (hash % 3) as i64;
+    for z in 0..height {
+      out.set(
+        origin + BlockDist::new(0, 0, z),
+        self.log,
+        Replace::NonSolid,
+      );
+    }
+    let top = origin + BlockDist::new(0, 0, height);
+    for dz in -2..=1 {
+      let radius: i64 = if dz < 0 {
+        2
+      } else {
+        1
+      };
+      for dy in -radius..=radius {
+        for dx in -radius..=radius {
+          // 角を乱数で欠けさせる
+          if dx.abs() == radius
+            && dy.abs() == radius
+            && hash3(hash, dx, dy, dz) & 1 == 0
+          {
+            continue;
+          }
+          out.set(
+            top + BlockDist::new(dx, dy, dz),
+            self.leaves,
+            Replace::Air,
+          );
+        }
+      }
+    }
+  }
+}
+
+/// 遺跡
+///
+/// 石畳の床と崩れた壁からなる。まれに陸上に置く。
+pub struct RuinFeature {
+  cobblestone: BlockId,
+  chance: f64,
+}
+impl RuinFeature {
+  pub fn new(blocks: &BlockRegistry) -> Self {
+    Self {
+      cobblestone: blocks
+        .id_of("cobblestone")
+        .unwrap_or(AIR),
+      chance: 1. / 8192.,
+    }
+  }
+}
+impl Feature for RuinFeature {
+  fn priority(&self) -> u8 {
+    2
+  }
+
+  fn max_chance(&self) -> f64 {
+    self.chance
+  }
+
+  fn place(
+    &self,
+    ctx: &FeatureContext,
+    origin: BlockPos,
+    _roll: f64,
+    hash: u64,
+    out: &mut StructureWriter,
+  ) {
+    if !ctx.is_open_ground(&origin) {
+      return;
+    }
+    let half = 2 + (hash % 3) as i64;
+    for dy in -half..=half {
+      for dx in -half..=half {
+        let column = origin + BlockDist::new(dx, dy, 0);
+        out.set(
+          column - BlockDist::new(0, 0, 1),
+          self.cobblestone,
+          Replace::Always,
+        );
+        if dx.abs() != half && dy.abs() != half {
+          continue;
+        }
+        // 壁の高さは0..=3で、崩れた所は途切れる
+        let wall = (mix64(hash ^ hash3(0, dx, dy, 0))
+          % 4) as i64;
+        for dz in 0..wall {
+          out.set(
+            column + BlockDist::new(0, 0, dz),
+            self.cobblestone,
+            Replace::Always,
+          );
+        }
+      }
+    }
+  }
+}
+
+/// 構造物の書き込みを保持する起点の列の数
+pub const PLAN_CACHE_COLUMNS: usize = 512;
+
+/// チャンクの列を起点とする構造物の書き込み
+/// 書き込み先のチャンク毎に分けて持つ
+type ColumnPlan =
+  HashMap<BlockPos, Vec<StructureWrite>>;
+
+/// 起点の列毎の書き込みと最後に使った時の`clock`
+#[derive(Default)]
+struct PlanCache {
+  columns: HashMap<(i64, i64), (Arc<ColumnPlan>, u64)>,
+  clock: u64,
+}
+
+/// 構造物の配置段階
+///
+/// チャンクの生成時に、届く範囲の全てのチャンクを起点とする構造物を
+/// 求め、そのチャンクへの書き込みのみを適用する。構造物の配置は
+/// 種値と座標のみから決まるため、生成順や生成するスレッドによらず
+/// 同じ結果になり、保存済みのチャンクの有無にも左右されない。
+///
+/// 起点の計算はチャンクの列単位で行い、隣接するチャンクの生成で
+/// 使い回せるよう`PLAN_CACHE_COLUMNS`列まで保持する。保持した
+/// 書き込みは地形に依存するため、一つの配置段階は常に同じ生成器と
+/// 組み合わせて使う。
+pub struct StructurePlacer {
+  seed: u64,
+  blocks: Arc<BlockRegistry>,
+  biomes: Arc<BiomeRegistry>,
+  features: Vec<Box<dyn Feature>>,
+  cache: PMutex<PlanCache>,
+}
+impl StructurePlacer {
+  pub fn new(
+    seed: u64,
+    blocks: Arc<BlockRegistry>,
+    biomes: Arc<BiomeRegistry>,
+  ) -> Self {
+    Self {
+      seed: derive_seed(seed, 0x5354_5255_4354),
+      blocks,
+      biomes,
+      features: Vec::new(),
+      cache: PMutex::default(),
+    }
+  }
+
+  /// 木と遺跡を置く配置段階
+  pub fn with_default_features(
+    seed: u64,
+    blocks: Arc<BlockRegistry>,
+    biomes: Arc<BiomeRegistry>,
+  ) -> Self {
+    let tree = TreeFeature::new(&blocks, &biomes);
+    let ruin = RuinFeature::new(&blocks);
+    let mut placer = Self::new(seed, blocks, biomes);
+    placer.add_feature(Box::new(tree));
+    placer.add_feature(Box::new(ruin));
+    placer
+  }
+
+  /// 構造物の種類を追加する
+  pub fn add_feature(
+    &mut self,
+    feature: Box<dyn Feature>,
+  ) {
+    self.features.push(feature);
+    self
+      .cache
+      .get_mut()
+      .columns
+      .clear();
+  }
+
+  /// チャンクの列`(cx, cy)`を起点とする構造物の書き込みを求める
+  ///
+  /// 地表の高さは列毎に一度だけ求める。起点のチャンクから
+  /// `STRUCTURE_REACH`を超えてはみ出す書き込みは捨てる。
+  fn plan_column(
+    &self,
+    cx: i64,
+    cy: i64,
+    generator: &dyn TerrainGenerator,
+  ) -> ColumnPlan {
+    let ctx = FeatureContext {
+      generator,
+      blocks: &self.blocks,
+      biomes: &self.biomes,
+    };
+    let base = BlockPos::new(cx, cy, 0).up_level(2);
+    let mut surfaces =
+      [None; (CHUNK_SIZE * CHUNK_SIZE) as usize];
+    let mut writes = Vec::new();
+    let mut plan = ColumnPlan::new();
+    for (index, feature) in
+      self.features.iter().enumerate()
+    {
+      let seed = derive_seed(self.seed, index as u64);
+      for i in 0..CHUNK_SIZE * CHUNK_SIZE {
+        let x = base.get_x() + i % CHUNK_SIZE;
+        let y = base.get_y() + i / CHUNK_SIZE;
+        let hash = hash3(seed, x, y, 0);
+        let roll = unit_f64(hash);
+        if feature.max_chance() <= roll {
+          continue;
+        }
+        let Some(surface) = *surfaces[i as usize]
+          .get_or_insert_with(|| {
+            generator.surface_height(x, y)
+          })
+        else {
+          continue;
+        };
+        let origin = BlockPos::new(x, y, surface + 1);
+        feature.place(
+          &ctx,
+          origin,
+          roll,
+          mix64(hash),
+          &mut StructureWriter {
+            priority: feature.priority(),
+            instance: hash,
+            writes: &mut writes,
+          },
+        );
+        let origin_chunk = origin.chunk_pos();
+        for write in writes.drain(..) {
+          let chunk_pos = write.pos.chunk_pos();
+          let d = chunk_pos - origin_chunk;
+          let reach = [d.get_x(), d.get_y(), d.get_z()]
+            .iter()
+            .all(|d| d.abs() <= STRUCTURE_REACH);
+          if reach {
+            plan
+              .entry(chunk_pos)
+              .or_default()
+              .push(write);
+          }
+        }
+      }
+    }
+    plan
+  }
+
+  /// チャンクの列を起点とする書き込みを保持したものから取得する
+  fn column(
+    &self,
+    cx: i64,
+    cy: i64,
+    generator: &dyn TerrainGenerator,
+  ) -> Arc<ColumnPlan> {
+    {
+      let mut cache = self.cache.lock();
+      cache.clock += 1;
+      let clock = cache.clock;
+      if let Some((plan, last_used)) =
+        cache.columns.get_mut(&(cx, cy))
+      {
+        *last_used = clock;
+        return Arc::clone(plan);
+      }
+    }
+    // 計算中はロックを外す(同じ列を重ねて求めても結果は同じ)
+    let plan =
+      Arc::new(self.plan_column(cx, cy, generator));
+    let mut cache = self.cache.lock();
+    if PLAN_CACHE_COLUMNS <= cache.columns.len()
+      && let Some(column) = cache
+        .columns
+        .iter()
+        .min_by_key(|(_, (_, last_used))| *last_used)
+        .map(|(column, _)| *column)
+    {
+      cache.columns.remove(&column);
+    }
+    let clock = cache.clock;
+    cache.columns.insert(
+      (cx, cy),
+      (Arc::clone(&plan), clock),
+    );
+    plan
+  }
+
+  /// 生成したチャンクに届く範囲の構造物を書き込む
+  pub fn populate(
+    &self,
+    chunk_pos: &BlockPos,
+    chunk: &mut Chunk,
+    generator: &dyn TerrainGenerator,
+  ) {
+    let r = STRUCTURE_REACH;
+    let mut writes = Vec::new();
+    for dy in -r..=r {
+      for dx in -r..=r {
+        let plan = self.column(
+          chunk_pos.get_x() + dx,
+          chunk_pos.get_y() + dy,
+          generator,
+        );
+        if let Some(column) = plan.get(chunk_pos) {
+          writes.extend_from_slice(column);
+        }
+      }
+    }
+    writes.sort_unstable_by_key(StructureWrite::key);
+    for write in writes {
+      let current = chunk.get(&write.pos);
+      let replace = match write.replace {
+        Replace::Air => current == AIR,
+        Replace::NonSolid => {
+          !self.blocks.is_solid(current)
+        }
+        Replace::Always => true,
+      };
+      if replace {
+        chunk.set(&write.pos, write.block);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use super::*;
+  use crate::types::BlockRange;
+  use crate::world::r#gen::{
+    generate_populated, DefaultTerrainGenerator,
+  };
+
+  /// 高さ0以下が草の平らな地形
+  struct Flat {
+    grass: BlockId,
+  }
+  impl TerrainGenerator for Flat {
+    fn block_at(&self, pos: &BlockPos) -> BlockId {
+      if pos.get_z() <= 0 {
+        self.grass
+      } else {
+        AIR
+      }
+    }
+
+    fn surface_height(
+      &self,
+      _x: i64,
+      _y: i64,
+    ) -> Option<i64> {
+      Some(0)
+    }
+  }
+
+  /// 地表の高さを求めた回数を数える`Flat`
+  struct Counting {
+    flat: Flat,
+    calls: AtomicUsize,
+  }
+  impl TerrainGenerator for Counting {
+    fn block_at(&self, pos: &BlockPos) -> BlockId {
+      self.flat.block_at(pos)
+    }
+
+    fn surface_height(
+      &self,
+      x: i64,
+      y: i64,
+    ) -> Option<i64> {
+      self
+        .calls
+        .fetch_add(1, Ordering::Relaxed);
+      self.flat.surface_height(x, y)
+    }
+  }
+
+  /// 列(15, 15)の地表に置く、チャンクを跨ぐ3x3x3の立方体
+  struct Cube {
+    block: BlockId,
+  }
+  impl Feature for Cube {
+    fn priority(&self) -> u8 {
+      0
+    }
+
+    fn max_chance(&self) -> f64 {
+      1.
+    }
+
+    fn place(
+      &self,
+      _ctx: &FeatureContext,
+      origin: BlockPos,
+      _roll: f64,
+      _hash: u64,
+      out: &mut StructureWriter,
+    ) {
+      if origin.get_x() != 15 || origin.get_y() != 15 {
+        return;
+      }
+      for pos in BlockRange::new(
+        origin - BlockDist::new(1, 1, 0),
+        origin + BlockDist::new(2, 2, 3),
+      ) {
+        out.set(pos, self.block, Replace::Always);
+      }
+    }
+  }
+
+  fn blocks_of(
+    chunks: &[(BlockPos, Chunk)],
+  ) -> Vec<BlockId> {
+    chunks
+      .iter()
+      .flat_map(|(chunk_pos, chunk)| {
+        BlockRange::chunk(chunk_pos)
+          .into_iter()
+          .map(|pos| chunk.get(&pos))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn cross_chunk_writes() {
+    let blocks = BlockRegistry::shared_builtin();
+    let stone = blocks.id_of("stone").unwrap();
+    let generator = Flat {
+      grass: blocks.id_of("grass").unwrap(),
+    };
+    let mut placer = StructurePlacer::new(
+      1,
+      Arc::clone(&blocks),
+      BiomeRegistry::shared_builtin(),
+    );
+    placer.add_feature(Box::new(Cube { block: stone }));
+
+    // 起点のチャンクより先に隣を生成しても書き込まれる
+    for chunk_pos in [
+      BlockPos::new(1, 1, 0),
+      BlockPos::new(0, 1, 0),
+      BlockPos::new(1, 0, 0),
+      BlockPos::new(0, 0, 0),
+    ] {
+      let chunk = generate_populated(
+        &generator,
+        Some(&placer),
+        &chunk_pos,
+      );
+      let range = BlockRange::chunk(&chunk_pos);
+      let expected = BlockRange::new(
+        BlockPos::new(14, 14, 1),
+        BlockPos::new(17, 17, 4),
+      );
+      for pos in range {
+        let block = chunk.get(&pos);
+        if expected.contains(&pos) {
+          assert_eq!(block, stone, "{pos:?}");
+        } else {
+          assert_ne!(block, stone, "{pos:?}");
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn independent_of_order() {
+    let blocks = BlockRegistry::shared_builtin();
+    let biomes = BiomeRegistry::shared_builtin();
+    let generator = DefaultTerrainGenerator::new(
+      7,
+      &blocks,
+      Arc::clone(&biomes),
+    );
+    let placer = StructurePlacer::with_default_features(
+      7,
+      Arc::clone(&blocks),
+      biomes,
+    );
+    let chunks: Vec<BlockPos> = BlockRange::new(
+      BlockPos::new(-3, -3, 0),
+      BlockPos::new(3, 3, 2),
+    )
+    .into_iter()
+    .collect();
+    let generate = |chunk_pos: &BlockPos| {
+      (
+        *chunk_pos,
+        generate_populated(
+          &generator,
+          Some(&placer),
+          chunk_pos,
+        ),
+      )
+    };
+    let forward: Vec<(BlockPos, Chunk)> = chunks
+      .iter()
+      .map(generate)
+      .collect();
+    let mut backward: Vec<(BlockPos, Chunk)> = chunks
+      .iter()
+      .rev()
+      .map(generate)
+      .collect();
+    backward.reverse();
+    let forward = blocks_of(&forward);
+    assert_eq!(forward, blocks_of(&backward));
+    let log = blocks.id_of("log").unwrap();
+    assert!(forward.contains(&log));
+  }
+
+  #[test]
+  fn surface_once_per_column() {
+    let blocks = BlockRegistry::shared_builtin();
+    let generator = Counting {
+      flat: Flat {
+        grass: blocks.id_of("grass").unwrap(),
+      },
+      calls: AtomicUsize::new(0),
+    };
+    let placer = StructurePlacer::with_default_features(
+      3,
+      Arc::clone(&blocks),
+      BiomeRegistry::shared_builtin(),
+    );
+    // 3x3x3のチャンクは5x5の起点の列を使う
+    for chunk_pos in BlockRange::new(
+      BlockPos::new(-1, -1, -1),
+      BlockPos::new(2, 2, 2),
+    ) {
+      generate_populated(
+        &generator,
+        Some(&placer),
+        &chunk_pos,
+      );
+    }
+    let columns = 5 * 5 * (CHUNK_SIZE * CHUNK_SIZE);
+    let calls = generator
+      .calls
+      .load(Ordering::Relaxed);
+    assert!(0 < calls);
+    assert!(
+      calls <= columns as usize,
+      "{calls}"
+    );
+  }
+
+  #[test]
+  fn cache_is_bounded() {
+    let blocks = BlockRegistry::shared_builtin();
+    let stone = blocks.id_of("stone").unwrap();
+    let generator = Flat {
+      grass: blocks.id_of("grass").unwrap(),
+    };
+    let mut placer = StructurePlacer::new(
+      1,
+      Arc::clone(&blocks),
+      BiomeRegistry::shared_builtin(),
+    );
+    placer.add_feature(Box::new(Cube { block: stone }));
+    let chunks = PLAN_CACHE_COLUMNS as i64;
+    for x in 0..chunks {
+      generate_populated(
+        &generator,
+        Some(&placer),
+        &BlockPos::new(x, 0, 0),
+      );
+    }
+    let cache = placer.cache.lock();
+    assert_eq!(
+      cache.columns.len(),
+      PLAN_CACHE_COLUMNS
+    );
+    // 最近使った列が残っている
+    assert!(cache
+      .columns
+      .contains_key(&(chunks, 0)));
+    assert!(!cache
+      .columns
+      .contains_key(&(-1, 0)));
+    drop(cache);
+    // 捨てた列を求め直しても同じ結果になる
+    let chunk = generate_populated(
+      &generator,
+      Some(&placer),
+      &BlockPos::new(1, 1, 0),
+    );
+    assert_eq!(
+      chunk.get(&BlockPos::new(16, 16, 1)),
+      stone
+    );
+  }
+}
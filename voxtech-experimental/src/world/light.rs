@@ -0,0 +1,847 @@
+//! Light
+//! 天空光とブロック光の計算
+
+use std::collections::VecDeque;
+
+use hashbrown::HashSet;
+
+use super::chunk_map::ChunkMap;
+use super::r#gen::TerrainGenerator;
+use super::CHUNK_VOLUME;
+use crate::block::BlockRegistry;
+use crate::types::{BlockPos, BlockRange, TileFace};
+
+/// 光量の最大値
+pub const MAX_LIGHT: u8 = 15;
+
+/// ブロック毎の光量
+///
+/// 上位4bitに天空光、下位4bitにブロック光を持つ。
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Hash,
+)]
+pub struct Light(u8);
+impl Light {
+  /// 真っ暗
+  pub const DARK: Self = Self(0);
+  /// 空に開けた場所
+  pub const SKY: Self = Self(MAX_LIGHT << 4);
+
+  #[inline]
+  pub fn new(sky: u8, block: u8) -> Self {
+    Self(
+      (sky.min(MAX_LIGHT) << 4) | block.min(MAX_LIGHT),
+    )
+  }
+
+  #[inline]
+  pub fn from_bits(bits: u8) -> Self {
+    Self(bits)
+  }
+
+  #[inline]
+  pub fn bits(&self) -> u8 {
+    self.0
+  }
+
+  /// 天空光
+  #[inline]
+  pub fn sky(&self) -> u8 {
+    self.0 >> 4
+  }
+
+  /// ブロック光
+  #[inline]
+  pub fn block(&self) -> u8 {
+    self.0 & MAX_LIGHT
+  }
+
+  /// 天空光とブロック光の明るい方
+  #[inline]
+  pub fn brightest(&self) -> u8 {
+    self.sky().max(self.block())
+  }
+
+  #[inline]
+  pub fn get(&self, channel: LightChannel) -> u8 {
+    match channel {
+      LightChannel::Sky => self.sky(),
+      LightChannel::Block => self.block(),
+    }
+  }
+
+  #[inline]
+  pub fn with(
+    &self,
+    channel: LightChannel,
+    level: u8,
+  ) -> Self {
+    match channel {
+      LightChannel::Sky => {
+        Self::new(level, self.block())
+      }
+      LightChannel::Block => {
+        Self::new(self.sky(), level)
+      }
+    }
+  }
+}
+
+/// 光の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LightChannel {
+  /// 空から差し込む光
+  Sky,
+  /// 発光するブロックの光
+  Block,
+}
+impl LightChannel {
+  pub const ALL: [Self; 2] = [Self::Sky, Self::Block];
+}
+
+/// 光の計算で光量が変わったチャンク
+#[derive(Debug, Default)]
+pub(super) struct LightChanges {
+  /// 光量が変わったチャンク
+  pub lit: HashSet<BlockPos>,
+  /// 光量が弱まったブロックを含むチャンク
+  pub darkened: HashSet<BlockPos>,
+}
+
+/// チャンクの集まりに対する光の幅優先探索
+///
+/// 光は不透明でないブロックを一つ進む毎に1ずつ弱まる。ただし最大の
+/// 天空光は真下へは弱まらずに届く。含まれないチャンクへは伝えない。
+/// 上のチャンクが含まれない列は、生成器の地表より上にあれば空に
+/// 開けているものとして扱う。生成器が無いか高さマップを持たない
+/// 場合は全て開けているものとする。
+pub(super) struct Lighting<'a> {
+  map: &'a mut ChunkMap,
+  registry: &'a BlockRegistry,
+  generator: Option<&'a dyn TerrainGenerator>,
+  increase: VecDeque<(BlockPos, u8)>,
+  decrease: VecDeque<(BlockPos, u8)>,
+  changes: LightChanges,
+}
+impl<'a> Lighting<'a> {
+  pub fn new(
+    map: &'a mut ChunkMap,
+    registry: &'a BlockRegistry,
+    generator: Option<&'a dyn TerrainGenerator>,
+  ) -> Self {
+    Self {
+      map,
+      registry,
+      generator,
+      increase: VecDeque::new(),
+      decrease: VecDeque::new(),
+      changes: LightChanges::default(),
+    }
+  }
+
+  /// 光量が変わったチャンクを返す
+  pub fn into_changes(self) -> LightChanges {
+    self.changes
+  }
+
+  /// 読み込まれていない場合は`None`を返す
+  #[inline]
+  fn get(
+    &self,
+    pos: &BlockPos,
+    channel: LightChannel,
+  ) -> Option<u8> {
+    self
+      .map
+      .get(&pos.chunk_pos())
+      .map(|chunk| chunk.light(pos).get(channel))
+  }
+
+  #[inline]
+  fn set(
+    &mut self,
+    pos: &BlockPos,
+    channel: LightChannel,
+    level: u8,
+  ) {
+    let chunk_pos = pos.chunk_pos();
+    if let Some(chunk) = self.map.get_mut(&chunk_pos) {
+      let light = chunk.light(pos);
+      let current = light.get(channel);
+      if current != level {
+        chunk
+          .set_light(pos, light.with(channel, level));
+        self
+          .changes
+          .lit
+          .insert(chunk_pos);
+        if level < current {
+          self
+            .changes
+            .darkened
+            .insert(chunk_pos);
+        }
+      }
+    }
+  }
+
+  #[inline]
+  fn is_opaque(&self, pos: &BlockPos) -> bool {
+    self
+      .map
+      .get(&pos.chunk_pos())
+      .is_some_and(|chunk| {
+        self
+          .registry
+          .is_opaque(chunk.get(pos))
+      })
+  }
+
+  /// ブロックが発する光量
+  #[inline]
+  fn emission(
+    &self,
+    pos: &BlockPos,
+    channel: LightChannel,
+  ) -> u8 {
+    match channel {
+      LightChannel::Sky => 0,
+      LightChannel::Block => self
+        .map
+        .get(&pos.chunk_pos())
+        .map_or(0, |chunk| {
+          self
+            .registry
+            .light_emission(chunk.get(pos))
+        }),
+    }
+  }
+
+  /// 上のチャンクが読み込まれておらず、地表より上にあって
+  /// 空に開けているか
+  fn is_open_sky(&self, pos: &BlockPos) -> bool {
+    let above = pos.neighbor(TileFace::TOP);
+    !self
+      .map
+      .contains_key(&above.chunk_pos())
+      && self
+        .generator
+        .and_then(|generator| {
+          generator
+            .surface_height(pos.get_x(), pos.get_y())
+        })
+        .is_none_or(|height| height < pos.get_z())
+  }
+
+  /// `face`方向の隣へ伝わる光量
+  #[inline]
+  fn spread(
+    channel: LightChannel,
+    level: u8,
+    face: TileFace,
+  ) -> u8 {
+    if channel == LightChannel::Sky
+      && face == TileFace::BTM
+      && level == MAX_LIGHT
+    {
+      MAX_LIGHT
+    } else {
+      level.saturating_sub(1)
+    }
+  }
+
+  /// 増加の待ち行列から光を広げる
+  fn propagate_increase(
+    &mut self,
+    channel: LightChannel,
+  ) {
+    while let Some((pos, _)) = self.increase.pop_front()
+    {
+      let Some(level) = self.get(&pos, channel) else {
+        continue;
+      };
+      if level == 0 {
+        continue;
+      }
+      for face in TileFace::ALL {
+        let neighbor = pos.neighbor(face);
+        let Some(current) =
+          self.get(&neighbor, channel)
+        else {
+          continue;
+        };
+        if self.is_opaque(&neighbor) {
+          continue;
+        }
+        let next = Self::spread(channel, level, face);
+        if current < next {
+          self.set(&neighbor, channel, next);
+          self
+            .increase
+            .push_back((neighbor, next));
+        }
+      }
+    }
+  }
+
+  /// 減少の待ち行列から光を取り除き、残る光を増加の待ち行列へ積む
+  fn propagate_decrease(
+    &mut self,
+    channel: LightChannel,
+  ) {
+    while let Some((pos, level)) =
+      self.decrease.pop_front()
+    {
+      for face in TileFace::ALL {
+        let neighbor = pos.neighbor(face);
+        let Some(current) =
+          self.get(&neighbor, channel)
+        else {
+          continue;
+        };
+        if current == 0 {
+          continue;
+        }
+        let lit_by_pos = current < level
+          || (channel == LightChannel::Sky
+            && face == TileFace::BTM
+            && level == MAX_LIGHT
+            && current == MAX_LIGHT);
+        if lit_by_pos {
+          self.set(&neighbor, channel, 0);
+          self
+            .decrease
+            .push_back((neighbor, current));
+          let emission =
+            self.emission(&neighbor, channel);
+          if 0 < emission {
+            self.set(&neighbor, channel, emission);
+            self
+              .increase
+              .push_back((neighbor, emission));
+          }
+        } else {
+          self
+            .increase
+            .push_back((neighbor, current));
+        }
+      }
+    }
+  }
+
+  /// ブロックの変更に合わせて周囲の光を計算し直す
+  ///
+  /// ブロックの光を取り除いてから、発光と空、隣接するブロックの光から
+  /// 広げ直す。
+  pub fn update_block(&mut self, pos: &BlockPos) {
+    for channel in LightChannel::ALL {
+      let Some(level) = self.get(pos, channel) else {
+        continue;
+      };
+      if 0 < level {
+        self.set(pos, channel, 0);
+        self
+          .decrease
+          .push_back((*pos, level));
+      }
+      self.propagate_decrease(channel);
+      let emission = self.emission(pos, channel);
+      if 0 < emission {
+        self.set(pos, channel, emission);
+        self
+          .increase
+          .push_back((*pos, emission));
+      }
+      if !self.is_opaque(pos) {
+        if channel == LightChannel::Sky
+          && self.is_open_sky(pos)
+        {
+          self.set(pos, channel, MAX_LIGHT);
+          self
+            .increase
+            .push_back((*pos, MAX_LIGHT));
+        }
+        for face in TileFace::ALL {
+          let neighbor = pos.neighbor(face);
+          if let Some(level) =
+            self.get(&neighbor, channel)
+            && 0 < level
+          {
+            self
+              .increase
+              .push_back((neighbor, level));
+          }
+        }
+      }
+      self.propagate_increase(channel);
+    }
+  }
+
+  /// 別に計算したチャンクの光`light`を反映する
+  ///
+  /// 弱まった光を周囲から取り除いてからチャンクの光を置き、隣接する
+  /// チャンクとの境界を越えて双方向に広げる。下のチャンクが空に開けて
+  /// いるものとして受けていた天空光も取り除く。
+  pub fn merge_chunk(
+    &mut self,
+    chunk_pos: &BlockPos,
+    light: &[u8; CHUNK_VOLUME],
+  ) {
+    let range = BlockRange::chunk(chunk_pos);
+    let bottom = range.min.get_z();
+    let level_at = |pos: &BlockPos, channel| {
+      Light::from_bits(
+        light[pos.chunk_index() as usize],
+      )
+      .get(channel)
+    };
+    for channel in LightChannel::ALL {
+      for pos in range {
+        let Some(old) = self.get(&pos, channel) else {
+          return;
+        };
+        let new = level_at(&pos, channel);
+        if new < old {
+          self.set(&pos, channel, 0);
+          self
+            .decrease
+            .push_back((pos, old));
+        }
+        let below = pos.neighbor(TileFace::BTM);
+        if channel == LightChannel::Sky
+          && pos.get_z() == bottom
+          && new != MAX_LIGHT
+          && self.get(&below, channel)
+            == Some(MAX_LIGHT)
+        {
+          self.set(&below, channel, 0);
+          self
+            .decrease
+            .push_back((below, MAX_LIGHT));
+        }
+      }
+      self.propagate_decrease(channel);
+      for pos in range {
+        let new = level_at(&pos, channel);
+        if self
+          .get(&pos, channel)
+          .is_some_and(|current| current < new)
+        {
+          self.set(&pos, channel, new);
+        }
+        if let Some(level) = self.get(&pos, channel)
+          && 0 < level
+        {
+          self
+            .increase
+            .push_back((pos, level));
+        }
+        for face in TileFace::ALL {
+          let neighbor = pos.neighbor(face);
+          if !range.contains(&neighbor)
+            && let Some(level) =
+              self.get(&neighbor, channel)
+            && 0 < level
+          {
+            self
+              .increase
+              .push_back((neighbor, level));
+          }
+        }
+      }
+      self.propagate_increase(channel);
+    }
+  }
+
+  /// チャンクの光を計算する
+  ///
+  /// チャンク内の発光ブロックと空に開けた列、隣接するチャンクの境界の
+  /// 光から広げ、下のチャンクが空に開けているものとして受けていた
+  /// 天空光を取り除く。
+  pub fn light_chunk(&mut self, chunk_pos: &BlockPos) {
+    let range = BlockRange::chunk(chunk_pos);
+    let top = range.max.get_z() - 1;
+    let bottom = range.min.get_z();
+    for channel in LightChannel::ALL {
+      for pos in range {
+        let level = match channel {
+          LightChannel::Block => {
+            self.emission(&pos, channel)
+          }
+          LightChannel::Sky
+            if pos.get_z() == top
+              && self.is_open_sky(&pos)
+              && !self.is_opaque(&pos) =>
+          {
+            MAX_LIGHT
+          }
+          LightChannel::Sky => 0,
+        };
+        if 0 < level {
+          self.set(&pos, channel, level);
+          self
+            .increase
+            .push_back((pos, level));
+        }
+        for face in TileFace::ALL {
+          let neighbor = pos.neighbor(face);
+          if !range.contains(&neighbor)
+            && let Some(level) =
+              self.get(&neighbor, channel)
+            && 0 < level
+          {
+            self
+              .increase
+              .push_back((neighbor, level));
+          }
+        }
+      }
+      self.propagate_increase(channel);
+      if channel == LightChannel::Sky {
+        for pos in BlockRange::new(
+          BlockPos::new(
+            range.min.get_x(),
+            range.min.get_y(),
+            bottom,
+          ),
+          BlockPos::new(
+            range.max.get_x(),
+            range.max.get_y(),
+            bottom + 1,
+          ),
+        ) {
+          let below = pos.neighbor(TileFace::BTM);
+          if self.get(&below, channel)
+            == Some(MAX_LIGHT)
+            && self.get(&pos, channel)
+              != Some(MAX_LIGHT)
+          {
+            self.set(&below, channel, 0);
+            self
+              .decrease
+              .push_back((below, MAX_LIGHT));
+          }
+        }
+        self.propagate_decrease(channel);
+        self.propagate_increase(channel);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::block::{BlockId, AIR};
+  use crate::world::change::{ChangeCause, DirtyFlags};
+  use crate::world::{Chunk, World};
+
+  /// 地表の高さのみを持つ生成器
+  struct Surface(i64);
+  impl TerrainGenerator for Surface {
+    fn block_at(&self, _pos: &BlockPos) -> BlockId {
+      AIR
+    }
+
+    fn surface_height(
+      &self,
+      _x: i64,
+      _y: i64,
+    ) -> Option<i64> {
+      Some(self.0)
+    }
+  }
+
+  #[test]
+  fn cave_below_surface_is_dark() {
+    let cave = BlockPos::new(0, 0, -2);
+    let open = BlockPos::new(0, 0, 5);
+    let mut world = World::new();
+    world.set_generator(Arc::new(Surface(64)));
+    for chunk_pos in [cave, open] {
+      world.spawn_chunk(chunk_pos, Chunk::empty_chunk);
+    }
+    world.update_light(None);
+    assert_eq!(
+      world
+        .light_at(&BlockPos::new(5, 5, -20))
+        .sky(),
+      0
+    );
+    assert_eq!(
+      world
+        .light_at(&BlockPos::new(5, 5, 90))
+        .sky(),
+      MAX_LIGHT
+    );
+
+    let mut world = World::new();
+    world.spawn_chunk(cave, Chunk::empty_chunk);
+    world.update_light(None);
+    assert_eq!(
+      world
+        .light_at(&BlockPos::new(5, 5, -20))
+        .sky(),
+      MAX_LIGHT
+    );
+  }
+
+  /// 空のチャンクを並べて光を計算したワールド
+  fn lit_world(chunks: &[BlockPos]) -> World {
+    let mut world = World::new();
+    for chunk_pos in chunks {
+      world.spawn_chunk(*chunk_pos, Chunk::empty_chunk);
+    }
+    world.update_light(None);
+    world
+  }
+
+  /// 同じブロックで光を一から計算したワールドと光が一致するか
+  fn assert_relit(world: &World) {
+    let mut fresh = World::new();
+    for chunk_pos in world.chunk_positions() {
+      let mut chunk = world
+        .chunk(chunk_pos)
+        .unwrap()
+        .duplicate();
+      chunk.light.fill(0);
+      fresh.spawn_chunk(*chunk_pos, || chunk);
+    }
+    fresh.update_light(None);
+    for chunk_pos in world.chunk_positions() {
+      for pos in BlockRange::chunk(chunk_pos) {
+        assert_eq!(
+          world.light_at(&pos),
+          fresh.light_at(&pos),
+          "{pos:?}"
+        );
+      }
+    }
+  }
+
+  fn id(world: &World, name: &str) -> BlockId {
+    world
+      .registry()
+      .id_of(name)
+      .unwrap()
+  }
+
+  #[test]
+  fn opaque_block_under_open_sky() {
+    let chunks = [
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(1, 0, 0),
+      BlockPos::new(0, 0, 1),
+      BlockPos::new(1, 0, 1),
+    ];
+    let mut world = lit_world(&chunks);
+    let stone = id(&world, "stone");
+    let below = BlockPos::new(5, 5, 3);
+    assert_eq!(
+      world.light_at(&below).sky(),
+      MAX_LIGHT
+    );
+    // 空に開けた最上段と、その下の段で置いて取り除く
+    for z in [31, 20] {
+      let pos = BlockPos::new(5, 5, z);
+      world.set_block(&pos, stone, ChangeCause::Player);
+      assert_eq!(world.light_at(&pos).sky(), 0);
+      assert_eq!(
+        world.light_at(&below).sky(),
+        MAX_LIGHT - 1
+      );
+      assert_relit(&world);
+      world.set_block(&pos, AIR, ChangeCause::Player);
+      assert_eq!(
+        world.light_at(&pos).sky(),
+        MAX_LIGHT
+      );
+      assert_eq!(
+        world.light_at(&below).sky(),
+        MAX_LIGHT
+      );
+      assert_relit(&world);
+    }
+    // その場で計算し、光の更新は残らない
+    assert_eq!(
+      world
+        .dirty_chunks(DirtyFlags::LIGHT)
+        .count(),
+      0
+    );
+  }
+
+  #[test]
+  fn removing_a_light_source() {
+    let chunks = [
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(1, 0, 0),
+    ];
+    let mut world = lit_world(&chunks);
+    let glowstone = id(&world, "glowstone");
+    let lamp = BlockPos::new(14, 5, 5);
+    let across = BlockPos::new(20, 5, 5);
+    world.set_block(
+      &lamp,
+      glowstone,
+      ChangeCause::Player,
+    );
+    assert_eq!(
+      world.light_at(&across).block(),
+      9
+    );
+    assert_relit(&world);
+    world.set_block(&lamp, AIR, ChangeCause::Player);
+    for chunk_pos in &chunks {
+      for pos in BlockRange::chunk(chunk_pos) {
+        assert_eq!(world.light_at(&pos).block(), 0);
+      }
+    }
+    assert_relit(&world);
+  }
+
+  #[test]
+  fn darkening_across_chunk_border() {
+    let chunks = [
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(1, 0, 0),
+      BlockPos::new(0, 0, 1),
+      BlockPos::new(1, 0, 1),
+    ];
+    let mut world = lit_world(&chunks);
+    let stone = id(&world, "stone");
+    // 境界を跨ぐ屋根
+    for pos in BlockRange::new(
+      BlockPos::new(8, 0, 24),
+      BlockPos::new(24, 16, 25),
+    ) {
+      world.set_block(&pos, stone, ChangeCause::Player);
+    }
+    let under = BlockPos::new(16, 8, 10);
+    assert!(world.light_at(&under).sky() < MAX_LIGHT);
+    assert_eq!(
+      world
+        .light_at(&BlockPos::new(26, 8, 10))
+        .sky(),
+      MAX_LIGHT
+    );
+    assert_relit(&world);
+    // 屋根の一部を外すと境界の向こうまで明るくなる
+    world.set_block(
+      &BlockPos::new(15, 8, 24),
+      AIR,
+      ChangeCause::Player,
+    );
+    assert_eq!(
+      world
+        .light_at(&BlockPos::new(15, 8, 10))
+        .sky(),
+      MAX_LIGHT
+    );
+    assert_relit(&world);
+  }
+
+  #[test]
+  fn jobs_exchange_light_across_borders() {
+    let west = BlockPos::new(0, 0, 0);
+    let east = BlockPos::new(1, 0, 0);
+    let mut world = World::new();
+    let glowstone = id(&world, "glowstone");
+    for chunk_pos in [west, east] {
+      world.spawn_chunk(chunk_pos, || {
+        Chunk::new(&chunk_pos, |pos| {
+          if pos == BlockPos::new(15, 5, 5) {
+            glowstone
+          } else {
+            AIR
+          }
+        })
+      });
+    }
+    // 両方の複製を先に作り、並行したジョブのように反映する
+    let lights: Vec<_> = [west, east]
+      .iter()
+      .map(|chunk_pos| {
+        world
+          .snapshot(chunk_pos)
+          .unwrap()
+          .light()
+      })
+      .collect();
+    world.take_dirty(DirtyFlags::LIGHT);
+    for (chunk_pos, light) in
+      [west, east].iter().zip(lights)
+    {
+      assert!(world.apply_light(chunk_pos, light));
+    }
+    assert_eq!(
+      world
+        .light_at(&BlockPos::new(20, 5, 5))
+        .block(),
+      10
+    );
+    assert_relit(&world);
+
+    // 上に読み込んだチャンクの屋根が下の天空光を遮る
+    let stone = id(&world, "stone");
+    let above = BlockPos::new(0, 0, 1);
+    world.spawn_chunk(above, || {
+      Chunk::new(&above, |pos| {
+        if pos.get_z() == 16 {
+          stone
+        } else {
+          AIR
+        }
+      })
+    });
+    let light = world
+      .snapshot(&above)
+      .unwrap()
+      .light();
+    world.take_dirty(DirtyFlags::LIGHT);
+    assert!(world.apply_light(&above, light));
+    assert!(
+      world
+        .light_at(&BlockPos::new(3, 8, 10))
+        .sky()
+        < MAX_LIGHT
+    );
+    assert_relit(&world);
+  }
+
+  #[test]
+  fn stale_light_is_rejected() {
+    let chunks = [
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(1, 0, 0),
+    ];
+    let mut world = lit_world(&chunks);
+    let glowstone = id(&world, "glowstone");
+    let lamp = BlockPos::new(18, 5, 5);
+    world.set_block(
+      &lamp,
+      glowstone,
+      ChangeCause::Player,
+    );
+    // 光源を取り除く前の複製から計算した光
+    let stale = world
+      .snapshot(&chunks[0])
+      .unwrap()
+      .light();
+    world.set_block(&lamp, AIR, ChangeCause::Player);
+    assert!(!world.apply_light(&chunks[0], stale));
+    assert!(world
+      .dirty_chunks(DirtyFlags::LIGHT)
+      .any(|chunk_pos| *chunk_pos == chunks[0]));
+    assert_eq!(
+      world
+        .light_at(&BlockPos::new(14, 5, 5))
+        .block(),
+      0
+    );
+    world.update_light(None);
+    assert_relit(&world);
+  }
+}
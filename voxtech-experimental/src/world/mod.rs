@@ -1,69 +1,1503 @@
 use std::collections::VecDeque;
+use std::sync::{mpsc, Arc};
 
-use hashbrown::HashMap;
+use hashbrown::HashSet;
 
-pub mod types;
+use crate::block::{BlockId, BlockRegistry, AIR};
+use crate::job::{JobKind, JobOutput, JobPool};
+use crate::types;
+
+pub mod change;
+pub mod chunk_map;
+pub mod entity;
+pub mod fluid;
+pub mod r#gen;
+pub mod palette;
+pub mod raycast;
+pub mod light;
+pub mod region;
+pub mod save;
+pub mod snapshot;
+pub mod stream;
+pub mod tick;
+pub mod tree64;
+
+use change::{BlockChange, ChangeCause, DirtyFlags};
+use chunk_map::ChunkMap;
+use entity::{Entities, Entity, EntityId, EntityKind, Motion};
+use fluid::FluidState;
+use light::{Light, LightChanges, Lighting};
+use r#gen::{
+  BiomeDef, BiomeRegistry, StructurePlacer, TerrainGenerator,
+};
+use palette::PalettedStorage;
+use raycast::{RaycastHit, VoxelRay};
+use region::{ChunkRecord, RegionError, RegionStorage};
+use snapshot::{ChunkLight, ChunkSnapshot};
+use tick::{TickHandler, TickScheduler};
+
+/// 取り出されずに保持するブロックの変更イベントの上限
+pub const MAX_PENDING_CHANGES: usize = 4096;
 
 /// Worldはプログラム上における空間インスタンスのバインダ
 /// World is the binder for dimension instances in the program.
 pub struct World {
-  map: HashMap<types::BlockPos, Chunk>,
+  map: ChunkMap,
+  registry: Arc<BlockRegistry>,
+  biomes: Arc<BiomeRegistry>,
+  storage: Option<RegionStorage>,
+  generator: Option<Arc<dyn TerrainGenerator>>,
+  structures: Option<Arc<StructurePlacer>>,
+  /// 更新フラグが立っているチャンク
+  dirty: HashSet<types::BlockPos>,
+  /// 取り出されていないブロックの変更イベント
+  /// `MAX_PENDING_CHANGES`件を超えると古いものから捨てる
+  changes: VecDeque<BlockChange>,
+  subscribers: Vec<mpsc::Sender<BlockChange>>,
+  ticks: TickScheduler,
+  /// ブロック番号毎の更新処理
+  tick_handlers: Vec<Option<Arc<dyn TickHandler>>>,
+  entities: Entities,
+}
+impl Default for World {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 impl World {
+  pub fn new() -> Self {
+    Self::with_registry(BlockRegistry::shared_builtin())
+  }
+
+  pub fn with_registry(
+    registry: Arc<BlockRegistry>,
+  ) -> Self {
+    Self::with_registries(
+      registry,
+      BiomeRegistry::shared_builtin(),
+    )
+  }
+
+  pub fn with_registries(
+    registry: Arc<BlockRegistry>,
+    biomes: Arc<BiomeRegistry>,
+  ) -> Self {
+    Self {
+      map: ChunkMap::new(),
+      registry,
+      biomes,
+      storage: None,
+      generator: None,
+      structures: None,
+      dirty: HashSet::new(),
+      changes: VecDeque::new(),
+      subscribers: Vec::new(),
+      ticks: TickScheduler::new(0),
+      tick_handlers: Vec::new(),
+      entities: Entities::new(),
+    }
+  }
+
+  /// チャンクの保存先を設定する
+  pub fn set_storage(
+    &mut self,
+    storage: RegionStorage,
+  ) {
+    self.storage = Some(storage);
+  }
+
+  #[inline]
+  pub fn storage(&self) -> Option<&RegionStorage> {
+    self.storage.as_ref()
+  }
+
+  /// 地形の生成器を設定する
+  pub fn set_generator(
+    &mut self,
+    generator: Arc<dyn TerrainGenerator>,
+  ) {
+    self.generator = Some(generator);
+  }
+
+  #[inline]
+  pub fn generator(
+    &self,
+  ) -> Option<&Arc<dyn TerrainGenerator>> {
+    self.generator.as_ref()
+  }
+
+  /// 構造物の配置段階を設定する
+  pub fn set_structures(
+    &mut self,
+    structures: StructurePlacer,
+  ) {
+    self.structures = Some(Arc::new(structures));
+  }
+
+  #[inline]
+  pub fn structures(
+    &self,
+  ) -> Option<&Arc<StructurePlacer>> {
+    self.structures.as_ref()
+  }
+
+  /// ブロックの登録簿
+  #[inline]
+  pub fn registry(&self) -> &Arc<BlockRegistry> {
+    &self.registry
+  }
+
+  /// バイオームの登録簿
+  #[inline]
+  pub fn biomes(&self) -> &Arc<BiomeRegistry> {
+    &self.biomes
+  }
+
+  /// 座標の列のバイオーム
+  /// 生成器がバイオームを持たない場合は`None`を返す
+  pub fn biome_at(
+    &self,
+    pos: &types::BlockPos,
+  ) -> Option<&BiomeDef> {
+    let id = self
+      .generator
+      .as_ref()?
+      .biome_at(pos.get_x(), pos.get_y())?;
+    self.biomes.get(id)
+  }
+
+  /// ゲーム内時間と予約更新
+  #[inline]
+  pub fn ticks(&self) -> &TickScheduler {
+    &self.ticks
+  }
+
+  #[inline]
+  pub fn ticks_mut(&mut self) -> &mut TickScheduler {
+    &mut self.ticks
+  }
+
+  /// ゲーム内時間(ティック)
+  #[inline]
+  pub fn time(&self) -> u64 {
+    self.ticks.time()
+  }
+
+  /// ブロックの更新処理を設定する
+  pub fn set_tick_handler(
+    &mut self,
+    block: BlockId,
+    handler: Arc<dyn TickHandler>,
+  ) {
+    let index = block as usize;
+    if self.tick_handlers.len() <= index {
+      self
+        .tick_handlers
+        .resize(index + 1, None);
+    }
+    self.tick_handlers[index] = Some(handler);
+  }
+
+  /// 組み込みの更新処理を登録する
+  pub fn register_default_tick_handlers(&mut self) {
+    for (block, handler) in
+      tick::default_handlers(&self.registry)
+    {
+      self.set_tick_handler(block, handler);
+    }
+  }
+
+  #[inline]
+  fn tick_handler(
+    &self,
+    block: BlockId,
+  ) -> Option<Arc<dyn TickHandler>> {
+    self
+      .tick_handlers
+      .get(block as usize)?
+      .clone()
+  }
+
+  /// ブロック自身と隣接するブロックの更新処理に変化を知らせる
+  fn notify_updated(&mut self, pos: &types::BlockPos) {
+    let neighbors =
+      types::TileFace::ALL.map(|face| pos.neighbor(face));
+    for pos in std::iter::once(*pos).chain(neighbors) {
+      let block = self.get_block(&pos);
+      if let Some(handler) = self.tick_handler(block) {
+        handler.block_updated(self, &pos, block);
+      }
+    }
+  }
+
+  /// `delay`ティック後のブロックの更新を予約する
+  /// チャンクが読み込まれていない場合や予約済みの場合は`false`を返す
+  ///
+  /// 予約はチャンクと共に保存されるため、チャンクに保存フラグを立てる。
+  pub fn schedule_tick(
+    &mut self,
+    pos: &types::BlockPos,
+    block: BlockId,
+    delay: u64,
+    priority: i32,
+  ) -> bool {
+    let chunk_pos = pos.chunk_pos();
+    if !self.map.contains_key(&chunk_pos)
+      || !self
+        .ticks
+        .schedule(*pos, block, delay, priority)
+    {
+      return false;
+    }
+    self.mark_dirty(&chunk_pos, DirtyFlags::SAVE);
+    true
+  }
+
+  /// エンティティの一覧
+  #[inline]
+  pub fn entities(&self) -> &Entities {
+    &self.entities
+  }
+
+  /// エンティティを出現させる
+  pub fn spawn_entity(&mut self, entity: Entity) -> EntityId {
+    self.entities.spawn(entity)
+  }
+
+  /// エンティティを一ティック分動かす
+  ///
+  /// 着地した落下中のブロックは空気の位置であればブロックに戻り、
+  /// 固体でないブロックに重なった場合はアイテムになる。同時に着地した
+  /// ブロックに重なった場合はその上に積み重なり、積み重なる先の
+  /// チャンクが読み込まれていなければ読み込まれるまで待つ。
+  /// 古いアイテムは消える。
+  fn step_entities(&mut self) {
+    for id in self.entities.ids() {
+      let Some(mut entity) = self.entities.get(id).copied()
+      else {
+        continue;
+      };
+      entity.age += 1;
+      let motion = entity.step(self);
+      match (entity.kind, motion) {
+        (
+          EntityKind::FallingBlock(block),
+          Motion::Landed(pos),
+        ) => {
+          let mut pos = pos;
+          while self
+            .registry
+            .is_solid(self.get_block(&pos))
+          {
+            pos = pos.neighbor(types::TileFace::TOP);
+          }
+          if !self.map.contains_key(&pos.chunk_pos()) {
+            entity.position[2] = pos.get_z() as f64;
+            if let Some(slot) =
+              self.entities.get_mut(id)
+            {
+              *slot = entity;
+            }
+            continue;
+          }
+          self.entities.remove(id);
+          if self.get_block(&pos) == AIR {
+            self.set_block(
+              &pos,
+              block,
+              ChangeCause::Gravity,
+            );
+          } else {
+            self.spawn_entity(Entity::new(
+              EntityKind::Item(block),
+              entity.position,
+            ));
+          }
+        }
+        (EntityKind::Item(_), _)
+          if entity::ITEM_LIFETIME <= entity.age =>
+        {
+          self.entities.remove(id);
+        }
+        _ => {
+          if let Some(slot) = self.entities.get_mut(id) {
+            *slot = entity;
+          }
+        }
+      }
+    }
+  }
+
+  /// ゲーム内時間を一ティック進める
+  ///
+  /// 予定時刻を迎えた予約更新を実行し、読み込まれている空でない
+  /// チャンクから無作為に選んだブロックを更新してから、エンティティを
+  /// 動かす。予約後にブロックが置き換えられていた場合は実行しない。
+  /// 無作為更新の座標はシードと時間から決まり、チャンクは座標順に
+  /// 処理するため再現性がある。
+  pub fn tick(&mut self) {
+    for tick in self.ticks.pop_due() {
+      if self.get_block(&tick.pos) != tick.block {
+        continue;
+      }
+      if let Some(handler) = self.tick_handler(tick.block)
+      {
+        handler.scheduled_tick(self, &tick.pos, tick.block);
+      }
+    }
+    if self.tick_handlers.iter().any(Option::is_some) {
+      let mut chunks: Vec<types::BlockPos> = self
+        .map
+        .iter()
+        .filter(|(_, chunk)| !chunk.is_empty())
+        .map(|(chunk_pos, _)| *chunk_pos)
+        .collect();
+      chunks.sort_by_key(types::BlockPos::as_array);
+      for chunk_pos in chunks {
+        for pos in self.ticks.random_positions(&chunk_pos) {
+          let block = self.get_block(&pos);
+          if let Some(handler) = self.tick_handler(block) {
+            handler.random_tick(self, &pos, block);
+          }
+        }
+      }
+    }
+    self.step_entities();
+    self.ticks.advance();
+  }
+
   pub fn spawn_chunk(
     &mut self,
     chunk_pos: types::BlockPos,
     f: impl FnOnce() -> Chunk,
   ) {
+    self.insert_chunk(
+      &chunk_pos,
+      f(),
+      DirtyFlags::ALL,
+    );
+  }
+
+  /// チャンクを挿入し、チャンクと隣接するチャンクに更新フラグを立てる
+  /// 隣接するチャンクとの光のやり取りは挿入したチャンクの光を反映する
+  /// 時に行う。格納できる範囲外のチャンクは挿入しない
+  fn insert_chunk(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+    mut chunk: Chunk,
+    flags: DirtyFlags,
+  ) {
+    if !ChunkMap::in_bounds(chunk_pos) {
+      return;
+    }
+    // 置き換える場合は光を引き継ぎ、周囲へ広げた光を後で取り除く
+    if let Some(old) = self.map.get(chunk_pos) {
+      chunk.light = old.light.clone();
+      chunk.light_revision = old.light_revision + 1;
+    }
+    self
+      .map
+      .insert(*chunk_pos, chunk);
+    self.mark_dirty(chunk_pos, flags);
+    for face in types::TileFace::ALL {
+      self.mark_dirty(
+        &(*chunk_pos + face.offset()),
+        DirtyFlags::MESH,
+      );
+    }
+  }
+
+  /// 読み込まれているチャンクに更新フラグを立てる
+  pub fn mark_dirty(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+    flags: DirtyFlags,
+  ) {
+    if let Some(chunk) = self.map.get_mut(chunk_pos) {
+      chunk.dirty |= flags;
+      self.dirty.insert(*chunk_pos);
+    }
+  }
+
+  /// `flags`のいずれかが立っているチャンクを走査する
+  pub fn dirty_chunks(
+    &self,
+    flags: DirtyFlags,
+  ) -> impl Iterator<Item = &types::BlockPos> {
+    self.dirty.iter().filter(move |chunk_pos| {
+      self.map.get(chunk_pos).is_some_and(|chunk| {
+        chunk.dirty.intersects(flags)
+      })
+    })
+  }
+
+  /// `flags`のいずれかが立っているチャンクを取り出し、そのフラグを下ろす
+  pub fn take_dirty(
+    &mut self,
+    flags: DirtyFlags,
+  ) -> Vec<types::BlockPos> {
+    let mut taken = Vec::new();
+    self.dirty.retain(|chunk_pos| {
+      let Some(chunk) = self.map.get_mut(chunk_pos) else {
+        return false;
+      };
+      if chunk.dirty.intersects(flags) {
+        chunk.dirty.remove(flags);
+        taken.push(*chunk_pos);
+      }
+      !chunk.dirty.is_empty()
+    });
+    taken
+  }
+
+  /// 取り出されていないブロックの変更イベントを全て取り出す
+  ///
+  /// 最新の`MAX_PENDING_CHANGES`件のみを保持するため、全ての変更を
+  /// 受け取る場合は`subscribe`を使う。
+  pub fn drain_changes(
+    &mut self,
+  ) -> impl Iterator<Item = BlockChange> + '_ {
+    self.changes.drain(..)
+  }
+
+  /// ブロックの変更イベントを受け取るチャンネルを作る
+  /// 受信側が破棄されると自動的に登録が解除される
+  pub fn subscribe(
+    &mut self,
+  ) -> mpsc::Receiver<BlockChange> {
+    let (sender, receiver) = mpsc::channel();
+    self.subscribers.push(sender);
+    receiver
+  }
+
+  /// チャンクを取得する
+  #[inline]
+  pub fn chunk(
+    &self,
+    chunk_pos: &types::BlockPos,
+  ) -> Option<&Chunk> {
+    self.map.get(chunk_pos)
+  }
+
+  /// 読み込まれているチャンクの位置を走査する
+  pub fn chunk_positions(
+    &self,
+  ) -> impl Iterator<Item = &types::BlockPos> {
+    self.map.keys()
+  }
+
+  /// 読み込まれているチャンク数
+  #[inline]
+  pub fn chunk_count(&self) -> usize {
+    self.map.len()
+  }
+
+  /// 読み込まれているチャンクの使用メモリのバイト数
+  pub fn memory_usage(&self) -> usize {
+    self
+      .map
+      .values()
+      .map(Chunk::memory_usage)
+      .sum()
+  }
+
+  /// ワールド座標のブロックを取得する
+  /// 読み込まれていないチャンクは空として扱う
+  pub fn get_block(
+    &self,
+    pos: &types::BlockPos,
+  ) -> BlockId {
+    self
+      .map
+      .get(&pos.chunk_pos())
+      .map_or(AIR, |chunk| chunk.get(pos))
+  }
+
+  /// ワールド座標にブロックを設置し、元のブロックを返す
+  /// チャンクが読み込まれていない場合は何もせず`None`を返す
+  ///
+  /// ブロックが変わった場合はチャンクに更新フラグを立て、チャンクの
+  /// 端であれば隣接するチャンクにメッシュの更新フラグを立てて、
+  /// 変更イベントを発行する。光は不透明さか発光量が変わった場合のみ
+  /// その場で周囲を計算し直し、光が変わったチャンクにメッシュの
+  /// 更新フラグを立てる。
+  pub fn set_block(
+    &mut self,
+    pos: &types::BlockPos,
+    block: BlockId,
+    cause: ChangeCause,
+  ) -> Option<BlockId> {
+    let chunk_pos = pos.chunk_pos();
+    let chunk = self.map.get_mut(&chunk_pos)?;
+    let was_empty = chunk.is_empty();
+    let old = chunk.set(pos, block);
+    if old == block {
+      return Some(old);
+    }
+    if was_empty != chunk.is_empty() {
+      self.map.refresh(&chunk_pos);
+    }
+    self.mark_dirty(
+      &chunk_pos,
+      DirtyFlags::MESH | DirtyFlags::SAVE,
+    );
+    for face in types::TileFace::ALL {
+      let neighbor = pos.neighbor(face).chunk_pos();
+      if neighbor != chunk_pos {
+        self.mark_dirty(&neighbor, DirtyFlags::MESH);
+      }
+    }
+    if self.registry.is_opaque(old)
+      != self.registry.is_opaque(block)
+      || self.registry.light_emission(old)
+        != self.registry.light_emission(block)
+    {
+      let mut lighting = Lighting::new(
+        &mut self.map,
+        &self.registry,
+        self.generator.as_deref(),
+      );
+      lighting.update_block(pos);
+      let mut changes = lighting.into_changes();
+      // 複製から計算中の光はブロックの変更を含まない
+      changes.darkened.insert(chunk_pos);
+      self.light_changed(changes);
+    }
+    let change = BlockChange {
+      pos: *pos,
+      old,
+      new: block,
+      cause,
+    };
+    self
+      .subscribers
+      .retain(|sender| sender.send(change).is_ok());
+    if MAX_PENDING_CHANGES <= self.changes.len() {
+      self.changes.pop_front();
+    }
+    self.changes.push_back(change);
+    self.notify_updated(pos);
+    Some(old)
+  }
+
+  /// 始点`origin`から方向`direction`へ`max_dist`までの光線が最初に
+  /// 当たる、空気と流体以外のブロックを求める
+  pub fn raycast(
+    &self,
+    origin: nalgebra::Point3<f64>,
+    direction: nalgebra::Vector3<f64>,
+    max_dist: f64,
+  ) -> Option<RaycastHit> {
+    self.raycast_by(origin, direction, max_dist, |block| {
+      block != AIR && self.registry.fluid(block).is_none()
+    })
+  }
+
+  /// 光線が最初に当たる、`hit`を満たすブロックを求める
+  ///
+  /// 空気は`hit`に関わらず当たらないものとして扱い、読み込まれて
+  /// いないチャンクと空のチャンクが続く領域、空のセルは一度に
+  /// 通過する。
+  /// 方向が零の場合は`None`を返す。
+  pub fn raycast_by(
+    &self,
+    origin: nalgebra::Point3<f64>,
+    direction: nalgebra::Vector3<f64>,
+    max_dist: f64,
+    hit: impl Fn(BlockId) -> bool,
+  ) -> Option<RaycastHit> {
+    let mut ray = VoxelRay::new(&origin, &direction)?;
+    while ray.t() <= max_dist {
+      let pos = ray.pos();
+      let chunk_pos = pos.chunk_pos();
+      let Some(chunk) = self
+        .map
+        .get(&chunk_pos)
+        .filter(|chunk| !chunk.is_empty())
+      else {
+        ray.skip(&self.map.empty_region(&chunk_pos));
+        continue;
+      };
+      if chunk.is_cell_empty(pos.cell_index()) {
+        let min = pos.down_level(1).up_level(1);
+        ray.skip(&types::BlockRange::new(
+          min,
+          min + types::BlockDist::new(4, 4, 4),
+        ));
+        continue;
+      }
+      let block = chunk.get(&pos);
+      if block != AIR && hit(block) {
+        return Some(RaycastHit {
+          pos,
+          face: ray.face(),
+          point: ray.point(),
+          distance: ray.t(),
+        });
+      }
+      ray.advance();
+    }
+    None
+  }
+
+  /// ワールド座標の天空光とブロック光
+  /// 読み込まれていないチャンクは空に開けているものとして扱う
+  pub fn light_at(&self, pos: &types::BlockPos) -> Light {
+    self
+      .map
+      .get(&pos.chunk_pos())
+      .map_or(Light::SKY, |chunk| chunk.light(pos))
+  }
+
+  /// チャンクと隣接するチャンクを複製する
+  /// チャンクが読み込まれていない場合は`None`を返す
+  #[inline]
+  pub fn snapshot(
+    &self,
+    chunk_pos: &types::BlockPos,
+  ) -> Option<ChunkSnapshot> {
+    ChunkSnapshot::new(self, chunk_pos)
+  }
+
+  /// 光の更新フラグが立ったチャンクの光を計算し直す
+  ///
+  /// ワーカースレッド群が渡された場合はチャンクの複製を渡して計算を
+  /// 任せ、同じチャンクの古い計算は取り消す。`None`の場合はこの
+  /// スレッドで計算して反映する。
+  pub fn update_light(
+    &mut self,
+    jobs: Option<&mut JobPool<JobOutput>>,
+  ) {
+    match jobs {
+      Some(jobs) => {
+        for chunk_pos in
+          self.take_dirty(DirtyFlags::LIGHT)
+        {
+          let Some(snapshot) =
+            self.snapshot(&chunk_pos)
+          else {
+            continue;
+          };
+          jobs.cancel_chunk(
+            &chunk_pos,
+            Some(JobKind::Light),
+          );
+          jobs.submit(
+            JobKind::Light,
+            chunk_pos,
+            0,
+            move |_| {
+              Some(JobOutput::Lit(snapshot.light()))
+            },
+          );
+        }
+      }
+      None => loop {
+        let dirty = self.take_dirty(DirtyFlags::LIGHT);
+        if dirty.is_empty() {
+          break;
+        }
+        for chunk_pos in dirty {
+          if let Some(snapshot) =
+            self.snapshot(&chunk_pos)
+          {
+            self
+              .apply_light(&chunk_pos, snapshot.light());
+          }
+        }
+      },
+    }
+  }
+
+  /// 計算したチャンクの光を反映する
+  ///
+  /// 弱まった光を取り除いてから置き換え、隣接するチャンクとの境界を
+  /// 越えて光を広げる。光が変わったチャンクにはメッシュの更新フラグを
+  /// 立てる。チャンクが読み込まれていないか、再び光の更新フラグが
+  /// 立っていて計算し直す予定の場合は捨てて`false`を返す。複製した
+  /// 後に光が弱まっていた場合も捨て、計算し直すよう光の更新フラグを
+  /// 立てる。
+  pub fn apply_light(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+    light: ChunkLight,
+  ) -> bool {
+    let Some(chunk) = self.map.get(chunk_pos) else {
+      return false;
+    };
+    if chunk.dirty.contains(DirtyFlags::LIGHT) {
+      return false;
+    }
+    if chunk.light_revision != light.revision() {
+      self.mark_dirty(chunk_pos, DirtyFlags::LIGHT);
+      return false;
+    }
+    let mut lighting = Lighting::new(
+      &mut self.map,
+      &self.registry,
+      self.generator.as_deref(),
+    );
+    lighting.merge_chunk(chunk_pos, light.light());
+    let changes = lighting.into_changes();
+    self.light_changed(changes);
+    true
+  }
+
+  /// 光が変わったチャンクにメッシュの更新フラグを立てる
+  ///
+  /// 光が弱まったチャンクとそれを複製に含むチャンクは光の版を進め、
+  /// 計算中の古い光を捨てさせる。
+  fn light_changed(&mut self, changes: LightChanges) {
+    for chunk_pos in &changes.lit {
+      self.mark_dirty(chunk_pos, DirtyFlags::MESH);
+    }
+    for chunk_pos in &changes.darkened {
+      let faces = types::TileFace::ALL
+        .map(|face| *chunk_pos + face.offset());
+      for chunk_pos in
+        std::iter::once(*chunk_pos).chain(faces)
+      {
+        if let Some(chunk) =
+          self.map.get_mut(&chunk_pos)
+        {
+          chunk.light_revision += 1;
+        }
+      }
+    }
+  }
+
+  /// ワールド座標の流体の水位
+  /// 流体でないブロックの値は意味を持たない
+  pub fn fluid_state(
+    &self,
+    pos: &types::BlockPos,
+  ) -> FluidState {
     self
       .map
-      .entry(chunk_pos)
-      .insert(f());
+      .get(&pos.chunk_pos())
+      .map_or(FluidState::SOURCE, |chunk| {
+        chunk.fluid_state(pos)
+      })
+  }
+
+  /// ワールド座標の流体の水位を設定する
+  /// 変わった場合は更新フラグを立て、周囲の更新処理に知らせる
+  pub fn set_fluid_state(
+    &mut self,
+    pos: &types::BlockPos,
+    state: FluidState,
+  ) {
+    let chunk_pos = pos.chunk_pos();
+    let Some(chunk) = self.map.get_mut(&chunk_pos) else {
+      return;
+    };
+    if chunk.set_fluid_state(pos, state) == state {
+      return;
+    }
+    self.mark_dirty(
+      &chunk_pos,
+      DirtyFlags::MESH | DirtyFlags::SAVE,
+    );
+    self.notify_updated(pos);
+  }
+
+  /// 読み込まれているチャンクを保存する
+  /// チャンクが読み込まれていない場合は`false`を返す
+  pub fn save_chunk(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+  ) -> crate::StdResult<bool> {
+    let storage = self
+      .storage
+      .as_mut()
+      .ok_or(RegionError::NoStorage)?;
+    let Some(chunk) = self.map.get_mut(chunk_pos) else {
+      return Ok(false);
+    };
+    storage.save_chunk(
+      chunk_pos,
+      &ChunkRecord::from_chunk(
+        chunk,
+        self.ticks.chunk_records(chunk_pos),
+      ),
+    )?;
+    chunk.dirty.remove(DirtyFlags::SAVE);
+    Ok(true)
+  }
+
+  /// 保存されたチャンクを読み込み、読み込み済みのものを置き換える
+  /// 保存されていない場合は`false`を返す
+  pub fn load_chunk(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+  ) -> crate::StdResult<bool> {
+    let storage = self
+      .storage
+      .as_mut()
+      .ok_or(RegionError::NoStorage)?;
+    let Some(mut record) = storage.load_chunk(chunk_pos)?
+    else {
+      return Ok(false);
+    };
+    let ticks = std::mem::take(&mut record.ticks);
+    let chunk = record.into_chunk(chunk_pos)?;
+    self.ticks.remove_chunk(chunk_pos);
+    self.ticks.restore(ticks);
+    self.insert_chunk(
+      chunk_pos,
+      chunk,
+      DirtyFlags::MESH | DirtyFlags::LIGHT,
+    );
+    Ok(true)
+  }
+
+  /// 生成器でチャンクを生成し、構造物を書き込んで読み込み済みのものを置き換える
+  /// 生成器が設定されていない場合は`false`を返す
+  pub fn generate_chunk(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+  ) -> bool {
+    let Some(generator) = self.generator.as_ref() else {
+      return false;
+    };
+    let chunk = r#gen::generate_populated(
+      generator.as_ref(),
+      self.structures.as_deref(),
+      chunk_pos,
+    );
+    self.insert_generated_chunk(chunk_pos, chunk);
+    true
+  }
+
+  /// 別スレッドなどで`r#gen::generate_populated`により生成した
+  /// チャンクを挿入する
+  pub fn insert_generated_chunk(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+    chunk: Chunk,
+  ) {
+    self.insert_chunk(
+      chunk_pos,
+      chunk,
+      DirtyFlags::ALL,
+    );
+  }
+
+  /// 保存されたチャンクを読み込み、無ければ生成する
+  pub fn load_or_generate_chunk(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+  ) -> crate::StdResult<()> {
+    if self.storage.is_some() && self.load_chunk(chunk_pos)?
+    {
+      return Ok(());
+    }
+    if !self.generate_chunk(chunk_pos) {
+      self.insert_chunk(
+        chunk_pos,
+        Chunk::empty_chunk(),
+        DirtyFlags::ALL,
+      );
+    }
+    Ok(())
+  }
+
+  /// チャンクを解放する
+  /// 保存先があり未保存の変更がある場合は解放前に保存し、
+  /// 読み込まれていない場合は`false`を返す
+  pub fn unload_chunk(
+    &mut self,
+    chunk_pos: &types::BlockPos,
+  ) -> crate::StdResult<bool> {
+    let needs_save = self
+      .map
+      .get(chunk_pos)
+      .is_some_and(|chunk| {
+        chunk.dirty.contains(DirtyFlags::SAVE)
+      });
+    if self.storage.is_some() && needs_save {
+      self.save_chunk(chunk_pos)?;
+    }
+    self.dirty.remove(chunk_pos);
+    self.ticks.remove_chunk(chunk_pos);
+    Ok(self.map.remove(chunk_pos).is_some())
+  }
+
+  /// 未保存の変更がある全てのチャンクを保存する
+  pub fn save_all(&mut self) -> crate::StdResult<()> {
+    let storage = self
+      .storage
+      .as_mut()
+      .ok_or(RegionError::NoStorage)?;
+    for (chunk_pos, chunk) in self.map.iter_mut() {
+      if !chunk.dirty.contains(DirtyFlags::SAVE) {
+        continue;
+      }
+      storage.save_chunk(
+        chunk_pos,
+        &ChunkRecord::from_chunk(
+          chunk,
+          self.ticks.chunk_records(chunk_pos),
+        ),
+      )?;
+      chunk.dirty.remove(DirtyFlags::SAVE);
+    }
+    storage.flush()
   }
 }
 
+/// チャンク一辺のブロック数
+pub const CHUNK_SIZE: i64 = 16;
+/// チャンク内のブロック数
+pub const CHUNK_VOLUME: usize = 4096;
+
+/// 16x16x16ブロックの空間
+///
+/// ブロックは`BlockPos::chunk_index`の順にパレット圧縮して保持し、
+/// 空気のみのチャンクはブロック配列を確保しない。
 pub struct Chunk {
-  cell: Option<Box<[Cell; 64]>>,
+  blocks: Option<Box<PalettedStorage<BlockId>>>,
+  /// ブロック毎の光量(`Light`のビット、保存されない)
+  light: Box<[u8; CHUNK_VOLUME]>,
+  /// 流体の水位(`FluidState`のビット)
+  /// 全て水源の場合は確保しない
+  fluids: Option<Box<PalettedStorage<u8>>>,
+  /// セル(4x4x4ブロック)毎の空気以外のブロック数
+  occupancy: [u8; 64],
+  /// 更新フラグ(保存されない)
+  dirty: DirtyFlags,
+  /// 光の版(保存されない)
+  /// 光が弱まる変更で進み、それより前の複製から計算した光を捨てる
+  light_revision: u64,
 }
 impl Chunk {
   pub fn empty_chunk() -> Self {
-    Self { cell: None }
+    Self {
+      blocks: None,
+      light: Box::new([0; CHUNK_VOLUME]),
+      fluids: None,
+      occupancy: [0; 64],
+      dirty: DirtyFlags::NONE,
+      light_revision: 0,
+    }
   }
 
   pub fn new(
     chunk_pos: &types::BlockPos,
-    f: impl Fn(types::BlockPos) -> u8 + Clone,
+    f: impl Fn(types::BlockPos) -> BlockId,
+  ) -> Self {
+    Self::from_storage(Some(PalettedStorage::from_fn(
+      CHUNK_VOLUME,
+      |i| {
+        f(
+          types::BlockPos::from_chunk_index(
+            chunk_pos, i as u16,
+          ),
+        )
+      },
+    )))
+  }
+
+  /// 保存されたブロック配列からチャンクを復元する
+  pub fn from_storage(
+    blocks: Option<PalettedStorage<BlockId>>,
   ) -> Self {
+    let Some(blocks) = blocks else {
+      return Self::empty_chunk();
+    };
+    let mut occupancy = [0; 64];
+    for i in 0..CHUNK_VOLUME {
+      if blocks.get(i) != AIR {
+        occupancy[i >> 6] += 1;
+      }
+    }
+    if occupancy
+      .iter()
+      .all(|n| *n == 0)
+    {
+      return Self::empty_chunk();
+    }
     Self {
-      cell: Some(Box::new(std::array::from_fn(
-        |i| {
-          let pos = chunk_pos.merge_inner(
-            types::BlockPos::from_64index(i as u8),
-          );
-          Cell::new(&pos, f.clone())
-        },
-      ))),
+      blocks: Some(Box::new(blocks)),
+      light: Box::new([0; CHUNK_VOLUME]),
+      fluids: None,
+      occupancy,
+      dirty: DirtyFlags::NONE,
+      light_revision: 0,
+    }
+  }
+
+  /// 更新フラグを除いて複製する
+  pub fn duplicate(&self) -> Self {
+    Self {
+      blocks: self.blocks.clone(),
+      light: self.light.clone(),
+      fluids: self.fluids.clone(),
+      occupancy: self.occupancy,
+      dirty: DirtyFlags::NONE,
+      light_revision: self.light_revision,
+    }
+  }
+
+  /// パレット圧縮されたブロック配列
+  #[inline]
+  pub fn storage(
+    &self,
+  ) -> Option<&PalettedStorage<BlockId>> {
+    self.blocks.as_deref()
+  }
+
+  /// 空気のみのチャンクか
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.blocks.is_none()
+  }
+
+  /// チャンク内の番号`cell_index`のセルが空気のみか
+  #[inline]
+  pub fn is_cell_empty(&self, cell_index: u8) -> bool {
+    self.occupancy[cell_index as usize & 63] == 0
+  }
+
+  /// ワールド座標のブロックを取得する
+  /// 座標はこのチャンクに含まれるものとして下位ビットのみを使う
+  #[inline]
+  pub fn get(&self, pos: &types::BlockPos) -> BlockId {
+    match self.blocks.as_ref() {
+      Some(blocks) => {
+        blocks.get(pos.chunk_index() as usize)
+      }
+      None => AIR,
+    }
+  }
+
+  /// ワールド座標にブロックを設置し、元のブロックを返す
+  /// 空のチャンクへの初回書き込み時にブロック配列を確保し、
+  /// 全て空気に戻った時に解放する
+  pub fn set(
+    &mut self,
+    pos: &types::BlockPos,
+    block: BlockId,
+  ) -> BlockId {
+    let blocks = match self.blocks.as_mut() {
+      Some(blocks) => blocks,
+      None if block == AIR => return AIR,
+      None => self.blocks.insert(Box::new(
+        PalettedStorage::new(CHUNK_VOLUME, AIR),
+      )),
+    };
+    let old = blocks.set(
+      pos.chunk_index() as usize,
+      block,
+    );
+    if old != block
+      && let Some(fluids) = self.fluids.as_mut()
+    {
+      fluids.set(pos.chunk_index() as usize, 0);
     }
+    let cell = pos.cell_index() as usize;
+    match (old == AIR, block == AIR) {
+      (true, false) => self.occupancy[cell] += 1,
+      (false, true) => {
+        self.occupancy[cell] -= 1;
+        if self
+          .occupancy
+          .iter()
+          .all(|n| *n == 0)
+        {
+          self.blocks = None;
+          self.fluids = None;
+        }
+      }
+      _ => {}
+    }
+    old
+  }
+
+  /// ワールド座標の光量
+  #[inline]
+  pub fn light(&self, pos: &types::BlockPos) -> Light {
+    Light::from_bits(
+      self.light[pos.chunk_index() as usize],
+    )
+  }
+
+  /// ワールド座標の光量を設定する
+  #[inline]
+  pub fn set_light(
+    &mut self,
+    pos: &types::BlockPos,
+    light: Light,
+  ) {
+    self.light[pos.chunk_index() as usize] = light.bits();
+  }
+
+  /// ワールド座標の流体の水位
+  #[inline]
+  pub fn fluid_state(
+    &self,
+    pos: &types::BlockPos,
+  ) -> FluidState {
+    self
+      .fluids
+      .as_ref()
+      .map_or(FluidState::SOURCE, |fluids| {
+        FluidState::from_bits(
+          fluids.get(pos.chunk_index() as usize),
+        )
+      })
+  }
+
+  /// ワールド座標の流体の水位を設定し、元の水位を返す
+  /// 空のチャンクには設定しない
+  pub fn set_fluid_state(
+    &mut self,
+    pos: &types::BlockPos,
+    state: FluidState,
+  ) -> FluidState {
+    if self.blocks.is_none() {
+      return FluidState::SOURCE;
+    }
+    let fluids = match self.fluids.as_mut() {
+      Some(fluids) => fluids,
+      None if state == FluidState::SOURCE => {
+        return FluidState::SOURCE;
+      }
+      None => self.fluids.insert(Box::new(
+        PalettedStorage::new(CHUNK_VOLUME, 0),
+      )),
+    };
+    FluidState::from_bits(fluids.set(
+      pos.chunk_index() as usize,
+      state.bits(),
+    ))
+  }
+
+  /// パレット圧縮された流体の水位
+  #[inline]
+  pub fn fluid_storage(
+    &self,
+  ) -> Option<&PalettedStorage<u8>> {
+    self.fluids.as_deref()
+  }
+
+  /// 保存された流体の水位を復元する
+  /// 空のチャンクや全て水源の場合は確保しない
+  pub fn set_fluid_storage(
+    &mut self,
+    fluids: Option<PalettedStorage<u8>>,
+  ) {
+    self.fluids = fluids
+      .filter(|fluids| {
+        self.blocks.is_some()
+          && fluids.uniform() != Some(0)
+      })
+      .map(Box::new);
+  }
+
+  /// 更新フラグ
+  #[inline]
+  pub fn dirty(&self) -> DirtyFlags {
+    self.dirty
+  }
+
+  /// パレットのビット幅(空のチャンクは0)
+  pub fn palette_bits(&self) -> u8 {
+    self
+      .blocks
+      .as_ref()
+      .map_or(0, |blocks| blocks.bits())
+  }
+
+  /// ヒープを含めた使用メモリのバイト数
+  pub fn memory_usage(&self) -> usize {
+    std::mem::size_of::<Self>()
+      + CHUNK_VOLUME
+      + self
+        .blocks
+        .as_ref()
+        .map_or(0, |blocks| {
+          blocks.memory_usage()
+        })
+      + self
+        .fluids
+        .as_ref()
+        .map_or(0, |fluids| {
+          fluids.memory_usage()
+        })
   }
 }
 
-#[repr(C, align(64))]
-#[derive(Debug, Clone, Copy)]
-pub struct Cell([u8; 64]);
-impl Cell {
-  pub fn empty_cell() -> Self {
-    Self([0u8; 64])
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::BlockPos;
+
+  fn stone(world: &World) -> BlockId {
+    world
+      .registry()
+      .id_of("stone")
+      .unwrap()
   }
 
-  pub fn new(
-    cell_pos: &types::BlockPos,
-    f: impl Fn(types::BlockPos) -> u8,
-  ) -> Self {
-    Self(std::array::from_fn(|i| {
-      let pos = cell_pos.merge_inner(
-        types::BlockPos::from_64index(i as u8),
+  #[test]
+  fn chunk_get_set_negative() {
+    let mut chunk = Chunk::empty_chunk();
+    let positions = [
+      BlockPos::new(-1, -1, -1),
+      BlockPos::new(-16, -16, -16),
+      BlockPos::new(-4, -5, -13),
+      BlockPos::new(-13, -2, -8),
+    ];
+    for (i, pos) in positions.iter().enumerate() {
+      assert_eq!(
+        chunk.set(pos, i as BlockId + 1),
+        AIR
+      );
+    }
+    for (i, pos) in positions.iter().enumerate() {
+      assert_eq!(chunk.get(pos), i as BlockId + 1);
+    }
+    assert_eq!(
+      chunk.get(&BlockPos::new(-2, -1, -1)),
+      AIR
+    );
+  }
+
+  #[test]
+  fn world_get_set_negative() {
+    let mut world = World::new();
+    let stone = stone(&world);
+    let positions = [
+      BlockPos::new(-1, -1, -1),
+      BlockPos::new(-16, 0, 15),
+      BlockPos::new(-17, -1, 16),
+      BlockPos::new(-4, -5, -13),
+      BlockPos::new(-33, 7, -48),
+    ];
+    for pos in &positions {
+      world.spawn_chunk(
+        pos.chunk_pos(),
+        Chunk::empty_chunk,
+      );
+    }
+    for pos in &positions {
+      assert_eq!(
+        world.set_block(
+          pos,
+          stone,
+          ChangeCause::Player
+        ),
+        Some(AIR)
       );
-      f(pos)
-    }))
+    }
+    for pos in &positions {
+      assert_eq!(world.get_block(pos), stone);
+      for face in types::TileFace::ALL {
+        let neighbor = pos.neighbor(face);
+        if !positions.contains(&neighbor) {
+          assert_eq!(world.get_block(&neighbor), AIR);
+        }
+      }
+    }
+    let unloaded = BlockPos::new(100, 0, 0);
+    assert_eq!(
+      world.set_block(
+        &unloaded,
+        stone,
+        ChangeCause::Player
+      ),
+      None
+    );
+    assert!(world
+      .chunk(&unloaded.chunk_pos())
+      .is_none());
+    assert_eq!(
+      BlockPos::new(-1, -1, -1).chunk_pos(),
+      BlockPos::new(-1, -1, -1)
+    );
+    assert_eq!(
+      BlockPos::new(-17, -1, 16).chunk_pos(),
+      BlockPos::new(-2, -1, 1)
+    );
+  }
+
+  #[test]
+  fn light_across_chunks() {
+    let mut world = World::new();
+    let glowstone = world
+      .registry()
+      .id_of("glowstone")
+      .unwrap();
+    for x in 0..2 {
+      world.spawn_chunk(
+        BlockPos::new(x, 0, 0),
+        Chunk::empty_chunk,
+      );
+    }
+    world.update_light(None);
+    let inner = BlockPos::new(5, 5, 5);
+    assert_eq!(world.light_at(&inner).sky(), 15);
+
+    let lamp = BlockPos::new(15, 5, 5);
+    let across = BlockPos::new(17, 5, 5);
+    world.set_block(
+      &lamp,
+      glowstone,
+      ChangeCause::Player,
+    );
+    world.update_light(None);
+    assert_eq!(world.light_at(&across).block(), 13);
+    world.set_block(&lamp, AIR, ChangeCause::Player);
+    world.update_light(None);
+    assert_eq!(world.light_at(&across).block(), 0);
+    assert_eq!(world.light_at(&inner).block(), 0);
+
+    let stone = stone(&world);
+    world.spawn_chunk(BlockPos::new(0, 0, 1), || {
+      Chunk::new(&BlockPos::new(0, 0, 1), |_| stone)
+    });
+    world.update_light(None);
+    assert_eq!(world.light_at(&inner).sky(), 4);
+    assert_eq!(world.light_at(&across).sky(), 15);
+  }
+
+  /// 空のチャンクを並べ、更新フラグを下ろしたワールド
+  fn empty_world(chunks: &[BlockPos]) -> World {
+    let mut world = World::new();
+    for chunk_pos in chunks {
+      world.spawn_chunk(*chunk_pos, Chunk::empty_chunk);
+    }
+    world.take_dirty(DirtyFlags::ALL);
+    world
+  }
+
+  fn dirty(
+    world: &World,
+    chunk_pos: BlockPos,
+  ) -> DirtyFlags {
+    world
+      .chunk(&chunk_pos)
+      .unwrap()
+      .dirty()
+  }
+
+  #[test]
+  fn set_block_marks_dirty() {
+    let center = BlockPos::new(0, 0, 0);
+    let west = BlockPos::new(-1, 0, 0);
+    let east = BlockPos::new(1, 0, 0);
+    let mut world = empty_world(&[center, west, east]);
+    let stone = stone(&world);
+    let edge = BlockPos::new(0, 5, 5);
+    assert_eq!(
+      world.set_block(
+        &edge,
+        stone,
+        ChangeCause::Player
+      ),
+      Some(AIR)
+    );
+    let flags = dirty(&world, center);
+    assert!(flags
+      .contains(DirtyFlags::MESH | DirtyFlags::SAVE));
+    // 面で接する隣のチャンクのみメッシュを作り直す
+    assert_eq!(
+      dirty(&world, west),
+      DirtyFlags::MESH
+    );
+    assert_eq!(
+      dirty(&world, east),
+      DirtyFlags::NONE
+    );
+
+    // 同じブロックの設置では何も起こらない
+    world.take_dirty(DirtyFlags::ALL);
+    world
+      .drain_changes()
+      .for_each(drop);
+    assert_eq!(
+      world.set_block(
+        &edge,
+        stone,
+        ChangeCause::Player
+      ),
+      Some(stone)
+    );
+    assert_eq!(
+      dirty(&world, center),
+      DirtyFlags::NONE
+    );
+    assert_eq!(world.drain_changes().count(), 0);
+    // 読み込まれていないチャンクには設置できない
+    assert_eq!(
+      world.set_block(
+        &BlockPos::new(0, 0, 16),
+        stone,
+        ChangeCause::Player
+      ),
+      None
+    );
+  }
+
+  #[test]
+  fn change_events() {
+    let mut world =
+      empty_world(&[BlockPos::new(0, 0, 0)]);
+    let stone = stone(&world);
+    let receiver = world.subscribe();
+    let dropped = world.subscribe();
+    drop(dropped);
+    let pos = BlockPos::new(3, 4, 5);
+    world.set_block(&pos, stone, ChangeCause::Tick);
+    let expected = BlockChange {
+      pos,
+      old: AIR,
+      new: stone,
+      cause: ChangeCause::Tick,
+    };
+    assert_eq!(
+      world
+        .drain_changes()
+        .collect::<Vec<_>>(),
+      [expected]
+    );
+    assert_eq!(
+      receiver
+        .try_iter()
+        .collect::<Vec<_>>(),
+      [expected]
+    );
+    // 破棄された受信側の登録は解除される
+    assert_eq!(world.subscribers.len(), 1);
+    drop(receiver);
+    world.set_block(&pos, AIR, ChangeCause::Player);
+    assert!(world.subscribers.is_empty());
+  }
+
+  #[test]
+  fn pending_changes_are_bounded() {
+    let mut world =
+      empty_world(&[BlockPos::new(0, 0, 0)]);
+    let stone = stone(&world);
+    let pos = BlockPos::new(1, 1, 1);
+    let n = MAX_PENDING_CHANGES + 10;
+    for i in 0..n {
+      let block = if i % 2 == 0 {
+        stone
+      } else {
+        AIR
+      };
+      world.set_block(&pos, block, ChangeCause::Other);
+    }
+    let changes: Vec<BlockChange> =
+      world.drain_changes().collect();
+    assert_eq!(
+      changes.len(),
+      MAX_PENDING_CHANGES
+    );
+    // 古いものから捨てられ、最後の変更が残る
+    assert_eq!(changes.last().unwrap().new, AIR);
+    assert_eq!(changes[0].new, stone);
   }
 }
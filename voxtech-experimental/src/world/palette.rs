@@ -0,0 +1,605 @@
+//! Palette
+//! パレットとビット詰めされた番号による圧縮配列
+
+use std::hash::Hash;
+
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+/// パレット圧縮された固定長の配列
+///
+/// 値はパレットに一度だけ格納され、各要素はパレット上の番号を
+/// `bits`ビットで保持する。ビット幅は2の冪(1, 2, 4, 8, 16)に限り、
+/// 番号の位置をシフトとマスクのみで求められるようにしている。
+/// 値から番号への逆引きと番号毎の参照数を持ち、参照されなくなった
+/// 番号は次に追加する値で再利用する。空きが無ければビット幅を倍に
+/// 広げ、使われている値が一段狭い幅の半分まで減った時にだけ詰め直す。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+  from = "PalettedData<I>",
+  bound(
+    serialize = "I: Serialize",
+    deserialize = "I: Deserialize<'de> + Copy + Eq + Hash"
+  )
+)]
+pub struct PalettedStorage<I> {
+  len: usize,
+  bits: u8,
+  palette: Vec<I>,
+  words: Vec<u64>,
+  /// パレットの値から番号への逆引き
+  #[serde(skip)]
+  lookup: HashMap<I, usize>,
+  /// パレットの番号毎の参照している要素の数
+  #[serde(skip)]
+  counts: Vec<u32>,
+  /// 参照されていないパレットの番号
+  #[serde(skip)]
+  free: Vec<usize>,
+}
+
+/// 保存される`PalettedStorage`の中身
+#[derive(Deserialize)]
+struct PalettedData<I> {
+  len: usize,
+  bits: u8,
+  palette: Vec<I>,
+  words: Vec<u64>,
+}
+impl<I: Copy + Eq + Hash> From<PalettedData<I>>
+  for PalettedStorage<I>
+{
+  /// 逆引きと参照数を組み立て直す
+  /// 壊れたデータは`is_consistent`で弾けるよう、そのまま残す
+  fn from(data: PalettedData<I>) -> Self {
+    let mut storage = Self {
+      len: data.len,
+      bits: data.bits,
+      palette: data.palette,
+      words: data.words,
+      lookup: HashMap::new(),
+      counts: Vec::new(),
+      free: Vec::new(),
+    };
+    storage.rebuild();
+    storage
+  }
+}
+
+impl<I: PartialEq> PartialEq for PalettedStorage<I> {
+  /// 逆引きと参照数は中身から決まるため比べない
+  fn eq(&self, other: &Self) -> bool {
+    self.len == other.len
+      && self.bits == other.bits
+      && self.palette == other.palette
+      && self.words == other.words
+  }
+}
+
+impl<I: Copy + Eq + Hash> PalettedStorage<I> {
+  /// 最小のビット幅
+  pub const MIN_BITS: u8 = 1;
+  /// 最大のビット幅
+  pub const MAX_BITS: u8 = 16;
+
+  /// 全要素が`value`の配列を生成する
+  pub fn new(len: usize, value: I) -> Self {
+    Self {
+      len,
+      bits: Self::MIN_BITS,
+      palette: vec![value],
+      words: vec![
+        0;
+        Self::word_count(len, Self::MIN_BITS)
+      ],
+      lookup: HashMap::from_iter([(value, 0)]),
+      counts: vec![len as u32],
+      free: Vec::new(),
+    }
+  }
+
+  /// 関数の戻り値で埋めた配列を生成する
+  pub fn from_fn(
+    len: usize,
+    mut f: impl FnMut(usize) -> I,
+  ) -> Self {
+    let mut storage = Self::new(len, f(0));
+    for i in 1..len {
+      storage.set(i, f(i));
+    }
+    storage
+  }
+
+  #[inline]
+  fn word_count(len: usize, bits: u8) -> usize {
+    len.div_ceil((64 / bits) as usize)
+  }
+
+  /// 要素`i`を含む語の番号と語内のビット位置
+  #[inline]
+  fn locate(bits: u8, i: usize) -> (usize, usize) {
+    let per_word_log2 = 6 - bits.trailing_zeros();
+    let slot = i & ((1 << per_word_log2) - 1);
+    (
+      i >> per_word_log2,
+      slot * bits as usize,
+    )
+  }
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// 番号一つあたりのビット幅
+  #[inline]
+  pub fn bits(&self) -> u8 {
+    self.bits
+  }
+
+  /// パレット(未使用の値を含む場合がある)
+  #[inline]
+  pub fn palette(&self) -> &[I] {
+    &self.palette
+  }
+
+  #[inline]
+  fn index_at(&self, i: usize) -> usize {
+    let (word, shift) = Self::locate(self.bits, i);
+    let mask = (1u64 << self.bits) - 1;
+    ((self.words[word] >> shift) & mask) as usize
+  }
+
+  #[inline]
+  fn write_index(
+    words: &mut [u64],
+    bits: u8,
+    i: usize,
+    index: usize,
+  ) {
+    let (word, shift) = Self::locate(bits, i);
+    let mask = ((1u64 << bits) - 1) << shift;
+    let word = &mut words[word];
+    *word = (*word & !mask) | ((index as u64) << shift);
+  }
+
+  /// 要素を取得する
+  #[inline]
+  pub fn get(&self, i: usize) -> I {
+    self.palette[self.index_at(i)]
+  }
+
+  /// 要素を設定し、元の値を返す
+  pub fn set(&mut self, i: usize, value: I) -> I {
+    let old_index = self.index_at(i);
+    let old = self.palette[old_index];
+    if old == value {
+      return old;
+    }
+    self.counts[old_index] -= 1;
+    if self.counts[old_index] == 0 {
+      self.lookup.remove(&old);
+      self.free.push(old_index);
+    }
+    let index = match self.lookup.get(&value) {
+      Some(index) => *index,
+      None => self.push_palette(value),
+    };
+    self.counts[index] += 1;
+    Self::write_index(
+      &mut self.words,
+      self.bits,
+      i,
+      index,
+    );
+    if self.should_shrink() {
+      self.compact();
+    }
+    old
+  }
+
+  /// パレットに値を追加し、その番号を返す
+  /// 参照されていない番号があれば再利用する
+  fn push_palette(&mut self, value: I) -> usize {
+    let index = match self.free.pop() {
+      Some(index) => {
+        self.palette[index] = value;
+        index
+      }
+      None => {
+        if self.palette.len() == 1 << self.bits {
+          assert!(
+            self.bits < Self::MAX_BITS,
+            "palette exceeds {} bits",
+            Self::MAX_BITS
+          );
+          self.repack(self.bits * 2, |index| index);
+        }
+        self.palette.push(value);
+        self.counts.push(0);
+        self.palette.len() - 1
+      }
+    };
+    self.lookup.insert(value, index);
+    index
+  }
+
+  /// 使われている値の数
+  #[inline]
+  fn used(&self) -> usize {
+    self.palette.len() - self.free.len()
+  }
+
+  /// 使われている値の数に足りる最小のビット幅
+  #[inline]
+  fn bits_for(used: usize) -> u8 {
+    (usize::BITS - (used.max(2) - 1).leading_zeros())
+      .next_power_of_two()
+      .clamp(
+        Self::MIN_BITS as u32,
+        Self::MAX_BITS as u32,
+      ) as u8
+  }
+
+  /// 一段狭いビット幅の半分まで使われている値が減ったか
+  ///
+  /// 境界の前後で値を出し入れするだけで詰め直しが繰り返されない
+  /// よう、縮められる数より更に半分まで減るのを待つ。
+  #[inline]
+  fn should_shrink(&self) -> bool {
+    Self::MIN_BITS < self.bits
+      && self.used() <= (1 << (self.bits / 2)) / 2
+  }
+
+  /// 番号を`remap`で付け替えつつビット幅`bits`で詰め直す
+  fn repack(
+    &mut self,
+    bits: u8,
+    remap: impl Fn(usize) -> usize,
+  ) {
+    let mut words =
+      vec![0; Self::word_count(self.len, bits)];
+    for i in 0..self.len {
+      Self::write_index(
+        &mut words,
+        bits,
+        i,
+        remap(self.index_at(i)),
+      );
+    }
+    self.bits = bits;
+    self.words = words;
+  }
+
+  /// 使われていない値をパレットから取り除き、ビット幅を縮める
+  pub fn compact(&mut self) {
+    if self.free.is_empty() {
+      return;
+    }
+    let mut remap = vec![0; self.palette.len()];
+    let mut palette = Vec::with_capacity(self.used());
+    let mut counts = Vec::with_capacity(self.used());
+    for (index, value) in
+      self.palette.iter().enumerate()
+    {
+      if 0 < self.counts[index] {
+        remap[index] = palette.len();
+        palette.push(*value);
+        counts.push(self.counts[index]);
+      }
+    }
+    self.repack(
+      Self::bits_for(palette.len()),
+      |index| remap[index],
+    );
+    self.lookup = palette
+      .iter()
+      .enumerate()
+      .map(|(index, value)| (*value, index))
+      .collect();
+    self.palette = palette;
+    self.counts = counts;
+    self.free.clear();
+  }
+
+  /// 逆引きと参照数を中身から組み立て直す
+  /// 番号が壊れている場合は数えずに空のままにする
+  fn rebuild(&mut self) {
+    self.lookup.clear();
+    self.counts = vec![0; self.palette.len()];
+    self.free.clear();
+    let valid = self.bits.is_power_of_two()
+      && (Self::MIN_BITS..=Self::MAX_BITS)
+        .contains(&self.bits)
+      && self.words.len()
+        == Self::word_count(self.len, self.bits);
+    if !valid {
+      self.counts.clear();
+      return;
+    }
+    for i in 0..self.len {
+      let index = self.index_at(i);
+      match self.counts.get_mut(index) {
+        Some(count) => *count += 1,
+        None => {
+          self.counts.clear();
+          return;
+        }
+      }
+    }
+    for (index, value) in
+      self.palette.iter().enumerate()
+    {
+      if self.counts[index] == 0 {
+        self.free.push(index);
+      } else {
+        self
+          .lookup
+          .entry(*value)
+          .or_insert(index);
+      }
+    }
+  }
+
+  /// 全要素が同じ値であればその値を返す
+  pub fn uniform(&self) -> Option<I> {
+    self
+      .counts
+      .iter()
+      .position(|count| *count as usize == self.len)
+      .map(|index| self.palette[index])
+  }
+
+  /// 読み込んだデータの整合性を検査する
+  ///
+  /// 番号がパレットの範囲内にあり、使われている値に重複が無いかを
+  /// 確かめる。
+  pub fn is_consistent(&self) -> bool {
+    !self.palette.is_empty()
+      && self.palette.len() <= 1 << self.bits
+      && self.counts.len() == self.palette.len()
+      && self.lookup.len() == self.used()
+  }
+
+  /// ヒープを含めた使用メモリのバイト数
+  pub fn memory_usage(&self) -> usize {
+    std::mem::size_of::<Self>()
+      + self.palette.capacity()
+        * std::mem::size_of::<I>()
+      + self.words.capacity()
+        * std::mem::size_of::<u64>()
+      + self.lookup.capacity()
+        * std::mem::size_of::<(I, usize)>()
+      + self.counts.capacity()
+        * std::mem::size_of::<u32>()
+      + self.free.capacity()
+        * std::mem::size_of::<usize>()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::hint::black_box;
+  use std::time::Instant;
+
+  use super::*;
+
+  #[test]
+  fn width_grows() {
+    let mut storage = PalettedStorage::new(4096, 0u16);
+    assert_eq!(storage.bits(), 1);
+    for (values, bits) in [
+      (2, 1),
+      (3, 2),
+      (5, 4),
+      (17, 8),
+      (257, 16),
+    ] {
+      for value in 0..values {
+        storage.set(value as usize, value);
+      }
+      assert_eq!(
+        storage.bits(),
+        bits,
+        "{values} values"
+      );
+    }
+    for i in 0..257 {
+      assert_eq!(storage.get(i), i as u16);
+    }
+    assert!(storage.is_consistent());
+  }
+
+  #[test]
+  fn compact_remaps() {
+    let mut storage =
+      PalettedStorage::from_fn(4096, |i| {
+        (i % 40) as u16
+      });
+    assert_eq!(storage.bits(), 8);
+    // 奇数の値を消すと偶数の20個の値が残る
+    for i in 0..4096 {
+      if i % 2 == 1 {
+        storage.set(i, 0);
+      }
+    }
+    assert_eq!(storage.bits(), 8);
+    assert_eq!(storage.palette().len(), 40);
+    storage.compact();
+    assert_eq!(storage.bits(), 8);
+    assert_eq!(storage.palette().len(), 20);
+    for i in 0..4096 {
+      let expected = if i % 2 == 1 {
+        0
+      } else {
+        (i % 40) as u16
+      };
+      assert_eq!(storage.get(i), expected);
+    }
+    assert!(storage.is_consistent());
+  }
+
+  #[test]
+  fn shrinks_with_hysteresis() {
+    let mut storage = PalettedStorage::new(4096, 0u16);
+    for value in 1..17 {
+      storage.set(value as usize, value);
+    }
+    assert_eq!(storage.bits(), 8);
+    // 4ビットに収まる数まで減らしても縮めない
+    for value in 9..17 {
+      storage.set(value as usize, 0);
+    }
+    assert_eq!(storage.bits(), 8);
+    // 境界の前後で値を出し入れしても幅は変わらない
+    for round in 0..100 {
+      let value = 100 + round;
+      storage.set(16, value);
+      assert_eq!(storage.bits(), 8);
+      assert!(storage.palette().len() <= 17);
+    }
+    storage.set(16, 0);
+    // 4ビットの半分まで減ったところで縮める
+    storage.set(8, 0);
+    assert_eq!(storage.bits(), 4);
+    assert_eq!(storage.palette().len(), 8);
+    for i in 0..8 {
+      assert_eq!(storage.get(i), i as u16);
+    }
+    assert!(storage.is_consistent());
+  }
+
+  #[test]
+  fn uniform() {
+    let mut storage = PalettedStorage::new(64, 3u16);
+    assert_eq!(storage.uniform(), Some(3));
+    storage.set(10, 4);
+    assert_eq!(storage.uniform(), None);
+    storage.set(10, 3);
+    assert_eq!(storage.uniform(), Some(3));
+    for i in 0..64 {
+      storage.set(i, 7);
+    }
+    assert_eq!(storage.uniform(), Some(7));
+  }
+
+  #[test]
+  fn serde_round_trip() {
+    let storage = PalettedStorage::from_fn(4096, |i| {
+      (i % 5) as u16
+    });
+    let json = serde_json::to_value(&storage).unwrap();
+    let loaded: PalettedStorage<u16> =
+      serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(loaded, storage);
+    assert!(loaded.is_consistent());
+    assert_eq!(loaded.uniform(), None);
+
+    let mut broken = json.clone();
+    broken["palette"] = serde_json::json!([0, 1]);
+    let broken: PalettedStorage<u16> =
+      serde_json::from_value(broken).unwrap();
+    assert!(!broken.is_consistent());
+
+    let mut broken = json;
+    broken["bits"] = serde_json::json!(3);
+    let broken: PalettedStorage<u16> =
+      serde_json::from_value(broken).unwrap();
+    assert!(!broken.is_consistent());
+  }
+
+  #[test]
+  fn wide_ids() {
+    let values = [0u32, 70_000, u32::MAX, 1 << 31];
+    let mut storage = PalettedStorage::new(4096, 0u32);
+    for (i, value) in values.iter().enumerate() {
+      storage.set(i * 1000, *value);
+    }
+    for (i, value) in values.iter().enumerate() {
+      assert_eq!(storage.get(i * 1000), *value);
+    }
+    assert_eq!(storage.bits(), 2);
+  }
+
+  #[test]
+  #[should_panic(expected = "palette exceeds 16 bits")]
+  fn limit_is_16_bits() {
+    let mut storage =
+      PalettedStorage::new((1 << 16) + 1, 0u32);
+    for i in 1..=1 << 16 {
+      storage.set(i, i as u32);
+    }
+  }
+
+  /// 配列への直接の読み書きとの比較
+  ///
+  /// `cargo test --release -- --ignored bench`で実行する。
+  #[test]
+  #[ignore]
+  fn bench_against_array() {
+    const ROUNDS: usize = 1 << 22;
+    let mut seed = 0x853c_49e6_748f_ea9bu64;
+    let ops: Vec<(usize, u16)> = (0..ROUNDS)
+      .map(|_| {
+        seed = seed
+          .wrapping_mul(6364136223846793005)
+          .wrapping_add(1442695040888963407);
+        (
+          (seed >> 40) as usize & 4095,
+          (seed >> 20) as u16 & 63,
+        )
+      })
+      .collect();
+
+    let mut array = [0u16; 4096];
+    let mut storage = PalettedStorage::new(4096, 0u16);
+    let start = Instant::now();
+    for (i, value) in &ops {
+      array[*i] = black_box(*value);
+    }
+    let array_set = start.elapsed();
+    let start = Instant::now();
+    for (i, value) in &ops {
+      storage.set(*i, black_box(*value));
+    }
+    let paletted_set = start.elapsed();
+
+    let start = Instant::now();
+    let mut sum = 0u64;
+    for (i, _) in &ops {
+      sum += black_box(array[*i]) as u64;
+    }
+    let array_get = start.elapsed();
+    let start = Instant::now();
+    let mut paletted_sum = 0u64;
+    for (i, _) in &ops {
+      paletted_sum += black_box(storage.get(*i)) as u64;
+    }
+    let paletted_get = start.elapsed();
+    assert_eq!(sum, paletted_sum);
+
+    // 2の冪の大きさのパレットで値を入れ替え続ける
+    let mut storage =
+      PalettedStorage::from_fn(4096, |i| {
+        (i % 16) as u16
+      });
+    let start = Instant::now();
+    for round in 0..ROUNDS {
+      storage.set(0, 16 + (round % 2) as u16);
+    }
+    let churn = start.elapsed();
+    assert_eq!(storage.bits(), 8);
+
+    println!(
+      "{ROUNDS} ops: get array {array_get:?} \
+       paletted {paletted_get:?}, set array \
+       {array_set:?} paletted {paletted_set:?}, \
+       churn {churn:?}"
+    );
+  }
+}
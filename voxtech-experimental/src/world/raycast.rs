@@ -0,0 +1,404 @@
+//! Raycast
+//! ブロック格子に対する光線の走査(Amanatides–Woo DDA)
+
+use nalgebra::{Point3, Vector3};
+
+use crate::types::{BlockPos, BlockRange, TileFace};
+
+/// 光線が当たったブロック
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+  /// 当たったブロックの座標
+  pub pos: BlockPos,
+  /// 光線が入った面
+  /// 始点がブロックの中にある場合は`TileFace::UNDEF`
+  pub face: TileFace,
+  /// 当たった点
+  pub point: Point3<f64>,
+  /// 始点から当たった点までの距離
+  pub distance: f64,
+}
+
+/// 光線が通過するブロックを順に辿る
+///
+/// `t`は始点からの距離で、各軸の次の境界までの距離`t_max`と
+/// 一ブロック分の距離`t_delta`を比べて最も近い境界を越える。
+/// 境界を同時に越える場合の丸め誤差で戻らないよう、`t`は
+/// 減らさない。
+#[derive(Debug, Clone)]
+pub(super) struct VoxelRay {
+  origin: Point3<f64>,
+  direction: Vector3<f64>,
+  voxel: [i64; 3],
+  step: [i64; 3],
+  t_max: [f64; 3],
+  t_delta: [f64; 3],
+  t: f64,
+  face: TileFace,
+}
+impl VoxelRay {
+  /// 方向が零か有限でない場合は`None`を返す
+  pub fn new(
+    origin: &Point3<f64>,
+    direction: &Vector3<f64>,
+  ) -> Option<Self> {
+    let direction =
+      direction.try_normalize(f64::EPSILON)?;
+    if !origin
+      .iter()
+      .all(|v| v.is_finite())
+    {
+      return None;
+    }
+    let mut ray = Self {
+      origin: *origin,
+      direction,
+      voxel: [0; 3],
+      step: [0; 3],
+      t_max: [f64::INFINITY; 3],
+      t_delta: [f64::INFINITY; 3],
+      t: 0.,
+      face: TileFace::UNDEF,
+    };
+    for i in 0..3 {
+      let (o, d) = (origin[i], direction[i]);
+      ray.voxel[i] = o.floor() as i64;
+      if 0. < d {
+        ray.step[i] = 1;
+        ray.t_delta[i] = 1. / d;
+        ray.t_max[i] = (o.floor() + 1. - o) / d;
+      } else if d < 0. {
+        ray.step[i] = -1;
+        ray.t_delta[i] = -1. / d;
+        ray.t_max[i] = (o - o.floor()) / -d;
+      }
+    }
+    Some(ray)
+  }
+
+  /// 現在のブロック
+  #[inline]
+  pub fn pos(&self) -> BlockPos {
+    BlockPos::from(self.voxel)
+  }
+
+  /// 現在のブロックに入った時の始点からの距離
+  #[inline]
+  pub fn t(&self) -> f64 {
+    self.t
+  }
+
+  /// 現在のブロックに入った面
+  #[inline]
+  pub fn face(&self) -> TileFace {
+    self.face
+  }
+
+  /// 現在のブロックに入った点
+  #[inline]
+  pub fn point(&self) -> Point3<f64> {
+    self.origin + self.direction * self.t
+  }
+
+  /// 軸`axis`を正負`step`の向きに越えた時に入る面
+  #[inline]
+  fn entered_face(axis: usize, step: i64) -> TileFace {
+    match (axis, 0 < step) {
+      (0, true) => TileFace::WST,
+      (0, false) => TileFace::EST,
+      (1, true) => TileFace::STH,
+      (1, false) => TileFace::NTH,
+      (2, true) => TileFace::BTM,
+      _ => TileFace::TOP,
+    }
+  }
+
+  /// 隣のブロックへ進む
+  pub fn advance(&mut self) {
+    let axis = (0..3)
+      .min_by(|a, b| {
+        self.t_max[*a].total_cmp(&self.t_max[*b])
+      })
+      .expect("three axes");
+    self.voxel[axis] += self.step[axis];
+    self.t = self.t.max(self.t_max[axis]);
+    self.t_max[axis] += self.t_delta[axis];
+    self.face =
+      Self::entered_face(axis, self.step[axis]);
+  }
+
+  /// 現在のブロックを含む範囲`range`の外へ一度に進む
+  ///
+  /// 最初に範囲の境界に達する軸を求め、その時刻までに他の軸が越える
+  /// 境界の数をまとめて進める。
+  pub fn skip(&mut self, range: &BlockRange) {
+    let (min, max) = (
+      range.min.as_array(),
+      range.max.as_array(),
+    );
+    let boundary = |i: usize, step: i64| {
+      if 0 < step {
+        max[i]
+      } else {
+        min[i] - 1
+      }
+    };
+    let mut exit: Option<(f64, usize)> = None;
+    for i in 0..3 {
+      if self.step[i] == 0 {
+        continue;
+      }
+      let crossings = (boundary(i, self.step[i])
+        - self.voxel[i])
+        .abs();
+      let t = self.t_max[i]
+        + (crossings - 1) as f64 * self.t_delta[i];
+      if exit.is_none_or(|(exit_t, _)| t < exit_t) {
+        exit = Some((t, i));
+      }
+    }
+    let Some((t, axis)) = exit else {
+      return;
+    };
+    for i in 0..3 {
+      if self.step[i] == 0 {
+        continue;
+      }
+      let limit = (boundary(i, self.step[i])
+        - self.voxel[i])
+        .abs();
+      let crossings = if i == axis {
+        limit
+      } else if t < self.t_max[i] {
+        0
+      } else {
+        (((t - self.t_max[i]) / self.t_delta[i]).floor()
+          as i64
+          + 1)
+          .min(limit - 1)
+      };
+      self.voxel[i] += self.step[i] * crossings;
+      self.t_max[i] +=
+        crossings as f64 * self.t_delta[i];
+    }
+    self.t = self.t.max(t);
+    self.face =
+      Self::entered_face(axis, self.step[axis]);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::block::BlockId;
+  use crate::types::BlockDist;
+  use crate::world::change::ChangeCause;
+  use crate::world::{Chunk, World};
+
+  fn stone(world: &World) -> BlockId {
+    world
+      .registry()
+      .id_of("stone")
+      .unwrap()
+  }
+
+  /// 空のチャンクを並べたワールド
+  fn world(chunks: &[BlockPos]) -> World {
+    let mut world = World::new();
+    for chunk_pos in chunks {
+      world.spawn_chunk(*chunk_pos, Chunk::empty_chunk);
+    }
+    world
+  }
+
+  fn hit(
+    world: &World,
+    origin: [f64; 3],
+    direction: [f64; 3],
+  ) -> Option<RaycastHit> {
+    world.raycast(
+      Point3::from(origin),
+      Vector3::from(direction),
+      64.,
+    )
+  }
+
+  #[test]
+  fn negative_to_positive() {
+    let mut world = world(&[
+      BlockPos::new(-1, 0, 0),
+      BlockPos::new(0, 0, 0),
+    ]);
+    let stone = stone(&world);
+    let target = BlockPos::new(2, 3, 3);
+    world.set_block(
+      &target,
+      stone,
+      ChangeCause::Player,
+    );
+    let hit = hit(
+      &world,
+      [-3.5, 3.5, 3.5],
+      [1., 0., 0.],
+    )
+    .unwrap();
+    assert_eq!(hit.pos, target);
+    assert_eq!(hit.face, TileFace::WST);
+    assert_eq!(hit.distance, 5.5);
+
+    let mut ray = VoxelRay::new(
+      &Point3::new(-2.25, 0.5, -1.75),
+      &Vector3::new(1., 0., 1.),
+    )
+    .unwrap();
+    let mut visited = vec![ray.pos()];
+    for _ in 0..5 {
+      ray.advance();
+      visited.push(ray.pos());
+    }
+    assert_eq!(
+      visited,
+      [
+        BlockPos::new(-3, 0, -2),
+        BlockPos::new(-2, 0, -2),
+        BlockPos::new(-2, 0, -1),
+        BlockPos::new(-1, 0, -1),
+        BlockPos::new(-1, 0, 0),
+        BlockPos::new(0, 0, 0),
+      ]
+    );
+  }
+
+  #[test]
+  fn along_chunk_boundary() {
+    let mut world = world(&[
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(0, 1, 0),
+      BlockPos::new(1, 1, 0),
+    ]);
+    let stone = stone(&world);
+    world.set_block(
+      &BlockPos::new(20, 15, 0),
+      stone,
+      ChangeCause::Player,
+    );
+    assert!(hit(
+      &world,
+      [0.5, 16., 0.5],
+      [1., 0., 0.]
+    )
+    .is_none());
+    let target = BlockPos::new(20, 16, 0);
+    world.set_block(
+      &target,
+      stone,
+      ChangeCause::Player,
+    );
+    let hit = hit(
+      &world,
+      [0.5, 16., 0.5],
+      [1., 0., 0.],
+    )
+    .unwrap();
+    assert_eq!(hit.pos, target);
+    assert_eq!(hit.distance, 19.5);
+  }
+
+  #[test]
+  fn through_empty_chunk() {
+    let mut world = world(&[
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(1, 0, 0),
+    ]);
+    let stone = stone(&world);
+    let target = BlockPos::new(20, 3, 3);
+    world.set_block(
+      &target,
+      stone,
+      ChangeCause::Player,
+    );
+    let origin = [0.5, 0.25, 0.75];
+    let direction = [
+      20.5 - 0.5,
+      3.5 - 0.25,
+      3.5 - 0.75,
+    ];
+    let hit = hit(&world, origin, direction).unwrap();
+    assert_eq!(hit.pos, target);
+    // 空のチャンクとセルを飛ばさずに辿った結果と一致する
+    let mut ray = VoxelRay::new(
+      &Point3::from(origin),
+      &Vector3::from(direction),
+    )
+    .unwrap();
+    while world.get_block(&ray.pos()) != stone {
+      ray.advance();
+    }
+    assert_eq!(ray.pos(), hit.pos);
+    assert_eq!(ray.face(), hit.face);
+    assert!((ray.t() - hit.distance).abs() < 1e-9);
+  }
+
+  /// 線形合同法による再現性のある乱数列(0..1)
+  fn random(state: &mut u64) -> f64 {
+    *state = state
+      .wrapping_mul(6364136223846793005)
+      .wrapping_add(1442695040888963407);
+    (*state >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  #[test]
+  fn skip_is_monotonic() {
+    let mut state = 1;
+    for _ in 0..20000 {
+      // 境界を同時に越えやすい格子上の始点と整数の方向
+      let mut lattice = |n: i64| {
+        (random(&mut state) * n as f64).floor()
+          - (n / 2) as f64
+      };
+      let origin = Point3::new(
+        lattice(512) / 8.,
+        lattice(512) / 8.,
+        lattice(512) / 8.,
+      );
+      let direction = Vector3::new(
+        lattice(7),
+        lattice(7),
+        lattice(7),
+      );
+      let Some(mut ray) =
+        VoxelRay::new(&origin, &direction)
+      else {
+        continue;
+      };
+      let mut t = ray.t();
+      for i in 0..40 {
+        let pos = ray.pos();
+        if i % 3 == 0 {
+          ray.advance();
+        } else {
+          let level = if i % 3 == 1 {
+            2
+          } else {
+            1
+          };
+          let min = pos
+            .down_level(level)
+            .up_level(level);
+          let size = 4i64.pow(level as u32);
+          let range = BlockRange::new(
+            min,
+            min + BlockDist::new(size, size, size),
+          );
+          ray.skip(&range);
+          assert!(!range.contains(&ray.pos()));
+        }
+        assert!(
+          t <= ray.t(),
+          "{origin:?} {direction:?}"
+        );
+        t = ray.t();
+      }
+    }
+  }
+}
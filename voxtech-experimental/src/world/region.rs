@@ -0,0 +1,743 @@
+//! Region
+//! 複数のチャンクを一つのファイルにまとめて保存するリージョン形式
+//!
+//! リージョンは16x16x16チャンクからなり、ファイルの先頭に
+//! チャンク毎の位置・長さ・更新時刻を並べたヘッダを持つ。
+//! チャンクのデータは4096バイトのセクタ単位で配置され、
+//! ヘッダを引くことで単一のチャンクのみを読み書きできる。
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+use super::palette::PalettedStorage;
+use super::Chunk;
+use super::tick::TickRecord;
+use crate::block::BlockId;
+use crate::types::BlockPos;
+
+/// セクタのバイト数
+pub const SECTOR_SIZE: u64 = 4096;
+/// リージョン内のチャンク数
+pub const REGION_CHUNKS: usize = 4096;
+/// ヘッダの1項目のバイト数
+const ENTRY_SIZE: usize = 16;
+/// ヘッダが占めるセクタ数
+const HEADER_SECTORS: u32 = (REGION_CHUNKS * ENTRY_SIZE)
+  as u32
+  / SECTOR_SIZE as u32;
+
+/// リージョンファイルの読み書き時のエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionError {
+  /// ヘッダが壊れている
+  CorruptedHeader(PathBuf),
+  /// チャンクのデータが壊れている
+  CorruptedChunk(BlockPos),
+  /// 未知の圧縮形式
+  UnknownCompression(u8),
+  /// 保存先が設定されていない
+  NoStorage,
+}
+impl std::fmt::Display for RegionError {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::CorruptedHeader(path) => write!(
+        f,
+        "region header is corrupted: {}",
+        path.display()
+      ),
+      Self::CorruptedChunk(pos) => {
+        write!(
+          f,
+          "chunk data is corrupted: {pos:?}"
+        )
+      }
+      Self::UnknownCompression(id) => {
+        write!(
+          f,
+          "unknown chunk compression: {id}"
+        )
+      }
+      Self::NoStorage => {
+        f.write_str("world has no region storage")
+      }
+    }
+  }
+}
+impl std::error::Error for RegionError {}
+
+/// チャンク毎の圧縮形式
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compression {
+  /// 無圧縮
+  None = 0,
+  /// zlib(Deflate)
+  Zlib = 1,
+}
+impl TryFrom<u8> for Compression {
+  type Error = RegionError;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0 => Ok(Self::None),
+      1 => Ok(Self::Zlib),
+      _ => Err(RegionError::UnknownCompression(
+        value,
+      )),
+    }
+  }
+}
+impl Compression {
+  fn compress(
+    &self,
+    data: &[u8],
+  ) -> std::io::Result<Vec<u8>> {
+    match self {
+      Self::None => Ok(data.to_vec()),
+      Self::Zlib => {
+        let mut encoder =
+          flate2::write::ZlibEncoder::new(
+            Vec::with_capacity(data.len() / 2),
+            flate2::Compression::default(),
+          );
+        encoder.write_all(data)?;
+        encoder.finish()
+      }
+    }
+  }
+
+  fn decompress(
+    &self,
+    data: &[u8],
+  ) -> std::io::Result<Vec<u8>> {
+    match self {
+      Self::None => Ok(data.to_vec()),
+      Self::Zlib => {
+        let mut out =
+          Vec::with_capacity(data.len() * 4);
+        flate2::read::ZlibDecoder::new(data)
+          .read_to_end(&mut out)?;
+        Ok(out)
+      }
+    }
+  }
+}
+
+/// ヘッダの1項目
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub struct RegionEntry {
+  /// データ先頭のセクタ番号(0は未保存)
+  pub sector: u32,
+  /// 圧縮形式の1バイトを含むデータのバイト数
+  pub length: u32,
+  /// 最終更新時刻(UNIX時間の秒)
+  pub timestamp: u64,
+}
+impl RegionEntry {
+  #[inline]
+  pub fn is_present(&self) -> bool {
+    self.sector != 0
+  }
+
+  /// データが占めるセクタ数
+  #[inline]
+  fn sectors(&self) -> u32 {
+    (self.length as u64).div_ceil(SECTOR_SIZE) as u32
+  }
+
+  fn read(bytes: &[u8]) -> Self {
+    let u32_at = |i: usize| {
+      u32::from_le_bytes(
+        bytes[i..i + 4]
+          .try_into()
+          .unwrap(),
+      )
+    };
+    Self {
+      sector: u32_at(0),
+      length: u32_at(4),
+      timestamp: u64::from_le_bytes(
+        bytes[8..16].try_into().unwrap(),
+      ),
+    }
+  }
+
+  fn write(&self, bytes: &mut [u8]) {
+    bytes[0..4]
+      .copy_from_slice(&self.sector.to_le_bytes());
+    bytes[4..8]
+      .copy_from_slice(&self.length.to_le_bytes());
+    bytes[8..16]
+      .copy_from_slice(&self.timestamp.to_le_bytes());
+  }
+}
+
+/// 保存されるチャンクの内容
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRecord {
+  pub blocks: Option<PalettedStorage<BlockId>>,
+  /// チャンク内の予約更新
+  #[serde(default)]
+  pub ticks: Vec<TickRecord>,
+  /// 流体の水位
+  #[serde(default)]
+  pub fluids: Option<PalettedStorage<u8>>,
+}
+impl ChunkRecord {
+  pub fn from_chunk(
+    chunk: &Chunk,
+    ticks: Vec<TickRecord>,
+  ) -> Self {
+    Self {
+      blocks: chunk.storage().cloned(),
+      ticks,
+      fluids: chunk.fluid_storage().cloned(),
+    }
+  }
+
+  /// チャンクへ復元する
+  pub fn into_chunk(
+    self,
+    chunk_pos: &BlockPos,
+  ) -> Result<Chunk, RegionError> {
+    let corrupted = self
+      .blocks
+      .as_ref()
+      .is_some_and(|blocks| {
+        blocks.len() != super::CHUNK_VOLUME
+          || !blocks.is_consistent()
+      })
+      || self
+        .fluids
+        .as_ref()
+        .is_some_and(|fluids| {
+          fluids.len() != super::CHUNK_VOLUME
+            || !fluids.is_consistent()
+        });
+    if corrupted {
+      return Err(RegionError::CorruptedChunk(
+        *chunk_pos,
+      ));
+    }
+    let mut chunk = Chunk::from_storage(self.blocks);
+    chunk.set_fluid_storage(self.fluids);
+    Ok(chunk)
+  }
+}
+
+/// 一つのリージョンファイル
+pub struct RegionFile {
+  path: PathBuf,
+  file: File,
+  entries: Box<[RegionEntry; REGION_CHUNKS]>,
+  /// セクタ毎の使用状況(ヘッダを含む)
+  used: Vec<bool>,
+}
+impl RegionFile {
+  /// リージョンファイルを開く(無ければ作成する)
+  pub fn open(
+    path: impl AsRef<Path>,
+  ) -> crate::StdResult<Self> {
+    let path = path.as_ref().to_path_buf();
+    let mut file = File::options()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)?;
+    let header_len = REGION_CHUNKS * ENTRY_SIZE;
+    let mut header = vec![0u8; header_len];
+    if file.metadata()?.len() == 0 {
+      file.write_all(&header)?;
+    } else {
+      file
+        .read_exact(&mut header)
+        .map_err(|_| {
+          RegionError::CorruptedHeader(path.clone())
+        })?;
+    }
+    let entries: Box<[RegionEntry; REGION_CHUNKS]> =
+      Box::new(std::array::from_fn(|i| {
+        RegionEntry::read(
+          &header[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE],
+        )
+      }));
+    let file_sectors = file
+      .metadata()?
+      .len()
+      .div_ceil(SECTOR_SIZE)
+      as usize;
+    let mut used = vec![
+      false;
+      file_sectors
+        .max(HEADER_SECTORS as usize)
+    ];
+    used[..HEADER_SECTORS as usize].fill(true);
+    for entry in entries
+      .iter()
+      .filter(|e| e.is_present())
+    {
+      let range = entry.sector as usize
+        ..(entry.sector + entry.sectors()) as usize;
+      if entry.sector < HEADER_SECTORS
+        || file_sectors < range.end
+      {
+        return Err(
+          RegionError::CorruptedHeader(path).into(),
+        );
+      }
+      used[range].fill(true);
+    }
+    Ok(Self {
+      path,
+      file,
+      entries,
+      used,
+    })
+  }
+
+  #[inline]
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// リージョン内のチャンクの番号
+  #[inline]
+  pub fn local_index(chunk_pos: &BlockPos) -> usize {
+    ((chunk_pos.get_x() & 15)
+      | ((chunk_pos.get_y() & 15) << 4)
+      | ((chunk_pos.get_z() & 15) << 8)) as usize
+  }
+
+  #[inline]
+  pub fn entry(&self, index: usize) -> &RegionEntry {
+    &self.entries[index]
+  }
+
+  /// チャンクのデータを展開して読み込む
+  pub fn read(
+    &mut self,
+    index: usize,
+  ) -> crate::StdResult<Option<Vec<u8>>> {
+    let entry = self.entries[index];
+    if !entry.is_present() || entry.length == 0 {
+      return Ok(None);
+    }
+    let mut data = vec![0u8; entry.length as usize];
+    self.file.seek(SeekFrom::Start(
+      entry.sector as u64 * SECTOR_SIZE,
+    ))?;
+    self
+      .file
+      .read_exact(&mut data)?;
+    let compression = Compression::try_from(data[0])?;
+    Ok(Some(
+      compression.decompress(&data[1..])?,
+    ))
+  }
+
+  /// チャンクのデータを圧縮して書き込む
+  pub fn write(
+    &mut self,
+    index: usize,
+    data: &[u8],
+    compression: Compression,
+    timestamp: u64,
+  ) -> crate::StdResult<()> {
+    let mut payload = vec![compression as u8];
+    payload.extend(compression.compress(data)?);
+    let sectors = (payload.len() as u64)
+      .div_ceil(SECTOR_SIZE) as u32;
+
+    // 元の領域を残したまま新しい領域へ書き込み、ヘッダを
+    // 差し替えてから解放する。途中で中断しても元のデータが残る。
+    let sector = self.allocate(sectors);
+    let entry = RegionEntry {
+      sector,
+      length: payload.len() as u32,
+      timestamp,
+    };
+
+    payload.resize(
+      sectors as usize * SECTOR_SIZE as usize,
+      0,
+    );
+    self.file.seek(SeekFrom::Start(
+      sector as u64 * SECTOR_SIZE,
+    ))?;
+    self.file.write_all(&payload)?;
+    let old = self.entries[index];
+    self.write_entry(index, entry)?;
+    if old.is_present() {
+      self.used[old.sector as usize
+        ..(old.sector + old.sectors()) as usize]
+        .fill(false);
+    }
+    Ok(())
+  }
+
+  /// 連続した`sectors`個の空きセクタを確保する
+  fn allocate(&mut self, sectors: u32) -> u32 {
+    let sectors = sectors as usize;
+    let mut start = HEADER_SECTORS as usize;
+    // ファイル末尾を越える部分は空きとして扱う
+    while start < self.used.len() {
+      let end = (start + sectors).min(self.used.len());
+      match self.used[start..end]
+        .iter()
+        .rposition(|u| *u)
+      {
+        Some(i) => start += i + 1,
+        None => break,
+      }
+    }
+    if self.used.len() < start + sectors {
+      self
+        .used
+        .resize(start + sectors, false);
+    }
+    self.used[start..start + sectors].fill(true);
+    start as u32
+  }
+
+  fn write_entry(
+    &mut self,
+    index: usize,
+    entry: RegionEntry,
+  ) -> crate::StdResult<()> {
+    let mut bytes = [0u8; ENTRY_SIZE];
+    entry.write(&mut bytes);
+    self.file.seek(SeekFrom::Start(
+      (index * ENTRY_SIZE) as u64,
+    ))?;
+    self.file.write_all(&bytes)?;
+    self.entries[index] = entry;
+    Ok(())
+  }
+
+  /// 書き込みを確定する
+  pub fn flush(&mut self) -> crate::StdResult<()> {
+    self.file.sync_data()?;
+    Ok(())
+  }
+}
+
+/// 既定で同時に開いておくリージョンファイルの数
+pub const DEFAULT_OPEN_REGIONS: usize = 32;
+
+/// ディレクトリ内のリージョンファイル群
+///
+/// 開いたリージョンファイルは`max_open`個まで保持し、それを超える
+/// 時は最も長く使われていないものの書き込みを確定して閉じる。
+pub struct RegionStorage {
+  dir: PathBuf,
+  compression: Compression,
+  /// 開いているリージョンファイルと最後に使った時の`clock`
+  regions: HashMap<BlockPos, (RegionFile, u64)>,
+  max_open: usize,
+  /// リージョンファイルを使う度に進む時計
+  clock: u64,
+}
+impl RegionStorage {
+  /// 保存先のディレクトリを開く(無ければ作成する)
+  pub fn new(
+    dir: impl AsRef<Path>,
+  ) -> crate::StdResult<Self> {
+    let dir = dir.as_ref().to_path_buf();
+    std::fs::create_dir_all(&dir)?;
+    Ok(Self {
+      dir,
+      compression: Compression::Zlib,
+      regions: HashMap::new(),
+      max_open: DEFAULT_OPEN_REGIONS,
+      clock: 0,
+    })
+  }
+
+  /// 同時に開いておくリージョンファイルの数を変更する(最低1)
+  pub fn with_max_open(
+    mut self,
+    max_open: usize,
+  ) -> Self {
+    self.max_open = max_open.max(1);
+    self
+  }
+
+  /// 開いているリージョンファイルの数
+  #[inline]
+  pub fn open_regions(&self) -> usize {
+    self.regions.len()
+  }
+
+  /// 書き込み時の圧縮形式を変更する
+  pub fn with_compression(
+    mut self,
+    compression: Compression,
+  ) -> Self {
+    self.compression = compression;
+    self
+  }
+
+  #[inline]
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// チャンクを含むリージョンの座標
+  #[inline]
+  pub fn region_pos(chunk_pos: &BlockPos) -> BlockPos {
+    chunk_pos.down_level(2)
+  }
+
+  /// リージョンファイルのパス
+  pub fn region_path(
+    &self,
+    region_pos: &BlockPos,
+  ) -> PathBuf {
+    self.dir.join(format!(
+      "r.{}.{}.{}.vxr",
+      region_pos.get_x(),
+      region_pos.get_y(),
+      region_pos.get_z()
+    ))
+  }
+
+  fn region(
+    &mut self,
+    chunk_pos: &BlockPos,
+  ) -> crate::StdResult<&mut RegionFile> {
+    let region_pos = Self::region_pos(chunk_pos);
+    self.clock += 1;
+    if !self
+      .regions
+      .contains_key(&region_pos)
+    {
+      if self.max_open <= self.regions.len() {
+        self.close_least_recent()?;
+      }
+      let region = RegionFile::open(
+        self.region_path(&region_pos),
+      )?;
+      self
+        .regions
+        .insert(region_pos, (region, 0));
+    }
+    let (region, last_used) = self
+      .regions
+      .get_mut(&region_pos)
+      .unwrap();
+    *last_used = self.clock;
+    Ok(region)
+  }
+
+  /// 最も長く使われていないリージョンファイルを閉じる
+  fn close_least_recent(
+    &mut self,
+  ) -> crate::StdResult<()> {
+    let Some(region_pos) = self
+      .regions
+      .iter()
+      .min_by_key(|(_, (_, last_used))| *last_used)
+      .map(|(region_pos, _)| *region_pos)
+    else {
+      return Ok(());
+    };
+    if let Some((mut region, _)) =
+      self.regions.remove(&region_pos)
+    {
+      region.flush()?;
+    }
+    Ok(())
+  }
+
+  /// チャンクを保存する
+  pub fn save_chunk(
+    &mut self,
+    chunk_pos: &BlockPos,
+    record: &ChunkRecord,
+  ) -> crate::StdResult<()> {
+    let data = rmp_serde::to_vec_named(record)?;
+    let compression = self.compression;
+    let timestamp = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .map_or(0, |d| d.as_secs());
+    self.region(chunk_pos)?.write(
+      RegionFile::local_index(chunk_pos),
+      &data,
+      compression,
+      timestamp,
+    )
+  }
+
+  /// チャンクを読み込む(未保存の場合は`None`)
+  pub fn load_chunk(
+    &mut self,
+    chunk_pos: &BlockPos,
+  ) -> crate::StdResult<Option<ChunkRecord>> {
+    let region_pos = Self::region_pos(chunk_pos);
+    if !self
+      .regions
+      .contains_key(&region_pos)
+      && !self
+        .region_path(&region_pos)
+        .exists()
+    {
+      return Ok(None);
+    }
+    let Some(data) = self.region(chunk_pos)?.read(
+      RegionFile::local_index(chunk_pos),
+    )?
+    else {
+      return Ok(None);
+    };
+    let record =
+      rmp_serde::from_slice(&data).map_err(|_| {
+        RegionError::CorruptedChunk(*chunk_pos)
+      })?;
+    Ok(Some(record))
+  }
+
+  /// チャンクの最終更新時刻
+  pub fn timestamp(
+    &mut self,
+    chunk_pos: &BlockPos,
+  ) -> crate::StdResult<Option<u64>> {
+    let region_pos = Self::region_pos(chunk_pos);
+    if !self
+      .regions
+      .contains_key(&region_pos)
+      && !self
+        .region_path(&region_pos)
+        .exists()
+    {
+      return Ok(None);
+    }
+    let entry = *self.region(chunk_pos)?.entry(
+      RegionFile::local_index(chunk_pos),
+    );
+    Ok(
+      entry
+        .is_present()
+        .then_some(entry.timestamp),
+    )
+  }
+
+  /// 開いている全てのリージョンファイルの書き込みを確定する
+  pub fn flush(&mut self) -> crate::StdResult<()> {
+    for (region, _) in self.regions.values_mut() {
+      region.flush()?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// テスト毎の一時ディレクトリ
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "voxtech-region-{name}-{}",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+  }
+
+  #[test]
+  fn rewrite_uses_fresh_sectors() {
+    let dir = temp_dir("rewrite");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut region =
+      RegionFile::open(dir.join("r.vxr")).unwrap();
+    let small = vec![1u8; 100];
+    let large = vec![2u8; SECTOR_SIZE as usize * 2];
+
+    region
+      .write(0, &small, Compression::None, 1)
+      .unwrap();
+    let first = *region.entry(0);
+    region
+      .write(0, &large, Compression::None, 2)
+      .unwrap();
+    let second = *region.entry(0);
+    assert_ne!(first.sector, second.sector);
+    assert_eq!(
+      region.read(0).unwrap(),
+      Some(large)
+    );
+
+    // 解放された元の領域は次の書き込みで再利用される
+    region
+      .write(1, &small, Compression::None, 3)
+      .unwrap();
+    assert_eq!(
+      region.entry(1).sector,
+      first.sector
+    );
+
+    let mut reopened =
+      RegionFile::open(dir.join("r.vxr")).unwrap();
+    assert_eq!(
+      reopened.read(1).unwrap(),
+      Some(small)
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn closes_least_recent_region() {
+    let dir = temp_dir("lru");
+    let mut storage = RegionStorage::new(&dir)
+      .unwrap()
+      .with_max_open(2);
+    let record = ChunkRecord {
+      blocks: None,
+      ticks: Vec::new(),
+      fluids: None,
+    };
+    let chunks = [
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(16, 0, 0),
+      BlockPos::new(-1, 0, 0),
+      BlockPos::new(0, 0, 0),
+    ];
+    for chunk_pos in &chunks {
+      storage
+        .save_chunk(chunk_pos, &record)
+        .unwrap();
+      assert!(storage.open_regions() <= 2);
+    }
+    assert!(storage
+      .regions
+      .contains_key(&BlockPos::new(0, 0, 0)));
+    assert!(!storage
+      .regions
+      .contains_key(&BlockPos::new(1, 0, 0)));
+    for chunk_pos in &chunks {
+      assert!(storage
+        .load_chunk(chunk_pos)
+        .unwrap()
+        .is_some());
+    }
+    assert_eq!(
+      storage
+        .load_chunk(&BlockPos::new(1, 0, 0))
+        .unwrap()
+        .map(|_| ()),
+      None
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
@@ -0,0 +1,463 @@
+//! Save
+//! ワールドのセーブディレクトリ
+//!
+//! `saves/<ワールド名>/`以下に次のファイルを配置する。
+//! - `level.json` : ワールドのメタデータ
+//! - `players/<プレイヤー名>.json` : プレイヤーの状態
+//! - `region/` : チャンクのリージョンファイル
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::r#gen::TerrainGenerator;
+use super::region::RegionStorage;
+use crate::block::AIR;
+use crate::player::{Player, PlayerState};
+
+/// 現在のセーブ形式のバージョン
+pub const FORMAT_VERSION: u32 = 1;
+
+const LEVEL_FILE: &str = "level.json";
+const PLAYERS_DIR: &str = "players";
+const REGION_DIR: &str = "region";
+/// スポーン地点の空きを地表から上へ探す高さ
+const SPAWN_SEARCH: i64 = 256;
+
+/// セーブの操作時のエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorldSaveError {
+  /// 同名のワールドが既に存在する
+  AlreadyExists(PathBuf),
+  /// ワールドが存在しない
+  NotFound(PathBuf),
+  /// 対応していない形式のバージョン
+  IncompatibleVersion { found: u32, supported: u32 },
+  /// ディレクトリ名として使えない名前
+  InvalidName(String),
+}
+impl std::fmt::Display for WorldSaveError {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::AlreadyExists(path) => write!(
+        f,
+        "world already exists: {}",
+        path.display()
+      ),
+      Self::NotFound(path) => {
+        write!(
+          f,
+          "world not found: {}",
+          path.display()
+        )
+      }
+      Self::IncompatibleVersion {
+        found,
+        supported,
+      } => {
+        write!(
+          f,
+          "world format version {found} is not supported (expected {supported})"
+        )
+      }
+      Self::InvalidName(name) => {
+        write!(
+          f,
+          "invalid world or player name: `{name}`"
+        )
+      }
+    }
+  }
+}
+impl std::error::Error for WorldSaveError {}
+
+/// `level.json`の内容
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct LevelMeta {
+  /// ワールド名
+  pub name: String,
+  /// 地形生成のシード値
+  pub seed: u64,
+  /// 作成日時(UNIX時間の秒)
+  pub created_at: u64,
+  /// セーブ形式のバージョン
+  pub format_version: u32,
+  /// 経過したゲーム内時間(ティック)
+  pub game_time: u64,
+  /// 初期スポーン地点
+  pub spawn: [f64; 3],
+}
+
+/// 一覧表示用のワールドの情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldSummary {
+  pub dir: PathBuf,
+  pub meta: LevelMeta,
+}
+
+/// 一つのワールドのセーブディレクトリ
+pub struct WorldSave {
+  dir: PathBuf,
+  meta: LevelMeta,
+}
+impl WorldSave {
+  /// ファイル名として安全な名前か
+  fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+      && !name.starts_with('.')
+      && name.chars().all(|c| {
+        c.is_alphanumeric()
+          || matches!(c, '-' | '_' | ' ')
+      })
+  }
+
+  /// 新しいワールドを作成する
+  /// スポーン地点は`generator`の地表の上に置く
+  pub fn create(
+    saves_dir: impl AsRef<Path>,
+    name: &str,
+    seed: u64,
+    generator: &dyn TerrainGenerator,
+  ) -> crate::StdResult<Self> {
+    if !Self::is_valid_name(name) {
+      return Err(
+        WorldSaveError::InvalidName(name.into()).into(),
+      );
+    }
+    let dir = saves_dir.as_ref().join(name);
+    if dir.join(LEVEL_FILE).exists() {
+      return Err(
+        WorldSaveError::AlreadyExists(dir).into(),
+      );
+    }
+    std::fs::create_dir_all(dir.join(PLAYERS_DIR))?;
+    std::fs::create_dir_all(dir.join(REGION_DIR))?;
+    let created_at = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .map_or(0, |d| d.as_secs());
+    let save = Self {
+      dir,
+      meta: LevelMeta {
+        name: name.into(),
+        seed,
+        created_at,
+        format_version: FORMAT_VERSION,
+        game_time: 0,
+        spawn: spawn_point(generator),
+      },
+    };
+    save.save_meta()?;
+    Ok(save)
+  }
+
+  /// 既存のワールドを開く
+  pub fn open(
+    saves_dir: impl AsRef<Path>,
+    name: &str,
+  ) -> crate::StdResult<Self> {
+    if !Self::is_valid_name(name) {
+      return Err(
+        WorldSaveError::InvalidName(name.into()).into(),
+      );
+    }
+    Self::open_dir(saves_dir.as_ref().join(name))
+  }
+
+  /// ディレクトリを指定して既存のワールドを開く
+  pub fn open_dir(
+    dir: impl AsRef<Path>,
+  ) -> crate::StdResult<Self> {
+    let dir = dir.as_ref().to_path_buf();
+    let level = Self::read_level(&dir)?;
+    // 形式が変わっていても先にバージョンを比べられるよう、
+    // 構造体へ変換する前に値として読む
+    let found = level
+      .get("format_version")
+      .and_then(serde_json::Value::as_u64)
+      .map_or(0, |version| {
+        u32::try_from(version).unwrap_or(u32::MAX)
+      });
+    if found != FORMAT_VERSION {
+      return Err(
+        WorldSaveError::IncompatibleVersion {
+          found,
+          supported: FORMAT_VERSION,
+        }
+        .into(),
+      );
+    }
+    let meta = serde_json::from_value(level)?;
+    Ok(Self { dir, meta })
+  }
+
+  /// 既存のワールドを開き、無ければ作成する
+  pub fn open_or_create(
+    saves_dir: impl AsRef<Path>,
+    name: &str,
+    seed: u64,
+    generator: &dyn TerrainGenerator,
+  ) -> crate::StdResult<Self> {
+    let saves_dir = saves_dir.as_ref();
+    if saves_dir
+      .join(name)
+      .join(LEVEL_FILE)
+      .exists()
+    {
+      Self::open(saves_dir, name)
+    } else {
+      Self::create(saves_dir, name, seed, generator)
+    }
+  }
+
+  /// `level.json`を値として読む
+  fn read_level(
+    dir: &Path,
+  ) -> crate::StdResult<serde_json::Value> {
+    let path = dir.join(LEVEL_FILE);
+    if !path.exists() {
+      return Err(
+        WorldSaveError::NotFound(dir.to_path_buf())
+          .into(),
+      );
+    }
+    let json = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&json)?)
+  }
+
+  fn read_meta(
+    dir: &Path,
+  ) -> crate::StdResult<LevelMeta> {
+    Ok(serde_json::from_value(Self::read_level(dir)?)?)
+  }
+
+  /// セーブディレクトリ内のワールドを作成日時順に列挙する
+  /// 読み込めないディレクトリは無視する
+  pub fn list(
+    saves_dir: impl AsRef<Path>,
+  ) -> crate::StdResult<Vec<WorldSummary>> {
+    let saves_dir = saves_dir.as_ref();
+    if !saves_dir.exists() {
+      return Ok(Vec::new());
+    }
+    let mut worlds = Vec::new();
+    for entry in std::fs::read_dir(saves_dir)? {
+      let dir = entry?.path();
+      if let Ok(meta) = Self::read_meta(&dir) {
+        worlds.push(WorldSummary { dir, meta });
+      }
+    }
+    worlds.sort_by_key(|w| w.meta.created_at);
+    Ok(worlds)
+  }
+
+  #[inline]
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  #[inline]
+  pub fn meta(&self) -> &LevelMeta {
+    &self.meta
+  }
+
+  #[inline]
+  pub fn meta_mut(&mut self) -> &mut LevelMeta {
+    &mut self.meta
+  }
+
+  /// `level.json`を書き出す
+  pub fn save_meta(&self) -> crate::StdResult<()> {
+    let json =
+      serde_json::to_string_pretty(&self.meta)?;
+    write_atomic(
+      &self.dir.join(LEVEL_FILE),
+      &json,
+    )
+  }
+
+  /// チャンクの保存先
+  pub fn region_storage(
+    &self,
+  ) -> crate::StdResult<RegionStorage> {
+    RegionStorage::new(self.dir.join(REGION_DIR))
+  }
+
+  fn player_path(
+    &self,
+    name: &str,
+  ) -> crate::StdResult<PathBuf> {
+    if !Self::is_valid_name(name) {
+      return Err(
+        WorldSaveError::InvalidName(name.into()).into(),
+      );
+    }
+    Ok(
+      self
+        .dir
+        .join(PLAYERS_DIR)
+        .join(format!("{name}.json")),
+    )
+  }
+
+  /// プレイヤーの状態を書き出す
+  pub fn save_player(
+    &self,
+    name: &str,
+    state: &PlayerState,
+  ) -> crate::StdResult<()> {
+    let path = self.player_path(name)?;
+    std::fs::create_dir_all(
+      self.dir.join(PLAYERS_DIR),
+    )?;
+    let json = serde_json::to_string_pretty(state)?;
+    write_atomic(&path, &json)
+  }
+
+  /// プレイヤーの状態を読み込む(未保存の場合は`None`)
+  pub fn load_player(
+    &self,
+    name: &str,
+  ) -> crate::StdResult<Option<PlayerState>> {
+    let path = self.player_path(name)?;
+    if !path.exists() {
+      return Ok(None);
+    }
+    let json = std::fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(
+      &json,
+    )?))
+  }
+}
+
+/// 生成器の列(0, 0)の地表に立つプレイヤーの視点の位置
+///
+/// 地表の上から2ブロック分の空気が続く高さを探す。高さマップを
+/// 持たない生成器では高さ0から探す。
+pub fn spawn_point(
+  generator: &dyn TerrainGenerator,
+) -> [f64; 3] {
+  let is_air = |z: i64| {
+    generator.block_at(&crate::types::BlockPos::new(
+      0, 0, z,
+    )) == AIR
+  };
+  let start = generator
+    .surface_height(0, 0)
+    .map_or(0, |height| height + 1);
+  let z = (start..start + SPAWN_SEARCH)
+    .find(|z| is_air(*z) && is_air(*z + 1))
+    .unwrap_or(start);
+  [0.5, 0.5, z as f64 + Player::EYE_HEIGHT]
+}
+
+/// 一時ファイルへ書き込んでから置き換える
+fn write_atomic(
+  path: &Path,
+  contents: &str,
+) -> crate::StdResult<()> {
+  let tmp = path.with_extension("json.tmp");
+  std::fs::write(&tmp, contents)?;
+  std::fs::rename(tmp, path)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::block::BlockId;
+  use crate::types::BlockPos;
+
+  /// 高さ`height`以下を石、その上の3ブロックを水で満たす生成器
+  struct Flat {
+    height: i64,
+  }
+  impl TerrainGenerator for Flat {
+    fn block_at(&self, pos: &BlockPos) -> BlockId {
+      match pos.get_z() - self.height {
+        ..=0 => 1,
+        1..=3 => 4,
+        _ => AIR,
+      }
+    }
+
+    fn surface_height(
+      &self,
+      _x: i64,
+      _y: i64,
+    ) -> Option<i64> {
+      Some(self.height)
+    }
+  }
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "voxtech-save-{name}-{}",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+  }
+
+  #[test]
+  fn spawn_above_surface() {
+    let spawn = spawn_point(&Flat { height: 37 });
+    assert_eq!(
+      spawn,
+      [
+        0.5,
+        0.5,
+        41. + Player::EYE_HEIGHT
+      ]
+    );
+
+    let dir = temp_dir("spawn");
+    let save = WorldSave::create(
+      &dir,
+      "w",
+      1,
+      &Flat { height: -5 },
+    )
+    .unwrap();
+    assert_eq!(
+      save.meta().spawn,
+      [
+        0.5,
+        0.5,
+        -1. + Player::EYE_HEIGHT
+      ]
+    );
+    let reopened = WorldSave::open(&dir, "w").unwrap();
+    assert_eq!(reopened.meta(), save.meta());
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn incompatible_schema() {
+    let dir = temp_dir("version");
+    let world = dir.join("w");
+    std::fs::create_dir_all(&world).unwrap();
+    std::fs::write(
+      world.join(LEVEL_FILE),
+      r#"{"format_version": 2, "title": "renamed"}"#,
+    )
+    .unwrap();
+    let error = WorldSave::open(&dir, "w")
+      .err()
+      .unwrap();
+    assert_eq!(
+      error.downcast_ref::<WorldSaveError>(),
+      Some(
+        &WorldSaveError::IncompatibleVersion {
+          found: 2,
+          supported: FORMAT_VERSION,
+        }
+      )
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
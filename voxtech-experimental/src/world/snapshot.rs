@@ -0,0 +1,129 @@
+//! Snapshot
+//! ワーカーへ渡すチャンクと隣接するチャンクの複製
+
+use std::sync::Arc;
+
+use super::chunk_map::ChunkMap;
+use super::light::Lighting;
+use super::r#gen::TerrainGenerator;
+use super::{Chunk, World, CHUNK_VOLUME};
+use crate::block::{BlockId, BlockRegistry, AIR};
+use crate::types::{BlockPos, TileFace};
+
+/// 光のジョブで計算したチャンクの光
+pub struct ChunkLight {
+  /// ブロック毎の光量(`Light`のビット)
+  light: Box<[u8; CHUNK_VOLUME]>,
+  /// 複製した時点の中心のチャンクの光の版
+  revision: u64,
+}
+impl ChunkLight {
+  #[inline]
+  pub fn light(&self) -> &[u8; CHUNK_VOLUME] {
+    &self.light
+  }
+
+  #[inline]
+  pub fn revision(&self) -> u64 {
+    self.revision
+  }
+}
+
+/// チャンクとその面で接するチャンクの複製
+///
+/// 光とメッシュの計算に必要な範囲だけを複製し、ワーカースレッドで
+/// ワールドを借りずに計算できるようにする。読み込まれていなかった
+/// 隣のチャンクは複製にも含まれない。
+pub struct ChunkSnapshot {
+  chunk_pos: BlockPos,
+  /// 複製した時点の中心のチャンクの光の版
+  revision: u64,
+  map: ChunkMap,
+  registry: Arc<BlockRegistry>,
+  /// 上のチャンクが無い列の地表の高さを求める生成器
+  generator: Option<Arc<dyn TerrainGenerator>>,
+}
+impl ChunkSnapshot {
+  /// チャンクが読み込まれていない場合は`None`を返す
+  pub fn new(
+    world: &World,
+    chunk_pos: &BlockPos,
+  ) -> Option<Self> {
+    let chunk = world.chunk(chunk_pos)?;
+    let mut map = ChunkMap::new();
+    map.insert(*chunk_pos, chunk.duplicate());
+    for face in TileFace::ALL {
+      let neighbor = *chunk_pos + face.offset();
+      if let Some(chunk) = world.chunk(&neighbor) {
+        map.insert(neighbor, chunk.duplicate());
+      }
+    }
+    Some(Self {
+      chunk_pos: *chunk_pos,
+      revision: chunk.light_revision,
+      map,
+      registry: Arc::clone(world.registry()),
+      generator: world.generator().cloned(),
+    })
+  }
+
+  /// 中心のチャンクの座標
+  #[inline]
+  pub fn chunk_pos(&self) -> BlockPos {
+    self.chunk_pos
+  }
+
+  /// 中心のチャンク
+  #[inline]
+  pub fn chunk(&self) -> &Chunk {
+    self
+      .map
+      .get(&self.chunk_pos)
+      .expect("snapshot always holds its center chunk")
+  }
+
+  /// ブロックの登録簿
+  #[inline]
+  pub fn registry(&self) -> &BlockRegistry {
+    &self.registry
+  }
+
+  /// ワールド座標のブロックを取得する
+  /// 複製に含まれないチャンクは空として扱う
+  #[inline]
+  pub fn get_block(&self, pos: &BlockPos) -> BlockId {
+    self
+      .map
+      .get(&pos.chunk_pos())
+      .map_or(AIR, |chunk| chunk.get(pos))
+  }
+
+  /// 中心のチャンクの光を一から計算する
+  ///
+  /// 中心の光を消してから発光ブロックと空に開けた列、隣接する
+  /// チャンクの境界の光から広げ直す。隣接するチャンクへ伝わる光と
+  /// 下のチャンクから取り除く天空光は、`World::apply_light`で結果を
+  /// 反映する時に実際のチャンクへ広げ直す。
+  pub fn light(mut self) -> ChunkLight {
+    let chunk_pos = self.chunk_pos;
+    if let Some(chunk) = self.map.get_mut(&chunk_pos) {
+      chunk.light.fill(0);
+    }
+    Lighting::new(
+      &mut self.map,
+      &self.registry,
+      self.generator.as_deref(),
+    )
+    .light_chunk(&chunk_pos);
+    ChunkLight {
+      light: self
+        .map
+        .remove(&chunk_pos)
+        .map_or_else(
+          || Box::new([0; CHUNK_VOLUME]),
+          |chunk| chunk.light,
+        ),
+      revision: self.revision,
+    }
+  }
+}
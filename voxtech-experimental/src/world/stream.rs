@@ -0,0 +1,574 @@
+//! Stream
+//! プレイヤーの周囲のチャンクの読み込みと解放
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use hashbrown::HashSet;
+
+use super::r#gen::generate_populated;
+use super::{Chunk, World};
+use crate::job::{JobKind, JobOutput, JobPool};
+use crate::types::{BlockDist, BlockPos};
+
+/// チャンクの読み込み範囲の設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamSettings {
+  /// 水平方向の読み込み半径(チャンク数)
+  pub horizontal_radius: i64,
+  /// 鉛直方向の読み込み半径(チャンク数)
+  pub vertical_radius: i64,
+  /// 解放するまでに読み込み半径から余分に離れる距離(チャンク数)
+  pub unload_margin: i64,
+  /// 一回の更新で読み込むチャンク数の上限
+  pub loads_per_update: usize,
+}
+impl Default for StreamSettings {
+  fn default() -> Self {
+    Self {
+      horizontal_radius: 8,
+      vertical_radius: 4,
+      unload_margin: 2,
+      loads_per_update: 4,
+    }
+  }
+}
+
+/// 読み込み状況
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub struct StreamStats {
+  /// 読み込まれているチャンク数
+  pub loaded: usize,
+  /// 読み込み待ち・生成中のチャンク数
+  pub pending: usize,
+  /// これまでに解放したチャンク数
+  pub evicted: u64,
+}
+
+/// プレイヤーの位置に追従してチャンクを読み込み、解放する
+///
+/// 中心のチャンクが変わる度に範囲内の未読み込みのチャンクを距離順に
+/// 並べ直し、近いものから少しずつ読み込む。範囲の外に出たチャンクは
+/// 読み込み半径より`unload_margin`だけ離れてから保存して解放するため、
+/// 境界付近を行き来しても読み込みと解放を繰り返さない。
+/// ワーカースレッド群が渡された場合、保存されていないチャンクの生成は
+/// ワーカーに任せ、範囲外に出たチャンクの生成は取り消す。
+pub struct ChunkStreamer {
+  settings: StreamSettings,
+  center: Option<BlockPos>,
+  queue: VecDeque<BlockPos>,
+  /// ワーカーで生成中のチャンク
+  generating: HashSet<BlockPos>,
+  evicted: u64,
+}
+impl Default for ChunkStreamer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+impl ChunkStreamer {
+  pub fn new() -> Self {
+    Self::with_settings(StreamSettings::default())
+  }
+
+  pub fn with_settings(
+    settings: StreamSettings,
+  ) -> Self {
+    Self {
+      settings,
+      center: None,
+      queue: VecDeque::new(),
+      generating: HashSet::new(),
+      evicted: 0,
+    }
+  }
+
+  #[inline]
+  pub fn settings(&self) -> &StreamSettings {
+    &self.settings
+  }
+
+  /// 読み込み範囲を変更する(次の更新で反映される)
+  pub fn set_settings(
+    &mut self,
+    settings: StreamSettings,
+  ) {
+    self.settings = settings;
+    self.center = None;
+  }
+
+  /// 中心のチャンク
+  #[inline]
+  pub fn center(&self) -> Option<BlockPos> {
+    self.center
+  }
+
+  /// 読み込み待ちのチャンクを近い順に走査する
+  ///
+  /// 並べ直した後に別の経路で読み込まれたチャンクも含む
+  /// (読み込みの順番が来た時に飛ばされる)。
+  pub fn pending(
+    &self,
+  ) -> impl Iterator<Item = &BlockPos> {
+    self.queue.iter()
+  }
+
+  /// 中心から`extra`だけ広げた範囲にチャンクが含まれるか
+  fn in_range(
+    &self,
+    center: &BlockPos,
+    chunk_pos: &BlockPos,
+    extra: i64,
+  ) -> bool {
+    let d = *chunk_pos - *center;
+    let h = self.settings.horizontal_radius + extra;
+    let v = self.settings.vertical_radius + extra;
+    d.get_x().pow(2) + d.get_y().pow(2) <= h * h
+      && d.get_z().abs() <= v
+  }
+
+  /// 中心からの距離の二乗
+  fn distance2(
+    center: &BlockPos,
+    chunk_pos: &BlockPos,
+  ) -> i64 {
+    let d = *chunk_pos - *center;
+    d.get_x().pow(2)
+      + d.get_y().pow(2)
+      + d.get_z().pow(2)
+  }
+
+  /// 範囲内の未読み込みのチャンクを距離順に並べ直す
+  fn rebuild_queue(
+    &mut self,
+    world: &World,
+    center: &BlockPos,
+  ) {
+    let (h, v) = (
+      self.settings.horizontal_radius,
+      self.settings.vertical_radius,
+    );
+    let mut chunks = Vec::new();
+    for z in -v..=v {
+      for y in -h..=h {
+        for x in -h..=h {
+          let chunk_pos =
+            *center + BlockDist::new(x, y, z);
+          if self.in_range(center, &chunk_pos, 0)
+            && world
+              .chunk(&chunk_pos)
+              .is_none()
+          {
+            chunks.push(chunk_pos);
+          }
+        }
+      }
+    }
+    chunks.sort_by_key(|chunk_pos| {
+      Self::distance2(center, chunk_pos)
+    });
+    self.queue = chunks.into();
+  }
+
+  /// 範囲外に出たチャンクを保存して解放する
+  fn evict(
+    &mut self,
+    world: &mut World,
+    center: &BlockPos,
+  ) -> crate::StdResult<()> {
+    let far: Vec<BlockPos> = world
+      .chunk_positions()
+      .filter(|chunk_pos| {
+        !self.in_range(
+          center,
+          chunk_pos,
+          self.settings.unload_margin,
+        )
+      })
+      .copied()
+      .collect();
+    for chunk_pos in far {
+      if world.unload_chunk(&chunk_pos)? {
+        self.evicted += 1;
+      }
+    }
+    Ok(())
+  }
+
+  /// 範囲外に出たチャンクの生成を取り消す
+  fn cancel_generating(
+    &mut self,
+    center: &BlockPos,
+    jobs: &JobPool<JobOutput>,
+  ) {
+    let far: Vec<BlockPos> = self
+      .generating
+      .iter()
+      .filter(|chunk_pos| {
+        !self.in_range(center, chunk_pos, 0)
+      })
+      .copied()
+      .collect();
+    for chunk_pos in far {
+      jobs.cancel_chunk(
+        &chunk_pos,
+        Some(JobKind::Generate),
+      );
+      self
+        .generating
+        .remove(&chunk_pos);
+    }
+  }
+
+  /// 位置`position`(ブロック単位)を中心に読み込みと解放を進める
+  /// `jobs`が`None`の場合はこのスレッドでチャンクを生成する
+  pub fn update(
+    &mut self,
+    world: &mut World,
+    position: nalgebra::Point3<f64>,
+    mut jobs: Option<&mut JobPool<JobOutput>>,
+  ) -> crate::StdResult<StreamStats> {
+    let center = BlockPos::new(
+      position.x.floor() as i64,
+      position.y.floor() as i64,
+      position.z.floor() as i64,
+    )
+    .chunk_pos();
+    if self.center != Some(center) {
+      self.center = Some(center);
+      self.evict(world, &center)?;
+      if let Some(jobs) = jobs.as_deref() {
+        self.cancel_generating(&center, jobs);
+      }
+      self.rebuild_queue(world, &center);
+    }
+    let mut loads = 0;
+    while loads < self.settings.loads_per_update {
+      let Some(chunk_pos) = self.queue.pop_front()
+      else {
+        break;
+      };
+      if world
+        .chunk(&chunk_pos)
+        .is_some()
+        || self
+          .generating
+          .contains(&chunk_pos)
+      {
+        continue;
+      }
+      loads += 1;
+      if world.storage().is_some()
+        && world.load_chunk(&chunk_pos)?
+      {
+        continue;
+      }
+      match (
+        jobs.as_deref_mut(),
+        world.generator(),
+      ) {
+        (Some(jobs), Some(generator)) => {
+          let generator = Arc::clone(generator);
+          let structures =
+            world.structures().cloned();
+          jobs.submit(
+            JobKind::Generate,
+            chunk_pos,
+            Self::distance2(&center, &chunk_pos),
+            move |_| {
+              Some(JobOutput::Generated(
+                generate_populated(
+                  generator.as_ref(),
+                  structures.as_deref(),
+                  &chunk_pos,
+                ),
+              ))
+            },
+          );
+          self
+            .generating
+            .insert(chunk_pos);
+        }
+        _ => {
+          world.load_or_generate_chunk(&chunk_pos)?
+        }
+      }
+    }
+    Ok(self.stats(world))
+  }
+
+  /// ワーカーで生成されたチャンクを受け取る
+  /// 生成を依頼していないチャンクは捨てる
+  pub fn receive_generated(
+    &mut self,
+    world: &mut World,
+    chunk_pos: BlockPos,
+    chunk: Chunk,
+  ) {
+    if self
+      .generating
+      .remove(&chunk_pos)
+      && world
+        .chunk(&chunk_pos)
+        .is_none()
+    {
+      world.insert_generated_chunk(&chunk_pos, chunk);
+    }
+  }
+
+  /// 現在の読み込み状況
+  /// 待ち行列のうち既に読み込まれたチャンクは数えない
+  pub fn stats(&self, world: &World) -> StreamStats {
+    let queued = self
+      .queue
+      .iter()
+      .filter(|chunk_pos| {
+        world
+          .chunk(chunk_pos)
+          .is_none()
+      })
+      .count();
+    StreamStats {
+      loaded: world.chunk_count(),
+      pending: queued + self.generating.len(),
+      evicted: self.evicted,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use nalgebra::Point3;
+
+  use super::*;
+  use crate::block::{BlockId, AIR};
+  use crate::world::change::ChangeCause;
+  use crate::world::r#gen::TerrainGenerator;
+  use crate::world::region::RegionStorage;
+
+  /// 高さ0未満が石の平らな地形
+  struct Flat {
+    stone: BlockId,
+  }
+  impl TerrainGenerator for Flat {
+    fn block_at(&self, pos: &BlockPos) -> BlockId {
+      if pos.get_z() < 0 {
+        self.stone
+      } else {
+        AIR
+      }
+    }
+  }
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "voxtech-stream-{name}-{}",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+  }
+
+  fn world() -> World {
+    let mut world = World::new();
+    let stone = world
+      .registry()
+      .id_of("stone")
+      .unwrap();
+    world.set_generator(Arc::new(Flat { stone }));
+    world
+  }
+
+  fn settings(
+    loads_per_update: usize,
+  ) -> StreamSettings {
+    StreamSettings {
+      horizontal_radius: 2,
+      vertical_radius: 1,
+      unload_margin: 1,
+      loads_per_update,
+    }
+  }
+
+  /// 水平半径2、鉛直半径1の範囲のチャンク数
+  const IN_RANGE: usize = 13 * 3;
+
+  /// チャンク`chunk_pos`の中にあるプレイヤーの位置
+  fn position(chunk_pos: BlockPos) -> Point3<f64> {
+    let pos = chunk_pos.up_level(2);
+    Point3::new(
+      pos.get_x() as f64 + 8.,
+      pos.get_y() as f64 + 8.,
+      pos.get_z() as f64 + 8.,
+    )
+  }
+
+  #[test]
+  fn loads_in_distance_order() {
+    let mut world = world();
+    let mut streamer =
+      ChunkStreamer::with_settings(settings(1));
+    let center = BlockPos::new(5, -3, 1);
+    let mut last = 0;
+    for _ in 0..IN_RANGE {
+      let loaded: HashSet<BlockPos> = world
+        .chunk_positions()
+        .copied()
+        .collect();
+      streamer
+        .update(
+          &mut world,
+          position(center),
+          None,
+        )
+        .unwrap();
+      let added: Vec<BlockPos> = world
+        .chunk_positions()
+        .filter(|chunk_pos| {
+          !loaded.contains(*chunk_pos)
+        })
+        .copied()
+        .collect();
+      assert_eq!(added.len(), 1);
+      let d =
+        ChunkStreamer::distance2(&center, &added[0]);
+      assert!(last <= d, "{last} <= {d}");
+      last = d;
+    }
+    assert_eq!(last, 5);
+    assert!(world.chunk(&center).is_some());
+    assert_eq!(world.chunk_count(), IN_RANGE);
+  }
+
+  #[test]
+  fn honours_loads_per_update() {
+    let mut world = world();
+    let mut streamer =
+      ChunkStreamer::with_settings(settings(4));
+    let center = BlockPos::new(0, 0, 0);
+    for update in 1..=IN_RANGE.div_ceil(4) {
+      let stats = streamer
+        .update(
+          &mut world,
+          position(center),
+          None,
+        )
+        .unwrap();
+      let loaded = (update * 4).min(IN_RANGE);
+      assert_eq!(
+        stats,
+        StreamStats {
+          loaded,
+          pending: IN_RANGE - loaded,
+          evicted: 0,
+        }
+      );
+    }
+    let stats = streamer
+      .update(
+        &mut world,
+        position(center),
+        None,
+      )
+      .unwrap();
+    assert_eq!(stats.loaded, IN_RANGE);
+    assert_eq!(stats.pending, 0);
+  }
+
+  #[test]
+  fn pending_skips_loaded() {
+    let mut world = world();
+    let mut streamer =
+      ChunkStreamer::with_settings(settings(1));
+    let center = BlockPos::new(0, 0, 0);
+    let stats = streamer
+      .update(
+        &mut world,
+        position(center),
+        None,
+      )
+      .unwrap();
+    assert_eq!(stats.pending, IN_RANGE - 1);
+    // 待ち行列にあるチャンクを別の経路で読み込む
+    let far = BlockPos::new(2, 0, 1);
+    assert!(streamer
+      .pending()
+      .any(|p| *p == far));
+    world
+      .load_or_generate_chunk(&far)
+      .unwrap();
+    let stats = streamer.stats(&world);
+    assert_eq!(stats.loaded, 2);
+    assert_eq!(stats.pending, IN_RANGE - 2);
+  }
+
+  #[test]
+  fn evicts_beyond_margin() {
+    let dir = temp_dir("evict");
+    let mut world = world();
+    world
+      .set_storage(RegionStorage::new(&dir).unwrap());
+    let stone = world
+      .registry()
+      .id_of("stone")
+      .unwrap();
+    let mut streamer =
+      ChunkStreamer::with_settings(settings(64));
+    let stats = streamer
+      .update(
+        &mut world,
+        position(BlockPos::new(0, 0, 0)),
+        None,
+      )
+      .unwrap();
+    assert_eq!(stats.loaded, IN_RANGE);
+    // 移動先から半径+1と半径+余白+1離れるチャンクを書き換える
+    let kept = BlockPos::new(0, 0, 0);
+    let evicted = BlockPos::new(-1, 0, 0);
+    let block = BlockPos::new(-5, 3, 7);
+    world.set_block(
+      &block,
+      stone,
+      ChangeCause::Other,
+    );
+    let old: Vec<BlockPos> = world
+      .chunk_positions()
+      .copied()
+      .collect();
+
+    let center = BlockPos::new(3, 0, 0);
+    let stats = streamer
+      .update(
+        &mut world,
+        position(center),
+        None,
+      )
+      .unwrap();
+    let far = old
+      .iter()
+      .filter(|chunk_pos| {
+        let d = **chunk_pos - center;
+        9 < d.get_x().pow(2) + d.get_y().pow(2)
+      })
+      .count();
+    assert!(0 < far);
+    assert!(world.chunk(&kept).is_some());
+    assert!(world.chunk(&evicted).is_none());
+    assert_eq!(stats.evicted, far as u64);
+    assert_eq!(
+      stats.loaded,
+      world.chunk_count()
+    );
+    assert_eq!(stats.pending, 0);
+    // 解放したチャンクは変更と共に保存されている
+    assert!(world
+      .load_chunk(&evicted)
+      .unwrap());
+    assert_eq!(world.get_block(&block), stone);
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
@@ -0,0 +1,629 @@
+//! Tick
+//! ブロックの予約更新と無作為更新
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+
+use super::change::ChangeCause;
+use super::entity::GravityHandler;
+use super::fluid::FluidHandler;
+use super::r#gen::noise::{derive_seed, hash3, mix64};
+use super::World;
+use crate::block::{BlockId, BlockRegistry, AIR};
+use crate::types::{BlockDist, BlockPos, BlockRange};
+
+/// 予約されたブロックの更新
+///
+/// 予定時刻、優先度(小さい程先)、予約順の順に実行される。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledTick {
+  pub pos: BlockPos,
+  pub block: BlockId,
+  /// 実行するゲーム内時間
+  pub due: u64,
+  pub priority: i32,
+  seq: u64,
+}
+impl PartialOrd for ScheduledTick {
+  fn partial_cmp(
+    &self,
+    other: &Self,
+  ) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+impl Ord for ScheduledTick {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    (
+      self.due,
+      self.priority,
+      self.seq,
+    )
+      .cmp(&(
+        other.due,
+        other.priority,
+        other.seq,
+      ))
+  }
+}
+
+/// チャンクと共に保存される予約更新
+#[derive(
+  Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct TickRecord {
+  pub pos: [i64; 3],
+  pub block: BlockId,
+  /// 保存時点からの残り時間
+  pub delay: u64,
+  pub priority: i32,
+}
+
+/// ゲーム内時間と予約更新の待ち行列
+pub struct TickScheduler {
+  seed: u64,
+  time: u64,
+  queue: BinaryHeap<Reverse<ScheduledTick>>,
+  scheduled: HashSet<(BlockPos, BlockId)>,
+  next_seq: u64,
+  /// チャンク毎に一回の更新で選ぶ無作為更新の数
+  pub random_ticks_per_chunk: u32,
+  /// 一回の更新で実行する予約更新の上限
+  /// 溢れた更新は次の更新へ持ち越される
+  pub max_scheduled_per_tick: usize,
+}
+impl Default for TickScheduler {
+  fn default() -> Self {
+    Self::new(0)
+  }
+}
+impl TickScheduler {
+  pub fn new(seed: u64) -> Self {
+    Self {
+      seed,
+      time: 0,
+      queue: BinaryHeap::new(),
+      scheduled: HashSet::new(),
+      next_seq: 0,
+      random_ticks_per_chunk: 3,
+      max_scheduled_per_tick: 4096,
+    }
+  }
+
+  #[inline]
+  pub fn seed(&self) -> u64 {
+    self.seed
+  }
+
+  #[inline]
+  pub fn set_seed(&mut self, seed: u64) {
+    self.seed = seed;
+  }
+
+  /// ゲーム内時間(ティック)
+  #[inline]
+  pub fn time(&self) -> u64 {
+    self.time
+  }
+
+  #[inline]
+  pub fn set_time(&mut self, time: u64) {
+    self.time = time;
+  }
+
+  /// 予約されている更新の数
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.queue.len()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.queue.is_empty()
+  }
+
+  /// `delay`ティック後の更新を予約する
+  /// 同じ座標とブロックの更新が予約済みの場合は`false`を返す
+  pub fn schedule(
+    &mut self,
+    pos: BlockPos,
+    block: BlockId,
+    delay: u64,
+    priority: i32,
+  ) -> bool {
+    if !self
+      .scheduled
+      .insert((pos, block))
+    {
+      return false;
+    }
+    self
+      .queue
+      .push(Reverse(ScheduledTick {
+        pos,
+        block,
+        due: self.time + delay.max(1),
+        priority,
+        seq: self.next_seq,
+      }));
+    self.next_seq += 1;
+    true
+  }
+
+  #[inline]
+  pub fn is_scheduled(
+    &self,
+    pos: &BlockPos,
+    block: BlockId,
+  ) -> bool {
+    self
+      .scheduled
+      .contains(&(*pos, block))
+  }
+
+  /// 現在の時間までに予定時刻を迎えた更新を実行順に取り出す
+  /// 一回に取り出す数は`max_scheduled_per_tick`までに制限される
+  pub fn pop_due(&mut self) -> Vec<ScheduledTick> {
+    let mut due = Vec::new();
+    while let Some(Reverse(tick)) = self.queue.peek() {
+      if self.time < tick.due
+        || self.max_scheduled_per_tick <= due.len()
+      {
+        break;
+      }
+      let tick = *tick;
+      self.queue.pop();
+      self
+        .scheduled
+        .remove(&(tick.pos, tick.block));
+      due.push(tick);
+    }
+    due
+  }
+
+  /// 時間を一ティック進める
+  #[inline]
+  pub fn advance(&mut self) {
+    self.time += 1;
+  }
+
+  /// チャンク内の予約更新を保存用に書き出す
+  pub fn chunk_records(
+    &self,
+    chunk_pos: &BlockPos,
+  ) -> Vec<TickRecord> {
+    let mut ticks: Vec<&ScheduledTick> = self
+      .queue
+      .iter()
+      .map(|Reverse(tick)| tick)
+      .filter(|tick| tick.pos.chunk_pos() == *chunk_pos)
+      .collect();
+    ticks.sort();
+    ticks
+      .into_iter()
+      .map(|tick| TickRecord {
+        pos: tick.pos.as_array(),
+        block: tick.block,
+        delay: tick
+          .due
+          .saturating_sub(self.time),
+        priority: tick.priority,
+      })
+      .collect()
+  }
+
+  /// チャンク内の予約更新を取り除く
+  pub fn remove_chunk(&mut self, chunk_pos: &BlockPos) {
+    let scheduled = &mut self.scheduled;
+    self
+      .queue
+      .retain(|Reverse(tick)| {
+        let keep = tick.pos.chunk_pos() != *chunk_pos;
+        if !keep {
+          scheduled.remove(&(tick.pos, tick.block));
+        }
+        keep
+      });
+  }
+
+  /// 保存された予約更新を戻す
+  pub fn restore(&mut self, records: Vec<TickRecord>) {
+    for record in records {
+      self.schedule(
+        record.pos.into(),
+        record.block,
+        record.delay,
+        record.priority,
+      );
+    }
+  }
+
+  /// 現在の時間と座標、用途`salt`から決まる乱数
+  pub fn random(
+    &self,
+    pos: &BlockPos,
+    salt: u64,
+  ) -> u64 {
+    let [x, y, z] = pos.as_array();
+    hash3(
+      derive_seed(
+        self.seed,
+        self.time ^ mix64(salt),
+      ),
+      x,
+      y,
+      z,
+    )
+  }
+
+  /// 現在の時間にチャンク内で無作為更新する座標
+  pub fn random_positions(
+    &self,
+    chunk_pos: &BlockPos,
+  ) -> Vec<BlockPos> {
+    let hash = self.random(chunk_pos, 0x5241_4e44);
+    (0..self.random_ticks_per_chunk as u64)
+      .map(|i| {
+        BlockPos::from_chunk_index(
+          chunk_pos,
+          (mix64(hash.wrapping_add(i)) & 0xfff) as u16,
+        )
+      })
+      .collect()
+  }
+}
+
+/// ブロック毎の更新処理
+pub trait TickHandler: Send + Sync {
+  /// 予約された更新
+  fn scheduled_tick(
+    &self,
+    _world: &mut World,
+    _pos: &BlockPos,
+    _block: BlockId,
+  ) {
+  }
+
+  /// 無作為に選ばれた時の更新
+  fn random_tick(
+    &self,
+    _world: &mut World,
+    _pos: &BlockPos,
+    _block: BlockId,
+  ) {
+  }
+
+  /// ブロック自身か隣接するブロックが変わった時の更新
+  fn block_updated(
+    &self,
+    _world: &mut World,
+    _pos: &BlockPos,
+    _block: BlockId,
+  ) {
+  }
+}
+
+/// 草の広がりと枯れ
+///
+/// 上を不透明なブロックで塞がれた草は土になり、そうでなければ
+/// 周囲の上が開いた土を草にする。
+pub struct GrassSpread {
+  grass: BlockId,
+  dirt: BlockId,
+}
+impl GrassSpread {
+  pub fn new(grass: BlockId, dirt: BlockId) -> Self {
+    Self { grass, dirt }
+  }
+
+  fn is_covered(world: &World, pos: &BlockPos) -> bool {
+    world.registry().is_opaque(
+      world
+        .get_block(&(*pos + BlockDist::new(0, 0, 1))),
+    )
+  }
+}
+impl TickHandler for GrassSpread {
+  fn random_tick(
+    &self,
+    world: &mut World,
+    pos: &BlockPos,
+    _block: BlockId,
+  ) {
+    if Self::is_covered(world, pos) {
+      world.set_block(
+        pos,
+        self.dirt,
+        ChangeCause::Tick,
+      );
+      return;
+    }
+    let hash = world
+      .ticks()
+      .random(pos, 0x4752_4153);
+    let target = *pos
+      + BlockDist::new(
+        (hash % 3) as i64 - 1,
+        ((hash >> 8) % 3) as i64 - 1,
+        ((hash >> 16) % 3) as i64 - 1,
+      );
+    if world.get_block(&target) == self.dirt
+      && !Self::is_covered(world, &target)
+    {
+      world.set_block(
+        &target,
+        self.grass,
+        ChangeCause::Tick,
+      );
+    }
+  }
+}
+
+/// 原木から離れた葉の消滅
+///
+/// 無作為更新で周囲に原木が無い葉を見つけると、少し遅らせて消す。
+/// 周囲に読み込まれていないチャンクがある場合は消さない。
+pub struct LeafDecay {
+  log: BlockId,
+  radius: i64,
+}
+impl LeafDecay {
+  pub fn new(log: BlockId) -> Self {
+    Self { log, radius: 4 }
+  }
+
+  fn is_supported(
+    &self,
+    world: &World,
+    pos: &BlockPos,
+  ) -> bool {
+    let r = BlockDist::new(
+      self.radius,
+      self.radius,
+      self.radius,
+    );
+    let range = BlockRange::new(
+      *pos - r,
+      *pos + r + BlockDist::new(1, 1, 1),
+    );
+    let corners = BlockRange::new(
+      range.min.chunk_pos(),
+      (range.max - BlockDist::new(1, 1, 1)).chunk_pos()
+        + BlockDist::new(1, 1, 1),
+    );
+    corners
+      .into_iter()
+      .any(|chunk_pos| {
+        world
+          .chunk(&chunk_pos)
+          .is_none()
+      })
+      || range
+        .into_iter()
+        .any(|p| world.get_block(&p) == self.log)
+  }
+}
+impl TickHandler for LeafDecay {
+  fn random_tick(
+    &self,
+    world: &mut World,
+    pos: &BlockPos,
+    block: BlockId,
+  ) {
+    if !self.is_supported(world, pos) {
+      let delay = 20
+        + world
+          .ticks()
+          .random(pos, 0x4c45_4146)
+          % 40;
+      world.schedule_tick(pos, block, delay, 0);
+    }
+  }
+
+  fn scheduled_tick(
+    &self,
+    world: &mut World,
+    pos: &BlockPos,
+    _block: BlockId,
+  ) {
+    if !self.is_supported(world, pos) {
+      world.set_block(pos, AIR, ChangeCause::Tick);
+    }
+  }
+}
+
+/// 組み込みの更新処理を登録簿の名前で解決する
+/// 登録簿に無いブロックの処理は含まれない
+///
+/// 流体の性質を持つブロックには流れの処理を、落下するブロックには
+/// 落下の処理を割り当てる。
+pub fn default_handlers(
+  registry: &BlockRegistry,
+) -> Vec<(BlockId, Arc<dyn TickHandler>)> {
+  let mut handlers: Vec<(
+    BlockId,
+    Arc<dyn TickHandler>,
+  )> = Vec::new();
+  if let (Some(grass), Some(dirt)) = (
+    registry.id_of("grass"),
+    registry.id_of("dirt"),
+  ) {
+    handlers.push((
+      grass,
+      Arc::new(GrassSpread::new(grass, dirt)),
+    ));
+  }
+  if let (Some(leaves), Some(log)) = (
+    registry.id_of("leaves"),
+    registry.id_of("log"),
+  ) {
+    handlers.push((
+      leaves,
+      Arc::new(LeafDecay::new(log)),
+    ));
+  }
+  for def in registry.iter() {
+    if let Some(fluid) = def.fluid.as_ref() {
+      handlers.push((
+        def.id,
+        Arc::new(FluidHandler::new(
+          def.id,
+          fluid.clone(),
+        )),
+      ));
+    } else if def.gravity {
+      handlers.push((
+        def.id,
+        Arc::new(GravityHandler::new()),
+      ));
+    }
+  }
+  handlers
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::world::region::RegionStorage;
+  use crate::world::Chunk;
+
+  #[test]
+  fn random_positions_are_deterministic() {
+    let chunk_pos = BlockPos::new(-3, 7, 1);
+    let at = |seed: u64, time: u64| {
+      let mut ticks = TickScheduler::new(seed);
+      ticks.set_time(time);
+      ticks.random_positions(&chunk_pos)
+    };
+    let positions = at(42, 100);
+    assert_eq!(positions, at(42, 100));
+    assert_eq!(positions.len(), 3);
+    assert!(positions
+      .iter()
+      .all(|pos| { pos.chunk_pos() == chunk_pos }));
+    assert_ne!(positions, at(42, 101));
+    assert_ne!(positions, at(43, 100));
+  }
+
+  #[test]
+  fn pop_due_order_and_limit() {
+    let mut ticks = TickScheduler::new(0);
+    let pos = |x| BlockPos::new(x, 0, 0);
+    // (x, 遅延, 優先度)
+    for (x, delay, priority) in [
+      (0, 2, 0),
+      (1, 1, 5),
+      (2, 1, -1),
+      (3, 1, 5),
+      (4, 3, -9),
+    ] {
+      assert!(ticks.schedule(
+        pos(x),
+        1,
+        delay,
+        priority
+      ));
+    }
+    assert!(ticks.pop_due().is_empty());
+    ticks.advance();
+    let order = |due: Vec<ScheduledTick>| {
+      due
+        .iter()
+        .map(|tick| tick.pos.get_x())
+        .collect::<Vec<_>>()
+    };
+    // 同じ予定時刻と優先度は予約順
+    assert_eq!(
+      order(ticks.pop_due()),
+      [2, 1, 3]
+    );
+    ticks.advance();
+    ticks.advance();
+    ticks.max_scheduled_per_tick = 1;
+    assert_eq!(order(ticks.pop_due()), [0]);
+    assert_eq!(order(ticks.pop_due()), [4]);
+    assert!(ticks.is_empty());
+  }
+
+  #[test]
+  fn schedule_is_deduplicated() {
+    let mut ticks = TickScheduler::new(0);
+    let pos = BlockPos::new(1, 2, 3);
+    assert!(ticks.schedule(pos, 5, 10, 0));
+    assert!(!ticks.schedule(pos, 5, 1, -1));
+    assert!(ticks.schedule(pos, 6, 10, 0));
+    assert_eq!(ticks.len(), 2);
+    assert!(ticks.is_scheduled(&pos, 5));
+    // 実行後は同じ更新を予約し直せる
+    ticks.set_time(10);
+    assert_eq!(ticks.pop_due().len(), 2);
+    assert!(!ticks.is_scheduled(&pos, 5));
+    assert!(ticks.schedule(pos, 5, 10, 0));
+  }
+
+  #[test]
+  fn pending_tick_survives_unload() {
+    let dir = std::env::temp_dir().join(format!(
+      "voxtech-tick-{}",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut world = World::new();
+    world
+      .set_storage(RegionStorage::new(&dir).unwrap());
+    let chunk_pos = BlockPos::new(0, 0, 0);
+    world.spawn_chunk(chunk_pos, Chunk::empty_chunk);
+    let pos = BlockPos::new(4, 5, 6);
+    assert!(world.schedule_tick(&pos, 7, 10, 2));
+    for _ in 0..4 {
+      world.ticks_mut().advance();
+    }
+    assert!(world
+      .save_chunk(&chunk_pos)
+      .unwrap());
+    assert!(world
+      .unload_chunk(&chunk_pos)
+      .unwrap());
+    assert!(world.ticks().is_empty());
+    // 解放されていた間の時間は残り時間に数えない
+    for _ in 0..100 {
+      world.ticks_mut().advance();
+    }
+    assert!(world
+      .load_chunk(&chunk_pos)
+      .unwrap());
+    assert!(world
+      .ticks()
+      .is_scheduled(&pos, 7));
+    assert_eq!(
+      world
+        .ticks()
+        .chunk_records(&chunk_pos),
+      [TickRecord {
+        pos: pos.as_array(),
+        block: 7,
+        delay: 6,
+        priority: 2,
+      }]
+    );
+    for _ in 0..5 {
+      world.ticks_mut().advance();
+    }
+    assert!(world
+      .ticks_mut()
+      .pop_due()
+      .is_empty());
+    world.ticks_mut().advance();
+    let due = world.ticks_mut().pop_due();
+    assert_eq!(due.len(), 1);
+    assert_eq!(
+      (due[0].pos, due[0].block),
+      (pos, 7)
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
@@ -0,0 +1,537 @@
+//! Tree64
+//! 任意の深さを持つ疎な64分木のボクセル構造
+
+use crate::types::{BlockPos, Tree64InnerPos};
+
+/// 64分木のノード
+///
+/// 子が全て同じ値の葉であれば一つの葉へ畳み込まれる。
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tree64Node<T> {
+  /// 配下の領域全体が同じ値を持つ葉
+  Leaf(T),
+  /// 4x4x4の子ノードを持つ枝
+  Branch(Box<[Tree64Node<T>; 64]>),
+}
+impl<T: Clone + PartialEq> Tree64Node<T> {
+  /// 葉を同じ値を持つ64個の子へ展開する
+  fn expand(&mut self) {
+    if let Self::Leaf(value) = self {
+      let value = value.clone();
+      *self =
+        Self::Branch(Box::new(std::array::from_fn(
+          |_| Self::Leaf(value.clone()),
+        )));
+    }
+  }
+
+  /// 子が全て同じ値の葉であれば一つの葉へ畳み込む
+  fn collapse(&mut self) {
+    let Self::Branch(children) = self else {
+      return;
+    };
+    let Self::Leaf(first) = &children[0] else {
+      return;
+    };
+    let uniform = children[1..].iter().all(
+      |c| matches!(c, Self::Leaf(v) if v == first),
+    );
+    if uniform {
+      *self = Self::Leaf(first.clone());
+    }
+  }
+
+  /// 配下のノード数
+  fn node_count(&self) -> usize {
+    match self {
+      Self::Leaf(_) => 1,
+      Self::Branch(children) => {
+        1 + children
+          .iter()
+          .map(|c| c.node_count())
+          .sum::<usize>()
+      }
+    }
+  }
+}
+
+/// 疎な64分木
+///
+/// 深さ`depth`の木は各軸`4^depth`ブロックの立方体を表し、
+/// 原点を`(0, 0, 0)`とする。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tree64<T> {
+  depth: u8,
+  root: Tree64Node<T>,
+}
+impl<T: Clone + PartialEq> Tree64<T> {
+  /// 木の最大の深さ(各軸2^62ブロック)
+  pub const MAX_DEPTH: u8 = 31;
+
+  /// 全体が`value`で埋められた木を生成する
+  pub fn new(depth: u8, value: T) -> Self {
+    assert!(
+      depth <= Self::MAX_DEPTH,
+      "Tree64 depth must be <= {}",
+      Self::MAX_DEPTH
+    );
+    Self {
+      depth,
+      root: Tree64Node::Leaf(value),
+    }
+  }
+
+  #[inline]
+  pub fn depth(&self) -> u8 {
+    self.depth
+  }
+
+  /// 各軸の大きさ
+  #[inline]
+  pub fn size(&self) -> i64 {
+    1 << (self.depth * 2)
+  }
+
+  #[inline]
+  pub fn root(&self) -> &Tree64Node<T> {
+    &self.root
+  }
+
+  /// 座標が木の範囲内にあるか
+  #[inline]
+  pub fn contains(&self, pos: &BlockPos) -> bool {
+    let size = self.size();
+    pos
+      .as_array()
+      .iter()
+      .all(|c| (0..size).contains(c))
+  }
+
+  /// 指定の階層における子ノードの番号
+  #[inline]
+  fn child_index(pos: &BlockPos, level: u8) -> usize {
+    let (inner, _) = pos
+      .down_level(level)
+      .split_innerpos();
+    inner.index()
+  }
+
+  /// 座標の値を取得する
+  pub fn get(&self, pos: &BlockPos) -> Option<&T> {
+    if !self.contains(pos) {
+      return None;
+    }
+    let mut node = &self.root;
+    let mut level = self.depth;
+    loop {
+      match node {
+        Tree64Node::Leaf(value) => return Some(value),
+        Tree64Node::Branch(children) => {
+          level -= 1;
+          node =
+            &children[Self::child_index(pos, level)];
+        }
+      }
+    }
+  }
+
+  /// 座標を含む葉を取得する
+  /// 葉の領域は同じ値で埋められている
+  pub fn leaf(
+    &self,
+    pos: &BlockPos,
+  ) -> Option<Tree64Leaf<'_, T>> {
+    if !self.contains(pos) {
+      return None;
+    }
+    let mut node = &self.root;
+    let mut level = self.depth;
+    loop {
+      match node {
+        Tree64Node::Leaf(value) => {
+          return Some(Tree64Leaf {
+            origin: pos
+              .down_level(level)
+              .up_level(level),
+            level,
+            value,
+          });
+        }
+        Tree64Node::Branch(children) => {
+          level -= 1;
+          node =
+            &children[Self::child_index(pos, level)];
+        }
+      }
+    }
+  }
+
+  /// 座標に値を設定し、元の値を返す
+  /// 範囲外の座標の場合は何もせず`None`を返す
+  pub fn set(
+    &mut self,
+    pos: &BlockPos,
+    value: T,
+  ) -> Option<T> {
+    if !self.contains(pos) {
+      return None;
+    }
+    Some(Self::set_node(
+      &mut self.root,
+      pos,
+      self.depth,
+      value,
+    ))
+  }
+
+  fn set_node(
+    node: &mut Tree64Node<T>,
+    pos: &BlockPos,
+    level: u8,
+    value: T,
+  ) -> T {
+    if let Tree64Node::Leaf(old) = node {
+      if level == 0 {
+        return std::mem::replace(old, value);
+      }
+      if *old == value {
+        return value;
+      }
+      node.expand();
+    }
+    let Tree64Node::Branch(children) = node else {
+      unreachable!()
+    };
+    let old = Self::set_node(
+      &mut children[Self::child_index(pos, level - 1)],
+      pos,
+      level - 1,
+      value,
+    );
+    node.collapse();
+    old
+  }
+
+  /// 半開区間`[min, max)`の領域を`value`で埋める
+  /// 範囲外の部分は無視される
+  pub fn fill(
+    &mut self,
+    min: &BlockPos,
+    max: &BlockPos,
+    value: T,
+  ) {
+    let size = self.size();
+    let min = min
+      .as_array()
+      .map(|c| c.clamp(0, size));
+    let max = max
+      .as_array()
+      .map(|c| c.clamp(0, size));
+    if (0..3).any(|i| max[i] <= min[i]) {
+      return;
+    }
+    Self::fill_node(
+      &mut self.root,
+      BlockPos::new(0, 0, 0),
+      self.depth,
+      &min,
+      &max,
+      &value,
+    );
+  }
+
+  /// `node_pos`はそのノードの大きさを単位とする座標
+  fn fill_node(
+    node: &mut Tree64Node<T>,
+    node_pos: BlockPos,
+    level: u8,
+    min: &[i64; 3],
+    max: &[i64; 3],
+    value: &T,
+  ) {
+    let origin = node_pos
+      .up_level(level)
+      .as_array();
+    let size = 1i64 << (level * 2);
+    let mut inside = true;
+    for i in 0..3 {
+      let (lo, hi) = (origin[i], origin[i] + size);
+      if hi <= min[i] || max[i] <= lo {
+        return;
+      }
+      inside &= min[i] <= lo && hi <= max[i];
+    }
+    if inside {
+      *node = Tree64Node::Leaf(value.clone());
+      return;
+    }
+    if matches!(node, Tree64Node::Leaf(v) if v == value)
+    {
+      return;
+    }
+    node.expand();
+    let Tree64Node::Branch(children) = node else {
+      unreachable!()
+    };
+    for (i, child) in children.iter_mut().enumerate() {
+      Self::fill_node(
+        child,
+        node_pos.insert_innerpos(Tree64InnerPos::new(
+          i as u8,
+        )),
+        level - 1,
+        min,
+        max,
+        value,
+      );
+    }
+    node.collapse();
+  }
+
+  /// 木を構成するノードの総数
+  pub fn node_count(&self) -> usize {
+    self.root.node_count()
+  }
+
+  /// 全ての葉を走査する
+  pub fn leaves(&self) -> Tree64Leaves<'_, T> {
+    Tree64Leaves {
+      stack: vec![(
+        &self.root,
+        BlockPos::new(0, 0, 0),
+        self.depth,
+      )],
+    }
+  }
+}
+impl<T: Clone + PartialEq + Default> Tree64<T> {
+  /// 既定値以外の値を持つ葉を走査する
+  pub fn iter(
+    &self,
+  ) -> impl Iterator<Item = Tree64Leaf<'_, T>> {
+    let empty = T::default();
+    self
+      .leaves()
+      .filter(move |leaf| *leaf.value != empty)
+  }
+}
+
+/// 走査で得られる葉
+#[derive(Debug, Clone, Copy)]
+pub struct Tree64Leaf<'a, T> {
+  /// 葉の領域の最小座標
+  pub origin: BlockPos,
+  /// 葉の階層(各軸`4^level`ブロック)
+  pub level: u8,
+  pub value: &'a T,
+}
+impl<T> Tree64Leaf<'_, T> {
+  /// 各軸の大きさ
+  #[inline]
+  pub fn size(&self) -> i64 {
+    1 << (self.level * 2)
+  }
+}
+
+/// 葉の走査子
+pub struct Tree64Leaves<'a, T> {
+  stack: Vec<(&'a Tree64Node<T>, BlockPos, u8)>,
+}
+impl<'a, T> Iterator for Tree64Leaves<'a, T> {
+  type Item = Tree64Leaf<'a, T>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    while let Some((node, node_pos, level)) =
+      self.stack.pop()
+    {
+      match node {
+        Tree64Node::Leaf(value) => {
+          return Some(Tree64Leaf {
+            origin: node_pos.up_level(level),
+            level,
+            value,
+          });
+        }
+        Tree64Node::Branch(children) => {
+          self.stack.extend(
+            children
+              .iter()
+              .enumerate()
+              .rev()
+              .map(|(i, child)| {
+                (
+                  child,
+                  node_pos.insert_innerpos(
+                    Tree64InnerPos::new(i as u8),
+                  ),
+                  level - 1,
+                )
+              }),
+          );
+        }
+      }
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn get_set() {
+    let mut tree = Tree64::new(3, 0u16);
+    assert_eq!(tree.size(), 64);
+    let positions = [
+      BlockPos::new(0, 0, 0),
+      BlockPos::new(63, 63, 63),
+      BlockPos::new(5, 17, 42),
+      BlockPos::new(4, 0, 0),
+    ];
+    for (i, pos) in positions.iter().enumerate() {
+      assert_eq!(
+        tree.set(pos, i as u16 + 1),
+        Some(0)
+      );
+    }
+    for (i, pos) in positions.iter().enumerate() {
+      assert_eq!(
+        tree.get(pos),
+        Some(&(i as u16 + 1))
+      );
+    }
+    assert_eq!(
+      tree.get(&BlockPos::new(1, 0, 0)),
+      Some(&0)
+    );
+    assert_eq!(
+      tree.set(&positions[2], 9),
+      Some(3)
+    );
+    assert_eq!(
+      tree.get(&positions[2]),
+      Some(&9)
+    );
+  }
+
+  #[test]
+  fn out_of_range() {
+    let mut tree = Tree64::new(1, 0u8);
+    for pos in [
+      BlockPos::new(-1, 0, 0),
+      BlockPos::new(0, 4, 0),
+      BlockPos::new(0, 0, 100),
+    ] {
+      assert_eq!(tree.get(&pos), None);
+      assert_eq!(tree.set(&pos, 1), None);
+    }
+    assert_eq!(tree.node_count(), 1);
+  }
+
+  #[test]
+  fn collapse() {
+    let mut tree = Tree64::new(2, 0u8);
+    let pos = BlockPos::new(7, 2, 13);
+    tree.set(&pos, 1);
+    assert_eq!(tree.node_count(), 1 + 64 + 64);
+    tree.set(&pos, 0);
+    assert_eq!(
+      tree.root(),
+      &Tree64Node::Leaf(0)
+    );
+
+    for x in 0..4 {
+      for y in 0..4 {
+        for z in 0..4 {
+          tree.set(&BlockPos::new(x, y, z), 1);
+        }
+      }
+    }
+    assert_eq!(tree.node_count(), 1 + 64);
+    assert_eq!(
+      tree
+        .leaves()
+        .filter(|leaf| *leaf.value == 1)
+        .count(),
+      1
+    );
+  }
+
+  #[test]
+  fn leaf() {
+    let mut tree = Tree64::new(3, 0u8);
+    tree.set(&BlockPos::new(17, 1, 2), 1);
+    let leaf =
+      tree.leaf(&BlockPos::new(40, 50, 60)).unwrap();
+    assert_eq!(leaf.origin, BlockPos::new(32, 48, 48));
+    assert_eq!(leaf.size(), 16);
+    assert_eq!(*leaf.value, 0);
+    let leaf =
+      tree.leaf(&BlockPos::new(22, 2, 3)).unwrap();
+    assert_eq!(leaf.origin, BlockPos::new(20, 0, 0));
+    assert_eq!(leaf.size(), 4);
+    let leaf =
+      tree.leaf(&BlockPos::new(17, 1, 2)).unwrap();
+    assert_eq!(leaf.size(), 1);
+    assert_eq!(*leaf.value, 1);
+    assert!(
+      tree
+        .leaf(&BlockPos::new(-1, 0, 0))
+        .is_none()
+    );
+  }
+
+  #[test]
+  fn fill() {
+    let mut tree = Tree64::new(2, 0u8);
+    tree.fill(
+      &BlockPos::new(2, 0, 0),
+      &BlockPos::new(9, 4, 1),
+      1,
+    );
+    for x in 0..16 {
+      for y in 0..16 {
+        for z in 0..16 {
+          let pos = BlockPos::new(x, y, z);
+          let inside = (2..9).contains(&x)
+            && (0..4).contains(&y)
+            && z == 0;
+          assert_eq!(
+            tree.get(&pos),
+            Some(&(inside as u8))
+          );
+        }
+      }
+    }
+    assert_eq!(
+      tree
+        .iter()
+        .map(|leaf| leaf.size().pow(3))
+        .sum::<i64>(),
+      7 * 4
+    );
+
+    tree.fill(
+      &BlockPos::new(-5, -5, -5),
+      &BlockPos::new(100, 100, 100),
+      2,
+    );
+    assert_eq!(
+      tree.root(),
+      &Tree64Node::Leaf(2)
+    );
+    tree.fill(
+      &BlockPos::new(4, 4, 4),
+      &BlockPos::new(8, 8, 8),
+      3,
+    );
+    assert_eq!(tree.node_count(), 1 + 64);
+    tree.fill(
+      &BlockPos::new(4, 4, 4),
+      &BlockPos::new(8, 8, 8),
+      2,
+    );
+    assert_eq!(tree.node_count(), 1);
+  }
+}